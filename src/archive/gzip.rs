@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crc::crc32;
+
+use crate::archive::ArchiveMember;
+use crate::inflate::{self, InflateError};
+
+pub const GZIP_FORMAT_MARKER: [u8; 3] = [0x1F, 0x8B, 0x08];
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+
+const GZIP_FLAG_HEADER_CRC: u8 = 0x02;
+const GZIP_FLAG_EXTRA: u8 = 0x04;
+const GZIP_FLAG_NAME: u8 = 0x08;
+const GZIP_FLAG_COMMENT: u8 = 0x10;
+
+#[derive(Debug)]
+pub enum GzipError {
+    Truncated,
+    FormatMarker,
+    Inflate(InflateError),
+    Checksum { expected: u32, received: u32 },
+    Size { expected: u32, received: u32 },
+}
+
+impl fmt::Display for GzipError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GzipError::Truncated => write!(formatter, "truncated gzip stream"),
+            GzipError::FormatMarker => write!(formatter, "invalid gzip format marker"),
+            GzipError::Inflate(error) => write!(formatter, "failed to decompress: {}", error),
+            GzipError::Checksum { expected, received } => write!(
+                formatter,
+                "invalid gzip checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+            GzipError::Size { expected, received } => write!(
+                formatter,
+                "invalid gzip size (expected: {}, received: {})",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl Error for GzipError {}
+
+// Skips a null-terminated header field
+fn skip_string(data: &[u8], offset: usize) -> Result<usize, GzipError> {
+    let length = data
+        .get(offset..)
+        .and_then(|field| field.iter().position(|&byte| byte == 0))
+        .ok_or(GzipError::Truncated)?;
+    Ok(offset + length + 1)
+}
+
+// Decompresses a single gzip member, returning its data, its modification time and the size of
+// the compressed member
+fn decompress_member(data: &[u8]) -> Result<(Vec<u8>, Option<SystemTime>, usize), GzipError> {
+    let header = data.get(..GZIP_HEADER_SIZE).ok_or(GzipError::Truncated)?;
+    if !header.starts_with(&GZIP_FORMAT_MARKER) {
+        return Err(GzipError::FormatMarker);
+    }
+
+    let flags = header[3];
+    let modified = match u32::from_le_bytes([header[4], header[5], header[6], header[7]]) {
+        0 => None,
+        seconds => Some(UNIX_EPOCH + Duration::from_secs(seconds as u64)),
+    };
+
+    let mut offset = GZIP_HEADER_SIZE;
+    if flags & GZIP_FLAG_EXTRA != 0 {
+        let size = data.get(offset..(offset + 2)).ok_or(GzipError::Truncated)?;
+        offset += 2 + u16::from_le_bytes([size[0], size[1]]) as usize;
+    }
+    if flags & GZIP_FLAG_NAME != 0 {
+        offset = skip_string(data, offset)?;
+    }
+    if flags & GZIP_FLAG_COMMENT != 0 {
+        offset = skip_string(data, offset)?;
+    }
+    if flags & GZIP_FLAG_HEADER_CRC != 0 {
+        offset += 2;
+    }
+
+    let (member_data, compressed_size) =
+        inflate::inflate(data.get(offset..).ok_or(GzipError::Truncated)?).map_err(GzipError::Inflate)?;
+    offset += compressed_size;
+
+    let trailer = data
+        .get(offset..(offset + GZIP_TRAILER_SIZE))
+        .ok_or(GzipError::Truncated)?;
+    let expected_checksum = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let expected_size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+    let checksum = crc32::checksum_ieee(&member_data);
+    if checksum != expected_checksum {
+        return Err(GzipError::Checksum {
+            expected: expected_checksum,
+            received: checksum,
+        });
+    }
+
+    // The size is stored modulo 2^32
+    if member_data.len() as u32 != expected_size {
+        return Err(GzipError::Size {
+            expected: expected_size,
+            received: member_data.len() as u32,
+        });
+    }
+
+    Ok((member_data, modified, offset + GZIP_TRAILER_SIZE))
+}
+
+// Gzip files hold a single file, named after the gzip file itself. Concatenated gzip members are
+// decompressed as one file.
+pub fn extract(
+    data: &[u8],
+    name: &OsStr,
+    modified: SystemTime,
+    wanted: impl Fn(&OsStr) -> bool,
+) -> Result<Vec<Result<ArchiveMember, GzipError>>, GzipError> {
+    if !wanted(name) {
+        return Ok(Vec::new());
+    }
+
+    let (mut member_data, member_modified, mut offset) = decompress_member(data)?;
+
+    while offset < data.len() {
+        let (next_data, _, next_size) = decompress_member(&data[offset..])?;
+        member_data.extend_from_slice(&next_data);
+        offset += next_size;
+    }
+
+    Ok(vec![Ok(ArchiveMember {
+        name: name.to_owned(),
+        modified: member_modified.unwrap_or(modified),
+        data: member_data,
+    })])
+}
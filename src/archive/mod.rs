@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::utils::MappedFile;
+
+pub mod gzip;
+pub mod sevenzip;
+pub mod zip;
+
+// File extracted from an archive, along with the modification time recorded in the archive
+pub struct ArchiveMember {
+    pub name: OsString,
+    pub modified: SystemTime,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    Gzip,
+    SevenZip,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn detect(path: &Path) -> io::Result<Option<ArchiveFormat>> {
+        let mut format_marker = Vec::new();
+        File::open(path)?.take(8).read_to_end(&mut format_marker)?;
+
+        if format_marker.starts_with(&gzip::GZIP_FORMAT_MARKER) {
+            Ok(Some(ArchiveFormat::Gzip))
+        } else if format_marker.starts_with(&sevenzip::SEVENZIP_FORMAT_MARKER) {
+            Ok(Some(ArchiveFormat::SevenZip))
+        } else if format_marker.starts_with(&zip::ZIP_FORMAT_MARKER) {
+            Ok(Some(ArchiveFormat::Zip))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Compressed files hold a single file named after them instead of a tree of members
+    pub fn is_compressed_file(self) -> bool {
+        matches!(self, ArchiveFormat::Gzip)
+    }
+
+    // Members are only extracted when `wanted` accepts their names. Members failing to
+    // extract are reported individually.
+    #[allow(clippy::type_complexity)]
+    pub fn extract(
+        self,
+        path: &Path,
+        wanted: impl Fn(&OsStr) -> bool,
+    ) -> Result<Vec<Result<ArchiveMember, Box<dyn Error>>>, Box<dyn Error>> {
+        let data = MappedFile::open(path)?;
+
+        match self {
+            ArchiveFormat::Gzip => {
+                let name = path.file_stem().unwrap_or_default();
+                Ok(gzip::extract(&data, name, fs::metadata(path)?.modified()?, wanted)?
+                    .into_iter()
+                    .map(|member| member.map_err(Into::into))
+                    .collect())
+            }
+            ArchiveFormat::SevenZip => Ok(sevenzip::extract(&data, fs::metadata(path)?.modified()?, wanted)?
+                .into_iter()
+                .map(|member| member.map_err(Into::into))
+                .collect()),
+            ArchiveFormat::Zip => Ok(zip::extract(&data, wanted)?
+                .into_iter()
+                .map(|member| member.map_err(Into::into))
+                .collect()),
+        }
+    }
+}
@@ -0,0 +1,935 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crc::crc32;
+
+use crate::archive::ArchiveMember;
+use crate::bzip2::{self, Bzip2Error};
+use crate::inflate::{self, InflateError};
+use crate::lzma::{self, LzmaError};
+
+pub const SEVENZIP_FORMAT_MARKER: [u8; 6] = [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C];
+const SEVENZIP_SIGNATURE_HEADER_SIZE: usize = 32;
+
+const SEVENZIP_ID_END: u8 = 0x00;
+const SEVENZIP_ID_HEADER: u8 = 0x01;
+const SEVENZIP_ID_ARCHIVE_PROPERTIES: u8 = 0x02;
+const SEVENZIP_ID_ADDITIONAL_STREAMS_INFO: u8 = 0x03;
+const SEVENZIP_ID_MAIN_STREAMS_INFO: u8 = 0x04;
+const SEVENZIP_ID_FILES_INFO: u8 = 0x05;
+const SEVENZIP_ID_PACK_INFO: u8 = 0x06;
+const SEVENZIP_ID_UNPACK_INFO: u8 = 0x07;
+const SEVENZIP_ID_SUBSTREAMS_INFO: u8 = 0x08;
+const SEVENZIP_ID_SIZE: u8 = 0x09;
+const SEVENZIP_ID_CRC: u8 = 0x0A;
+const SEVENZIP_ID_FOLDER: u8 = 0x0B;
+const SEVENZIP_ID_CODERS_UNPACK_SIZE: u8 = 0x0C;
+const SEVENZIP_ID_NUM_UNPACK_STREAM: u8 = 0x0D;
+const SEVENZIP_ID_EMPTY_STREAM: u8 = 0x0E;
+const SEVENZIP_ID_EMPTY_FILE: u8 = 0x0F;
+const SEVENZIP_ID_NAME: u8 = 0x11;
+const SEVENZIP_ID_MTIME: u8 = 0x14;
+const SEVENZIP_ID_ENCODED_HEADER: u8 = 0x17;
+
+const SEVENZIP_METHOD_COPY: &[u8] = &[0x00];
+const SEVENZIP_METHOD_LZMA2: &[u8] = &[0x21];
+const SEVENZIP_METHOD_LZMA: &[u8] = &[0x03, 0x01, 0x01];
+const SEVENZIP_METHOD_DEFLATE: &[u8] = &[0x04, 0x01, 0x08];
+const SEVENZIP_METHOD_BZIP2: &[u8] = &[0x04, 0x02, 0x02];
+
+// Seconds between the Windows FILETIME epoch (1601) and the Unix epoch
+const FILETIME_UNIX_EPOCH: u64 = 11_644_473_600;
+
+#[derive(Debug)]
+pub enum SevenZipError {
+    Truncated,
+    Signature,
+    HeaderChecksum {
+        expected: u32,
+        received: u32,
+    },
+    Header {
+        property: u8,
+    },
+    Method {
+        names: Vec<String>,
+        method: String,
+    },
+    Lzma {
+        names: Vec<String>,
+        error: LzmaError,
+    },
+    Bzip2 {
+        names: Vec<String>,
+        error: Bzip2Error,
+    },
+    Inflate {
+        names: Vec<String>,
+        error: InflateError,
+    },
+    Size {
+        names: Vec<String>,
+        expected: usize,
+        received: usize,
+    },
+    Checksum {
+        names: Vec<String>,
+        expected: u32,
+        received: u32,
+    },
+}
+
+// Members of a solid block are decompressed together, they fail together too
+struct Names<'a>(&'a [String]);
+
+impl fmt::Display for Names<'_> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(formatter, "the archive header");
+        }
+
+        for (index, name) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(formatter, ", ")?;
+            }
+            write!(formatter, "{:?}", name)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SevenZipError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SevenZipError::Truncated => write!(formatter, "truncated 7z archive"),
+            SevenZipError::Signature => write!(formatter, "invalid 7z signature header"),
+            SevenZipError::HeaderChecksum { expected, received } => write!(
+                formatter,
+                "invalid 7z header checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+            SevenZipError::Header { property } => write!(formatter, "unexpected 7z header property 0x{:02X}", property),
+            SevenZipError::Method { names, method } => {
+                write!(
+                    formatter,
+                    "unsupported compression method {} of {}",
+                    method,
+                    Names(names)
+                )
+            }
+            SevenZipError::Lzma { names, error } => {
+                write!(formatter, "failed to decompress {}: {}", Names(names), error)
+            }
+            SevenZipError::Bzip2 { names, error } => {
+                write!(formatter, "failed to decompress {}: {}", Names(names), error)
+            }
+            SevenZipError::Inflate { names, error } => {
+                write!(formatter, "failed to decompress {}: {}", Names(names), error)
+            }
+            SevenZipError::Size {
+                names,
+                expected,
+                received,
+            } => write!(
+                formatter,
+                "invalid decompressed size of {} (expected: {}, received: {})",
+                Names(names),
+                expected,
+                received
+            ),
+            SevenZipError::Checksum {
+                names,
+                expected,
+                received,
+            } => write!(
+                formatter,
+                "invalid checksum of {} (expected: 0x{:08X}, received: 0x{:08X})",
+                Names(names),
+                expected,
+                received
+            ),
+        }
+    }
+}
+
+impl Error for SevenZipError {}
+
+fn method_name(method: &[u8]) -> String {
+    match method {
+        SEVENZIP_METHOD_COPY => "Copy".to_owned(),
+        SEVENZIP_METHOD_LZMA2 => "LZMA2".to_owned(),
+        SEVENZIP_METHOD_LZMA => "LZMA".to_owned(),
+        SEVENZIP_METHOD_DEFLATE => "Deflate".to_owned(),
+        SEVENZIP_METHOD_BZIP2 => "BZip2".to_owned(),
+        [0x03] => "Delta".to_owned(),
+        [0x03, 0x03, 0x01, 0x03] => "BCJ".to_owned(),
+        [0x03, 0x03, 0x01, 0x1B] => "BCJ2".to_owned(),
+        [0x03, 0x04, 0x01] => "PPMd".to_owned(),
+        [0x06, 0xF1, 0x07, 0x01] => "AES-256".to_owned(),
+        _ => method.iter().map(|byte| format!("{:02X}", byte)).collect(),
+    }
+}
+
+struct HeaderReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> HeaderReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, SevenZipError> {
+        let byte = *self.data.get(self.offset).ok_or(SevenZipError::Truncated)?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], SevenZipError> {
+        let bytes = self
+            .data
+            .get(self.offset..(self.offset.checked_add(count).ok_or(SevenZipError::Truncated)?))
+            .ok_or(SevenZipError::Truncated)?;
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, SevenZipError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, SevenZipError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // The leading one bits of the first byte give the count of the little-endian bytes
+    // following it, the remaining bits of the first byte are the most significant ones
+    fn read_number(&mut self) -> Result<usize, SevenZipError> {
+        let first_byte = self.read_u8()?;
+        let mut value: u64 = 0;
+
+        for index in 0..8 {
+            let mask = 0x80 >> index;
+            if first_byte & mask == 0 {
+                value |= ((first_byte & (mask - 1)) as u64) << (8 * index);
+                break;
+            }
+            value |= (self.read_u8()? as u64) << (8 * index);
+        }
+
+        usize::try_from(value).map_err(|_| SevenZipError::Truncated)
+    }
+
+    // Counts of header items, every item takes at least one byte of the header
+    fn read_count(&mut self) -> Result<usize, SevenZipError> {
+        let count = self.read_number()?;
+        if count > self.data.len() - self.offset {
+            return Err(SevenZipError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn read_bits(&mut self, count: usize) -> Result<Vec<bool>, SevenZipError> {
+        let bytes = self.read_bytes(count.div_ceil(8))?;
+        Ok((0..count)
+            .map(|index| bytes[index / 8] & (0x80 >> (index % 8)) != 0)
+            .collect())
+    }
+
+    // Bit vectors are preceded by a flag byte set when every bit would be set
+    fn read_defined(&mut self, count: usize) -> Result<Vec<bool>, SevenZipError> {
+        if self.read_u8()? != 0 {
+            Ok(vec![true; count])
+        } else {
+            self.read_bits(count)
+        }
+    }
+
+    fn read_checksums(&mut self, count: usize) -> Result<Vec<Option<u32>>, SevenZipError> {
+        self.read_defined(count)?
+            .into_iter()
+            .map(|defined| if defined { self.read_u32().map(Some) } else { Ok(None) })
+            .collect()
+    }
+
+    fn expect(&mut self, property: u8) -> Result<(), SevenZipError> {
+        match self.read_u8()? {
+            byte if byte == property => Ok(()),
+            byte => Err(SevenZipError::Header { property: byte }),
+        }
+    }
+}
+
+struct Coder {
+    method: Vec<u8>,
+    properties: Vec<u8>,
+    in_streams: usize,
+    out_streams: usize,
+}
+
+// Folders are chains of coders decompressing one or more packed streams into a single
+// output stream, solid archives store several files in the same folder
+struct Folder {
+    coders: Vec<Coder>,
+    packed_stream_count: usize,
+    unpack_sizes: Vec<usize>,
+    checksum: Option<u32>,
+}
+
+impl Folder {
+    fn method_name(&self) -> String {
+        self.coders
+            .iter()
+            .map(|coder| method_name(&coder.method))
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+
+    // The output stream not bound to the input of another coder
+    fn unpack_size(&self) -> usize {
+        self.unpack_sizes.last().copied().unwrap_or(0)
+    }
+}
+
+#[derive(Default)]
+struct StreamsInfo {
+    pack_position: usize,
+    pack_sizes: Vec<usize>,
+    folders: Vec<Folder>,
+    substream_sizes: Vec<Vec<usize>>,
+    substream_checksums: Vec<Vec<Option<u32>>>,
+}
+
+fn read_folder(reader: &mut HeaderReader) -> Result<Folder, SevenZipError> {
+    let coder_count = reader.read_count()?;
+    let mut coders = Vec::with_capacity(coder_count);
+
+    for _ in 0..coder_count {
+        let flags = reader.read_u8()?;
+        let method = reader.read_bytes((flags & 0x0F) as usize)?.to_vec();
+        let (in_streams, out_streams) = if flags & 0x10 != 0 {
+            (reader.read_count()?, reader.read_count()?)
+        } else {
+            (1, 1)
+        };
+        let properties = if flags & 0x20 != 0 {
+            let size = reader.read_number()?;
+            reader.read_bytes(size)?.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        // Alternative methods were never written by any known archiver
+        if flags & 0x80 != 0 {
+            return Err(SevenZipError::Header { property: flags });
+        }
+
+        coders.push(Coder {
+            method,
+            properties,
+            in_streams,
+            out_streams,
+        });
+    }
+
+    let out_stream_count: usize = coders.iter().map(|coder| coder.out_streams).sum();
+    let in_stream_count: usize = coders.iter().map(|coder| coder.in_streams).sum();
+    let bind_pair_count = out_stream_count.saturating_sub(1);
+    for _ in 0..bind_pair_count {
+        let _in_index = reader.read_number()?;
+        let _out_index = reader.read_number()?;
+    }
+
+    let packed_stream_count = in_stream_count
+        .checked_sub(bind_pair_count)
+        .ok_or(SevenZipError::Header {
+            property: SEVENZIP_ID_FOLDER,
+        })?;
+    if packed_stream_count > 1 {
+        for _ in 0..packed_stream_count {
+            let _packed_stream_index = reader.read_number()?;
+        }
+    }
+
+    Ok(Folder {
+        coders,
+        packed_stream_count,
+        unpack_sizes: vec![0; out_stream_count],
+        checksum: None,
+    })
+}
+
+fn read_pack_info(reader: &mut HeaderReader, streams_info: &mut StreamsInfo) -> Result<(), SevenZipError> {
+    streams_info.pack_position = reader.read_number()?;
+    let pack_stream_count = reader.read_count()?;
+
+    loop {
+        match reader.read_u8()? {
+            SEVENZIP_ID_END => return Ok(()),
+            SEVENZIP_ID_SIZE => {
+                streams_info.pack_sizes = (0..pack_stream_count)
+                    .map(|_| reader.read_number())
+                    .collect::<Result<_, _>>()?;
+            }
+            SEVENZIP_ID_CRC => {
+                reader.read_checksums(pack_stream_count)?;
+            }
+            property => return Err(SevenZipError::Header { property }),
+        }
+    }
+}
+
+fn read_unpack_info(reader: &mut HeaderReader, streams_info: &mut StreamsInfo) -> Result<(), SevenZipError> {
+    reader.expect(SEVENZIP_ID_FOLDER)?;
+    let folder_count = reader.read_count()?;
+
+    // External folders are stored in additional streams, no archiver writes them
+    if reader.read_u8()? != 0 {
+        return Err(SevenZipError::Header {
+            property: SEVENZIP_ID_FOLDER,
+        });
+    }
+
+    streams_info.folders = (0..folder_count)
+        .map(|_| read_folder(reader))
+        .collect::<Result<_, _>>()?;
+
+    reader.expect(SEVENZIP_ID_CODERS_UNPACK_SIZE)?;
+    for folder in &mut streams_info.folders {
+        for unpack_size in &mut folder.unpack_sizes {
+            *unpack_size = reader.read_number()?;
+        }
+    }
+
+    loop {
+        match reader.read_u8()? {
+            SEVENZIP_ID_END => return Ok(()),
+            SEVENZIP_ID_CRC => {
+                for (folder, checksum) in streams_info
+                    .folders
+                    .iter_mut()
+                    .zip(reader.read_checksums(folder_count)?)
+                {
+                    folder.checksum = checksum;
+                }
+            }
+            property => return Err(SevenZipError::Header { property }),
+        }
+    }
+}
+
+fn read_substreams_info(reader: &mut HeaderReader, streams_info: &mut StreamsInfo) -> Result<(), SevenZipError> {
+    let mut substream_counts = vec![1; streams_info.folders.len()];
+    let mut property = reader.read_u8()?;
+
+    if property == SEVENZIP_ID_NUM_UNPACK_STREAM {
+        for substream_count in &mut substream_counts {
+            *substream_count = reader.read_count()?;
+        }
+        property = reader.read_u8()?;
+    }
+
+    // The size of the last substream is what remains of the folder
+    streams_info.substream_sizes = Vec::with_capacity(streams_info.folders.len());
+    for (folder, &substream_count) in streams_info.folders.iter().zip(&substream_counts) {
+        let mut sizes = Vec::with_capacity(substream_count);
+        if substream_count > 0 {
+            if property == SEVENZIP_ID_SIZE {
+                for _ in 1..substream_count {
+                    sizes.push(reader.read_number()?);
+                }
+            }
+
+            let size_error = || SevenZipError::Header {
+                property: SEVENZIP_ID_SIZE,
+            };
+            let total = sizes
+                .iter()
+                .try_fold(0usize, |total, &size| total.checked_add(size))
+                .ok_or_else(size_error)?;
+            sizes.push(folder.unpack_size().checked_sub(total).ok_or_else(size_error)?);
+        }
+        streams_info.substream_sizes.push(sizes);
+    }
+
+    if property == SEVENZIP_ID_SIZE {
+        property = reader.read_u8()?;
+    }
+
+    // Checksums of single substream folders are the checksums of the folders
+    streams_info.substream_checksums = streams_info
+        .folders
+        .iter()
+        .zip(&substream_counts)
+        .map(|(folder, &substream_count)| match (substream_count, folder.checksum) {
+            (1, Some(checksum)) => vec![Some(checksum)],
+            _ => vec![None; substream_count],
+        })
+        .collect();
+
+    loop {
+        match property {
+            SEVENZIP_ID_END => return Ok(()),
+            SEVENZIP_ID_CRC => {
+                let unknown_count = streams_info
+                    .substream_checksums
+                    .iter()
+                    .flatten()
+                    .filter(|checksum| checksum.is_none())
+                    .count();
+                let mut checksums = reader.read_checksums(unknown_count)?.into_iter();
+
+                for checksum in streams_info.substream_checksums.iter_mut().flatten() {
+                    if checksum.is_none() {
+                        *checksum = checksums.next().flatten();
+                    }
+                }
+            }
+            property => return Err(SevenZipError::Header { property }),
+        }
+        property = reader.read_u8()?;
+    }
+}
+
+fn read_streams_info(reader: &mut HeaderReader) -> Result<StreamsInfo, SevenZipError> {
+    let mut streams_info = StreamsInfo::default();
+    let mut has_substreams_info = false;
+
+    loop {
+        match reader.read_u8()? {
+            SEVENZIP_ID_END => break,
+            SEVENZIP_ID_PACK_INFO => read_pack_info(reader, &mut streams_info)?,
+            SEVENZIP_ID_UNPACK_INFO => read_unpack_info(reader, &mut streams_info)?,
+            SEVENZIP_ID_SUBSTREAMS_INFO => {
+                read_substreams_info(reader, &mut streams_info)?;
+                has_substreams_info = true;
+            }
+            property => return Err(SevenZipError::Header { property }),
+        }
+    }
+
+    // Without substreams every folder holds a single file
+    if !has_substreams_info {
+        streams_info.substream_sizes = streams_info
+            .folders
+            .iter()
+            .map(|folder| vec![folder.unpack_size()])
+            .collect();
+        streams_info.substream_checksums = streams_info
+            .folders
+            .iter()
+            .map(|folder| vec![folder.checksum])
+            .collect();
+    }
+
+    Ok(streams_info)
+}
+
+struct SevenZipEntry {
+    name: String,
+    modified: Option<SystemTime>,
+    has_stream: bool,
+    is_directory: bool,
+}
+
+fn read_files_info(reader: &mut HeaderReader) -> Result<Vec<SevenZipEntry>, SevenZipError> {
+    let file_count = reader.read_count()?;
+    let mut empty_streams = vec![false; file_count];
+    let mut empty_files = Vec::new();
+    let mut names = Vec::new();
+    let mut modification_times = vec![None; file_count];
+
+    loop {
+        let property = reader.read_u8()?;
+        if property == SEVENZIP_ID_END {
+            break;
+        }
+
+        let size = reader.read_number()?;
+        let mut property_reader = HeaderReader::new(reader.read_bytes(size)?);
+
+        match property {
+            SEVENZIP_ID_EMPTY_STREAM => empty_streams = property_reader.read_bits(file_count)?,
+            SEVENZIP_ID_EMPTY_FILE => {
+                empty_files = property_reader.read_bits(empty_streams.iter().filter(|&&empty| empty).count())?
+            }
+            SEVENZIP_ID_NAME => {
+                if property_reader.read_u8()? != 0 {
+                    return Err(SevenZipError::Header { property });
+                }
+
+                // Null-terminated UTF-16LE strings
+                let units: Vec<u16> = property_reader.data[1..]
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                    .collect();
+                names = units
+                    .split(|&unit| unit == 0)
+                    .take(file_count)
+                    .map(String::from_utf16_lossy)
+                    .collect();
+            }
+            SEVENZIP_ID_MTIME => {
+                let defined = property_reader.read_defined(file_count)?;
+                if property_reader.read_u8()? != 0 {
+                    return Err(SevenZipError::Header { property });
+                }
+
+                for (modification_time, defined) in modification_times.iter_mut().zip(defined) {
+                    if defined {
+                        // FILETIME counts 100 nanosecond intervals
+                        let filetime = property_reader.read_u64()?;
+                        *modification_time = (filetime / 10_000_000)
+                            .checked_sub(FILETIME_UNIX_EPOCH)
+                            .map(|seconds| UNIX_EPOCH + Duration::new(seconds, (filetime % 10_000_000) as u32 * 100));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut empty_files = empty_files.into_iter();
+    Ok((0..file_count)
+        .map(|index| {
+            let has_stream = !empty_streams[index];
+            SevenZipEntry {
+                name: names.get(index).cloned().unwrap_or_default(),
+                modified: modification_times[index],
+                has_stream,
+                is_directory: !has_stream && !empty_files.next().unwrap_or(false),
+            }
+        })
+        .collect())
+}
+
+fn decode_folder(
+    data: &[u8],
+    streams_info: &StreamsInfo,
+    folder_index: usize,
+    names: &[String],
+) -> Result<Vec<u8>, SevenZipError> {
+    let folder = &streams_info.folders[folder_index];
+
+    // Only single coder folders are supported, filters like BCJ are only applied to executables
+    let coder = match folder.coders.as_slice() {
+        [coder] if coder.in_streams == 1 && coder.out_streams == 1 && folder.packed_stream_count == 1 => coder,
+        _ => {
+            return Err(SevenZipError::Method {
+                names: names.to_vec(),
+                method: folder.method_name(),
+            })
+        }
+    };
+
+    // Packed streams are stored back to back after the signature header
+    let pack_index: usize = streams_info.folders[..folder_index]
+        .iter()
+        .map(|folder| folder.packed_stream_count)
+        .sum();
+    let pack_offset = streams_info.pack_sizes[..pack_index.min(streams_info.pack_sizes.len())]
+        .iter()
+        .try_fold(
+            SEVENZIP_SIGNATURE_HEADER_SIZE + streams_info.pack_position,
+            |offset, &size| offset.checked_add(size),
+        )
+        .ok_or(SevenZipError::Truncated)?;
+    let pack_size = *streams_info
+        .pack_sizes
+        .get(pack_index)
+        .ok_or(SevenZipError::Truncated)?;
+    let packed_data = data
+        .get(pack_offset..(pack_offset.checked_add(pack_size).ok_or(SevenZipError::Truncated)?))
+        .ok_or(SevenZipError::Truncated)?;
+
+    let unpack_size = folder.unpack_size();
+    let unpacked_data = match coder.method.as_slice() {
+        SEVENZIP_METHOD_COPY => packed_data.to_vec(),
+        SEVENZIP_METHOD_LZMA2 => {
+            lzma::decompress_lzma2(packed_data, unpack_size).map_err(|error| SevenZipError::Lzma {
+                names: names.to_vec(),
+                error,
+            })?
+        }
+        SEVENZIP_METHOD_LZMA => {
+            lzma::decompress_lzma(&coder.properties, packed_data, unpack_size).map_err(|error| SevenZipError::Lzma {
+                names: names.to_vec(),
+                error,
+            })?
+        }
+        SEVENZIP_METHOD_DEFLATE => {
+            inflate::inflate(packed_data)
+                .map_err(|error| SevenZipError::Inflate {
+                    names: names.to_vec(),
+                    error,
+                })?
+                .0
+        }
+        SEVENZIP_METHOD_BZIP2 => bzip2::decompress(packed_data).map_err(|error| SevenZipError::Bzip2 {
+            names: names.to_vec(),
+            error,
+        })?,
+        _ => {
+            return Err(SevenZipError::Method {
+                names: names.to_vec(),
+                method: folder.method_name(),
+            })
+        }
+    };
+
+    if unpacked_data.len() != unpack_size {
+        return Err(SevenZipError::Size {
+            names: names.to_vec(),
+            expected: unpack_size,
+            received: unpacked_data.len(),
+        });
+    }
+
+    if let Some(expected) = folder.checksum {
+        let received = crc32::checksum_ieee(&unpacked_data);
+        if received != expected {
+            return Err(SevenZipError::Checksum {
+                names: names.to_vec(),
+                expected,
+                received,
+            });
+        }
+    }
+
+    Ok(unpacked_data)
+}
+
+fn read_archive(data: &[u8]) -> Result<(StreamsInfo, Vec<SevenZipEntry>), SevenZipError> {
+    let signature_header = data
+        .get(..SEVENZIP_SIGNATURE_HEADER_SIZE)
+        .ok_or(SevenZipError::Truncated)?;
+    if !signature_header.starts_with(&SEVENZIP_FORMAT_MARKER) {
+        return Err(SevenZipError::Signature);
+    }
+
+    let mut start_header_reader = HeaderReader::new(&signature_header[8..]);
+    let start_header_checksum = start_header_reader.read_u32()?;
+    let received = crc32::checksum_ieee(&signature_header[12..]);
+    if received != start_header_checksum {
+        return Err(SevenZipError::HeaderChecksum {
+            expected: start_header_checksum,
+            received,
+        });
+    }
+
+    let next_header_offset = usize::try_from(start_header_reader.read_u64()?).map_err(|_| SevenZipError::Truncated)?;
+    let next_header_size = usize::try_from(start_header_reader.read_u64()?).map_err(|_| SevenZipError::Truncated)?;
+    let next_header_checksum = start_header_reader.read_u32()?;
+
+    // Empty archives have no header at all
+    if next_header_size == 0 {
+        return Ok((StreamsInfo::default(), Vec::new()));
+    }
+
+    let next_header_start = SEVENZIP_SIGNATURE_HEADER_SIZE
+        .checked_add(next_header_offset)
+        .ok_or(SevenZipError::Truncated)?;
+    let mut header = data
+        .get(
+            next_header_start
+                ..(next_header_start
+                    .checked_add(next_header_size)
+                    .ok_or(SevenZipError::Truncated)?),
+        )
+        .ok_or(SevenZipError::Truncated)?
+        .to_vec();
+    let received = crc32::checksum_ieee(&header);
+    if received != next_header_checksum {
+        return Err(SevenZipError::HeaderChecksum {
+            expected: next_header_checksum,
+            received,
+        });
+    }
+
+    // The header is usually compressed itself, into the first folder of its own streams
+    while header.first() == Some(&SEVENZIP_ID_ENCODED_HEADER) {
+        let mut reader = HeaderReader::new(&header[1..]);
+        let streams_info = read_streams_info(&mut reader)?;
+        if streams_info.folders.is_empty() {
+            return Err(SevenZipError::Header {
+                property: SEVENZIP_ID_ENCODED_HEADER,
+            });
+        }
+        header = decode_folder(data, &streams_info, 0, &[])?;
+    }
+
+    let mut reader = HeaderReader::new(&header);
+    reader.expect(SEVENZIP_ID_HEADER)?;
+
+    let mut streams_info = StreamsInfo::default();
+    let mut entries = Vec::new();
+
+    loop {
+        match reader.read_u8()? {
+            SEVENZIP_ID_END => return Ok((streams_info, entries)),
+            SEVENZIP_ID_ARCHIVE_PROPERTIES => loop {
+                if reader.read_u8()? == SEVENZIP_ID_END {
+                    break;
+                }
+                let size = reader.read_number()?;
+                reader.read_bytes(size)?;
+            },
+            SEVENZIP_ID_ADDITIONAL_STREAMS_INFO => {
+                read_streams_info(&mut reader)?;
+            }
+            SEVENZIP_ID_MAIN_STREAMS_INFO => streams_info = read_streams_info(&mut reader)?,
+            SEVENZIP_ID_FILES_INFO => entries = read_files_info(&mut reader)?,
+            property => return Err(SevenZipError::Header { property }),
+        }
+    }
+}
+
+// Files of the archive accepted by `wanted`, directories are skipped. Folders without wanted
+// files are not decompressed at all, and a failing folder only fails the files stored in it.
+pub fn extract(
+    data: &[u8],
+    modified: SystemTime,
+    wanted: impl Fn(&OsStr) -> bool,
+) -> Result<Vec<Result<ArchiveMember, SevenZipError>>, SevenZipError> {
+    let (streams_info, entries) = read_archive(data)?;
+
+    // Files with streams are stored in the substreams of the folders in order
+    let mut folder_entries: Vec<Vec<(usize, &SevenZipEntry)>> = vec![Vec::new(); streams_info.folders.len()];
+    let mut substreams = streams_info
+        .substream_sizes
+        .iter()
+        .enumerate()
+        .flat_map(|(folder_index, sizes)| (0..sizes.len()).map(move |substream_index| (folder_index, substream_index)));
+    let mut members = Vec::new();
+
+    for entry in &entries {
+        if !entry.has_stream {
+            if !entry.is_directory && wanted(OsStr::new(&entry.name)) {
+                members.push(Ok(ArchiveMember {
+                    name: entry.name.clone().into(),
+                    modified: entry.modified.unwrap_or(modified),
+                    data: Vec::new(),
+                }));
+            }
+            continue;
+        }
+
+        let (folder_index, substream_index) = substreams.next().ok_or(SevenZipError::Header {
+            property: SEVENZIP_ID_FILES_INFO,
+        })?;
+        if wanted(OsStr::new(&entry.name)) {
+            folder_entries[folder_index].push((substream_index, entry));
+        }
+    }
+
+    for (folder_index, folder_entries) in folder_entries.iter().enumerate() {
+        if folder_entries.is_empty() {
+            continue;
+        }
+
+        let names: Vec<String> = folder_entries.iter().map(|(_, entry)| entry.name.clone()).collect();
+        let folder_data = match decode_folder(data, &streams_info, folder_index, &names) {
+            Ok(folder_data) => folder_data,
+            Err(err) => {
+                members.push(Err(err));
+                continue;
+            }
+        };
+
+        let sizes = &streams_info.substream_sizes[folder_index];
+        for &(substream_index, entry) in folder_entries {
+            let start: usize = sizes[..substream_index].iter().sum();
+            let member_data = folder_data[start..(start + sizes[substream_index])].to_vec();
+
+            if let Some(expected) = streams_info.substream_checksums[folder_index][substream_index] {
+                let received = crc32::checksum_ieee(&member_data);
+                if received != expected {
+                    members.push(Err(SevenZipError::Checksum {
+                        names: vec![entry.name.clone()],
+                        expected,
+                        received,
+                    }));
+                    continue;
+                }
+            }
+
+            members.push(Ok(ArchiveMember {
+                name: entry.name.clone().into(),
+                modified: entry.modified.unwrap_or(modified),
+                data: member_data,
+            }));
+        }
+    }
+
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_substream_sizes(header: &[u8]) -> Result<Vec<Vec<usize>>, SevenZipError> {
+        let mut streams_info = StreamsInfo {
+            folders: vec![Folder {
+                coders: Vec::new(),
+                packed_stream_count: 1,
+                unpack_sizes: vec![10],
+                checksum: None,
+            }],
+            ..StreamsInfo::default()
+        };
+        read_substreams_info(&mut HeaderReader::new(header), &mut streams_info)?;
+        Ok(streams_info.substream_sizes)
+    }
+
+    #[test]
+    fn test_substream_sizes() {
+        let header = [
+            SEVENZIP_ID_NUM_UNPACK_STREAM,
+            3,
+            SEVENZIP_ID_SIZE,
+            3,
+            4,
+            SEVENZIP_ID_END,
+        ];
+        assert_eq!(read_substream_sizes(&header).unwrap(), [[3, 4, 3]]);
+    }
+
+    #[test]
+    fn test_oversized_substreams() {
+        let header = [
+            SEVENZIP_ID_NUM_UNPACK_STREAM,
+            3,
+            SEVENZIP_ID_SIZE,
+            8,
+            4,
+            SEVENZIP_ID_END,
+        ];
+        assert!(matches!(
+            read_substream_sizes(&header),
+            Err(SevenZipError::Header {
+                property: SEVENZIP_ID_SIZE
+            })
+        ));
+    }
+
+    #[test]
+    fn test_overflowing_substream_sizes() {
+        let mut header = vec![SEVENZIP_ID_NUM_UNPACK_STREAM, 3, SEVENZIP_ID_SIZE];
+        header.extend_from_slice(&[0xFF; 9]);
+        header.extend_from_slice(&[2, SEVENZIP_ID_END]);
+        assert!(matches!(
+            read_substream_sizes(&header),
+            Err(SevenZipError::Header {
+                property: SEVENZIP_ID_SIZE
+            })
+        ));
+    }
+}
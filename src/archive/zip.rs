@@ -0,0 +1,267 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, Cursor, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use crc::crc32;
+
+use crate::archive::ArchiveMember;
+use crate::inflate::{self, InflateError};
+
+pub const ZIP_FORMAT_MARKER: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+const ZIP_LOCAL_HEADER_SIGNATURE: u32 = 0x04034B50;
+const ZIP_CENTRAL_HEADER_SIGNATURE: u32 = 0x02014B50;
+const ZIP_END_SIGNATURE: u32 = 0x06054B50;
+
+const ZIP_LOCAL_HEADER_SIZE: usize = 30;
+const ZIP_END_SIZE: usize = 22;
+const ZIP_MAX_COMMENT_SIZE: usize = 0xFFFF;
+
+const ZIP_METHOD_STORED: u16 = 0;
+const ZIP_METHOD_DEFLATED: u16 = 8;
+const ZIP_FLAG_ENCRYPTED: u16 = 0x0001;
+const ZIP_EXTENDED_TIMESTAMP_TAG: u16 = 0x5455;
+
+#[derive(Debug)]
+pub enum ZipError {
+    NoEndRecord,
+    Truncated,
+    Signature { received: u32 },
+    Zip64,
+    Encrypted { name: String },
+    Method { name: String, method: u16 },
+    Inflate { name: String, error: InflateError },
+    Checksum { name: String, expected: u32, received: u32 },
+}
+
+impl fmt::Display for ZipError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZipError::NoEndRecord => write!(formatter, "missing end of central directory record"),
+            ZipError::Truncated => write!(formatter, "truncated zip archive"),
+            ZipError::Signature { received } => write!(formatter, "invalid zip signature (0x{:08X})", received),
+            ZipError::Zip64 => write!(formatter, "unsupported zip64 archive"),
+            ZipError::Encrypted { name } => write!(formatter, "unsupported encrypted member {:?}", name),
+            ZipError::Method { name, method } => {
+                write!(formatter, "unsupported compression method {} of {:?}", method, name)
+            }
+            ZipError::Inflate { name, error } => write!(formatter, "failed to decompress {:?}: {}", name, error),
+            ZipError::Checksum {
+                name,
+                expected,
+                received,
+            } => write!(
+                formatter,
+                "invalid checksum of {:?} (expected: 0x{:08X}, received: 0x{:08X})",
+                name, expected, received
+            ),
+        }
+    }
+}
+
+impl Error for ZipError {}
+
+// Reads only fail past the end of the archive
+impl From<io::Error> for ZipError {
+    fn from(_: io::Error) -> Self {
+        ZipError::Truncated
+    }
+}
+
+// Days since the Unix epoch of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// MS-DOS timestamps have a two second resolution and no time zone, they are taken as UTC
+fn dos_timestamp(date: u16, time: u16) -> SystemTime {
+    let days = days_from_civil(
+        1980 + (date >> 9) as i64,
+        ((date >> 5) & 0x0F).clamp(1, 12) as i64,
+        (date & 0x1F).max(1) as i64,
+    );
+    let seconds =
+        days * 86400 + (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3F) as i64 * 60 + (time & 0x1F) as i64 * 2;
+    UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+}
+
+// The extended timestamp extra field holds the modification time as a Unix timestamp
+fn extended_timestamp(extra_field: &[u8]) -> Option<SystemTime> {
+    let mut extra_cursor = Cursor::new(extra_field);
+
+    while let (Ok(tag), Ok(size)) = (
+        extra_cursor.read_u16::<LittleEndian>(),
+        extra_cursor.read_u16::<LittleEndian>(),
+    ) {
+        let start = extra_cursor.position() as usize;
+        let data = extra_field.get(start..(start + size as usize))?;
+
+        if tag == ZIP_EXTENDED_TIMESTAMP_TAG && data.len() >= 5 && data[0] & 1 != 0 {
+            let modified = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            return Some(UNIX_EPOCH + Duration::from_secs(modified as u64));
+        }
+
+        extra_cursor.set_position((start + size as usize) as u64);
+    }
+
+    None
+}
+
+struct ZipEntry {
+    name: String,
+    modified: SystemTime,
+    flags: u16,
+    method: u16,
+    checksum: u32,
+    compressed_size: usize,
+    uncompressed_size: usize,
+    local_header_offset: usize,
+}
+
+fn read_central_directory(data: &[u8]) -> Result<Vec<ZipEntry>, ZipError> {
+    // The end record is followed by a variable length comment, it is searched backwards
+    let search_start = data.len().saturating_sub(ZIP_END_SIZE + ZIP_MAX_COMMENT_SIZE);
+    let end_offset = (search_start..=data.len().saturating_sub(ZIP_END_SIZE))
+        .rev()
+        .find(|&offset| data[offset..].starts_with(&ZIP_END_SIGNATURE.to_le_bytes()))
+        .ok_or(ZipError::NoEndRecord)?;
+
+    let mut end_cursor = Cursor::new(&data[(end_offset + 10)..]);
+    let entry_count = end_cursor.read_u16::<LittleEndian>()?;
+    let _directory_size = end_cursor.read_u32::<LittleEndian>()?;
+    let directory_offset = end_cursor.read_u32::<LittleEndian>()?;
+
+    if entry_count == 0xFFFF || directory_offset == 0xFFFFFFFF {
+        return Err(ZipError::Zip64);
+    }
+
+    let mut directory_cursor = Cursor::new(data.get(directory_offset as usize..).ok_or(ZipError::Truncated)?);
+    let mut entries = Vec::with_capacity(entry_count as usize);
+
+    for _ in 0..entry_count {
+        entries.push(read_central_header(&mut directory_cursor)?);
+    }
+
+    Ok(entries)
+}
+
+fn read_central_header(cursor: &mut Cursor<&[u8]>) -> Result<ZipEntry, ZipError> {
+    let signature = cursor.read_u32::<LittleEndian>()?;
+    if signature != ZIP_CENTRAL_HEADER_SIGNATURE {
+        return Err(ZipError::Signature { received: signature });
+    }
+
+    let _version_made_by = cursor.read_u16::<LittleEndian>()?;
+    let _version_needed = cursor.read_u16::<LittleEndian>()?;
+    let flags = cursor.read_u16::<LittleEndian>()?;
+    let method = cursor.read_u16::<LittleEndian>()?;
+    let time = cursor.read_u16::<LittleEndian>()?;
+    let date = cursor.read_u16::<LittleEndian>()?;
+    let checksum = cursor.read_u32::<LittleEndian>()?;
+    let compressed_size = cursor.read_u32::<LittleEndian>()?;
+    let uncompressed_size = cursor.read_u32::<LittleEndian>()?;
+    let name_size = cursor.read_u16::<LittleEndian>()?;
+    let extra_size = cursor.read_u16::<LittleEndian>()?;
+    let comment_size = cursor.read_u16::<LittleEndian>()?;
+    let _disk_number = cursor.read_u16::<LittleEndian>()?;
+    let _internal_attributes = cursor.read_u16::<LittleEndian>()?;
+    let _external_attributes = cursor.read_u32::<LittleEndian>()?;
+    let local_header_offset = cursor.read_u32::<LittleEndian>()?;
+
+    if compressed_size == 0xFFFFFFFF || uncompressed_size == 0xFFFFFFFF || local_header_offset == 0xFFFFFFFF {
+        return Err(ZipError::Zip64);
+    }
+
+    let mut name = vec![0; name_size as usize];
+    cursor.read_exact(&mut name)?;
+    let mut extra_field = vec![0; extra_size as usize];
+    cursor.read_exact(&mut extra_field)?;
+    cursor.set_position(cursor.position() + comment_size as u64);
+
+    Ok(ZipEntry {
+        name: String::from_utf8_lossy(&name).into_owned(),
+        modified: extended_timestamp(&extra_field).unwrap_or_else(|| dos_timestamp(date, time)),
+        flags,
+        method,
+        checksum,
+        compressed_size: compressed_size as usize,
+        uncompressed_size: uncompressed_size as usize,
+        local_header_offset: local_header_offset as usize,
+    })
+}
+
+fn extract_entry(data: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, ZipError> {
+    if entry.flags & ZIP_FLAG_ENCRYPTED != 0 {
+        return Err(ZipError::Encrypted {
+            name: entry.name.clone(),
+        });
+    }
+
+    // The sizes of the local header fields may differ from the central directory
+    let local_header = data
+        .get(entry.local_header_offset..(entry.local_header_offset + ZIP_LOCAL_HEADER_SIZE))
+        .ok_or(ZipError::Truncated)?;
+    let signature = u32::from_le_bytes([local_header[0], local_header[1], local_header[2], local_header[3]]);
+    if signature != ZIP_LOCAL_HEADER_SIGNATURE {
+        return Err(ZipError::Signature { received: signature });
+    }
+
+    let name_size = u16::from_le_bytes([local_header[26], local_header[27]]) as usize;
+    let extra_size = u16::from_le_bytes([local_header[28], local_header[29]]) as usize;
+    let data_offset = entry.local_header_offset + ZIP_LOCAL_HEADER_SIZE + name_size + extra_size;
+    let compressed_data = data
+        .get(data_offset..(data_offset + entry.compressed_size))
+        .ok_or(ZipError::Truncated)?;
+
+    let member_data = match entry.method {
+        ZIP_METHOD_STORED => compressed_data.to_vec(),
+        ZIP_METHOD_DEFLATED => {
+            inflate::inflate(compressed_data)
+                .map_err(|error| ZipError::Inflate {
+                    name: entry.name.clone(),
+                    error,
+                })?
+                .0
+        }
+        method => {
+            return Err(ZipError::Method {
+                name: entry.name.clone(),
+                method,
+            })
+        }
+    };
+
+    let checksum = crc32::checksum_ieee(&member_data);
+    if member_data.len() != entry.uncompressed_size || checksum != entry.checksum {
+        return Err(ZipError::Checksum {
+            name: entry.name.clone(),
+            expected: entry.checksum,
+            received: checksum,
+        });
+    }
+
+    Ok(member_data)
+}
+
+// Files of the archive accepted by `wanted`, directories are skipped. Members failing to extract
+// are reported one by one, without failing the rest of the archive.
+pub fn extract(data: &[u8], wanted: impl Fn(&OsStr) -> bool) -> Result<Vec<Result<ArchiveMember, ZipError>>, ZipError> {
+    Ok(read_central_directory(data)?
+        .into_iter()
+        .filter(|entry| !entry.name.ends_with('/') && wanted(OsStr::new(&entry.name)))
+        .map(|entry| {
+            Ok(ArchiveMember {
+                data: extract_entry(data, &entry)?,
+                name: entry.name.into(),
+                modified: entry.modified,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,364 @@
+use std::error::Error;
+use std::fmt;
+
+// Minimal bzip2 decompressor, enough for the compressed streams embedded in patch files
+
+const BZIP2_FORMAT_MARKER: [u8; 3] = [b'B', b'Z', b'h'];
+const BZIP2_BLOCK_MARKER: u64 = 0x314159265359;
+const BZIP2_END_MARKER: u64 = 0x177245385090;
+
+const BZIP2_MAX_CODE_LENGTH: usize = 20;
+const BZIP2_GROUP_SIZE: usize = 50;
+
+#[derive(Debug)]
+pub enum Bzip2Error {
+    Truncated,
+    FormatMarker,
+    BlockMarker { received: u64 },
+    Randomized,
+    InvalidBlock,
+    BlockChecksum { expected: u32, received: u32 },
+    StreamChecksum { expected: u32, received: u32 },
+}
+
+impl fmt::Display for Bzip2Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bzip2Error::Truncated => write!(formatter, "truncated bzip2 stream"),
+            Bzip2Error::FormatMarker => write!(formatter, "invalid bzip2 format marker"),
+            Bzip2Error::BlockMarker { received } => {
+                write!(formatter, "invalid bzip2 block marker (0x{:012X})", received)
+            }
+            Bzip2Error::Randomized => write!(formatter, "unsupported randomized bzip2 block"),
+            Bzip2Error::InvalidBlock => write!(formatter, "invalid bzip2 block"),
+            Bzip2Error::BlockChecksum { expected, received } => write!(
+                formatter,
+                "invalid bzip2 block checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+            Bzip2Error::StreamChecksum { expected, received } => write!(
+                formatter,
+                "invalid bzip2 stream checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl Error for Bzip2Error {}
+
+// bzip2 uses the non-reflected variant of CRC32
+fn crc32_bzip2(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (index, entry) in table.iter_mut().enumerate() {
+        let mut crc = (index as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04C11DB7
+            } else {
+                crc << 1
+            };
+        }
+        *entry = crc;
+    }
+
+    !data.iter().fold(0xFFFFFFFF, |crc, &byte| {
+        (crc << 8) ^ table[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    buffer: u64,
+    buffered_bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            buffer: 0,
+            buffered_bits: 0,
+        }
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u32, Bzip2Error> {
+        while self.buffered_bits < count {
+            let byte = *self.data.get(self.offset).ok_or(Bzip2Error::Truncated)?;
+            self.offset += 1;
+            self.buffer = (self.buffer << 8) | byte as u64;
+            self.buffered_bits += 8;
+        }
+
+        self.buffered_bits -= count;
+        Ok(((self.buffer >> self.buffered_bits) & ((1 << count) - 1)) as u32)
+    }
+
+    fn read_bit(&mut self) -> Result<bool, Bzip2Error> {
+        Ok(self.read_bits(1)? != 0)
+    }
+}
+
+// Canonical Huffman codes, shorter codes first and symbols in increasing order within a length
+struct HuffmanTable {
+    counts: [usize; BZIP2_MAX_CODE_LENGTH + 1],
+    symbols: Vec<usize>,
+}
+
+impl HuffmanTable {
+    fn new(code_lengths: &[usize]) -> Self {
+        let mut counts = [0; BZIP2_MAX_CODE_LENGTH + 1];
+        for &code_length in code_lengths {
+            counts[code_length] += 1;
+        }
+
+        let mut symbols = Vec::with_capacity(code_lengths.len());
+        for length in 1..=BZIP2_MAX_CODE_LENGTH {
+            for (symbol, &code_length) in code_lengths.iter().enumerate() {
+                if code_length == length {
+                    symbols.push(symbol);
+                }
+            }
+        }
+
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<usize, Bzip2Error> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+
+        for length in 1..=BZIP2_MAX_CODE_LENGTH {
+            code |= reader.read_bits(1)? as usize;
+            let count = self.counts[length];
+            if code >= first && code - first < count {
+                return Ok(self.symbols[index + code - first]);
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(Bzip2Error::InvalidBlock)
+    }
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Bzip2Error> {
+    if data.len() < 4 || data[0..3] != BZIP2_FORMAT_MARKER || !(b'1'..=b'9').contains(&data[3]) {
+        return Err(Bzip2Error::FormatMarker);
+    }
+
+    let max_block_size = (data[3] - b'0') as usize * 100_000;
+    let mut reader = BitReader::new(&data[4..]);
+
+    let mut output = Vec::new();
+    let mut stream_checksum: u32 = 0;
+
+    loop {
+        let marker = ((reader.read_bits(24)? as u64) << 24) | reader.read_bits(24)? as u64;
+        let expected_checksum = reader.read_bits(32)?;
+
+        match marker {
+            BZIP2_BLOCK_MARKER => {
+                let block_start = output.len();
+                decompress_block(&mut reader, max_block_size, &mut output)?;
+
+                let block_checksum = crc32_bzip2(&output[block_start..]);
+                if block_checksum != expected_checksum {
+                    return Err(Bzip2Error::BlockChecksum {
+                        expected: expected_checksum,
+                        received: block_checksum,
+                    });
+                }
+
+                stream_checksum = stream_checksum.rotate_left(1) ^ block_checksum;
+            }
+            BZIP2_END_MARKER => {
+                if stream_checksum != expected_checksum {
+                    return Err(Bzip2Error::StreamChecksum {
+                        expected: expected_checksum,
+                        received: stream_checksum,
+                    });
+                }
+
+                return Ok(output);
+            }
+            _ => return Err(Bzip2Error::BlockMarker { received: marker }),
+        }
+    }
+}
+
+fn decompress_block(reader: &mut BitReader, max_block_size: usize, output: &mut Vec<u8>) -> Result<(), Bzip2Error> {
+    if reader.read_bit()? {
+        return Err(Bzip2Error::Randomized);
+    }
+
+    let origin_pointer = reader.read_bits(24)? as usize;
+
+    // Bytes present in the block, in a two level bitmap
+    let mut symbol_map = Vec::new();
+    let used_ranges = reader.read_bits(16)?;
+    for range in 0..16 {
+        if used_ranges & (0x8000 >> range) != 0 {
+            let used_bytes = reader.read_bits(16)?;
+            for byte in 0..16 {
+                if used_bytes & (0x8000 >> byte) != 0 {
+                    symbol_map.push((range * 16 + byte) as u8);
+                }
+            }
+        }
+    }
+
+    if symbol_map.is_empty() {
+        return Err(Bzip2Error::InvalidBlock);
+    }
+
+    // RUNA, RUNB, the move-to-front indices and the end of block symbol
+    let alphabet_size = symbol_map.len() + 2;
+
+    let group_count = reader.read_bits(3)? as usize;
+    let selector_count = reader.read_bits(15)? as usize;
+    if !(2..=6).contains(&group_count) || selector_count == 0 {
+        return Err(Bzip2Error::InvalidBlock);
+    }
+
+    // Selectors are move-to-front encoded in unary
+    let mut group_order: Vec<usize> = (0..group_count).collect();
+    let mut selectors = Vec::with_capacity(selector_count);
+    for _ in 0..selector_count {
+        let mut index = 0;
+        while reader.read_bit()? {
+            index += 1;
+            if index >= group_count {
+                return Err(Bzip2Error::InvalidBlock);
+            }
+        }
+
+        let group = group_order.remove(index);
+        group_order.insert(0, group);
+        selectors.push(group);
+    }
+
+    // Code lengths are delta encoded
+    let mut tables = Vec::with_capacity(group_count);
+    for _ in 0..group_count {
+        let mut code_length = reader.read_bits(5)? as usize;
+        let mut code_lengths = Vec::with_capacity(alphabet_size);
+
+        for _ in 0..alphabet_size {
+            loop {
+                if !(1..=BZIP2_MAX_CODE_LENGTH).contains(&code_length) {
+                    return Err(Bzip2Error::InvalidBlock);
+                }
+                if !reader.read_bit()? {
+                    break;
+                }
+                if reader.read_bit()? {
+                    code_length -= 1;
+                } else {
+                    code_length += 1;
+                }
+            }
+            code_lengths.push(code_length);
+        }
+
+        tables.push(HuffmanTable::new(&code_lengths));
+    }
+
+    // Huffman and run-length decoding, followed by undoing the move-to-front transform
+    let end_of_block = alphabet_size - 1;
+    let mut move_to_front: Vec<u8> = symbol_map.clone();
+    let mut block = Vec::with_capacity(max_block_size);
+
+    let mut run_length = 0;
+    let mut run_weight = 1;
+
+    for symbol_index in 0.. {
+        let selector = *selectors
+            .get(symbol_index / BZIP2_GROUP_SIZE)
+            .ok_or(Bzip2Error::InvalidBlock)?;
+        let symbol = tables[selector].decode(reader)?;
+
+        if symbol <= 1 {
+            if run_weight > max_block_size {
+                return Err(Bzip2Error::InvalidBlock);
+            }
+            run_length += run_weight << symbol;
+            run_weight <<= 1;
+            continue;
+        }
+
+        if run_length > 0 {
+            if block.len() + run_length > max_block_size {
+                return Err(Bzip2Error::InvalidBlock);
+            }
+            block.resize(block.len() + run_length, move_to_front[0]);
+            run_length = 0;
+            run_weight = 1;
+        }
+
+        if symbol == end_of_block {
+            break;
+        }
+
+        if block.len() >= max_block_size {
+            return Err(Bzip2Error::InvalidBlock);
+        }
+
+        let byte = move_to_front.remove(symbol - 1);
+        move_to_front.insert(0, byte);
+        block.push(byte);
+    }
+
+    if origin_pointer >= block.len() {
+        return Err(Bzip2Error::InvalidBlock);
+    }
+
+    // Inverse Burrows-Wheeler transform
+    let mut byte_starts = [0; 256];
+    for &byte in &block {
+        byte_starts[byte as usize] += 1;
+    }
+
+    let mut total = 0;
+    for byte_start in byte_starts.iter_mut() {
+        let count = *byte_start;
+        *byte_start = total;
+        total += count;
+    }
+
+    let mut next = vec![0; block.len()];
+    for (index, &byte) in block.iter().enumerate() {
+        next[byte_starts[byte as usize]] = index;
+        byte_starts[byte as usize] += 1;
+    }
+
+    // Undoing the initial run-length encoding, four equal bytes are followed by a repeat count
+    let mut position = next[origin_pointer];
+    let mut previous_byte = None;
+    let mut repeat_count = 0;
+
+    for _ in 0..block.len() {
+        let byte = block[position];
+        position = next[position];
+
+        if repeat_count == 4 {
+            output.resize(output.len() + byte as usize, previous_byte.unwrap());
+            repeat_count = 0;
+            continue;
+        }
+
+        if previous_byte == Some(byte) {
+            repeat_count += 1;
+        } else {
+            previous_byte = Some(byte);
+            repeat_count = 1;
+        }
+
+        output.push(byte);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crc::crc32;
+use log::warn;
+
+use crate::utils::MappedFile;
+
+const CHECKSUM_INDEX_HEADER: &[u8] = b"fuse-softpatch checksum index v1";
+
+// SNES copier devices prepend a 512-byte header to the ROMs
+const COPIER_HEADER_SIZE: usize = 512;
+
+#[derive(Copy, Clone, PartialEq)]
+struct IndexEntry {
+    size: u64,
+    modified: u128,
+    checksum: u32,
+    // Only computed for the candidates for stripping the copier header
+    headerless_checksum: Option<u32>,
+}
+
+// The CRC32 checksums of the whole file and, on request, of the data after the copier header
+pub fn file_checksums(path: &Path, headerless: bool) -> io::Result<(u32, Option<u32>)> {
+    let data = MappedFile::open(path)?;
+    let headerless_checksum = if headerless && data.len() >= COPIER_HEADER_SIZE {
+        Some(crc32::checksum_ieee(&data[COPIER_HEADER_SIZE..]))
+    } else {
+        None
+    };
+    Ok((crc32::checksum_ieee(&data), headerless_checksum))
+}
+
+// Checksums of the source ROMs persisted between mounts, only the files changing in size or
+// modification time are hashed again. The index starts with a version line, followed by a line
+// for every file: "<size> <modified> <crc32> <headerless crc32 or -> <path>".
+#[derive(Clone)]
+pub struct ChecksumIndex {
+    index_path: PathBuf,
+    entries: HashMap<PathBuf, IndexEntry>,
+    // Files of the current scan, the others are dropped when saving
+    scanned_paths: HashSet<PathBuf>,
+    changed: bool,
+}
+
+impl ChecksumIndex {
+    // Under the cache directory of the user
+    pub fn default_path() -> Option<PathBuf> {
+        let cache_directory = env::var_os("XDG_CACHE_HOME")
+            .filter(|directory| !directory.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+        Some(cache_directory.join("fuse-softpatch").join("checksums.idx"))
+    }
+
+    // Missing, outdated and corrupted indexes start out empty, everything gets hashed again
+    pub fn new(index_path: &Path, rebuild: bool) -> Self {
+        let entries = if rebuild {
+            HashMap::new()
+        } else {
+            match fs::read(index_path) {
+                Ok(data) => parse_index(&data).unwrap_or_else(|| {
+                    warn!("Corrupted checksum index {:?}, rebuilding", index_path);
+                    HashMap::new()
+                }),
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::NotFound {
+                        warn!("Failed to load {:?}: {}", index_path, err);
+                    }
+                    HashMap::new()
+                }
+            }
+        };
+
+        Self {
+            index_path: index_path.to_owned(),
+            entries,
+            scanned_paths: HashSet::new(),
+            changed: rebuild,
+        }
+    }
+
+    // Checksums of files unchanged since they were indexed
+    pub fn lookup(&mut self, path: &Path, metadata: &fs::Metadata, headerless: bool) -> Option<(u32, Option<u32>)> {
+        self.scanned_paths.insert(path.to_owned());
+
+        let (size, modified) = file_stamp(metadata)?;
+        let entry = self.entries.get(path)?;
+        if entry.size == size && entry.modified == modified && (!headerless || entry.headerless_checksum.is_some()) {
+            Some((entry.checksum, entry.headerless_checksum.filter(|_| headerless)))
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        (checksum, headerless_checksum): (u32, Option<u32>),
+    ) {
+        if let Some((size, modified)) = file_stamp(metadata) {
+            self.entries.insert(
+                path.to_owned(),
+                IndexEntry {
+                    size,
+                    modified,
+                    checksum,
+                    headerless_checksum,
+                },
+            );
+            self.changed = true;
+        }
+    }
+
+    // Written to a temporary file first, so an interrupted save never corrupts the index
+    pub fn save(&mut self) -> io::Result<()> {
+        let scanned_paths = &self.scanned_paths;
+        let entry_count = self.entries.len();
+        self.entries.retain(|path, _| scanned_paths.contains(path));
+        let changed = self.changed || self.entries.len() != entry_count;
+
+        self.scanned_paths.clear();
+        self.changed = false;
+        if !changed {
+            return Ok(());
+        }
+
+        let mut data = CHECKSUM_INDEX_HEADER.to_vec();
+        data.push(b'\n');
+        for (path, entry) in &self.entries {
+            // Such names could not be read back
+            if path.as_os_str().as_bytes().contains(&b'\n') {
+                continue;
+            }
+
+            let headerless_checksum = entry
+                .headerless_checksum
+                .map_or_else(|| "-".to_owned(), |checksum| format!("{:08x}", checksum));
+            data.extend_from_slice(
+                format!(
+                    "{} {} {:08x} {} ",
+                    entry.size, entry.modified, entry.checksum, headerless_checksum
+                )
+                .as_bytes(),
+            );
+            data.extend_from_slice(path.as_os_str().as_bytes());
+            data.push(b'\n');
+        }
+
+        let tmp_index_path = self.index_path.with_extension("idx.tmp");
+        fs::create_dir_all(self.index_path.parent().unwrap())?;
+        fs::write(&tmp_index_path, data)?;
+        fs::rename(&tmp_index_path, &self.index_path)
+    }
+}
+
+// Files without a modification time are never indexed
+fn file_stamp(metadata: &fs::Metadata) -> Option<(u64, u128)> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_nanos()))
+}
+
+fn parse_index(data: &[u8]) -> Option<HashMap<PathBuf, IndexEntry>> {
+    let mut lines = data.split(|&b| b == b'\n');
+    if lines.next()? != CHECKSUM_INDEX_HEADER {
+        return None;
+    }
+
+    let mut entries = HashMap::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let mut fields = line.splitn(5, |&b| b == b' ');
+        let mut field = || std::str::from_utf8(fields.next()?).ok();
+
+        let size = field()?.parse().ok()?;
+        let modified = field()?.parse().ok()?;
+        let checksum = u32::from_str_radix(field()?, 16).ok()?;
+        let headerless_checksum = match field()? {
+            "-" => None,
+            value => Some(u32::from_str_radix(value, 16).ok()?),
+        };
+        let path = PathBuf::from(OsStr::from_bytes(fields.next()?));
+
+        entries.insert(
+            path,
+            IndexEntry {
+                size,
+                modified,
+                checksum,
+                headerless_checksum,
+            },
+        );
+    }
+
+    Some(entries)
+}
@@ -0,0 +1,64 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crc::crc32;
+
+use crate::md5::md5;
+use crate::patch::Patch;
+use crate::sha1::sha1;
+
+// Checksum files listing every target ROM, in the formats of the common verification tools
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ChecksumFormat {
+    Sfv,
+    Md5,
+    Sha1,
+}
+
+impl ChecksumFormat {
+    pub fn parse(name: &str) -> Option<ChecksumFormat> {
+        match name {
+            "sfv" => Some(ChecksumFormat::Sfv),
+            "md5" => Some(ChecksumFormat::Md5),
+            "sha1" => Some(ChecksumFormat::Sha1),
+            _ => None,
+        }
+    }
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            ChecksumFormat::Sfv => "checksums.sfv",
+            ChecksumFormat::Md5 => "md5sum.txt",
+            ChecksumFormat::Sha1 => "sha1sum.txt",
+        }
+    }
+
+    // CRC32 checksums stored in the patches are known without patching
+    pub fn stored_checksum(self, patch: &dyn Patch) -> Option<String> {
+        match self {
+            ChecksumFormat::Sfv => patch.target_checksum().map(|crc| format!("{:08X}", crc)),
+            _ => None,
+        }
+    }
+
+    pub fn checksum(self, data: &[u8]) -> String {
+        match self {
+            ChecksumFormat::Sfv => format!("{:08X}", crc32::checksum_ieee(data)),
+            ChecksumFormat::Md5 => hex(&md5(data)),
+            ChecksumFormat::Sha1 => hex(&sha1(data)),
+        }
+    }
+
+    // Paths are kept as raw bytes, the checking tools have to find the files by these names
+    pub fn line(self, target_path: &Path, checksum: &str) -> Vec<u8> {
+        let path = target_path.as_os_str().as_bytes();
+        match self {
+            ChecksumFormat::Sfv => [path, b" ", checksum.as_bytes(), b"\n"].concat(),
+            _ => [checksum.as_bytes(), b"  ", path, b"\n"].concat(),
+        }
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
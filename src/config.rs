@@ -0,0 +1,111 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Configuration files describe target ROMs explicitly, along with their source ROMs and the
+// patches applied to them in order:
+//
+//     [[target]]
+//     name = "Hacks/Hack.sfc"
+//     source = "roms/Game (U) (V1.1).sfc"
+//     patches = ["patches/Hack.bps", "patches/Hack Addon.ups"]
+//     crc32 = "1a2b3c4d"
+//
+// A single patch may also be given as `patch`. Paths are relative to the configuration file,
+// names are relative to the root of the filesystem.
+#[derive(Clone)]
+pub struct TargetConfig {
+    // Served as the patch of the target ROMs made of several patches
+    pub config_path: PathBuf,
+    pub name: PathBuf,
+    pub source_path: PathBuf,
+    pub patch_paths: Vec<PathBuf>,
+    // Checked after patching, in addition to the checksums stored in the patches
+    pub crc32: Option<u32>,
+    // Source ROMs are checked against the first patch for formats supporting it, unless disabled
+    pub verify: bool,
+}
+
+// Every file has to exist and every target ROM needs a name of its own, otherwise the whole
+// configuration is rejected
+pub fn read_config_file(config_path: &Path) -> Result<Vec<TargetConfig>, Box<dyn Error>> {
+    let config_directory = config_path.parent().unwrap();
+    let document = fs::read_to_string(config_path)?.parse::<toml::Value>()?;
+    let mut targets = Vec::new();
+    let mut names = HashSet::new();
+
+    let entries = match document.get("target") {
+        Some(toml::Value::Array(entries)) => entries.as_slice(),
+        Some(_) => return Err("\"target\" is not an array of tables".into()),
+        None => &[],
+    };
+
+    for (index, entry) in entries.iter().enumerate() {
+        let string = |key: &str| -> Result<&str, Box<dyn Error>> {
+            entry
+                .get(key)
+                .and_then(toml::Value::as_str)
+                .ok_or_else(|| format!("no {} is given for target #{}", key, index + 1).into())
+        };
+
+        let name = PathBuf::from(string("name")?);
+        if !name
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!("invalid target name {:?}", name).into());
+        }
+        if !names.insert(name.clone()) {
+            return Err(format!("target name {:?} is given more than once", name).into());
+        }
+
+        let source_path = config_directory.join(string("source")?);
+        if !source_path.is_file() {
+            return Err(format!("source ROM {:?} of {:?} does not exist", source_path, name).into());
+        }
+
+        let patch_names = match (entry.get("patch"), entry.get("patches")) {
+            (Some(toml::Value::String(patch_name)), None) => vec![patch_name.as_str()],
+            (None, Some(toml::Value::Array(patch_names))) if !patch_names.is_empty() => patch_names
+                .iter()
+                .map(toml::Value::as_str)
+                .collect::<Option<_>>()
+                .ok_or_else(|| format!("patches of {:?} are not strings", name))?,
+            _ => return Err(format!("either a patch or a list of patches is needed for {:?}", name).into()),
+        };
+        let patch_paths: Vec<PathBuf> = patch_names
+            .iter()
+            .map(|patch_name| config_directory.join(patch_name))
+            .collect();
+        if let Some(patch_path) = patch_paths.iter().find(|patch_path| !patch_path.is_file()) {
+            return Err(format!("patch {:?} of {:?} does not exist", patch_path, name).into());
+        }
+
+        let crc32 = match entry.get("crc32") {
+            None => None,
+            Some(toml::Value::String(value)) => Some(
+                u32::from_str_radix(value.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("invalid CRC32 {:?} of {:?}", value, name))?,
+            ),
+            Some(value) => Some(
+                value
+                    .as_integer()
+                    .and_then(|value| u32::try_from(value).ok())
+                    .ok_or_else(|| format!("invalid CRC32 of {:?}", name))?,
+            ),
+        };
+
+        targets.push(TargetConfig {
+            config_path: config_path.to_owned(),
+            name,
+            source_path,
+            patch_paths,
+            crc32,
+            verify: entry.get("verify").and_then(toml::Value::as_bool).unwrap_or(true),
+        });
+    }
+
+    Ok(targets)
+}
@@ -0,0 +1,134 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crc::{crc32, crc64};
+use log::{debug, warn};
+
+use crate::patch::Patch;
+
+// Patched ROMs persisted between mounts. Every entry is a `<key>.rom` file holding the
+// patched data and a `<key>.idx` index file describing what it was generated from.
+pub struct DiskCache {
+    cache_directory: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(cache_directory: &Path) -> io::Result<Self> {
+        fs::create_dir_all(cache_directory)?;
+        Ok(Self {
+            cache_directory: cache_directory.to_owned(),
+        })
+    }
+
+    pub fn load(&self, patch: &dyn Patch) -> Option<Vec<u8>> {
+        let (key, index) = self.index(patch).ok()?;
+
+        let stored_index = fs::read_to_string(self.cache_directory.join(format!("{}.idx", key))).ok()?;
+        if !stored_index.starts_with(&index) {
+            debug!("Outdated disk cache entry for {:?}", patch.patch_path());
+            return None;
+        }
+
+        let (expected_size, expected_checksum) = parse_data_fields(&stored_index[index.len()..])?;
+        let data = fs::read(self.cache_directory.join(format!("{}.rom", key))).ok()?;
+
+        if data.len() as u64 != expected_size || crc32::checksum_ieee(&data) != expected_checksum {
+            warn!("Corrupted disk cache entry for {:?}, regenerating", patch.patch_path());
+            return None;
+        }
+
+        debug!("Loaded {:?} from the disk cache", patch.patch_path());
+        Some(data)
+    }
+
+    pub fn store(&self, patch: &dyn Patch, data: &[u8]) -> io::Result<()> {
+        let (key, mut index) = self.index(patch)?;
+        index.push_str(&format!(
+            "size={}\ncrc32={:08x}\n",
+            data.len(),
+            crc32::checksum_ieee(data)
+        ));
+
+        // Written to temporary files first so an interrupted store never leaves a valid-looking entry
+        let rom_path = self.cache_directory.join(format!("{}.rom", key));
+        let idx_path = self.cache_directory.join(format!("{}.idx", key));
+        let tmp_rom_path = self.cache_directory.join(format!("{}.rom.tmp", key));
+        let tmp_idx_path = self.cache_directory.join(format!("{}.idx.tmp", key));
+
+        fs::write(&tmp_rom_path, data)?;
+        fs::write(&tmp_idx_path, index)?;
+        fs::rename(&tmp_rom_path, &rom_path)?;
+        fs::rename(&tmp_idx_path, &idx_path)?;
+
+        debug!("Stored {:?} in the disk cache", patch.patch_path());
+        Ok(())
+    }
+
+    // Returns whether there was an entry of the patch to remove
+    pub fn remove(&self, patch: &dyn Patch) -> io::Result<bool> {
+        let (key, _) = self.index(patch)?;
+
+        match fs::remove_file(self.cache_directory.join(format!("{}.idx", key))) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        match fs::remove_file(self.cache_directory.join(format!("{}.rom", key))) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(err) => Err(err),
+        }
+    }
+
+    // The index lists the inputs of the patched data followed by the size and checksum of the
+    // data itself. Only the input part is returned here, the data fields are appended on store.
+    fn index(&self, patch: &dyn Patch) -> io::Result<(String, String)> {
+        let patch_path = patch.patch_path();
+        let source_path = patch
+            .source_path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no source ROM"))?;
+
+        let key_index = format!(
+            "patch_path={}\npatch_modified={}\nsource_path={}\n",
+            patch_path.display(),
+            nanos_since_epoch(patch.patch_modified()),
+            source_path.display()
+        );
+        let key = format!("{:016x}", crc64::checksum_ecma(key_index.as_bytes()));
+
+        let source_metadata = fs::metadata(source_path)?;
+        let index = format!(
+            "{}source_modified={}\nsource_size={}\n",
+            key_index,
+            modified_nanos(source_path)?,
+            source_metadata.len()
+        );
+
+        Ok((key, index))
+    }
+}
+
+fn modified_nanos(path: &Path) -> io::Result<u128> {
+    Ok(nanos_since_epoch(fs::metadata(path)?.modified()?))
+}
+
+fn nanos_since_epoch(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+fn parse_data_fields(index: &str) -> Option<(u64, u32)> {
+    let mut size = None;
+    let mut checksum = None;
+
+    for line in index.lines() {
+        if let Some(value) = line.strip_prefix("size=") {
+            size = value.parse().ok();
+        } else if let Some(value) = line.strip_prefix("crc32=") {
+            checksum = u32::from_str_radix(value, 16).ok();
+        }
+    }
+
+    Some((size?, checksum?))
+}
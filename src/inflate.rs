@@ -0,0 +1,277 @@
+use std::error::Error;
+use std::fmt;
+
+// Minimal DEFLATE (RFC 1951) decompressor, for patches distributed in archives
+
+const INFLATE_MAX_CODE_LENGTH: usize = 15;
+
+#[rustfmt::skip]
+const LENGTH_BASES: [usize; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+
+#[rustfmt::skip]
+const LENGTH_EXTRA_BITS: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+#[rustfmt::skip]
+const DISTANCE_BASES: [usize; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+#[rustfmt::skip]
+const DISTANCE_EXTRA_BITS: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+// Order of the code length code lengths in dynamic block headers
+#[rustfmt::skip]
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Debug)]
+pub enum InflateError {
+    Truncated,
+    BlockType,
+    StoredLength,
+    InvalidCode,
+    Distance,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InflateError::Truncated => write!(formatter, "truncated deflate stream"),
+            InflateError::BlockType => write!(formatter, "invalid deflate block type"),
+            InflateError::StoredLength => write!(formatter, "invalid deflate stored block length"),
+            InflateError::InvalidCode => write!(formatter, "invalid deflate Huffman code"),
+            InflateError::Distance => write!(formatter, "deflate distance too far back"),
+        }
+    }
+}
+
+impl Error for InflateError {}
+
+// Bits are packed starting from the least significant bit of each byte
+struct BitReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    buffer: u64,
+    buffered_bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            buffer: 0,
+            buffered_bits: 0,
+        }
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u32, InflateError> {
+        while self.buffered_bits < count {
+            let byte = *self.data.get(self.offset).ok_or(InflateError::Truncated)?;
+            self.offset += 1;
+            self.buffer |= (byte as u64) << self.buffered_bits;
+            self.buffered_bits += 8;
+        }
+
+        let value = (self.buffer & ((1 << count) - 1)) as u32;
+        self.buffer >>= count;
+        self.buffered_bits -= count;
+        Ok(value)
+    }
+
+    // Stored blocks start at the next byte boundary
+    fn align_to_byte(&mut self) {
+        let padding = self.buffered_bits % 8;
+        self.buffer >>= padding;
+        self.buffered_bits -= padding;
+    }
+
+    // Bytes fully consumed from the input, the buffered whole bytes are given back
+    fn consumed_bytes(&self) -> usize {
+        self.offset - (self.buffered_bits / 8) as usize
+    }
+}
+
+// Canonical Huffman codes, shorter codes first and symbols in increasing order within a length
+struct HuffmanTable {
+    counts: [usize; INFLATE_MAX_CODE_LENGTH + 1],
+    symbols: Vec<usize>,
+}
+
+impl HuffmanTable {
+    fn new(code_lengths: &[usize]) -> Self {
+        let mut counts = [0; INFLATE_MAX_CODE_LENGTH + 1];
+        for &code_length in code_lengths {
+            counts[code_length] += 1;
+        }
+        counts[0] = 0;
+
+        let mut symbols = Vec::with_capacity(code_lengths.len());
+        for length in 1..=INFLATE_MAX_CODE_LENGTH {
+            for (symbol, &code_length) in code_lengths.iter().enumerate() {
+                if code_length == length {
+                    symbols.push(symbol);
+                }
+            }
+        }
+
+        Self { counts, symbols }
+    }
+
+    // Huffman codes are packed starting from their most significant bit
+    fn decode(&self, reader: &mut BitReader) -> Result<usize, InflateError> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+
+        for length in 1..=INFLATE_MAX_CODE_LENGTH {
+            code |= reader.read_bits(1)? as usize;
+            let count = self.counts[length];
+            if code >= first && code - first < count {
+                return Ok(self.symbols[index + code - first]);
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(InflateError::InvalidCode)
+    }
+}
+
+// Decompresses a raw DEFLATE stream, returning the data and the size of the compressed stream
+pub fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize), InflateError> {
+    let mut reader = BitReader::new(data);
+    let mut output = Vec::new();
+
+    loop {
+        let last_block = reader.read_bits(1)? != 0;
+
+        match reader.read_bits(2)? {
+            0 => inflate_stored_block(&mut reader, &mut output)?,
+            1 => {
+                let (literals, distances) = fixed_tables();
+                inflate_block(&mut reader, &literals, &distances, &mut output)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_tables(&mut reader)?;
+                inflate_block(&mut reader, &literals, &distances, &mut output)?;
+            }
+            _ => return Err(InflateError::BlockType),
+        }
+
+        if last_block {
+            return Ok((output, reader.consumed_bytes()));
+        }
+    }
+}
+
+fn inflate_stored_block(reader: &mut BitReader, output: &mut Vec<u8>) -> Result<(), InflateError> {
+    reader.align_to_byte();
+
+    let length = reader.read_bits(16)?;
+    let inverted_length = reader.read_bits(16)?;
+    if length != !inverted_length & 0xFFFF {
+        return Err(InflateError::StoredLength);
+    }
+
+    for _ in 0..length {
+        output.push(reader.read_bits(8)? as u8);
+    }
+
+    Ok(())
+}
+
+fn fixed_tables() -> (HuffmanTable, HuffmanTable) {
+    let mut literal_lengths = [0; 288];
+    literal_lengths[0..144].fill(8);
+    literal_lengths[144..256].fill(9);
+    literal_lengths[256..280].fill(7);
+    literal_lengths[280..288].fill(8);
+
+    (HuffmanTable::new(&literal_lengths), HuffmanTable::new(&[5; 30]))
+}
+
+fn dynamic_tables(reader: &mut BitReader) -> Result<(HuffmanTable, HuffmanTable), InflateError> {
+    let literal_count = reader.read_bits(5)? as usize + 257;
+    let distance_count = reader.read_bits(5)? as usize + 1;
+    let code_length_count = reader.read_bits(4)? as usize + 4;
+
+    let mut code_length_lengths = [0; 19];
+    for &index in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_length_lengths[index] = reader.read_bits(3)? as usize;
+    }
+    let code_length_table = HuffmanTable::new(&code_length_lengths);
+
+    // Literal and distance code lengths are run-length encoded in a single sequence
+    let mut code_lengths = Vec::with_capacity(literal_count + distance_count);
+    while code_lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_length_table.decode(reader)? {
+            symbol @ 0..=15 => (symbol, 1),
+            16 => (
+                *code_lengths.last().ok_or(InflateError::InvalidCode)?,
+                3 + reader.read_bits(2)? as usize,
+            ),
+            17 => (0, 3 + reader.read_bits(3)? as usize),
+            _ => (0, 11 + reader.read_bits(7)? as usize),
+        };
+
+        if code_lengths.len() + repeat > literal_count + distance_count {
+            return Err(InflateError::InvalidCode);
+        }
+        code_lengths.resize(code_lengths.len() + repeat, value);
+    }
+
+    Ok((
+        HuffmanTable::new(&code_lengths[..literal_count]),
+        HuffmanTable::new(&code_lengths[literal_count..]),
+    ))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    literals: &HuffmanTable,
+    distances: &HuffmanTable,
+    output: &mut Vec<u8>,
+) -> Result<(), InflateError> {
+    loop {
+        match literals.decode(reader)? {
+            symbol @ 0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            symbol => {
+                let length_index = symbol - 257;
+                if length_index >= LENGTH_BASES.len() {
+                    return Err(InflateError::InvalidCode);
+                }
+                let length = LENGTH_BASES[length_index] + reader.read_bits(LENGTH_EXTRA_BITS[length_index])? as usize;
+
+                let distance_index = distances.decode(reader)?;
+                if distance_index >= DISTANCE_BASES.len() {
+                    return Err(InflateError::InvalidCode);
+                }
+                let distance =
+                    DISTANCE_BASES[distance_index] + reader.read_bits(DISTANCE_EXTRA_BITS[distance_index])? as usize;
+
+                if distance > output.len() {
+                    return Err(InflateError::Distance);
+                }
+
+                // The copied range may overlap the bytes being written
+                let start = output.len() - distance;
+                for i in 0..length {
+                    output.push(output[start + i]);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Minimal JSON reader, enough for the metadata blobs embedded in patch files, and writer for
+// the generated files of the filesystem
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(HashMap<String, JsonValue>),
+}
+
+impl JsonValue {
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// Written compactly, with the object members sorted for a stable output
+impl fmt::Display for JsonValue {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonValue::Null => write!(formatter, "null"),
+            JsonValue::Bool(value) => write!(formatter, "{}", value),
+            JsonValue::Number(value) => write!(formatter, "{}", value),
+            JsonValue::String(value) => write_string(formatter, value),
+            JsonValue::Array(elements) => {
+                write!(formatter, "[")?;
+                for (index, element) in elements.iter().enumerate() {
+                    if index > 0 {
+                        write!(formatter, ",")?;
+                    }
+                    write!(formatter, "{}", element)?;
+                }
+                write!(formatter, "]")
+            }
+            JsonValue::Object(members) => {
+                let mut keys: Vec<&String> = members.keys().collect();
+                keys.sort();
+
+                write!(formatter, "{{")?;
+                for (index, key) in keys.into_iter().enumerate() {
+                    if index > 0 {
+                        write!(formatter, ",")?;
+                    }
+                    write_string(formatter, key)?;
+                    write!(formatter, ":{}", members[key])?;
+                }
+                write!(formatter, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(formatter: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    write!(formatter, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(formatter, "\\\"")?,
+            '\\' => write!(formatter, "\\\\")?,
+            '\n' => write!(formatter, "\\n")?,
+            '\r' => write!(formatter, "\\r")?,
+            '\t' => write!(formatter, "\\t")?,
+            c if (c as u32) < 0x20 => write!(formatter, "\\u{:04x}", c as u32)?,
+            c => write!(formatter, "{}", c)?,
+        }
+    }
+    write!(formatter, "\"")
+}
+
+pub fn parse(text: &str) -> Option<JsonValue> {
+    let mut chars = text.chars().peekable();
+    let value = parse_value(&mut chars)?;
+
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return None;
+    }
+
+    Some(value)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+        chars.next();
+    }
+}
+
+fn expect_literal(chars: &mut Peekable<Chars>, literal: &str, value: JsonValue) -> Option<JsonValue> {
+    for expected in literal.chars() {
+        if chars.next()? != expected {
+            return None;
+        }
+    }
+    Some(value)
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
+    skip_whitespace(chars);
+
+    match *chars.peek()? {
+        '{' => parse_object(chars),
+        '[' => parse_array(chars),
+        '"' => parse_string(chars).map(JsonValue::String),
+        't' => expect_literal(chars, "true", JsonValue::Bool(true)),
+        'f' => expect_literal(chars, "false", JsonValue::Bool(false)),
+        'n' => expect_literal(chars, "null", JsonValue::Null),
+        _ => parse_number(chars),
+    }
+}
+
+fn parse_object(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
+    let mut members = HashMap::new();
+    chars.next();
+
+    skip_whitespace(chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+        return Some(JsonValue::Object(members));
+    }
+
+    loop {
+        skip_whitespace(chars);
+        let key = parse_string(chars)?;
+
+        skip_whitespace(chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+
+        members.insert(key, parse_value(chars)?);
+
+        skip_whitespace(chars);
+        match chars.next()? {
+            ',' => {}
+            '}' => return Some(JsonValue::Object(members)),
+            _ => return None,
+        }
+    }
+}
+
+fn parse_array(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
+    let mut elements = Vec::new();
+    chars.next();
+
+    skip_whitespace(chars);
+    if chars.peek() == Some(&']') {
+        chars.next();
+        return Some(JsonValue::Array(elements));
+    }
+
+    loop {
+        elements.push(parse_value(chars)?);
+
+        skip_whitespace(chars);
+        match chars.next()? {
+            ',' => {}
+            ']' => return Some(JsonValue::Array(elements)),
+            _ => return None,
+        }
+    }
+}
+
+fn parse_hex_escape(chars: &mut Peekable<Chars>) -> Option<u32> {
+    let mut value = 0;
+    for _ in 0..4 {
+        value = value * 16 + chars.next()?.to_digit(16)?;
+    }
+    Some(value)
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+
+    let mut result = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(result),
+            '\\' => match chars.next()? {
+                '"' => result.push('"'),
+                '\\' => result.push('\\'),
+                '/' => result.push('/'),
+                'b' => result.push('\u{8}'),
+                'f' => result.push('\u{c}'),
+                'n' => result.push('\n'),
+                'r' => result.push('\r'),
+                't' => result.push('\t'),
+                'u' => {
+                    let mut code_point = parse_hex_escape(chars)?;
+
+                    // Characters outside of the BMP are escaped as UTF-16 surrogate pairs
+                    if (0xD800..0xDC00).contains(&code_point) {
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low_surrogate = parse_hex_escape(chars)?;
+                        if !(0xDC00..0xE000).contains(&low_surrogate) {
+                            return None;
+                        }
+                        code_point = 0x10000 + ((code_point - 0xD800) << 10) + (low_surrogate - 0xDC00);
+                    }
+
+                    result.push(char::from_u32(code_point)?);
+                }
+                _ => return None,
+            },
+            c => result.push(c),
+        }
+    }
+}
+
+fn parse_number(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
+    let mut number = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_ascii_digit() || "+-.eE".contains(c) {
+            number.push(c);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+
+    number.parse().ok().map(JsonValue::Number)
+}
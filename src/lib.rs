@@ -0,0 +1,25 @@
+#![feature(seek_convenience)]
+#![feature(slice_fill)]
+
+pub mod archive;
+pub mod bzip2;
+pub mod checksum_index;
+pub mod checksums;
+pub mod config;
+pub mod disk_cache;
+pub mod inflate;
+pub mod json;
+pub mod logging;
+pub mod lzma;
+pub mod mapping;
+pub mod md5;
+pub mod patch;
+pub mod rom_cache;
+pub mod rom_filesystem;
+pub mod rom_manager;
+pub mod rom_watcher;
+pub mod scan_progress;
+pub mod sha1;
+pub mod signals;
+pub mod stats;
+pub mod utils;
@@ -0,0 +1,91 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use log::Record;
+
+use crate::json::JsonValue;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+thread_local! {
+    // Fields of the event being logged on this thread, loggers format the records on the
+    // logging thread
+    static EVENT_FIELDS: RefCell<HashMap<String, JsonValue>> = RefCell::new(HashMap::new());
+}
+
+// Structured fields attached to the messages logged by `log`, only the JSON format shows them:
+// Event::new("patch").path(target_path).duration(patching_time).log(|| info!("Patched {:?}", target_path))
+pub struct Event {
+    fields: HashMap<String, JsonValue>,
+}
+
+impl Event {
+    pub fn new(op: &str) -> Self {
+        let mut fields = HashMap::new();
+        fields.insert("op".to_owned(), JsonValue::String(op.to_owned()));
+        Self { fields }
+    }
+
+    pub fn path(mut self, path: &Path) -> Self {
+        self.fields.insert(
+            "path".to_owned(),
+            JsonValue::String(path.to_string_lossy().into_owned()),
+        );
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.fields.insert(
+            "duration_ms".to_owned(),
+            JsonValue::Number(duration.as_secs_f64() * 1000.0),
+        );
+        self
+    }
+
+    pub fn count(mut self, count: usize) -> Self {
+        self.fields.insert("count".to_owned(), JsonValue::Number(count as f64));
+        self
+    }
+
+    pub fn error(mut self, err: impl fmt::Display) -> Self {
+        self.fields
+            .insert("error".to_owned(), JsonValue::String(err.to_string()));
+        self
+    }
+
+    pub fn log(self, log: impl FnOnce()) {
+        EVENT_FIELDS.with(|fields| *fields.borrow_mut() = self.fields);
+        log();
+        EVENT_FIELDS.with(|fields| fields.borrow_mut().clear());
+    }
+}
+
+// One JSON object per line. Messages logged outside of events take their targets as their
+// operations, "fuse::read" and the like for the filesystem callbacks.
+pub fn write_json_record(writer: &mut impl Write, record: &Record) -> io::Result<()> {
+    let ts = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    let mut members = EVENT_FIELDS.with(|fields| fields.borrow().clone());
+    members
+        .entry("op".to_owned())
+        .or_insert_with(|| JsonValue::String(record.target().to_owned()));
+    members.insert("ts".to_owned(), JsonValue::Number(ts as f64 / 1000.0));
+    members.insert(
+        "level".to_owned(),
+        JsonValue::String(record.level().to_string().to_lowercase()),
+    );
+    members.insert("message".to_owned(), JsonValue::String(record.args().to_string()));
+
+    writeln!(writer, "{}", JsonValue::Object(members))
+}
@@ -0,0 +1,496 @@
+use std::error::Error;
+use std::fmt;
+
+// Minimal LZMA and LZMA2 decompressor, for patches distributed in 7-Zip archives. The whole
+// output is kept in memory, it doubles as the dictionary.
+
+const LZMA_NUM_STATES: usize = 12;
+const LZMA_NUM_POS_STATES_MAX: usize = 1 << 4;
+const LZMA_NUM_LEN_TO_POS_STATES: usize = 4;
+const LZMA_END_POS_MODEL_INDEX: u32 = 14;
+const LZMA_NUM_FULL_DISTANCES: usize = 1 << 7;
+const LZMA_NUM_ALIGN_BITS: u32 = 4;
+const LZMA_MATCH_MIN_LEN: usize = 2;
+
+const LZMA_PROBABILITY_BITS: u32 = 11;
+const LZMA_PROBABILITY_INIT: u16 = 1 << (LZMA_PROBABILITY_BITS - 1);
+const LZMA_MOVE_BITS: u32 = 5;
+const LZMA_TOP_VALUE: u32 = 1 << 24;
+
+#[derive(Debug)]
+pub enum LzmaError {
+    Truncated,
+    Properties,
+    RangeCoder,
+    Distance,
+    Lzma2Control { received: u8 },
+    Size { expected: usize, received: usize },
+}
+
+impl fmt::Display for LzmaError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LzmaError::Truncated => write!(formatter, "truncated LZMA stream"),
+            LzmaError::Properties => write!(formatter, "invalid LZMA properties"),
+            LzmaError::RangeCoder => write!(formatter, "corrupted LZMA range coder data"),
+            LzmaError::Distance => write!(formatter, "LZMA match distance too far back"),
+            LzmaError::Lzma2Control { received } => {
+                write!(formatter, "invalid LZMA2 chunk control byte (0x{:02X})", received)
+            }
+            LzmaError::Size { expected, received } => write!(
+                formatter,
+                "LZMA output size mismatch (expected: {}, received: {})",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl Error for LzmaError {}
+
+struct RangeDecoder<'a> {
+    data: &'a [u8],
+    offset: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, LzmaError> {
+        if data.len() < 5 {
+            return Err(LzmaError::Truncated);
+        }
+        if data[0] != 0 {
+            return Err(LzmaError::RangeCoder);
+        }
+
+        Ok(Self {
+            data,
+            offset: 5,
+            range: 0xFFFFFFFF,
+            code: u32::from_be_bytes([data[1], data[2], data[3], data[4]]),
+        })
+    }
+
+    fn normalize(&mut self) -> Result<(), LzmaError> {
+        if self.range < LZMA_TOP_VALUE {
+            let byte = *self.data.get(self.offset).ok_or(LzmaError::Truncated)?;
+            self.offset += 1;
+            self.range <<= 8;
+            self.code = (self.code << 8) | byte as u32;
+        }
+        Ok(())
+    }
+
+    // Adaptive bits, the probability of a zero is updated after every decoded bit
+    fn decode_bit(&mut self, probability: &mut u16) -> Result<usize, LzmaError> {
+        let bound = (self.range >> LZMA_PROBABILITY_BITS) * *probability as u32;
+
+        let bit = if self.code < bound {
+            self.range = bound;
+            *probability += ((1 << LZMA_PROBABILITY_BITS) - *probability) >> LZMA_MOVE_BITS;
+            0
+        } else {
+            self.range -= bound;
+            self.code -= bound;
+            *probability -= *probability >> LZMA_MOVE_BITS;
+            1
+        };
+
+        self.normalize()?;
+        Ok(bit)
+    }
+
+    // Fixed probability bits
+    fn decode_direct_bits(&mut self, count: u32) -> Result<u32, LzmaError> {
+        let mut result = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = if self.code >= self.range {
+                self.code -= self.range;
+                1
+            } else {
+                0
+            };
+            result = (result << 1) | bit;
+            self.normalize()?;
+        }
+        Ok(result)
+    }
+
+    fn decode_bit_tree(&mut self, probabilities: &mut [u16], bit_count: u32) -> Result<usize, LzmaError> {
+        let mut index = 1;
+        for _ in 0..bit_count {
+            index = (index << 1) | self.decode_bit(&mut probabilities[index])?;
+        }
+        Ok(index - (1 << bit_count))
+    }
+
+    // Least significant bit first
+    fn decode_reverse_bit_tree(&mut self, probabilities: &mut [u16], bit_count: u32) -> Result<usize, LzmaError> {
+        let mut index = 1;
+        let mut result = 0;
+        for i in 0..bit_count {
+            let bit = self.decode_bit(&mut probabilities[index])?;
+            index = (index << 1) | bit;
+            result |= bit << i;
+        }
+        Ok(result)
+    }
+}
+
+struct LengthDecoder {
+    choice: u16,
+    choice2: u16,
+    low: Vec<[u16; 1 << 3]>,
+    mid: Vec<[u16; 1 << 3]>,
+    high: [u16; 1 << 8],
+}
+
+impl LengthDecoder {
+    fn new() -> Self {
+        Self {
+            choice: LZMA_PROBABILITY_INIT,
+            choice2: LZMA_PROBABILITY_INIT,
+            low: vec![[LZMA_PROBABILITY_INIT; 1 << 3]; LZMA_NUM_POS_STATES_MAX],
+            mid: vec![[LZMA_PROBABILITY_INIT; 1 << 3]; LZMA_NUM_POS_STATES_MAX],
+            high: [LZMA_PROBABILITY_INIT; 1 << 8],
+        }
+    }
+
+    fn decode(&mut self, range_decoder: &mut RangeDecoder, pos_state: usize) -> Result<usize, LzmaError> {
+        if range_decoder.decode_bit(&mut self.choice)? == 0 {
+            range_decoder.decode_bit_tree(&mut self.low[pos_state], 3)
+        } else if range_decoder.decode_bit(&mut self.choice2)? == 0 {
+            Ok(8 + range_decoder.decode_bit_tree(&mut self.mid[pos_state], 3)?)
+        } else {
+            Ok(16 + range_decoder.decode_bit_tree(&mut self.high, 8)?)
+        }
+    }
+}
+
+struct LzmaDecoder {
+    literal_context_bits: u32,
+    literal_pos_bits: u32,
+    pos_bits: u32,
+
+    literal_probabilities: Vec<u16>,
+    is_match: [u16; LZMA_NUM_STATES * LZMA_NUM_POS_STATES_MAX],
+    is_rep: [u16; LZMA_NUM_STATES],
+    is_rep_g0: [u16; LZMA_NUM_STATES],
+    is_rep_g1: [u16; LZMA_NUM_STATES],
+    is_rep_g2: [u16; LZMA_NUM_STATES],
+    is_rep0_long: [u16; LZMA_NUM_STATES * LZMA_NUM_POS_STATES_MAX],
+    pos_slot: [[u16; 1 << 6]; LZMA_NUM_LEN_TO_POS_STATES],
+    pos_special: [u16; 1 + LZMA_NUM_FULL_DISTANCES - LZMA_END_POS_MODEL_INDEX as usize],
+    align: [u16; 1 << LZMA_NUM_ALIGN_BITS],
+    length_decoder: LengthDecoder,
+    rep_length_decoder: LengthDecoder,
+
+    state: usize,
+    reps: [usize; 4],
+}
+
+impl LzmaDecoder {
+    // The properties byte packs the literal context bits, the literal position bits and the
+    // position bits
+    fn new(properties: u8) -> Result<Self, LzmaError> {
+        let mut decoder = Self {
+            literal_context_bits: 0,
+            literal_pos_bits: 0,
+            pos_bits: 0,
+            literal_probabilities: Vec::new(),
+            is_match: [LZMA_PROBABILITY_INIT; LZMA_NUM_STATES * LZMA_NUM_POS_STATES_MAX],
+            is_rep: [LZMA_PROBABILITY_INIT; LZMA_NUM_STATES],
+            is_rep_g0: [LZMA_PROBABILITY_INIT; LZMA_NUM_STATES],
+            is_rep_g1: [LZMA_PROBABILITY_INIT; LZMA_NUM_STATES],
+            is_rep_g2: [LZMA_PROBABILITY_INIT; LZMA_NUM_STATES],
+            is_rep0_long: [LZMA_PROBABILITY_INIT; LZMA_NUM_STATES * LZMA_NUM_POS_STATES_MAX],
+            pos_slot: [[LZMA_PROBABILITY_INIT; 1 << 6]; LZMA_NUM_LEN_TO_POS_STATES],
+            pos_special: [LZMA_PROBABILITY_INIT; 1 + LZMA_NUM_FULL_DISTANCES - LZMA_END_POS_MODEL_INDEX as usize],
+            align: [LZMA_PROBABILITY_INIT; 1 << LZMA_NUM_ALIGN_BITS],
+            length_decoder: LengthDecoder::new(),
+            rep_length_decoder: LengthDecoder::new(),
+            state: 0,
+            reps: [0; 4],
+        };
+        decoder.set_properties(properties)?;
+        Ok(decoder)
+    }
+
+    fn set_properties(&mut self, properties: u8) -> Result<(), LzmaError> {
+        if properties >= 9 * 5 * 5 {
+            return Err(LzmaError::Properties);
+        }
+
+        self.literal_context_bits = (properties % 9) as u32;
+        self.literal_pos_bits = ((properties / 9) % 5) as u32;
+        self.pos_bits = (properties / 45) as u32;
+        self.reset_state();
+        Ok(())
+    }
+
+    fn reset_state(&mut self) {
+        let literal_states = 1 << (self.literal_context_bits + self.literal_pos_bits);
+        self.literal_probabilities = vec![LZMA_PROBABILITY_INIT; 0x300 * literal_states];
+        self.is_match.fill(LZMA_PROBABILITY_INIT);
+        self.is_rep.fill(LZMA_PROBABILITY_INIT);
+        self.is_rep_g0.fill(LZMA_PROBABILITY_INIT);
+        self.is_rep_g1.fill(LZMA_PROBABILITY_INIT);
+        self.is_rep_g2.fill(LZMA_PROBABILITY_INIT);
+        self.is_rep0_long.fill(LZMA_PROBABILITY_INIT);
+        self.pos_slot = [[LZMA_PROBABILITY_INIT; 1 << 6]; LZMA_NUM_LEN_TO_POS_STATES];
+        self.pos_special.fill(LZMA_PROBABILITY_INIT);
+        self.align.fill(LZMA_PROBABILITY_INIT);
+        self.length_decoder = LengthDecoder::new();
+        self.rep_length_decoder = LengthDecoder::new();
+        self.state = 0;
+        self.reps = [0; 4];
+    }
+
+    fn decode_literal(
+        &mut self,
+        range_decoder: &mut RangeDecoder,
+        output: &mut Vec<u8>,
+        dictionary_start: usize,
+    ) -> Result<(), LzmaError> {
+        let previous_byte = if output.len() > dictionary_start {
+            output[output.len() - 1]
+        } else {
+            0
+        };
+
+        let literal_state = ((output.len() & ((1 << self.literal_pos_bits) - 1)) << self.literal_context_bits)
+            + (previous_byte as usize >> (8 - self.literal_context_bits));
+        let probabilities = &mut self.literal_probabilities[(0x300 * literal_state)..(0x300 * (literal_state + 1))];
+
+        let mut symbol = 1;
+
+        // After a match the literal is predicted from the byte following the match
+        if self.state >= 7 {
+            let mut match_byte = output[output.len() - self.reps[0] - 1];
+            while symbol < 0x100 {
+                let match_bit = ((match_byte >> 7) & 1) as usize;
+                match_byte <<= 1;
+                let bit = range_decoder.decode_bit(&mut probabilities[((1 + match_bit) << 8) + symbol])?;
+                symbol = (symbol << 1) | bit;
+                if match_bit != bit {
+                    break;
+                }
+            }
+        }
+
+        while symbol < 0x100 {
+            symbol = (symbol << 1) | range_decoder.decode_bit(&mut probabilities[symbol])?;
+        }
+
+        output.push((symbol - 0x100) as u8);
+
+        self.state = match self.state {
+            0..=3 => 0,
+            4..=9 => self.state - 3,
+            _ => self.state - 6,
+        };
+        Ok(())
+    }
+
+    fn decode_distance(&mut self, range_decoder: &mut RangeDecoder, length: usize) -> Result<usize, LzmaError> {
+        let length_state = length.min(LZMA_NUM_LEN_TO_POS_STATES - 1);
+        let pos_slot = range_decoder.decode_bit_tree(&mut self.pos_slot[length_state], 6)? as u32;
+        if pos_slot < 4 {
+            return Ok(pos_slot as usize);
+        }
+
+        let direct_bits = (pos_slot >> 1) - 1;
+        let mut distance = ((2 | (pos_slot & 1)) << direct_bits) as usize;
+
+        if pos_slot < LZMA_END_POS_MODEL_INDEX {
+            distance += range_decoder
+                .decode_reverse_bit_tree(&mut self.pos_special[(distance - pos_slot as usize)..], direct_bits)?;
+        } else {
+            distance +=
+                (range_decoder.decode_direct_bits(direct_bits - LZMA_NUM_ALIGN_BITS)? << LZMA_NUM_ALIGN_BITS) as usize;
+            distance += range_decoder.decode_reverse_bit_tree(&mut self.align, LZMA_NUM_ALIGN_BITS)?;
+        }
+
+        Ok(distance)
+    }
+
+    // Decodes until the output reaches `end` bytes or the end marker, whichever comes first
+    fn decode(
+        &mut self,
+        range_decoder: &mut RangeDecoder,
+        output: &mut Vec<u8>,
+        dictionary_start: usize,
+        end: usize,
+    ) -> Result<(), LzmaError> {
+        while output.len() < end {
+            let pos_state = output.len() & ((1 << self.pos_bits) - 1);
+            let state_index = (self.state << 4) + pos_state;
+
+            if range_decoder.decode_bit(&mut self.is_match[state_index])? == 0 {
+                self.decode_literal(range_decoder, output, dictionary_start)?;
+                continue;
+            }
+
+            let length = if range_decoder.decode_bit(&mut self.is_rep[self.state])? == 0 {
+                let length = self.length_decoder.decode(range_decoder, pos_state)?;
+                self.state = if self.state < 7 { 7 } else { 10 };
+
+                let distance = self.decode_distance(range_decoder, length)?;
+                if distance == 0xFFFFFFFF {
+                    return Ok(());
+                }
+
+                self.reps = [distance, self.reps[0], self.reps[1], self.reps[2]];
+                length
+            } else {
+                if output.len() == dictionary_start {
+                    return Err(LzmaError::Distance);
+                }
+
+                if range_decoder.decode_bit(&mut self.is_rep_g0[self.state])? == 0 {
+                    // A single byte from the last match distance
+                    if range_decoder.decode_bit(&mut self.is_rep0_long[state_index])? == 0 {
+                        self.state = if self.state < 7 { 9 } else { 11 };
+                        output.push(output[output.len() - self.reps[0] - 1]);
+                        continue;
+                    }
+                } else {
+                    let distance = if range_decoder.decode_bit(&mut self.is_rep_g1[self.state])? == 0 {
+                        self.reps[1]
+                    } else {
+                        let distance = if range_decoder.decode_bit(&mut self.is_rep_g2[self.state])? == 0 {
+                            self.reps[2]
+                        } else {
+                            let distance = self.reps[3];
+                            self.reps[3] = self.reps[2];
+                            distance
+                        };
+                        self.reps[2] = self.reps[1];
+                        distance
+                    };
+                    self.reps[1] = self.reps[0];
+                    self.reps[0] = distance;
+                }
+
+                let length = self.rep_length_decoder.decode(range_decoder, pos_state)?;
+                self.state = if self.state < 7 { 8 } else { 11 };
+                length
+            };
+
+            if self.reps[0] >= output.len() - dictionary_start {
+                return Err(LzmaError::Distance);
+            }
+
+            // The copied range may overlap the bytes being written
+            let start = output.len() - self.reps[0] - 1;
+            let length = (length + LZMA_MATCH_MIN_LEN).min(end - output.len());
+            for i in 0..length {
+                output.push(output[start + i]);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// LZMA streams of 7-Zip archives, with the 5-byte properties stored separately and a known
+// output size
+pub fn decompress_lzma(properties: &[u8], data: &[u8], output_size: usize) -> Result<Vec<u8>, LzmaError> {
+    if properties.len() != 5 {
+        return Err(LzmaError::Properties);
+    }
+
+    let mut decoder = LzmaDecoder::new(properties[0])?;
+    let mut range_decoder = RangeDecoder::new(data)?;
+    let mut output = Vec::new();
+
+    decoder.decode(&mut range_decoder, &mut output, 0, output_size)?;
+
+    if output.len() != output_size {
+        return Err(LzmaError::Size {
+            expected: output_size,
+            received: output.len(),
+        });
+    }
+
+    Ok(output)
+}
+
+// LZMA2 streams are a sequence of uncompressed and LZMA compressed chunks
+pub fn decompress_lzma2(data: &[u8], output_size: usize) -> Result<Vec<u8>, LzmaError> {
+    let mut output = Vec::new();
+    let mut decoder: Option<LzmaDecoder> = None;
+    let mut dictionary_start = 0;
+    let mut offset = 0;
+
+    loop {
+        let control = *data.get(offset).ok_or(LzmaError::Truncated)?;
+        offset += 1;
+
+        match control {
+            0x00 => break,
+            0x01 | 0x02 => {
+                if control == 0x01 {
+                    dictionary_start = output.len();
+                }
+
+                let size_bytes = data.get(offset..(offset + 2)).ok_or(LzmaError::Truncated)?;
+                let size = u16::from_be_bytes([size_bytes[0], size_bytes[1]]) as usize + 1;
+                offset += 2;
+
+                output.extend_from_slice(data.get(offset..(offset + size)).ok_or(LzmaError::Truncated)?);
+                offset += size;
+            }
+            0x80..=0xFF => {
+                let header = data.get(offset..(offset + 4)).ok_or(LzmaError::Truncated)?;
+                let unpacked_size =
+                    (((control & 0x1F) as usize) << 16) + u16::from_be_bytes([header[0], header[1]]) as usize + 1;
+                let packed_size = u16::from_be_bytes([header[2], header[3]]) as usize + 1;
+                offset += 4;
+
+                // Bits 5 and 6 select what gets reset before the chunk
+                let reset = (control >> 5) & 0x03;
+                if reset == 3 {
+                    dictionary_start = output.len();
+                }
+
+                if reset >= 2 {
+                    let properties = *data.get(offset).ok_or(LzmaError::Truncated)?;
+                    offset += 1;
+
+                    match &mut decoder {
+                        Some(decoder) => decoder.set_properties(properties)?,
+                        None => decoder = Some(LzmaDecoder::new(properties)?),
+                    }
+                }
+
+                let decoder = decoder.as_mut().ok_or(LzmaError::Properties)?;
+                if reset == 1 {
+                    decoder.reset_state();
+                }
+
+                let chunk = data.get(offset..(offset + packed_size)).ok_or(LzmaError::Truncated)?;
+                let mut range_decoder = RangeDecoder::new(chunk)?;
+                let end = output.len() + unpacked_size;
+                decoder.decode(&mut range_decoder, &mut output, dictionary_start, end)?;
+                if output.len() != end {
+                    return Err(LzmaError::Truncated);
+                }
+
+                offset += packed_size;
+            }
+            _ => return Err(LzmaError::Lzma2Control { received: control }),
+        }
+    }
+
+    if output.len() != output_size {
+        return Err(LzmaError::Size {
+            expected: output_size,
+            received: output.len(),
+        });
+    }
+
+    Ok(output)
+}
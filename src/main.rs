@@ -1,41 +1,720 @@
-#![feature(seek_convenience)]
-#![feature(slice_fill)]
-
 use std::env;
 use std::error::Error;
 use std::ffi::{OsStr, OsString};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use clap::builder::RangedU64ValueParser;
+use clap::error::ErrorKind;
+use clap::{value_parser, Arg, ArgAction, Command};
+use crc::crc32;
+use log::{error, warn, LevelFilter};
+
+use bps_fuse::checksums::ChecksumFormat;
+use bps_fuse::disk_cache::DiskCache;
+use bps_fuse::logging::{self, LogFormat};
+use bps_fuse::patch::bps::BpsPatch;
+use bps_fuse::patch::ups::UpsPatch;
+use bps_fuse::patch::{self, Patch, PatchFormat};
+use bps_fuse::rom_cache::DEFAULT_CACHE_SIZE;
+use bps_fuse::rom_filesystem::{RomFilesystem, DEFAULT_ATTR_TTL};
+use bps_fuse::rom_manager::{BadSourcePolicy, HeaderStripping, Layout, RomManager, DEFAULT_MAX_TARGET_SIZE};
+use bps_fuse::rom_watcher::RomWatcher;
+use bps_fuse::scan_progress::{ProgressReporter, ScanProgress};
+use bps_fuse::stats::StatsFormat;
+use bps_fuse::utils::MappedFile;
+use bps_fuse::{config, signals};
+
+// The options are listed in the order of the help text
+fn command() -> Command {
+    let program = env::args().next().unwrap_or_else(|| "bps-fuse".to_owned());
+    let path = || value_parser!(PathBuf);
+
+    Command::new("bps-fuse")
+        .override_usage(format!(
+            "{0} [options] [<patch_dirs> [<mount_point>]]\n       {0} --verify-only <source_rom> <patch>",
+            program
+        ))
+        .help_template("Usage: {usage}\n\nOptions:\n{options}")
+        .term_width(120)
+        .disable_help_flag(true)
+        .arg(
+            Arg::new("source-dir")
+                .long("source-dir")
+                .value_name("path")
+                .value_parser(path())
+                .help("Directory of the source ROMs (default: the patch directory)"),
+        )
+        .arg(
+            Arg::new("rom-dir")
+                .long("rom-dir")
+                .value_name("path")
+                .value_parser(path())
+                .action(ArgAction::Append)
+                .help(
+                    "Library of source ROMs, matched by their checksums whatever their names are. Can be given \
+                     repeatedly.",
+                ),
+        )
+        .arg(
+            Arg::new("scan-threads")
+                .long("scan-threads")
+                .value_name("count")
+                .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+                .help("Hash the source ROMs and detect the patches on this many threads (default: the number of CPUs)"),
+        )
+        .arg(Arg::new("rebuild-index").long("rebuild-index").action(ArgAction::SetTrue).help(
+            "Hash every source ROM again, instead of reusing the checksums of the unchanged ones from \
+             ~/.cache/fuse-softpatch",
+        ))
+        .arg(
+            Arg::new("patch-dir")
+                .long("patch-dir")
+                .value_name("paths")
+                .value_parser(path())
+                .action(ArgAction::Append)
+                .help(
+                    "Directories of the patch files, separated by colons or given repeatedly. Later directories \
+                     take precedence when their target ROMs collide.",
+                ),
+        )
+        .arg(
+            Arg::new("mountpoint")
+                .long("mountpoint")
+                .value_name("path")
+                .value_parser(path())
+                .help("Directory to mount the patched ROMs at"),
+        )
+        // Subdirectories are always scanned, unless limited by --max-depth
+        .arg(
+            Arg::new("recursive")
+                .long("recursive")
+                .action(ArgAction::SetTrue)
+                .help("Scan the subdirectories of the patch and source directories (default)"),
+        )
+        .arg(
+            Arg::new("max-depth")
+                .long("max-depth")
+                .value_name("depth")
+                .value_parser(value_parser!(usize))
+                .help("Scan the subdirectories only this many levels deep (default: unlimited)"),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-watch")
+                .help("Refresh the ROMs when the directories change (default)"),
+        )
+        .arg(
+            Arg::new("no-watch")
+                .long("no-watch")
+                .action(ArgAction::SetTrue)
+                .overrides_with("watch")
+                .help("Only refresh the ROMs on SIGUSR1"),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .action(ArgAction::SetTrue)
+                .help("Refuse to mount when any of the patches fails to load, instead of skipping it"),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("path")
+                .value_parser(path())
+                .help(
+                    "Describe target ROMs explicitly with a TOML configuration file, giving their names, source \
+                     ROMs, patches and expected checksums",
+                ),
+        )
+        .arg(
+            Arg::new("map")
+                .long("map")
+                .value_name("path")
+                .value_parser(path())
+                .help(
+                    "Assign source ROMs to patches explicitly with a TOML mapping file, skipping the automatic \
+                     matching of the mapped patches",
+                ),
+        )
+        .arg(
+            Arg::new("include")
+                .long("include")
+                .value_name("pattern")
+                .action(ArgAction::Append)
+                .help(
+                    "Only load the patches matching the glob pattern, relative to their patch directory, like \
+                     '*.bps'. Can be given repeatedly.",
+                ),
+        )
+        .arg(
+            Arg::new("exclude")
+                .long("exclude")
+                .value_name("pattern")
+                .action(ArgAction::Append)
+                .help("Skip the patches matching the glob pattern, taking precedence over --include. Can be given repeatedly."),
+        )
+        // The filesystem never modifies anything, only the control file accepts writes
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .action(ArgAction::SetTrue)
+                .help("Refuse every modification but the commands written to .control (default)"),
+        )
+        .arg(
+            Arg::new("allow-other")
+                .long("allow-other")
+                .action(ArgAction::SetTrue)
+                .help("Allow other users to access the filesystem"),
+        )
+        .arg(
+            Arg::new("cache-size")
+                .long("cache-size")
+                .value_name("bytes")
+                .value_parser(value_parser!(u64))
+                .help("Size of the in-memory cache of patched ROMs"),
+        )
+        .arg(
+            Arg::new("keep-cached")
+                .long("keep-cached")
+                .action(ArgAction::SetTrue)
+                .help("Keep the patched ROMs in the kernel page cache"),
+        )
+        .arg(
+            Arg::new("dedup")
+                .long("dedup")
+                .action(ArgAction::SetTrue)
+                .help("Share the cached data of ROMs with identical contents, patched by different patches"),
+        )
+        .arg(
+            Arg::new("pregenerate")
+                .long("pregenerate")
+                .action(ArgAction::SetTrue)
+                .help("Patch every ROM fitting in the cache in the background after mounting, on --scan-threads threads"),
+        )
+        .arg(
+            Arg::new("cache-dir")
+                .long("cache-dir")
+                .value_name("path")
+                .value_parser(path())
+                .help("Directory to persist the patched ROMs in"),
+        )
+        .arg(
+            Arg::new("attr-ttl")
+                .long("attr-ttl")
+                .value_name("seconds")
+                .value_parser(value_parser!(u64))
+                .help("How long the kernel caches the attributes of the files (default: 60)"),
+        )
+        .arg(
+            Arg::new("read-ahead")
+                .long("read-ahead")
+                .value_name("bytes")
+                .value_parser(value_parser!(u64))
+                .help(
+                    "Keep patching this far ahead of the reads in the background (default: 0). Only with \
+                     --no-verify, verified ROMs are patched completely on the first read.",
+                ),
+        )
+        .arg(
+            Arg::new("no-verify")
+                .long("no-verify")
+                .action(ArgAction::SetTrue)
+                .help("Skip verifying the patched ROMs against their stored checksums"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Patch every ROM in memory and report the results without mounting"),
+        )
+        .arg(
+            Arg::new("show-stats")
+                .long("show-stats")
+                .action(ArgAction::SetTrue)
+                .help("List the statistics file (.stats) in the root directory"),
+        )
+        .arg(
+            Arg::new("stats-format")
+                .long("stats-format")
+                .value_name("format")
+                .value_parser(["text", "json"])
+                .hide_possible_values(true)
+                .help("Render the statistics file as: text (default), json"),
+        )
+        .arg(
+            Arg::new("expose-metadata")
+                .long("expose-metadata")
+                .action(ArgAction::SetTrue)
+                .help("List the metadata of the patches as .meta.xml/.meta.txt files"),
+        )
+        .arg(
+            Arg::new("expose-patchinfo")
+                .long("expose-patchinfo")
+                .action(ArgAction::SetTrue)
+                .help("List summaries of the patching of the ROMs as .patchinfo files"),
+        )
+        .arg(
+            Arg::new("emit-checksums")
+                .long("emit-checksums")
+                .value_name("formats")
+                .value_parser(|value: &str| {
+                    value
+                        .split(',')
+                        .map(ChecksumFormat::parse)
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or("expected a comma separated list of sfv, md5, sha1")
+                })
+                .help("List checksum files of the ROMs in the root directory: sfv, md5, sha1"),
+        )
+        .arg(
+            Arg::new("expose-patches")
+                .long("expose-patches")
+                .action(ArgAction::SetTrue)
+                .help("List the patch files of the ROMs in the .patches directory"),
+        )
+        .arg(
+            Arg::new("include-sources")
+                .long("include-sources")
+                .action(ArgAction::SetTrue)
+                .help("List the source ROMs along with the target ROMs, target ROMs take precedence"),
+        )
+        .arg(
+            Arg::new("layout")
+                .long("layout")
+                .value_name("layout")
+                .value_parser(["flat", "per-rom"])
+                .hide_possible_values(true)
+                .help(
+                    "Arrange the ROMs: flat (default), per-rom (a directory for every ROM, along with its patch and \
+                     metadata)",
+                ),
+        )
+        .arg(
+            Arg::new("latest-links")
+                .long("latest-links")
+                .action(ArgAction::SetTrue)
+                .help("Add symlinks to the newest versions of versioned ROMs"),
+        )
+        .arg(
+            Arg::new("metadata-names")
+                .long("metadata-names")
+                .action(ArgAction::SetTrue)
+                .help("Name the ROMs of BPS patches after the <name> in their metadata"),
+        )
+        .arg(
+            Arg::new("on-bad-source")
+                .long("on-bad-source")
+                .value_name("mode")
+                .value_parser(["hide", "error", "ignore"])
+                .hide_possible_values(true)
+                .help(
+                    "Handle BPS patches of mismatching source ROMs: hide (default), error, ignore (only when there \
+                     is a single source ROM, or a single one of the size expected by the patch)",
+                ),
+        )
+        .arg(
+            Arg::new("auto-strip-header")
+                .long("auto-strip-header")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-auto-strip-header")
+                .help(
+                    "Strip the copier headers of source ROMs when only that makes them match (default: only for \
+                     .smc files)",
+                ),
+        )
+        .arg(
+            Arg::new("no-auto-strip-header")
+                .long("no-auto-strip-header")
+                .action(ArgAction::SetTrue)
+                .overrides_with("auto-strip-header")
+                .help("Never strip the copier headers of source ROMs, not even of .smc files"),
+        )
+        .arg(
+            Arg::new("max-target-size")
+                .long("max-target-size")
+                .value_name("bytes")
+                .value_parser(value_parser!(u64))
+                .help("Skip patches producing larger ROMs (default: 4 GiB)"),
+        )
+        .arg(
+            Arg::new("verify-only")
+                .long("verify-only")
+                .num_args(2)
+                .value_names(["source_rom", "patch"])
+                .value_parser(path())
+                .help(
+                    "Check the source ROM against the source checksum of a BPS or UPS patch and exit, with a \
+                     non-zero exit code on mismatch",
+                ),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                // Counted by the values, clap lists counted flags as "--verbose..."
+                .num_args(0)
+                .default_missing_value("1")
+                .action(ArgAction::Append)
+                .help(
+                    "Log more, repeatable up to -vvvv (debug and trace messages). Overrides the log level of \
+                     RUST_LOG.",
+                ),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .action(ArgAction::SetTrue)
+                .help("Log nothing, not even the errors"),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("format")
+                .value_parser(["text", "json"])
+                .hide_possible_values(true)
+                .help(
+                    "Log as: text (default), json (an object per line, with the operation, path, duration and error \
+                     fields of the patching and scanning events)",
+                ),
+        )
+        .arg(
+            Arg::new("help")
+                .short('h')
+                .long("help")
+                .action(ArgAction::Help)
+                .help("Print this help"),
+        )
+        // The directories are also accepted as positional arguments
+        .arg(
+            Arg::new("args")
+                .num_args(0..=2)
+                .value_names(["patch_dirs", "mount_point"])
+                .value_parser(value_parser!(OsString))
+                .hide(true),
+        )
+}
+
+// Invalid command lines print the usage on stderr, followed by the first line of the error
+fn usage(err: Option<clap::Error>) -> ! {
+    eprint!("{}", command().render_help());
+    if let Some(line) = err
+        .as_ref()
+        .and_then(|err| err.to_string().lines().next().map(str::to_owned))
+    {
+        eprintln!("\n{}", line);
+    }
+    process::exit(1);
+}
+
+// Without any -v or -q flags RUST_LOG decides, only the errors are logged without it
+fn init_logger(verbosity: Option<i32>, log_format: LogFormat) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if log_format == LogFormat::Json {
+        builder.format(logging::write_json_record);
+    }
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    } else {
+        builder.filter_level(LevelFilter::Error);
+    }
+
+    if let Some(verbosity) = verbosity {
+        builder.filter_level(match verbosity {
+            i32::MIN..=-1 => LevelFilter::Off,
+            0 => LevelFilter::Error,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Info,
+            3 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        });
+    }
+
+    builder.init();
+}
+
+// Only BPS and UPS patches store the checksums of their source ROMs
+fn verify_source(source_path: &Path, patch_path: &Path) -> Result<bool, Box<dyn Error>> {
+    let (source_checksum, target_checksum) = match PatchFormat::detect(patch_path)? {
+        Some(PatchFormat::Bps) => {
+            let patch = BpsPatch::new(patch_path)?;
+            (patch.source_checksum(), patch.target_checksum())
+        }
+        Some(PatchFormat::Ups) => {
+            let patch = UpsPatch::new(patch_path)?;
+            (patch.source_checksum(), patch.target_checksum())
+        }
+        Some(patch_format) => return Err(format!("{} patches store no source checksum", patch_format.name()).into()),
+        None => return Err("unrecognized patch format".into()),
+    };
+
+    let source = MappedFile::open(source_path)?;
+    let checksum = crc32::checksum_ieee(&source);
+
+    if checksum == source_checksum {
+        println!("MATCH  {} (CRC32=0x{:08X})", source_path.display(), checksum);
+    } else {
+        println!(
+            "MISMATCH  {} (expected CRC32=0x{:08X}, received CRC32=0x{:08X})",
+            source_path.display(),
+            source_checksum,
+            checksum
+        );
+    }
+    if let Some(target_checksum) = target_checksum {
+        println!("Target CRC32=0x{:08X}", target_checksum);
+    }
+
+    Ok(checksum == source_checksum)
+}
+
+// Every target is patched and verified once, patches without targets are failures too
+fn validate_patches(rom_manager: &RomManager) -> bool {
+    let mut target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+    target_paths.sort();
+
+    let mut success = true;
 
-mod patch;
-mod rom_filesystem;
-mod rom_manager;
-mod rom_watcher;
-mod utils;
+    for target_path in target_paths {
+        let patch = &rom_manager.target_roms[target_path];
+        let result = patch
+            .patched_rom()
+            .and_then(|target| patch::verify_target_checksum(patch.as_ref(), &target).map_err(Into::into));
 
-use rom_filesystem::RomFilesystem;
-use rom_manager::RomManager;
-use rom_watcher::RomWatcher;
+        match result {
+            Ok(()) => println!("PASS  {}", target_path.display()),
+            Err(err) => {
+                println!("FAIL  {}: {}", target_path.display(), err);
+                success = false;
+            }
+        }
+    }
+
+    for patch_path in &rom_manager.unmatched_patches {
+        println!("FAIL  {}: no target ROM", patch_path.display());
+        success = false;
+    }
+
+    success
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<OsString> = env::args_os().collect();
+    let matches = command().try_get_matches().unwrap_or_else(|err| match err.kind() {
+        ErrorKind::DisplayHelp => err.exit(),
+        _ => usage(Some(err)),
+    });
+
+    let flag = |name| matches.get_flag(name);
+    let path = |name| matches.get_one::<PathBuf>(name).cloned();
+    let strings = |name| {
+        matches
+            .get_many::<String>(name)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    let source_directory = path("source-dir");
+    let rom_directories: Vec<PathBuf> = matches.get_many("rom-dir").into_iter().flatten().cloned().collect();
+    let mut patch_directories: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("patch-dir")
+        .into_iter()
+        .flatten()
+        .flat_map(env::split_paths)
+        .collect();
+    let mount_point = path("mountpoint");
+    let allow_other = flag("allow-other");
+    let cache_size = matches.get_one("cache-size").copied().unwrap_or(DEFAULT_CACHE_SIZE);
+    let keep_cached = flag("keep-cached");
+    let pregenerate = flag("pregenerate");
+    let dedup = flag("dedup");
+    let cache_directory = path("cache-dir");
+    let read_ahead = matches.get_one("read-ahead").copied().unwrap_or(0);
+    let attr_ttl = matches.get_one("attr-ttl").copied().unwrap_or(DEFAULT_ATTR_TTL);
+    let verify = !flag("no-verify");
+    let dry_run = flag("dry-run");
+    let log_format = match matches.get_one::<String>("log-format").map(String::as_str) {
+        Some("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+    let latest_links = flag("latest-links");
+    let metadata_names = flag("metadata-names");
+    let show_stats = flag("show-stats");
+    let stats_format = match matches.get_one::<String>("stats-format").map(String::as_str) {
+        Some("json") => StatsFormat::Json,
+        _ => StatsFormat::Text,
+    };
+    let mut expose_metadata = flag("expose-metadata");
+    let expose_patchinfo = flag("expose-patchinfo");
+    let checksum_formats = matches
+        .get_one::<Vec<ChecksumFormat>>("emit-checksums")
+        .cloned()
+        .unwrap_or_default();
+    let bad_source_policy = match matches.get_one::<String>("on-bad-source").map(String::as_str) {
+        Some("error") => BadSourcePolicy::Error,
+        Some("ignore") => BadSourcePolicy::Ignore,
+        _ => BadSourcePolicy::Hide,
+    };
+    let max_target_size = matches
+        .get_one("max-target-size")
+        .copied()
+        .unwrap_or(DEFAULT_MAX_TARGET_SIZE);
+    let max_depth = matches.get_one("max-depth").copied();
+    let mapping_path = path("map");
+    let config_path = path("config");
+    let strict = flag("strict");
+    let include_patterns = strings("include");
+    let exclude_patterns = strings("exclude");
+    let header_stripping = if flag("auto-strip-header") {
+        HeaderStripping::Always
+    } else if flag("no-auto-strip-header") {
+        HeaderStripping::Never
+    } else {
+        HeaderStripping::CopierExtensions
+    };
+    let expose_patches = flag("expose-patches");
+    let include_sources = flag("include-sources");
+    let rebuild_index = flag("rebuild-index");
+    let watch = !flag("no-watch");
+    let scan_threads = matches
+        .get_one("scan-threads")
+        .copied()
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |count| count.get()));
+    let layout = match matches.get_one::<String>("layout").map(String::as_str) {
+        Some("per-rom") => Layout::PerRom,
+        _ => Layout::Flat,
+    };
+
+    let verbosity = if flag("quiet") {
+        Some(-1)
+    } else {
+        matches
+            .get_many::<String>("verbose")
+            .map(|values| values.count() as i32)
+    };
 
-    if args.len() != 3 {
-        println!("Usage: {} <base_directory> <mount_point>", &env::args().next().unwrap());
-        process::exit(-1);
+    if let Some(mut verify_only) = matches.get_many::<PathBuf>("verify-only") {
+        let (source_path, patch_path) = (verify_only.next().unwrap(), verify_only.next().unwrap());
+        init_logger(verbosity, log_format);
+        let matches = verify_source(source_path, patch_path)?;
+        process::exit(if matches { 0 } else { 1 });
     }
 
-    pretty_env_logger::init();
+    // The directories are also accepted as positional arguments
+    let mut args_iter = matches
+        .get_many::<OsString>("args")
+        .into_iter()
+        .flatten()
+        .map(PathBuf::from);
+    if patch_directories.is_empty() {
+        patch_directories.extend(env::split_paths(&args_iter.next().unwrap_or_else(|| usage(None))));
+    }
+    let mount_point = mount_point.or_else(|| args_iter.next());
+    if args_iter.next().is_some() || (mount_point.is_none() && !dry_run) {
+        usage(None);
+    }
+
+    // The directories of the ROMs hold everything related to them
+    if layout == Layout::PerRom {
+        expose_metadata = true;
+    }
+
+    init_logger(verbosity, log_format);
+
+    // Invalid configurations are rejected upfront, instead of mounting whatever is valid of them
+    let configured_targets = match &config_path {
+        Some(config_path) => config::read_config_file(config_path).unwrap_or_else(|err| {
+            error!("Failed to load {:?}: {}", config_path, err);
+            process::exit(1);
+        }),
+        None => Vec::new(),
+    };
 
-    let base_directory = PathBuf::from(&args[1]);
-    let rom_manager = Arc::new(Mutex::new(RomManager::new(&base_directory)?));
+    let scan_progress = Arc::new(ScanProgress::default());
+    let progress_reporter = ProgressReporter::new(&scan_progress);
 
-    let rom_filesystem = RomFilesystem::new(rom_manager.clone());
-    let _rom_watcher = RomWatcher::new(rom_manager.clone())?;
+    let rom_manager = Arc::new(RwLock::new(RomManager::new(
+        &patch_directories,
+        source_directory.as_deref(),
+        &rom_directories,
+        latest_links,
+        bad_source_policy,
+        max_target_size,
+        max_depth,
+        mapping_path.as_deref(),
+        include_patterns,
+        exclude_patterns,
+        header_stripping,
+        expose_patches,
+        include_sources,
+        layout,
+        metadata_names,
+        rebuild_index,
+        scan_threads,
+        configured_targets,
+        scan_progress,
+        strict,
+    )?));
+    progress_reporter.finish(rom_manager.read().unwrap().target_roms.len());
 
-    let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
-    fuse_mt::mount(fuse_mt::FuseMT::new(rom_filesystem, 1), &args[2], &fuse_args)?;
+    if dry_run {
+        let rom_manager = rom_manager.read().unwrap();
+        let success = validate_patches(&rom_manager);
+        rom_manager.remove_extracted_patches();
+        process::exit(if success { 0 } else { 1 });
+    }
+
+    let disk_cache = match cache_directory {
+        Some(cache_directory) => Some(DiskCache::new(&cache_directory)?),
+        None => None,
+    };
+
+    let rom_filesystem = RomFilesystem::new(
+        rom_manager.clone(),
+        cache_size,
+        keep_cached,
+        dedup,
+        disk_cache,
+        verify,
+        show_stats,
+        stats_format,
+        expose_metadata,
+        expose_patchinfo,
+        checksum_formats,
+        read_ahead,
+        attr_ttl,
+        pregenerate.then_some(scan_threads),
+    );
+    let mount_point = mount_point.unwrap();
+    signals::handle_signals(&mount_point, rom_manager.clone())?;
+    // Changes are still picked up on SIGUSR1 without watches, like when running out of them
+    let _rom_watcher = if watch {
+        match RomWatcher::new(rom_manager.clone()) {
+            Ok(rom_watcher) => Some(rom_watcher),
+            Err(err) => {
+                warn!(
+                    "Failed to watch the ROM directories, refresh with SIGUSR1 instead: {}",
+                    err
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
+    if allow_other {
+        fuse_args.extend(&[OsStr::new("-o"), OsStr::new("allow_other")]);
+    }
+    let result = fuse_mt::mount(fuse_mt::FuseMT::new(rom_filesystem, 1), &mount_point, &fuse_args);
 
-    Ok(())
+    rom_manager.read().unwrap().remove_extracted_patches();
+    Ok(result?)
 }
@@ -0,0 +1,135 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Mapping files assign source ROMs to patches explicitly, instead of matching them by checksums:
+//
+//     [["Hack.bps"]]
+//     source = "roms/Game (U) (V1.1).sfc"
+//     target_name = "Hack.sfc"
+//     verify = false
+//
+// Paths are relative to the mapping file, target names are plain file names placed next to the
+// other targets of the patches. Patches may be mapped to several source ROMs.
+pub struct PatchMapping {
+    pub patch_path: PathBuf,
+    pub source_path: PathBuf,
+    // Named after the patch by default
+    pub target_name: Option<String>,
+    // Source ROMs are checked against the patches for formats supporting it, unless disabled
+    pub verify: bool,
+}
+
+pub fn read_mapping_file(mapping_path: &Path) -> Result<Vec<PatchMapping>, Box<dyn Error>> {
+    let mapping_directory = mapping_path.parent().unwrap();
+    let document = fs::read_to_string(mapping_path)?.parse::<toml::Value>()?;
+    let mut mappings = Vec::new();
+
+    for (patch_name, value) in document.as_table().unwrap() {
+        let entries = match value {
+            toml::Value::Array(entries) => entries.iter().collect(),
+            entry => vec![entry],
+        };
+
+        for entry in entries {
+            let source_name = entry
+                .get("source")
+                .and_then(toml::Value::as_str)
+                .ok_or_else(|| format!("no source ROM is given for {:?}", patch_name))?;
+
+            let target_name = entry.get("target_name").and_then(toml::Value::as_str);
+            if let Some(target_name) = target_name {
+                let mut components = Path::new(target_name).components();
+                if !matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(_)), None)
+                ) {
+                    return Err(format!("invalid target name {:?} of {:?}", target_name, patch_name).into());
+                }
+            }
+
+            mappings.push(PatchMapping {
+                patch_path: mapping_directory.join(patch_name),
+                source_path: mapping_directory.join(source_name),
+                target_name: target_name.map(str::to_owned),
+                verify: entry.get("verify").and_then(toml::Value::as_bool).unwrap_or(true),
+            });
+        }
+    }
+
+    // Tables are unordered, but later mappings shadow earlier ones
+    mappings.sort_by(|a, b| a.patch_path.cmp(&b.patch_path));
+    Ok(mappings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn read_mapping(name: &str, mapping: &str) -> Result<Vec<PatchMapping>, Box<dyn Error>> {
+        let mapping_path = env::temp_dir().join(format!("mapping-{}-{}.toml", name, std::process::id()));
+        fs::write(&mapping_path, mapping).unwrap();
+        let result = read_mapping_file(&mapping_path);
+        fs::remove_file(&mapping_path).unwrap();
+        result
+    }
+
+    #[test]
+    fn test_mappings() {
+        let mappings = read_mapping(
+            "mappings",
+            r#"
+                ["Hack.bps"]
+                source = "roms/Game.sfc"
+
+                [["Addon.ups"]]
+                source = "roms/Game (U).sfc"
+                target_name = "Addon (U).sfc"
+                verify = false
+
+                [["Addon.ups"]]
+                source = "roms/Game (E).sfc"
+            "#,
+        )
+        .unwrap();
+
+        let mapping_directory = env::temp_dir();
+        assert_eq!(mappings.len(), 3);
+        assert_eq!(mappings[0].patch_path, mapping_directory.join("Addon.ups"));
+        assert_eq!(mappings[0].target_name.as_deref(), Some("Addon (U).sfc"));
+        assert!(!mappings[0].verify);
+        assert_eq!(mappings[1].source_path, mapping_directory.join("roms/Game (E).sfc"));
+        assert_eq!(mappings[1].target_name, None);
+        assert!(mappings[1].verify);
+        assert_eq!(mappings[2].patch_path, mapping_directory.join("Hack.bps"));
+    }
+
+    #[test]
+    fn test_missing_source() {
+        let err = read_mapping("missing-source", "[\"Hack.bps\"]\nverify = false\n")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "no source ROM is given for \"Hack.bps\"");
+    }
+
+    #[test]
+    fn test_invalid_target_names() {
+        for (index, target_name) in ["../Hack.sfc", "Hacks/Hack.sfc", "/Hack.sfc", "..", "."]
+            .iter()
+            .enumerate()
+        {
+            let mapping = format!(
+                "[\"Hack.bps\"]\nsource = \"Game.sfc\"\ntarget_name = {:?}\n",
+                target_name
+            );
+            let err = read_mapping(&format!("invalid-target-name-{}", index), &mapping)
+                .err()
+                .unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!("invalid target name {:?} of \"Hack.bps\"", target_name)
+            );
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::patch::{Patch, PatchFormat};
+use crate::utils::MappedFile;
+
+pub const APS_FORMAT_MARKER: [u8; 5] = [b'A', b'P', b'S', b'1', b'0'];
+const APS_TYPE_N64: u8 = 1;
+const APS_ENCODING_SIMPLE: u8 = 0;
+
+const N64_CART_ID_OFFSET: usize = 0x3C;
+const N64_CRC_OFFSET: usize = 0x10;
+
+#[derive(Debug)]
+pub enum ApsError {
+    OutdatedCache,
+    FormatMarker { expected: [u8; 5], received: [u8; 5] },
+    PatchType { received: u8 },
+    EncodingMethod { received: u8 },
+    SourceRom,
+    TargetOverflow { offset: u64, target_size: u64 },
+}
+
+impl fmt::Display for ApsError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApsError::OutdatedCache => write!(formatter, "outdated cache"),
+            ApsError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            ApsError::PatchType { received } => write!(formatter, "unsupported patch type ({})", received),
+            ApsError::EncodingMethod { received } => write!(formatter, "unsupported encoding method ({})", received),
+            ApsError::SourceRom => write!(formatter, "source ROM does not match the cartridge ID and CRC"),
+            ApsError::TargetOverflow { offset, target_size } => write!(
+                formatter,
+                "patch record out of target bounds (offset: {}, target size: {})",
+                offset, target_size
+            ),
+        }
+    }
+}
+
+impl Error for ApsError {}
+
+// Nintendo 64 flavor of the APS format, the original ROM is identified by the cartridge ID
+// and the CRC stored in the ROM header
+#[derive(Debug)]
+pub struct ApsPatch {
+    source_path: Option<PathBuf>,
+    source_cart_id: [u8; 3],
+    source_crc: [u8; 8],
+
+    target_size: u64,
+
+    patch_path: PathBuf,
+    patch_description: Vec<u8>,
+    patch_offset: u64,
+    patch_modified: SystemTime,
+}
+
+impl ApsPatch {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut patch_file = File::open(patch_path)?;
+        let patch_modified = patch_file.metadata()?.modified()?;
+
+        let mut format_marker: [u8; 5] = [0; 5];
+        patch_file.read_exact(&mut format_marker)?;
+        if format_marker != APS_FORMAT_MARKER {
+            return Err(Box::new(ApsError::FormatMarker {
+                expected: APS_FORMAT_MARKER,
+                received: format_marker,
+            }));
+        }
+
+        let patch_type = patch_file.read_u8()?;
+        if patch_type != APS_TYPE_N64 {
+            return Err(Box::new(ApsError::PatchType { received: patch_type }));
+        }
+
+        let encoding_method = patch_file.read_u8()?;
+        if encoding_method != APS_ENCODING_SIMPLE {
+            return Err(Box::new(ApsError::EncodingMethod {
+                received: encoding_method,
+            }));
+        }
+
+        let mut patch_description = vec![0; 50];
+        patch_file.read_exact(&mut patch_description)?;
+        let description_size = patch_description.iter().rposition(|&b| b != 0 && b != b' ');
+        patch_description.truncate(description_size.map_or(0, |size| size + 1));
+
+        let _source_format = patch_file.read_u8()?;
+
+        let mut source_cart_id: [u8; 3] = [0; 3];
+        patch_file.read_exact(&mut source_cart_id)?;
+
+        let mut source_crc: [u8; 8] = [0; 8];
+        patch_file.read_exact(&mut source_crc)?;
+
+        let mut _padding: [u8; 5] = [0; 5];
+        patch_file.read_exact(&mut _padding)?;
+
+        let target_size = patch_file.read_u32::<LittleEndian>()? as u64;
+        let patch_offset = patch_file.stream_position()?;
+
+        Ok(Self {
+            source_path: None,
+            source_cart_id,
+            source_crc,
+            target_size,
+            patch_path: patch_path.to_owned(),
+            patch_description,
+            patch_offset,
+            patch_modified,
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) {
+        self.source_path = Some(source_path.to_path_buf());
+    }
+
+    pub fn description(&self) -> String {
+        String::from_utf8_lossy(&self.patch_description).into_owned()
+    }
+
+    pub fn verify_source(&self, source: &[u8]) -> Result<(), ApsError> {
+        let cart_id = source.get(N64_CART_ID_OFFSET..(N64_CART_ID_OFFSET + self.source_cart_id.len()));
+        let crc = source.get(N64_CRC_OFFSET..(N64_CRC_OFFSET + self.source_crc.len()));
+
+        if cart_id == Some(&self.source_cart_id[..]) && crc == Some(&self.source_crc[..]) {
+            Ok(())
+        } else {
+            Err(ApsError::SourceRom)
+        }
+    }
+}
+
+impl Patch for ApsPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::Aps.name()
+    }
+
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let patch_data = {
+            let mut patch_file = File::open(&self.patch_path)?;
+
+            if patch_file.metadata()?.modified()? != self.patch_modified {
+                return Err(Box::new(ApsError::OutdatedCache));
+            }
+
+            let mut patch_data = Vec::new();
+            patch_file.read_to_end(&mut patch_data)?;
+            patch_data
+        };
+
+        let source = MappedFile::open(self.source_path.as_ref().unwrap())?;
+        self.verify_source(&source)?;
+
+        let mut target = source.to_vec();
+        target.resize(self.target_size as usize, 0);
+
+        let mut patch_cursor = Cursor::new(&patch_data[self.patch_offset as usize..]);
+
+        while patch_cursor.stream_position()? < patch_cursor.get_ref().len() as u64 {
+            let offset = patch_cursor.read_u32::<LittleEndian>()? as usize;
+            let size = patch_cursor.read_u8()? as usize;
+
+            // Zero sized records are run-length encoded
+            let (size, rle_value) = if size == 0 {
+                let rle_value = patch_cursor.read_u8()?;
+                let rle_size = patch_cursor.read_u8()? as usize;
+                (rle_size, Some(rle_value))
+            } else {
+                (size, None)
+            };
+
+            if offset + size > target.len() {
+                return Err(Box::new(ApsError::TargetOverflow {
+                    offset: offset as u64,
+                    target_size: self.target_size,
+                }));
+            }
+
+            match rle_value {
+                Some(rle_value) => target[offset..(offset + size)].fill(rle_value),
+                None => patch_cursor.read_exact(&mut target[offset..(offset + size)])?,
+            }
+        }
+
+        Ok(target)
+    }
+}
@@ -0,0 +1,252 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::patch::{Patch, PatchFormat};
+use crate::utils::MappedFile;
+
+pub const APS_GBA_FORMAT_MARKER: [u8; 4] = [b'A', b'P', b'S', b'1'];
+const APS_GBA_HEADER_SIZE: u64 = 12;
+const APS_GBA_BLOCK_SIZE: usize = 0x10000;
+const APS_GBA_RECORD_SIZE: u64 = 8 + APS_GBA_BLOCK_SIZE as u64;
+
+#[derive(Debug)]
+pub enum ApsGbaError {
+    OutdatedCache,
+    FormatMarker { expected: [u8; 4], received: [u8; 4] },
+    Structure { size: u64 },
+    SourceLength { expected: u64, received: u64 },
+    SourceChecksum { offset: u64, expected: u16, received: u16 },
+    TargetChecksum { offset: u64, expected: u16, received: u16 },
+}
+
+impl fmt::Display for ApsGbaError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApsGbaError::OutdatedCache => write!(formatter, "outdated cache"),
+            ApsGbaError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            ApsGbaError::Structure { size } => write!(formatter, "invalid patch file size ({} bytes)", size),
+            ApsGbaError::SourceLength { expected, received } => write!(
+                formatter,
+                "source length mismatch (expected: {}, received: {})",
+                expected, received
+            ),
+            ApsGbaError::SourceChecksum {
+                offset,
+                expected,
+                received,
+            } => write!(
+                formatter,
+                "invalid source block checksum at 0x{:X} (expected: 0x{:04X}, received: 0x{:04X})",
+                offset, expected, received
+            ),
+            ApsGbaError::TargetChecksum {
+                offset,
+                expected,
+                received,
+            } => write!(
+                formatter,
+                "invalid target block checksum at 0x{:X} (expected: 0x{:04X}, received: 0x{:04X})",
+                offset, expected, received
+            ),
+        }
+    }
+}
+
+impl Error for ApsGbaError {}
+
+// The file consists of the header and fixed-size block records, nothing else
+pub fn has_aps_gba_structure(patch_size: u64) -> bool {
+    patch_size >= APS_GBA_HEADER_SIZE && (patch_size - APS_GBA_HEADER_SIZE).is_multiple_of(APS_GBA_RECORD_SIZE)
+}
+
+// CRC16/CCITT-FALSE, used for the per-block checksums
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Content of the block at `offset`, padded with zeroes past the end of the data
+fn block_at(data: &[u8], offset: usize) -> Vec<u8> {
+    let mut block = vec![0; APS_GBA_BLOCK_SIZE];
+    if offset < data.len() {
+        let size = APS_GBA_BLOCK_SIZE.min(data.len() - offset);
+        block[0..size].clone_from_slice(&data[offset..(offset + size)]);
+    }
+    block
+}
+
+#[derive(Debug)]
+struct ApsGbaBlock {
+    offset: u64,
+    source_checksum: u16,
+    target_checksum: u16,
+}
+
+// Game Boy Advance flavor of the APS format, storing XOR-ed 64 KiB blocks
+#[derive(Debug)]
+pub struct ApsGbaPatch {
+    source_path: Option<PathBuf>,
+    source_size: u64,
+
+    target_size: u64,
+
+    patch_path: PathBuf,
+    patch_blocks: Vec<ApsGbaBlock>,
+    patch_modified: SystemTime,
+}
+
+impl ApsGbaPatch {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut patch_file = File::open(patch_path)?;
+        let patch_size = patch_file.metadata()?.len();
+        let patch_modified = patch_file.metadata()?.modified()?;
+
+        let mut format_marker: [u8; 4] = [0; 4];
+        patch_file.read_exact(&mut format_marker)?;
+        if format_marker != APS_GBA_FORMAT_MARKER {
+            return Err(Box::new(ApsGbaError::FormatMarker {
+                expected: APS_GBA_FORMAT_MARKER,
+                received: format_marker,
+            }));
+        }
+
+        if !has_aps_gba_structure(patch_size) {
+            return Err(Box::new(ApsGbaError::Structure { size: patch_size }));
+        }
+
+        let source_size = patch_file.read_u32::<LittleEndian>()? as u64;
+        let target_size = patch_file.read_u32::<LittleEndian>()? as u64;
+
+        let mut patch_blocks = Vec::new();
+        while patch_file.stream_position()? < patch_size {
+            patch_blocks.push(ApsGbaBlock {
+                offset: patch_file.read_u32::<LittleEndian>()? as u64,
+                source_checksum: patch_file.read_u16::<LittleEndian>()?,
+                target_checksum: patch_file.read_u16::<LittleEndian>()?,
+            });
+            patch_file.seek(SeekFrom::Current(APS_GBA_BLOCK_SIZE as i64))?;
+        }
+
+        Ok(Self {
+            source_path: None,
+            source_size,
+            target_size,
+            patch_path: patch_path.to_owned(),
+            patch_blocks,
+            patch_modified,
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) {
+        self.source_path = Some(source_path.to_path_buf());
+    }
+
+    pub fn verify_source(&self, source: &[u8]) -> Result<(), ApsGbaError> {
+        if source.len() as u64 != self.source_size {
+            return Err(ApsGbaError::SourceLength {
+                expected: self.source_size,
+                received: source.len() as u64,
+            });
+        }
+
+        for block in &self.patch_blocks {
+            let source_checksum = crc16(&block_at(source, block.offset as usize));
+            if source_checksum != block.source_checksum {
+                return Err(ApsGbaError::SourceChecksum {
+                    offset: block.offset,
+                    expected: block.source_checksum,
+                    received: source_checksum,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Patch for ApsGbaPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::ApsGba.name()
+    }
+
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut patch_file = File::open(&self.patch_path)?;
+
+        if patch_file.metadata()?.modified()? != self.patch_modified {
+            return Err(Box::new(ApsGbaError::OutdatedCache));
+        }
+
+        let source = MappedFile::open(self.source_path.as_ref().unwrap())?;
+        self.verify_source(&source)?;
+
+        let mut target = source.to_vec();
+        target.resize(self.target_size as usize, 0);
+
+        let mut xor_block = vec![0; APS_GBA_BLOCK_SIZE];
+
+        for (index, block) in self.patch_blocks.iter().enumerate() {
+            let xor_offset = APS_GBA_HEADER_SIZE + index as u64 * APS_GBA_RECORD_SIZE + 8;
+            patch_file.seek(SeekFrom::Start(xor_offset))?;
+            patch_file.read_exact(&mut xor_block)?;
+
+            let mut target_block = block_at(&source, block.offset as usize);
+            for (target_byte, xor_byte) in target_block.iter_mut().zip(&xor_block) {
+                *target_byte ^= xor_byte;
+            }
+
+            let target_checksum = crc16(&target_block);
+            if target_checksum != block.target_checksum {
+                return Err(Box::new(ApsGbaError::TargetChecksum {
+                    offset: block.offset,
+                    expected: block.target_checksum,
+                    received: target_checksum,
+                }));
+            }
+
+            // Blocks may hang over the end of the target
+            let offset = block.offset as usize;
+            if offset < target.len() {
+                let size = APS_GBA_BLOCK_SIZE.min(target.len() - offset);
+                target[offset..(offset + size)].clone_from_slice(&target_block[0..size]);
+            }
+        }
+
+        Ok(target)
+    }
+}
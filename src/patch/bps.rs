@@ -2,19 +2,23 @@ use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
 use std::fs::{self, File};
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::io::{self, Cursor, Read};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use crc::crc32;
-use num_enum::TryFromPrimitive;
 
-use crate::patch::Patch;
-use crate::utils::ReadExt;
+use crate::patch::{PartialRom, Patch, PatchFormat};
+use crate::utils::{MappedFile, ReadExt, VlqOverflowError};
 
-const BPS_FORMAT_MARKER: [u8; 4] = [b'B', b'P', b'S', b'1'];
+pub const BPS_FORMAT_MARKER: [u8; 4] = [b'B', b'P', b'S', b'1'];
 const BPS_FOOTER_SIZE: usize = 12;
+const COPIER_HEADER_SIZE: u64 = 512;
+
+// The target size comes from the patch, so it is not trusted when preallocating the target
+const BPS_MAX_PREALLOCATION: u64 = 64 * 1024 * 1024;
 
 #[derive(Debug)]
 pub enum BpsError {
@@ -25,6 +29,10 @@ pub enum BpsError {
     SourceChecksum { expected: u32, received: u32 },
     TargetChecksum { expected: u32, received: u32 },
     PatchChecksum { expected: u32, received: u32 },
+    Truncated,
+    VarintOverflow,
+    SourceRange { offset: i64, length: u64 },
+    TargetRange { offset: i64, length: u64 },
 }
 
 impl fmt::Display for BpsError {
@@ -36,11 +44,25 @@ impl fmt::Display for BpsError {
                 "invalid format marker (expected: {:?}, received: {:?})",
                 expected, received
             ),
-            BpsError::SourceLength { expected, received } => write!(
-                formatter,
-                "source length mismatch (expected: {}, received: {})",
-                expected, received
-            ),
+            BpsError::SourceLength { expected, received } => {
+                write!(
+                    formatter,
+                    "source length mismatch (expected: {}, received: {})",
+                    expected, received
+                )?;
+                // Usually the 512-byte header added by copier devices, on either side
+                if *received == 0 {
+                    write!(formatter, ", the source ROM is empty")?;
+                } else if *received == expected + COPIER_HEADER_SIZE {
+                    write!(formatter, ", the source ROM probably has a copier header")?;
+                } else if *expected == received + COPIER_HEADER_SIZE {
+                    write!(
+                        formatter,
+                        ", the patch probably expects a source ROM with a copier header"
+                    )?;
+                }
+                Ok(())
+            }
             BpsError::TargetLength { expected, received } => write!(
                 formatter,
                 "target length mismatch (expected: {}, received: {})",
@@ -61,17 +83,39 @@ impl fmt::Display for BpsError {
                 "invalid patch checksum (expected: 0x{:08X}, received: 0x{:08X})",
                 expected, received
             ),
+            BpsError::Truncated => write!(formatter, "truncated patch"),
+            BpsError::VarintOverflow => write!(formatter, "variable-length integer overflow"),
+            BpsError::SourceRange { offset, length } => write!(
+                formatter,
+                "source range out of bounds (offset: {}, length: {})",
+                offset, length
+            ),
+            BpsError::TargetRange { offset, length } => write!(
+                formatter,
+                "target range out of bounds (offset: {}, length: {})",
+                offset, length
+            ),
         }
     }
 }
 
 impl Error for BpsError {}
 
+// Patch data in memory only fails to read when the patch is malformed
+fn data_error(err: io::Error) -> BpsError {
+    if err.get_ref().is_some_and(|inner| inner.is::<VlqOverflowError>()) {
+        BpsError::VarintOverflow
+    } else {
+        BpsError::Truncated
+    }
+}
+
 #[derive(Debug)]
 pub struct BpsPatch {
     source_path: Option<PathBuf>,
     source_size: u64,
     source_checksum: u32,
+    ignore_source_checksum: bool,
 
     target_size: u64,
     target_checksum: u32,
@@ -85,44 +129,65 @@ pub struct BpsPatch {
 
 impl BpsPatch {
     pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
-        let mut patch_file = File::open(patch_path)?;
+        // Truncated downloads are caught while scanning, not when the target gets read
+        let patch_data = MappedFile::open(patch_path)?;
+        let mut patch = Self::parse(&patch_data)?;
+
+        patch.patch_path = patch_path.to_owned();
+        patch.patch_modified = fs::metadata(patch_path)?.modified()?;
+        Ok(patch)
+    }
+
+    // Everything but the patch commands, along with the patch checksum
+    fn parse(patch_data: &[u8]) -> Result<Self, BpsError> {
+        let mut patch_cursor = Cursor::new(patch_data);
 
         let mut format_marker: [u8; 4] = [0; 4];
-        patch_file.read_exact(&mut format_marker)?;
+        patch_cursor.read_exact(&mut format_marker).map_err(data_error)?;
         if format_marker != BPS_FORMAT_MARKER {
-            return Err(Box::new(BpsError::FormatMarker {
+            return Err(BpsError::FormatMarker {
                 expected: BPS_FORMAT_MARKER,
                 received: format_marker,
-            }));
+            });
         }
 
-        let source_size = patch_file.read_vlq()?;
-        let target_size = patch_file.read_vlq()?;
-        let patch_metadata_size = patch_file.read_vlq()?;
-
-        let mut patch_metadata: Vec<u8> = vec![0; patch_metadata_size as usize];
-        patch_file.read_exact(&mut patch_metadata)?;
-
-        let patch_offset = patch_file.stream_position()?;
-
-        patch_file.seek(SeekFrom::End(-(BPS_FOOTER_SIZE as i64)))?;
-        let source_checksum = patch_file.read_u32::<LittleEndian>()?;
-        let target_checksum = patch_file.read_u32::<LittleEndian>()?;
-        let patch_checksum = patch_file.read_u32::<LittleEndian>()?;
-
-        let patch_modified = patch_file.metadata()?.modified()?;
+        let source_size = patch_cursor.read_vlq().map_err(data_error)?;
+        let target_size = patch_cursor.read_vlq().map_err(data_error)?;
+        let patch_metadata_size = patch_cursor.read_vlq().map_err(data_error)?;
+
+        // Sizes are checked against the patch before allocating anything
+        let patch_offset = patch_cursor
+            .position()
+            .checked_add(patch_metadata_size)
+            .filter(|&offset| offset + BPS_FOOTER_SIZE as u64 <= patch_data.len() as u64)
+            .ok_or(BpsError::Truncated)?;
+        let patch_metadata = patch_data[(patch_cursor.position() as usize)..(patch_offset as usize)].to_vec();
+
+        let mut footer_cursor = Cursor::new(&patch_data[(patch_data.len() - BPS_FOOTER_SIZE)..]);
+        let source_checksum = footer_cursor.read_u32::<LittleEndian>().map_err(data_error)?;
+        let target_checksum = footer_cursor.read_u32::<LittleEndian>().map_err(data_error)?;
+        let patch_checksum = footer_cursor.read_u32::<LittleEndian>().map_err(data_error)?;
+
+        let received_checksum = crc32::checksum_ieee(&patch_data[0..(patch_data.len() - 4)]);
+        if received_checksum != patch_checksum {
+            return Err(BpsError::PatchChecksum {
+                expected: patch_checksum,
+                received: received_checksum,
+            });
+        }
 
         Ok(Self {
             source_path: None,
             source_size,
             source_checksum,
+            ignore_source_checksum: false,
             target_size,
             target_checksum,
-            patch_path: patch_path.to_owned(),
+            patch_path: PathBuf::new(),
             patch_offset,
             patch_checksum,
             patch_metadata,
-            patch_modified,
+            patch_modified: SystemTime::UNIX_EPOCH,
         })
     }
 
@@ -133,15 +198,53 @@ impl BpsPatch {
     pub fn source_checksum(&self) -> u32 {
         self.source_checksum
     }
-}
 
-impl Patch for BpsPatch {
-    fn target_size(&self) -> u64 {
-        self.target_size
+    pub fn source_size(&self) -> u64 {
+        self.source_size
     }
 
-    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        let patch_data = {
+    // The text of the first `<name>` element of XML metadata, with the predefined entities
+    // decoded. Missing for metadata without one or not in UTF-8.
+    pub fn metadata_name(&self) -> Option<String> {
+        let metadata = std::str::from_utf8(&self.patch_metadata).ok()?;
+        let start = metadata.find("<name>")? + "<name>".len();
+        let end = start + metadata[start..].find("</name>")?;
+
+        let name = metadata[start..end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&");
+        Some(name)
+    }
+
+    // For patching a source ROM known not to match, at the user's own risk
+    pub fn set_ignore_source_checksum(&mut self) {
+        self.ignore_source_checksum = true;
+    }
+
+    pub fn verify_source(&self, source: &[u8]) -> Result<(), BpsError> {
+        if source.len() as u64 != self.source_size {
+            return Err(BpsError::SourceLength {
+                expected: self.source_size,
+                received: source.len() as u64,
+            });
+        }
+
+        let source_checksum = crc32::checksum_ieee(source);
+        if source_checksum != self.source_checksum && !self.ignore_source_checksum {
+            return Err(BpsError::SourceChecksum {
+                expected: self.source_checksum,
+                received: source_checksum,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn partial_bps_rom(&self) -> Result<PartialBpsRom, Box<dyn Error>> {
+        let mut patch_data = {
             let mut patch_file = File::open(&self.patch_path)?;
 
             if patch_file.metadata()?.modified()? != self.patch_modified {
@@ -153,6 +256,10 @@ impl Patch for BpsPatch {
             patch_data
         };
 
+        if (patch_data.len() as u64) < self.patch_offset + BPS_FOOTER_SIZE as u64 {
+            return Err(Box::new(BpsError::Truncated));
+        }
+
         let patch_checksum = crc32::checksum_ieee(&patch_data[0..(patch_data.len() - 4)]);
         if patch_checksum != self.patch_checksum {
             return Err(Box::new(BpsError::PatchChecksum {
@@ -161,96 +268,718 @@ impl Patch for BpsPatch {
             }));
         }
 
-        let mut patch_cursor =
-            Cursor::new(&patch_data[self.patch_offset as usize..(patch_data.len() - BPS_FOOTER_SIZE)]);
+        patch_data.truncate(patch_data.len() - BPS_FOOTER_SIZE);
+        patch_data.drain(0..self.patch_offset as usize);
 
-        let source = fs::read(self.source_path.as_ref().unwrap())?;
+        // Patches creating their targets from scratch never read their sources
+        let source = if self.source_size == 0 {
+            MappedFile::Empty
+        } else {
+            MappedFile::open(self.source_path.as_ref().unwrap())?
+        };
+        self.verify_source(&source)?;
+
+        Ok(PartialBpsRom {
+            source,
+            target: Vec::with_capacity(self.target_size.min(BPS_MAX_PREALLOCATION) as usize),
+            target_size: self.target_size,
+            patch_commands: patch_data,
+            patch_position: 0,
+            source_relative_offset: 0,
+            target_relative_offset: 0,
+            source_spans: None,
+        })
+    }
+}
 
-        if source.len() as u64 != self.source_size {
-            return Err(Box::new(BpsError::SourceLength {
-                expected: self.source_size,
-                received: source.len() as u64,
-            }));
+impl Patch for BpsPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::Bps.name()
+    }
+
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    // The stored target checksum only holds for the matching source ROM
+    fn target_checksum(&self) -> Option<u32> {
+        if self.ignore_source_checksum {
+            None
+        } else {
+            Some(self.target_checksum)
         }
+    }
 
-        let source_checksum = crc32::checksum_ieee(&source);
-        if source_checksum != self.source_checksum {
-            return Err(Box::new(BpsError::SourceChecksum {
-                expected: self.source_checksum,
-                received: source_checksum,
-            }));
+    fn metadata(&self) -> Option<&[u8]> {
+        if self.patch_metadata.is_empty() {
+            None
+        } else {
+            Some(&self.patch_metadata)
         }
+    }
 
-        let mut target = vec![0; self.target_size as usize];
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut partial_rom = self.partial_bps_rom()?;
+        partial_rom.patch_until(self.target_size)?;
+        Ok(partial_rom.target)
+    }
 
-        let mut output_offset = 0;
-        let mut source_relative_offset = 0;
-        let mut target_relative_offset = 0;
+    fn partial_patched_rom(&self) -> Result<Option<Box<dyn PartialRom>>, Box<dyn Error>> {
+        Ok(Some(Box::new(self.partial_bps_rom()?)))
+    }
+}
 
-        while patch_cursor.stream_position()? < patch_cursor.stream_len()? {
-            #[derive(TryFromPrimitive)]
-            #[repr(usize)]
-            enum BpsCommand {
-                SourceRead,
-                TargetRead,
-                SourceCopy,
-                TargetCopy,
-            }
+// Patches the source in memory, without any files involved. The patch, the source and the target
+// are all verified against the checksums stored in the patch.
+pub fn apply_bps(source: &[u8], patch_data: &[u8]) -> Result<Vec<u8>, BpsError> {
+    let patch = BpsPatch::parse(patch_data)?;
+    patch.verify_source(source)?;
+
+    let mut partial_rom = PartialBpsRom {
+        source,
+        target: Vec::with_capacity(patch.target_size.min(BPS_MAX_PREALLOCATION) as usize),
+        target_size: patch.target_size,
+        patch_commands: patch_data[(patch.patch_offset as usize)..(patch_data.len() - BPS_FOOTER_SIZE)].to_vec(),
+        patch_position: 0,
+        source_relative_offset: 0,
+        target_relative_offset: 0,
+        source_spans: None,
+    };
+    partial_rom.patch_until_offset(patch.target_size)?;
+
+    let target_checksum = crc32::checksum_ieee(&partial_rom.target);
+    if target_checksum != patch.target_checksum {
+        return Err(BpsError::TargetChecksum {
+            expected: patch.target_checksum,
+            received: target_checksum,
+        });
+    }
+
+    Ok(partial_rom.target)
+}
 
-            let (command, length) = {
-                let data = patch_cursor.read_vlq()? as usize;
-                (BpsCommand::try_from(data & 3)?, (data >> 2) + 1)
+enum BpsCommand {
+    SourceRead,
+    TargetRead,
+    SourceCopy,
+    TargetCopy,
+}
+
+// Target data copied by a SourceRead or a SourceCopy command
+struct SourceSpan {
+    target_offset: u64,
+    source_offset: u64,
+    length: u64,
+}
+
+// BPS commands only refer to the target data before the output offset, the target can be
+// patched front to back in arbitrary steps. The target checksum is verified by the caller.
+pub struct PartialBpsRom<S = MappedFile> {
+    source: S,
+
+    target: Vec<u8>,
+    target_size: u64,
+
+    patch_commands: Vec<u8>,
+    patch_position: u64,
+
+    source_relative_offset: usize,
+    target_relative_offset: usize,
+
+    // Indexed on the first read of source data, ordered by their target offsets
+    source_spans: Option<Vec<SourceSpan>>,
+}
+
+impl<S: Deref<Target = [u8]>> PartialBpsRom<S> {
+    fn patch_until_offset(&mut self, end: u64) -> Result<(), BpsError> {
+        while (self.target.len() as u64) < end && self.patch_position < self.patch_commands.len() as u64 {
+            self.patch_command()?;
+        }
+
+        if self.patch_position == self.patch_commands.len() as u64 && self.target.len() as u64 != self.target_size {
+            return Err(BpsError::TargetLength {
+                expected: self.target_size,
+                received: self.target.len() as u64,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn patch_command(&mut self) -> Result<(), BpsError> {
+        let mut patch_cursor = Cursor::new(&self.patch_commands[..]);
+        patch_cursor.set_position(self.patch_position);
+
+        let source = &self.source;
+        let target = &mut self.target;
+        let output_offset = target.len();
+
+        let (command, length) = {
+            let data = patch_cursor.read_vlq().map_err(data_error)?;
+            let command = match data & 3 {
+                0 => BpsCommand::SourceRead,
+                1 => BpsCommand::TargetRead,
+                2 => BpsCommand::SourceCopy,
+                _ => BpsCommand::TargetCopy,
             };
+            (command, (data >> 2) + 1)
+        };
 
-            match command {
-                BpsCommand::SourceRead => {
-                    target[output_offset..(output_offset + length)]
-                        .clone_from_slice(&source[output_offset..(output_offset + length)]);
-                    output_offset += length;
+        // Commands writing past the end of the target are rejected before allocating anything
+        if output_offset as u64 + length > self.target_size {
+            return Err(BpsError::TargetLength {
+                expected: self.target_size,
+                received: output_offset as u64 + length,
+            });
+        }
+        let length = length as usize;
+
+        match command {
+            BpsCommand::SourceRead => {
+                let source_data = source
+                    .get(output_offset..(output_offset + length))
+                    .ok_or(BpsError::SourceRange {
+                        offset: output_offset as i64,
+                        length: length as u64,
+                    })?;
+                target.extend_from_slice(source_data);
+            }
+            BpsCommand::TargetRead => {
+                let patch_data = self
+                    .patch_commands
+                    .get((patch_cursor.position() as usize)..(patch_cursor.position() as usize + length))
+                    .ok_or(BpsError::Truncated)?;
+                target.extend_from_slice(patch_data);
+                patch_cursor.set_position(patch_cursor.position() + length as u64);
+            }
+            BpsCommand::SourceCopy => {
+                // Relative offsets may point anywhere, they are only checked when used
+                let offset = patch_cursor.read_signed_vlq().map_err(data_error)?;
+                let source_offset = (self.source_relative_offset as i64).saturating_add(offset);
+                let source_data = usize::try_from(source_offset)
+                    .ok()
+                    .and_then(|offset| source.get(offset..(offset.checked_add(length)?)))
+                    .ok_or(BpsError::SourceRange {
+                        offset: source_offset,
+                        length: length as u64,
+                    })?;
+                target.extend_from_slice(source_data);
+
+                self.source_relative_offset = source_offset as usize + length;
+            }
+            BpsCommand::TargetCopy => {
+                let offset = patch_cursor.read_signed_vlq().map_err(data_error)?;
+                let target_offset = (self.target_relative_offset as i64).saturating_add(offset);
+                if target_offset < 0 || target_offset as usize >= output_offset {
+                    return Err(BpsError::TargetRange {
+                        offset: target_offset,
+                        length: length as u64,
+                    });
                 }
-                BpsCommand::TargetRead => {
-                    patch_cursor.read_exact(&mut target[output_offset..(output_offset + length)])?;
-                    output_offset += length;
+
+                // The copied range may overlap the bytes being written
+                let target_offset = target_offset as usize;
+                for i in 0..length {
+                    target.push(target[target_offset + i]);
                 }
-                BpsCommand::SourceCopy => {
-                    let offset = patch_cursor.read_signed_vlq()?;
-                    source_relative_offset = (source_relative_offset as isize + offset as isize) as usize; // unsafe
 
-                    target[output_offset..(output_offset + length)]
-                        .clone_from_slice(&source[source_relative_offset..(source_relative_offset + length)]);
+                self.target_relative_offset = target_offset + length;
+            }
+        }
+
+        self.patch_position = patch_cursor.position();
+        Ok(())
+    }
+
+    // Walks the commands without patching anything, up to the end of the target. Spans out of the
+    // source bounds are left out, patching them fails anyway.
+    fn index_source_spans(&self) -> Result<Vec<SourceSpan>, BpsError> {
+        let mut source_spans = Vec::new();
+        let mut patch_cursor = Cursor::new(&self.patch_commands[..]);
+        let mut output_offset: u64 = 0;
+        let mut source_relative_offset: i64 = 0;
+
+        while patch_cursor.position() < self.patch_commands.len() as u64 && output_offset < self.target_size {
+            let data = patch_cursor.read_vlq().map_err(data_error)?;
+            let length = (data >> 2) + 1;
+
+            let source_offset = match data & 3 {
+                0 => Some(output_offset as i64),
+                1 => {
+                    patch_cursor.set_position(patch_cursor.position().saturating_add(length));
+                    None
+                }
+                2 => {
+                    let offset = patch_cursor.read_signed_vlq().map_err(data_error)?;
+                    let source_offset = source_relative_offset.saturating_add(offset);
+                    source_relative_offset = source_offset.saturating_add(length as i64);
+                    Some(source_offset)
+                }
+                _ => {
+                    patch_cursor.read_signed_vlq().map_err(data_error)?;
+                    None
+                }
+            };
 
-                    source_relative_offset += length;
-                    output_offset += length;
+            if let Some(source_offset) = source_offset {
+                let in_bounds = source_offset >= 0
+                    && (source_offset as u64)
+                        .checked_add(length)
+                        .is_some_and(|source_end| source_end <= self.source.len() as u64);
+                if in_bounds {
+                    source_spans.push(SourceSpan {
+                        target_offset: output_offset,
+                        source_offset: source_offset as u64,
+                        length,
+                    });
                 }
-                BpsCommand::TargetCopy => {
-                    let offset = patch_cursor.read_signed_vlq()?;
-                    target_relative_offset = (target_relative_offset as isize + offset as isize) as usize; // unsafe
+            }
+
+            output_offset = output_offset.saturating_add(length);
+        }
+
+        Ok(source_spans)
+    }
+}
+
+impl<S: Deref<Target = [u8]> + Send> PartialRom for PartialBpsRom<S> {
+    fn patch_until(&mut self, end: u64) -> Result<(), Box<dyn Error>> {
+        Ok(self.patch_until_offset(end)?)
+    }
+
+    fn patched_data(&self) -> &[u8] {
+        &self.target
+    }
+
+    // Malformed patches have no source spans, their errors surface when patching
+    fn source_data(&mut self, offset: u64, end: u64) -> Option<&[u8]> {
+        let end = end.min(self.target_size);
+        if offset >= end {
+            return None;
+        }
+
+        if self.source_spans.is_none() {
+            self.source_spans = Some(self.index_source_spans().unwrap_or_default());
+        }
+        let source_spans = self.source_spans.as_ref().unwrap();
+
+        let index = source_spans.partition_point(|span| span.target_offset + span.length <= offset);
+        let span = source_spans.get(index)?;
+        if span.target_offset > offset || span.target_offset + span.length < end {
+            return None;
+        }
+
+        let source_offset = (span.source_offset + (offset - span.target_offset)) as usize;
+        self.source
+            .get(source_offset..(source_offset + (end - offset) as usize))
+    }
+
+    fn is_complete(&self) -> bool {
+        self.patch_position == self.patch_commands.len() as u64 && self.target.len() as u64 == self.target_size
+    }
+
+    fn into_patched_rom(self: Box<Self>) -> Vec<u8> {
+        self.target
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::write_vlq;
 
-                    for i in 0..length {
-                        target[output_offset + i] = target[target_relative_offset + i];
-                    }
+    const SOURCE: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+    enum Command<'a> {
+        SourceRead(u64),
+        TargetRead(&'a [u8]),
+        SourceCopy(u64, i64),
+        TargetCopy(u64, i64),
+    }
 
-                    target_relative_offset += length;
-                    output_offset += length;
+    fn write_signed_vlq(output: &mut Vec<u8>, value: i64) {
+        write_vlq(output, (value.unsigned_abs() << 1) | (value < 0) as u64);
+    }
+
+    fn write_commands(output: &mut Vec<u8>, commands: &[Command]) {
+        for command in commands {
+            match *command {
+                Command::SourceRead(length) => write_vlq(output, (length - 1) << 2),
+                Command::TargetRead(data) => {
+                    write_vlq(output, ((data.len() as u64 - 1) << 2) | 1);
+                    output.extend_from_slice(data);
+                }
+                Command::SourceCopy(length, offset) => {
+                    write_vlq(output, ((length - 1) << 2) | 2);
+                    write_signed_vlq(output, offset);
+                }
+                Command::TargetCopy(length, offset) => {
+                    write_vlq(output, ((length - 1) << 2) | 3);
+                    write_signed_vlq(output, offset);
                 }
             }
         }
+    }
 
-        if target.len() as u64 != self.target_size {
-            return Err(Box::new(BpsError::TargetLength {
-                expected: self.target_size,
-                received: target.len() as u64,
-            }));
+    // Patches with the given header fields and checksums, the patch checksum is always valid
+    fn build_raw_patch(source_size: u64, target_size: u64, commands: &[u8], checksums: (u32, u32)) -> Vec<u8> {
+        let mut patch_data = BPS_FORMAT_MARKER.to_vec();
+        write_vlq(&mut patch_data, source_size);
+        write_vlq(&mut patch_data, target_size);
+        write_vlq(&mut patch_data, 0);
+        patch_data.extend_from_slice(commands);
+        patch_data.extend_from_slice(&checksums.0.to_le_bytes());
+        patch_data.extend_from_slice(&checksums.1.to_le_bytes());
+        let patch_checksum = crc32::checksum_ieee(&patch_data);
+        patch_data.extend_from_slice(&patch_checksum.to_le_bytes());
+        patch_data
+    }
+
+    fn build_patch(source: &[u8], target: &[u8], commands: &[Command]) -> Vec<u8> {
+        let mut command_data = Vec::new();
+        write_commands(&mut command_data, commands);
+        build_raw_patch(
+            source.len() as u64,
+            target.len() as u64,
+            &command_data,
+            (crc32::checksum_ieee(source), crc32::checksum_ieee(target)),
+        )
+    }
+
+    fn sample_patch() -> (Vec<u8>, Vec<u8>) {
+        let target = b"The quick red fox jumps over the lazy dog!!!".to_vec();
+        let patch_data = build_patch(
+            SOURCE,
+            &target,
+            &[
+                Command::SourceRead(10),
+                Command::TargetRead(b"red"),
+                Command::SourceCopy(28, 15),
+                Command::TargetRead(b"!"),
+                Command::TargetCopy(2, 41),
+            ],
+        );
+        (patch_data, target)
+    }
+
+    #[test]
+    fn test_apply() {
+        let (patch_data, target) = sample_patch();
+        assert_eq!(apply_bps(SOURCE, &patch_data).unwrap(), target);
+    }
+
+    #[test]
+    fn test_source_checksum_mismatch() {
+        let (patch_data, _) = sample_patch();
+        let mut source = SOURCE.to_vec();
+        source[0] = b't';
+
+        assert!(matches!(
+            apply_bps(&source, &patch_data),
+            Err(BpsError::SourceChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn test_source_length_mismatch() {
+        let (patch_data, _) = sample_patch();
+        let mut source = vec![0; COPIER_HEADER_SIZE as usize];
+        source.extend_from_slice(SOURCE);
+
+        let err = apply_bps(&source, &patch_data).unwrap_err();
+        assert!(matches!(
+            err,
+            BpsError::SourceLength {
+                expected: 43,
+                received: 555
+            }
+        ));
+        assert!(err
+            .to_string()
+            .ends_with(", the source ROM probably has a copier header"));
+    }
+
+    #[test]
+    fn test_target_checksum_mismatch() {
+        let target = b"The quick brown fox".to_vec();
+        let patch_data = build_patch(SOURCE, b"The quick brown fix", &[Command::SourceRead(19)]);
+
+        assert!(matches!(
+            apply_bps(SOURCE, &patch_data),
+            Err(BpsError::TargetChecksum { received, .. }) if received == crc32::checksum_ieee(&target)
+        ));
+    }
+
+    #[test]
+    fn test_patch_checksum_mismatch() {
+        let (mut patch_data, _) = sample_patch();
+        patch_data[10] ^= 0xFF;
+
+        assert!(matches!(
+            apply_bps(SOURCE, &patch_data),
+            Err(BpsError::PatchChecksum { .. })
+        ));
+    }
+
+    // Malformed patches, the target checksums do not matter as none of them gets that far
+    fn apply_malformed(target_size: u64, command_data: &[u8]) -> Result<Vec<u8>, BpsError> {
+        let patch_data = build_raw_patch(
+            SOURCE.len() as u64,
+            target_size,
+            command_data,
+            (crc32::checksum_ieee(SOURCE), 0),
+        );
+        apply_bps(SOURCE, &patch_data)
+    }
+
+    fn apply_malformed_commands(target_size: u64, commands: &[Command]) -> Result<Vec<u8>, BpsError> {
+        let mut command_data = Vec::new();
+        write_commands(&mut command_data, commands);
+        apply_malformed(target_size, &command_data)
+    }
+
+    #[test]
+    fn test_truncated_header_varint() {
+        assert!(matches!(apply_bps(SOURCE, b"BPS1\x00"), Err(BpsError::Truncated)));
+    }
+
+    #[test]
+    fn test_truncated_command_varint() {
+        assert!(matches!(apply_malformed(10, &[0x00, 0x00]), Err(BpsError::Truncated)));
+    }
+
+    #[test]
+    fn test_truncated_target_read() {
+        let mut command_data = Vec::new();
+        write_vlq(&mut command_data, (10 - 1) << 2 | 1);
+        command_data.extend_from_slice(b"short");
+
+        assert!(matches!(apply_malformed(10, &command_data), Err(BpsError::Truncated)));
+    }
+
+    #[test]
+    fn test_overlong_command_varint() {
+        assert!(matches!(
+            apply_malformed(10, &[0x00; 11]),
+            Err(BpsError::VarintOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_source_copy_out_of_range() {
+        assert!(matches!(
+            apply_malformed_commands(10, &[Command::SourceCopy(10, 40)]),
+            Err(BpsError::SourceRange { offset: 40, length: 10 })
+        ));
+        assert!(matches!(
+            apply_malformed_commands(10, &[Command::SourceCopy(10, -1)]),
+            Err(BpsError::SourceRange { offset: -1, length: 10 })
+        ));
+    }
+
+    #[test]
+    fn test_source_read_out_of_range() {
+        assert!(matches!(
+            apply_malformed_commands(50, &[Command::SourceRead(50)]),
+            Err(BpsError::SourceRange { offset: 0, length: 50 })
+        ));
+    }
+
+    #[test]
+    fn test_target_copy_out_of_range() {
+        assert!(matches!(
+            apply_malformed_commands(10, &[Command::TargetCopy(10, 0)]),
+            Err(BpsError::TargetRange { offset: 0, length: 10 })
+        ));
+        assert!(matches!(
+            apply_malformed_commands(10, &[Command::TargetRead(b"abc"), Command::TargetCopy(3, 5)]),
+            Err(BpsError::TargetRange { offset: 5, length: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_bad_footer() {
+        let (mut patch_data, _) = sample_patch();
+        let footer_offset = patch_data.len() - BPS_FOOTER_SIZE;
+        patch_data[footer_offset] ^= 0xFF;
+
+        assert!(matches!(
+            apply_bps(SOURCE, &patch_data),
+            Err(BpsError::PatchChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn test_missing_footer() {
+        let (patch_data, _) = sample_patch();
+
+        assert!(matches!(
+            apply_bps(SOURCE, &patch_data[..(patch_data.len() - BPS_FOOTER_SIZE)]),
+            Err(BpsError::Truncated)
+        ));
+        assert!(matches!(apply_bps(SOURCE, &patch_data[..8]), Err(BpsError::Truncated)));
+    }
+
+    #[test]
+    fn test_oversized_target() {
+        assert!(matches!(
+            apply_malformed_commands(5, &[Command::TargetRead(b"0123456789")]),
+            Err(BpsError::TargetLength {
+                expected: 5,
+                received: 10
+            })
+        ));
+    }
+
+    #[test]
+    fn test_undersized_target() {
+        assert!(matches!(
+            apply_malformed_commands(5, &[Command::TargetRead(b"012")]),
+            Err(BpsError::TargetLength {
+                expected: 5,
+                received: 3
+            })
+        ));
+    }
+
+    // Every truncation either fails the patch checksum or misses parts of the patch, none of
+    // them may panic
+    #[test]
+    fn test_every_truncation() {
+        let (patch_data, _) = sample_patch();
+
+        for size in 0..patch_data.len() {
+            assert!(apply_bps(SOURCE, &patch_data[..size]).is_err(), "size: {}", size);
         }
+    }
 
-        let target_checksum = crc32::checksum_ieee(&target);
-        if target_checksum != self.target_checksum {
-            return Err(Box::new(BpsError::TargetChecksum {
-                expected: self.target_checksum,
-                received: target_checksum,
-            }));
+    // The same for the commands alone, with valid checksums around them
+    #[test]
+    fn test_every_command_truncation() {
+        let mut command_data = Vec::new();
+        write_commands(
+            &mut command_data,
+            &[
+                Command::SourceRead(10),
+                Command::TargetRead(b"red"),
+                Command::SourceCopy(28, 15),
+                Command::TargetRead(b"!"),
+                Command::TargetCopy(2, 41),
+            ],
+        );
+
+        for size in 0..command_data.len() {
+            assert!(apply_malformed(44, &command_data[..size]).is_err(), "size: {}", size);
+        }
+    }
+
+    // Nothing gets allocated for the claimed target size upfront
+    #[test]
+    fn test_huge_target_size() {
+        assert!(matches!(
+            apply_malformed_commands(u64::MAX, &[Command::TargetRead(b"abc")]),
+            Err(BpsError::TargetLength {
+                expected: u64::MAX,
+                received: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_overflowing_target_size() {
+        let mut patch_data = BPS_FORMAT_MARKER.to_vec();
+        write_vlq(&mut patch_data, SOURCE.len() as u64);
+        patch_data.extend_from_slice(&[0x00; 11]);
+        patch_data.extend_from_slice(&[0x80; 16]);
+
+        assert!(matches!(apply_bps(SOURCE, &patch_data), Err(BpsError::VarintOverflow)));
+    }
+
+    #[test]
+    fn test_verify_matching_source() {
+        let (patch_data, _) = sample_patch();
+        let patch = BpsPatch::parse(&patch_data).unwrap();
+
+        assert!(patch.verify_source(SOURCE).is_ok());
+    }
+
+    #[test]
+    fn test_verify_mismatching_source() {
+        let (patch_data, _) = sample_patch();
+        let mut patch = BpsPatch::parse(&patch_data).unwrap();
+        let mut source = SOURCE.to_vec();
+        source[4] = b'Q';
+
+        let expected = crc32::checksum_ieee(SOURCE);
+        let received = crc32::checksum_ieee(&source);
+        match patch.verify_source(&source) {
+            Err(err @ BpsError::SourceChecksum { .. }) => {
+                assert_eq!(
+                    err.to_string(),
+                    format!(
+                        "invalid source checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                        expected, received
+                    )
+                );
+            }
+            result => panic!("unexpected result: {:?}", result),
         }
 
-        Ok(target)
+        assert!(matches!(
+            patch.verify_source(&SOURCE[..40]),
+            Err(BpsError::SourceLength {
+                expected: 43,
+                received: 40
+            })
+        ));
+
+        // Only the checksum is ignored, the length still has to match
+        patch.set_ignore_source_checksum();
+        assert!(patch.verify_source(&source).is_ok());
+        assert!(patch.verify_source(&SOURCE[..40]).is_err());
+    }
+
+    #[test]
+    fn test_source_spans_end_with_target() {
+        let mut commands = Vec::new();
+        write_commands(
+            &mut commands,
+            &[
+                Command::SourceRead(10),
+                Command::TargetRead(b"red"),
+                Command::SourceCopy(28, 15),
+                Command::SourceRead(1),
+                Command::SourceCopy(5, -10),
+            ],
+        );
+        let partial_rom = PartialBpsRom {
+            source: SOURCE,
+            target: Vec::new(),
+            target_size: 41,
+            patch_commands: commands,
+            patch_position: 0,
+            source_relative_offset: 0,
+            target_relative_offset: 0,
+            source_spans: None,
+        };
+
+        let source_spans = partial_rom.index_source_spans().unwrap();
+        let source_spans: Vec<_> = source_spans
+            .iter()
+            .map(|span| (span.target_offset, span.source_offset, span.length))
+            .collect();
+        assert_eq!(source_spans, [(0, 0, 10), (13, 15, 28)]);
     }
 }
@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::bzip2;
+use crate::patch::{Patch, PatchFormat};
+use crate::utils::MappedFile;
+
+pub const BSDIFF_FORMAT_MARKER: [u8; 8] = [b'B', b'S', b'D', b'I', b'F', b'F', b'4', b'0'];
+const BSDIFF_HEADER_SIZE: u64 = 32;
+
+#[derive(Debug)]
+pub enum BsdiffError {
+    OutdatedCache,
+    Broken,
+    FormatMarker { expected: [u8; 8], received: [u8; 8] },
+    Truncated { size: u64 },
+    InvalidHeader,
+    InvalidControl,
+    SourceSize { expected: u64, received: u64 },
+}
+
+impl fmt::Display for BsdiffError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BsdiffError::OutdatedCache => write!(formatter, "outdated cache"),
+            BsdiffError::Broken => write!(formatter, "patch failed to apply before"),
+            BsdiffError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            BsdiffError::Truncated { size } => write!(formatter, "truncated patch file ({} bytes)", size),
+            BsdiffError::InvalidHeader => write!(formatter, "invalid header"),
+            BsdiffError::InvalidControl => write!(formatter, "control entry out of bounds"),
+            BsdiffError::SourceSize { expected, received } => write!(
+                formatter,
+                "source too small (expected at least: {}, received: {})",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl Error for BsdiffError {}
+
+// Sign-magnitude 64-bit integers
+fn read_offset<R: Read>(reader: &mut R) -> Result<i64, Box<dyn Error>> {
+    let value = reader.read_u64::<LittleEndian>()?;
+    let magnitude = (value & !(1 << 63)) as i64;
+    Ok(if value & (1 << 63) != 0 { -magnitude } else { magnitude })
+}
+
+#[derive(Debug)]
+struct BsdiffControl {
+    diff_size: u64,
+    extra_size: u64,
+    seek: i64,
+}
+
+// bsdiff 4.x patches, the control entries, the difference and the extra bytes are stored
+// in three separate bzip2 streams
+#[derive(Debug)]
+pub struct BsdiffPatch {
+    source_path: Option<PathBuf>,
+    source_size: u64,
+
+    target_size: u64,
+
+    patch_path: PathBuf,
+    patch_controls: Vec<BsdiffControl>,
+    patch_diff_offset: u64,
+    patch_extra_offset: u64,
+    patch_modified: SystemTime,
+
+    // Set after a failed patching attempt, the same failure is not reproduced on every read
+    broken: AtomicBool,
+}
+
+impl BsdiffPatch {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let patch_modified = fs::metadata(patch_path)?.modified()?;
+        let patch_data = MappedFile::open(patch_path)?;
+        let patch_size = patch_data.len() as u64;
+
+        if patch_size < BSDIFF_HEADER_SIZE {
+            return Err(Box::new(BsdiffError::Truncated { size: patch_size }));
+        }
+
+        let mut patch_cursor = Cursor::new(&patch_data[..]);
+
+        let mut format_marker: [u8; 8] = [0; 8];
+        patch_cursor.read_exact(&mut format_marker)?;
+        if format_marker != BSDIFF_FORMAT_MARKER {
+            return Err(Box::new(BsdiffError::FormatMarker {
+                expected: BSDIFF_FORMAT_MARKER,
+                received: format_marker,
+            }));
+        }
+
+        let control_size = read_offset(&mut patch_cursor)?;
+        let diff_size = read_offset(&mut patch_cursor)?;
+        let target_size = read_offset(&mut patch_cursor)?;
+        if control_size < 0 || diff_size < 0 || target_size < 0 {
+            return Err(Box::new(BsdiffError::InvalidHeader));
+        }
+
+        // The sizes come from the patch, their sums may overflow
+        let (patch_diff_offset, patch_extra_offset) = BSDIFF_HEADER_SIZE
+            .checked_add(control_size as u64)
+            .and_then(|patch_diff_offset| Some((patch_diff_offset, patch_diff_offset.checked_add(diff_size as u64)?)))
+            .filter(|&(_, patch_extra_offset)| patch_extra_offset <= patch_size)
+            .ok_or(BsdiffError::Truncated { size: patch_size })?;
+
+        // The control entries are small, they are validated against the target size up front
+        let control_data = bzip2::decompress(&patch_data[BSDIFF_HEADER_SIZE as usize..patch_diff_offset as usize])?;
+        let mut control_cursor = Cursor::new(&control_data);
+
+        let mut patch_controls = Vec::new();
+        let mut source_size: u64 = 0;
+        let mut source_offset: i64 = 0;
+        let mut target_offset: u64 = 0;
+
+        while target_offset < target_size as u64 {
+            let diff_size = read_offset(&mut control_cursor)?;
+            let extra_size = read_offset(&mut control_cursor)?;
+            let seek = read_offset(&mut control_cursor)?;
+
+            if diff_size < 0 || extra_size < 0 || (diff_size > 0 && source_offset < 0) {
+                return Err(Box::new(BsdiffError::InvalidControl));
+            }
+
+            target_offset = target_offset
+                .checked_add(diff_size as u64 + extra_size as u64)
+                .filter(|&target_offset| target_offset <= target_size as u64)
+                .ok_or(BsdiffError::InvalidControl)?;
+
+            if diff_size > 0 {
+                let source_end = source_offset
+                    .checked_add(diff_size)
+                    .ok_or(BsdiffError::InvalidControl)?;
+                source_size = source_size.max(source_end as u64);
+            }
+
+            source_offset = source_offset
+                .checked_add(diff_size)
+                .and_then(|source_offset| source_offset.checked_add(seek))
+                .ok_or(BsdiffError::InvalidControl)?;
+
+            patch_controls.push(BsdiffControl {
+                diff_size: diff_size as u64,
+                extra_size: extra_size as u64,
+                seek,
+            });
+        }
+
+        Ok(Self {
+            source_path: None,
+            source_size,
+            target_size: target_size as u64,
+            patch_path: patch_path.to_owned(),
+            patch_controls,
+            patch_diff_offset,
+            patch_extra_offset,
+            patch_modified,
+            broken: AtomicBool::new(false),
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) -> Result<(), Box<dyn Error>> {
+        let source_size = fs::metadata(source_path)?.len();
+        if source_size < self.source_size {
+            return Err(Box::new(BsdiffError::SourceSize {
+                expected: self.source_size,
+                received: source_size,
+            }));
+        }
+
+        self.source_path = Some(source_path.to_path_buf());
+        Ok(())
+    }
+
+    fn apply(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if File::open(&self.patch_path)?.metadata()?.modified()? != self.patch_modified {
+            return Err(Box::new(BsdiffError::OutdatedCache));
+        }
+
+        let patch_data = MappedFile::open(&self.patch_path)?;
+        let diff_data =
+            bzip2::decompress(&patch_data[self.patch_diff_offset as usize..self.patch_extra_offset as usize])?;
+        let extra_data = bzip2::decompress(&patch_data[self.patch_extra_offset as usize..])?;
+
+        let source = MappedFile::open(self.source_path.as_ref().unwrap())?;
+        if (source.len() as u64) < self.source_size {
+            return Err(Box::new(BsdiffError::SourceSize {
+                expected: self.source_size,
+                received: source.len() as u64,
+            }));
+        }
+
+        let mut target = Vec::with_capacity(self.target_size as usize);
+        let mut diff_offset = 0;
+        let mut extra_offset = 0;
+        let mut source_offset: i64 = 0;
+
+        for control in &self.patch_controls {
+            let diff_size = control.diff_size as usize;
+            let extra_size = control.extra_size as usize;
+
+            let diff_bytes = diff_data
+                .get(diff_offset..(diff_offset + diff_size))
+                .ok_or(BsdiffError::InvalidControl)?;
+            // Seeking before the start of the source wraps around, out of bounds either way
+            let source_bytes = source
+                .get((source_offset as usize)..(source_offset as usize).saturating_add(diff_size))
+                .ok_or(BsdiffError::InvalidControl)?;
+            target.extend(diff_bytes.iter().zip(source_bytes).map(|(d, s)| d.wrapping_add(*s)));
+
+            let extra_bytes = extra_data
+                .get(extra_offset..(extra_offset + extra_size))
+                .ok_or(BsdiffError::InvalidControl)?;
+            target.extend_from_slice(extra_bytes);
+
+            diff_offset += diff_size;
+            extra_offset += extra_size;
+            source_offset += control.diff_size as i64 + control.seek;
+        }
+
+        Ok(target)
+    }
+}
+
+impl Patch for BsdiffPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::Bsdiff.name()
+    }
+
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if self.broken.load(Ordering::Relaxed) {
+            return Err(Box::new(BsdiffError::Broken));
+        }
+
+        let result = self.apply();
+        if result.is_err() {
+            self.broken.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_overflowing_header_sizes() {
+        let mut patch_data = BSDIFF_FORMAT_MARKER.to_vec();
+        patch_data.extend_from_slice(&(i64::MAX as u64).to_le_bytes());
+        patch_data.extend_from_slice(&(i64::MAX as u64).to_le_bytes());
+        patch_data.extend_from_slice(&0u64.to_le_bytes());
+
+        let patch_path = env::temp_dir().join(format!("bsdiff-overflow-{}.bsdiff", std::process::id()));
+        fs::write(&patch_path, &patch_data).unwrap();
+        let result = BsdiffPatch::new(&patch_path);
+        fs::remove_file(&patch_path).unwrap();
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BsdiffError>(),
+            Some(BsdiffError::Truncated { size: 32 })
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::patch::{self, Patch};
+
+// Chain files list patches applied one after another, one path per line, relative to the
+// chain file. Empty lines and lines starting with '#' are ignored.
+pub const CHAIN_EXTENSION: &str = "chain";
+
+pub fn read_chain_file(chain_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let chain_directory = chain_path.parent().unwrap();
+    let mut patch_paths = Vec::new();
+
+    for line in BufReader::new(fs::File::open(chain_path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            patch_paths.push(chain_directory.join(line));
+        }
+    }
+
+    Ok(patch_paths)
+}
+
+// Every patch of the chain takes the target of the previous one as its source. The intermediate
+// targets are written to files, as that is where the patches read their sources from.
+pub struct PatchChain {
+    chain_path: PathBuf,
+    chain_modified: SystemTime,
+    patches: Vec<Arc<dyn Patch + Send + Sync>>,
+    intermediate_paths: Vec<PathBuf>,
+    // The formats of the patches in order, like "BPS + UPS"
+    format: String,
+    // Overrides the checksum stored in the last patch
+    target_checksum: Option<u32>,
+    // The intermediate files are shared by every patching of the chain
+    patching: Mutex<()>,
+}
+
+impl PatchChain {
+    // `intermediate_paths` are the source paths of all patches but the first one
+    pub fn new(
+        chain_path: &Path,
+        patches: Vec<Arc<dyn Patch + Send + Sync>>,
+        intermediate_paths: Vec<PathBuf>,
+    ) -> io::Result<Self> {
+        let format = patches
+            .iter()
+            .map(|patch| patch.format())
+            .collect::<Vec<_>>()
+            .join(" + ");
+
+        Ok(Self {
+            chain_path: chain_path.to_owned(),
+            chain_modified: fs::metadata(chain_path)?.modified()?,
+            patches,
+            format,
+            intermediate_paths,
+            target_checksum: None,
+            patching: Mutex::new(()),
+        })
+    }
+
+    pub fn set_target_checksum(&mut self, target_checksum: u32) {
+        self.target_checksum = Some(target_checksum);
+    }
+}
+
+impl Patch for PatchChain {
+    fn patch_path(&self) -> &Path {
+        &self.chain_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.patches[0].source_path()
+    }
+
+    // Changing any of the patches changes the chain
+    fn patch_modified(&self) -> SystemTime {
+        self.patches
+            .iter()
+            .map(|patch| patch.patch_modified())
+            .fold(self.chain_modified, SystemTime::max)
+    }
+
+    fn format(&self) -> &str {
+        &self.format
+    }
+
+    fn target_size(&self) -> u64 {
+        self.patches.last().unwrap().target_size()
+    }
+
+    fn target_checksum(&self) -> Option<u32> {
+        self.target_checksum
+            .or_else(|| self.patches.last().unwrap().target_checksum())
+    }
+
+    fn metadata(&self) -> Option<&[u8]> {
+        self.patches.last().unwrap().metadata()
+    }
+
+    // Intermediate targets are verified against the checksums stored in their patches
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let _patching = self.patching.lock().unwrap();
+        let mut target = self.patches[0].patched_rom()?;
+
+        for (previous_patch, (patch, intermediate_path)) in self
+            .patches
+            .iter()
+            .zip(self.patches[1..].iter().zip(&self.intermediate_paths))
+        {
+            patch::verify_target_checksum(previous_patch.as_ref(), &target)?;
+
+            fs::create_dir_all(intermediate_path.parent().unwrap())?;
+            fs::write(intermediate_path, &target)?;
+            let result = patch.patched_rom();
+            fs::remove_file(intermediate_path)?;
+
+            target = result?;
+        }
+
+        Ok(target)
+    }
+}
@@ -2,45 +2,117 @@ use std::cmp;
 use std::error::Error;
 use std::fmt;
 use std::fs::{self, File};
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use byteorder::{BigEndian, ReadBytesExt};
+use log::debug;
 
-use crate::patch::Patch;
+use crate::json::{self, JsonValue};
+use crate::patch::{Patch, PatchFormat};
+use crate::utils::MappedFile;
 
-const IPS_FORMAT_MARKER: [u8; 5] = [b'P', b'A', b'T', b'C', b'H'];
-const IPS_EOF_MARKER: usize = 0x454F46;
+pub const IPS_FORMAT_MARKER: [u8; 5] = [b'P', b'A', b'T', b'C', b'H'];
+pub const IPS32_FORMAT_MARKER: [u8; 5] = [b'I', b'P', b'S', b'3', b'2'];
+const IPS_EOF_MARKER: u64 = 0x454F46;
+const IPS32_EOF_MARKER: u64 = 0x45454F46;
 
 #[derive(Debug)]
 pub enum IpsError {
+    OutdatedCache,
     FormatMarker { expected: [u8; 5], received: [u8; 5] },
+    TargetSize { size: u64, limit: u64 },
+    Truncated,
 }
 
 impl fmt::Display for IpsError {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            IpsError::OutdatedCache => write!(formatter, "outdated cache"),
             IpsError::FormatMarker { expected, received } => write!(
                 formatter,
                 "invalid format marker (expected: {:?}, received: {:?})",
                 expected, received
             ),
+            IpsError::TargetSize { size, limit } => write!(
+                formatter,
+                "target size out of bounds (size: {}, limit: {})",
+                size, limit
+            ),
+            IpsError::Truncated => write!(formatter, "truncated patch"),
         }
     }
 }
 
 impl Error for IpsError {}
 
+// IPS32 is plain IPS with 32-bit offsets, sharing everything else
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum IpsVariant {
+    Ips,
+    Ips32,
+}
+
+impl IpsVariant {
+    fn read_format_marker<R: Read>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let mut format_marker: [u8; 5] = [0; 5];
+        reader.read_exact(&mut format_marker)?;
+        Ok(Self::from_format_marker(format_marker)?)
+    }
+
+    fn from_format_marker(format_marker: [u8; 5]) -> Result<Self, IpsError> {
+        match format_marker {
+            IPS_FORMAT_MARKER => Ok(IpsVariant::Ips),
+            IPS32_FORMAT_MARKER => Ok(IpsVariant::Ips32),
+            _ => Err(IpsError::FormatMarker {
+                expected: IPS_FORMAT_MARKER,
+                received: format_marker,
+            }),
+        }
+    }
+
+    // Offsets and the truncation extension share the same width
+    fn read_offset<R: Read>(self, reader: &mut R) -> io::Result<u64> {
+        match self {
+            IpsVariant::Ips => reader.read_u24::<BigEndian>().map(u64::from),
+            IpsVariant::Ips32 => reader.read_u32::<BigEndian>().map(u64::from),
+        }
+    }
+
+    fn offset_size(self) -> usize {
+        match self {
+            IpsVariant::Ips => 3,
+            IpsVariant::Ips32 => 4,
+        }
+    }
+
+    fn eof_marker(self) -> u64 {
+        match self {
+            IpsVariant::Ips => IPS_EOF_MARKER,
+            IpsVariant::Ips32 => IPS32_EOF_MARKER,
+        }
+    }
+}
+
 pub struct IpsPatch {
     source_path: PathBuf,
     patch_path: PathBuf,
+    patch_modified: SystemTime,
 
     target_size: u64,
     truncated_size: Option<u64>,
+    max_target_size: u64,
+
+    // EarthBound patcher (EBP) patches append a JSON object describing the hack
+    ebp_metadata: Option<Vec<u8>>,
+    ebp_fields: Option<JsonValue>,
 }
 
 impl IpsPatch {
-    pub fn new(patch_path: &Path, source_path: &Path) -> Result<Self, Box<dyn Error>> {
+    // Records and truncation extensions past `max_target_size` are rejected instead of blindly
+    // allocating the target
+    pub fn new(patch_path: &Path, source_path: &Path, max_target_size: u64) -> Result<Self, Box<dyn Error>> {
         let mut patch_file = File::open(patch_path)?;
 
         let mut target_size: u64 = {
@@ -48,83 +120,363 @@ impl IpsPatch {
             source_file.metadata()?.len()
         };
 
-        let mut format_marker: [u8; 5] = [0; 5];
-        patch_file.read_exact(&mut format_marker)?;
-        if format_marker != IPS_FORMAT_MARKER {
-            return Err(Box::new(IpsError::FormatMarker {
-                expected: IPS_FORMAT_MARKER,
-                received: format_marker,
-            }));
-        }
+        let variant = IpsVariant::read_format_marker(&mut patch_file)?;
 
         loop {
-            let offset = patch_file.read_u24::<BigEndian>()? as usize;
-            if offset == IPS_EOF_MARKER {
+            let offset = variant.read_offset(&mut patch_file)?;
+            if offset == variant.eof_marker() {
                 break;
             }
 
-            let size = patch_file.read_u16::<BigEndian>()? as usize;
+            let size = patch_file.read_u16::<BigEndian>()? as u64;
             if size == 0 {
-                let rle_size = patch_file.read_u16::<BigEndian>()? as usize;
+                let rle_size = patch_file.read_u16::<BigEndian>()? as u64;
                 let _rle_value = patch_file.read_u8()?;
-                target_size = cmp::max(target_size, offset as u64 + rle_size as u64);
+                target_size = cmp::max(target_size, offset + rle_size);
             } else {
                 patch_file.seek(SeekFrom::Current(size as i64))?;
-                target_size = cmp::max(target_size, offset as u64 + size as u64);
+                target_size = cmp::max(target_size, offset + size);
+            }
+
+            if target_size > max_target_size {
+                return Err(Box::new(IpsError::TargetSize {
+                    size: target_size,
+                    limit: max_target_size,
+                }));
             }
         }
 
-        let truncated_size = patch_file.read_u24::<BigEndian>().ok().map(u64::from);
+        // The end of the records is followed either by the truncation extension, the EBP metadata
+        // or by nothing, anything else is left alone
+        let mut trailer = Vec::new();
+        patch_file.read_to_end(&mut trailer)?;
+
+        let mut truncated_size = None;
+        let mut ebp_metadata = None;
+        let mut ebp_fields = None;
+
+        if trailer.len() == variant.offset_size() {
+            let size = variant.read_offset(&mut &trailer[..])?;
+            if size > max_target_size {
+                return Err(Box::new(IpsError::TargetSize {
+                    size,
+                    limit: max_target_size,
+                }));
+            }
+
+            // Truncation extensions only ever shrink the target
+            if size < target_size {
+                truncated_size = Some(size);
+            }
+        } else if trailer.first() == Some(&b'{') {
+            match std::str::from_utf8(&trailer).ok().and_then(json::parse) {
+                Some(fields @ JsonValue::Object(_)) => {
+                    ebp_metadata = Some(trailer);
+                    ebp_fields = Some(fields);
+                }
+                _ => debug!("Ignoring invalid EBP metadata of {:?}", patch_path),
+            }
+        } else if !trailer.is_empty() {
+            debug!("Ignoring {} bytes after the end of {:?}", trailer.len(), patch_path);
+        }
+
+        let patch_modified = patch_file.metadata()?.modified()?;
 
         Ok(Self {
             patch_path: patch_path.to_path_buf(),
             source_path: source_path.to_path_buf(),
+            patch_modified,
             target_size,
             truncated_size,
+            max_target_size,
+            ebp_metadata,
+            ebp_fields,
         })
     }
+
+    // Text fields of the EBP metadata, like "title", "author" or "description"
+    pub fn ebp_field(&self, name: &str) -> Option<&str> {
+        self.ebp_fields.as_ref()?.get(name)?.as_str()
+    }
 }
 
 impl Patch for IpsPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        Some(&self.source_path)
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::Ips.name()
+    }
+
     fn target_size(&self) -> u64 {
         self.truncated_size.unwrap_or(self.target_size)
     }
 
+    fn metadata(&self) -> Option<&[u8]> {
+        self.ebp_metadata.as_deref()
+    }
+
     fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut target = fs::read(&self.source_path)?;
-        target.resize(self.target_size as usize, 0);
+        let source = MappedFile::open(&self.source_path)?;
 
-        let mut patch_file = File::open(&self.patch_path)?;
+        let patch_data = fs::read(&self.patch_path)?;
+        if fs::metadata(&self.patch_path)?.modified()? != self.patch_modified {
+            return Err(Box::new(IpsError::OutdatedCache));
+        }
 
-        let mut format_marker: [u8; 5] = [0; 5];
-        patch_file.read_exact(&mut format_marker)?;
-        if format_marker != IPS_FORMAT_MARKER {
-            return Err(Box::new(IpsError::FormatMarker {
-                expected: IPS_FORMAT_MARKER,
-                received: format_marker,
-            }));
+        Ok(apply_ips(&source, &patch_data, self.max_target_size)?)
+    }
+}
+
+// Patches the source in memory, without any files involved. IPS patches store no checksums,
+// nothing gets verified beyond the structure of the patch. Targets are never grown past
+// `max_target_size`.
+pub fn apply_ips(source: &[u8], patch_data: &[u8], max_target_size: u64) -> Result<Vec<u8>, IpsError> {
+    let mut patch_cursor = Cursor::new(patch_data);
+    let truncated = |_| IpsError::Truncated;
+
+    let mut format_marker: [u8; 5] = [0; 5];
+    patch_cursor.read_exact(&mut format_marker).map_err(truncated)?;
+    let variant = IpsVariant::from_format_marker(format_marker)?;
+
+    let mut target = source.to_vec();
+
+    loop {
+        let offset = variant.read_offset(&mut patch_cursor).map_err(truncated)?;
+        if offset == variant.eof_marker() {
+            break;
         }
 
-        loop {
-            let offset = patch_file.read_u24::<BigEndian>()? as usize;
-            if offset == IPS_EOF_MARKER {
-                break;
-            }
+        let (size, rle_value) = match patch_cursor.read_u16::<BigEndian>().map_err(truncated)? {
+            0 => (
+                patch_cursor.read_u16::<BigEndian>().map_err(truncated)? as u64,
+                Some(patch_cursor.read_u8().map_err(truncated)?),
+            ),
+            size => (size as u64, None),
+        };
 
-            let size = patch_file.read_u16::<BigEndian>()? as usize;
-            if size == 0 {
-                let rle_size = patch_file.read_u16::<BigEndian>()? as usize;
-                let rle_value = patch_file.read_u8()?;
-                target[offset..(offset + rle_size)].fill(rle_value);
-            } else {
-                patch_file.read_exact(&mut target[offset..(offset + size)])?;
-            }
+        // Records past the end of the source extend the target
+        let end = offset + size;
+        if end > max_target_size {
+            return Err(IpsError::TargetSize {
+                size: end,
+                limit: max_target_size,
+            });
+        }
+        if end > target.len() as u64 {
+            target.resize(end as usize, 0);
+        }
+
+        let record = &mut target[(offset as usize)..(end as usize)];
+        match rle_value {
+            Some(rle_value) => record.fill(rle_value),
+            None => patch_cursor.read_exact(record).map_err(truncated)?,
+        }
+    }
+
+    // Only the truncation extension affects the target, EBP metadata is left alone
+    let trailer_size = patch_data.len() as u64 - patch_cursor.position();
+    if trailer_size == variant.offset_size() as u64 {
+        let truncated_size = variant.read_offset(&mut patch_cursor).map_err(truncated)?;
+        if truncated_size > max_target_size {
+            return Err(IpsError::TargetSize {
+                size: truncated_size,
+                limit: max_target_size,
+            });
+        }
+        if truncated_size < target.len() as u64 {
+            target.truncate(truncated_size as usize);
         }
+    }
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_manager::DEFAULT_MAX_TARGET_SIZE;
+
+    const SOURCE: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+    fn write_record(patch_data: &mut Vec<u8>, offset: u32, data: &[u8]) {
+        patch_data.extend_from_slice(&offset.to_be_bytes()[1..]);
+        patch_data.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        patch_data.extend_from_slice(data);
+    }
+
+    fn build_patch(records: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut patch_data = IPS_FORMAT_MARKER.to_vec();
+        for &(offset, data) in records {
+            write_record(&mut patch_data, offset, data);
+        }
+        patch_data.extend_from_slice(b"EOF");
+        patch_data
+    }
+
+    #[test]
+    fn test_apply() {
+        let patch_data = build_patch(&[(10, b"black"), (40, b"cat")]);
+        assert_eq!(
+            apply_ips(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            b"The quick black fox jumps over the lazy cat"
+        );
+    }
+
+    #[test]
+    fn test_format_marker_mismatch() {
+        let mut patch_data = build_patch(&[(10, b"black")]);
+        patch_data[0] = b'B';
 
-        if let Some(truncated_size) = self.truncated_size {
-            target.resize(truncated_size as usize, 0);
+        assert!(matches!(
+            apply_ips(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE),
+            Err(IpsError::FormatMarker { .. })
+        ));
+    }
+
+    #[test]
+    fn test_truncated_patch() {
+        let patch_data = build_patch(&[(10, b"black")]);
+
+        for size in 0..(patch_data.len() - 3) {
+            assert!(
+                matches!(
+                    apply_ips(SOURCE, &patch_data[..size], DEFAULT_MAX_TARGET_SIZE),
+                    Err(IpsError::Truncated)
+                ),
+                "size: {}",
+                size
+            );
         }
+    }
+
+    fn write_rle_record(patch_data: &mut Vec<u8>, offset: u32, size: u16, value: u8) {
+        patch_data.extend_from_slice(&offset.to_be_bytes()[1..]);
+        patch_data.extend_from_slice(&[0, 0]);
+        patch_data.extend_from_slice(&size.to_be_bytes());
+        patch_data.push(value);
+    }
+
+    #[test]
+    fn test_normal_record() {
+        let patch_data = build_patch(&[(4, b"QUICK")]);
+        assert_eq!(
+            apply_ips(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            b"The QUICK brown fox jumps over the lazy dog"
+        );
+    }
+
+    #[test]
+    fn test_rle_record() {
+        let mut patch_data = IPS_FORMAT_MARKER.to_vec();
+        write_rle_record(&mut patch_data, 4, 5, b'-');
+        patch_data.extend_from_slice(b"EOF");
+
+        assert_eq!(
+            apply_ips(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            b"The ----- brown fox jumps over the lazy dog"
+        );
+    }
+
+    #[test]
+    fn test_extending_records() {
+        let mut patch_data = IPS_FORMAT_MARKER.to_vec();
+        write_record(&mut patch_data, 43, b"!");
+        write_rle_record(&mut patch_data, 46, 2, b'?');
+        patch_data.extend_from_slice(b"EOF");
+
+        assert_eq!(
+            apply_ips(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            b"The quick brown fox jumps over the lazy dog!\0\0??"
+        );
+    }
+
+    #[test]
+    fn test_truncation_trailer() {
+        let mut patch_data = build_patch(&[(4, b"QUICK")]);
+        patch_data.extend_from_slice(&[0x00, 0x00, 0x08]);
+
+        assert_eq!(
+            apply_ips(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            b"The QUIC"
+        );
+    }
+
+    // Truncation extensions past the end of the target are ignored
+    #[test]
+    fn test_growing_truncation_trailer() {
+        let mut patch_data = build_patch(&[(4, b"QUICK")]);
+        patch_data.extend_from_slice(&[0x00, 0x01, 0x00]);
+
+        assert_eq!(
+            apply_ips(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            b"The QUICK brown fox jumps over the lazy dog"
+        );
+    }
+
+    #[test]
+    fn test_truncation_trailer_limit() {
+        let mut patch_data = build_patch(&[(4, b"QUICK")]);
+        patch_data.extend_from_slice(&[0x00, 0x01, 0x00]);
+
+        assert!(matches!(
+            apply_ips(SOURCE, &patch_data, 0xFF),
+            Err(IpsError::TargetSize {
+                size: 0x100,
+                limit: 0xFF
+            })
+        ));
+    }
+
+    // Trailers of other lengths are EBP metadata, they leave the target alone
+    #[test]
+    fn test_metadata_trailer() {
+        let mut patch_data = build_patch(&[(4, b"QUICK")]);
+        patch_data.extend_from_slice(b"{\"title\":\"Quick\"}");
+
+        assert_eq!(
+            apply_ips(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            b"The QUICK brown fox jumps over the lazy dog"
+        );
+    }
+
+    #[test]
+    fn test_ips32() {
+        let mut patch_data = IPS32_FORMAT_MARKER.to_vec();
+        patch_data.extend_from_slice(&4u32.to_be_bytes());
+        patch_data.extend_from_slice(&5u16.to_be_bytes());
+        patch_data.extend_from_slice(b"QUICK");
+        patch_data.extend_from_slice(b"EEOF");
+        patch_data.extend_from_slice(&8u32.to_be_bytes());
+
+        assert_eq!(
+            apply_ips(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            b"The QUIC"
+        );
+    }
+
+    #[test]
+    fn test_target_size_limit() {
+        let mut patch_data = IPS32_FORMAT_MARKER.to_vec();
+        patch_data.extend_from_slice(&0xFFFF_0000u32.to_be_bytes());
+        patch_data.extend_from_slice(&[0, 0, 0xFF, 0xFF, 0x00]);
+        patch_data.extend_from_slice(b"EEOF");
 
-        Ok(target)
+        assert!(matches!(
+            apply_ips(SOURCE, &patch_data, 1024 * 1024 * 1024),
+            Err(IpsError::TargetSize {
+                size: 0xFFFF_FFFF,
+                limit: 0x4000_0000
+            })
+        ));
     }
 }
@@ -1,10 +1,181 @@
 use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::SystemTime;
 
+use crc::crc32;
+
+pub mod aps;
+pub mod aps_gba;
 pub mod bps;
+pub mod bsdiff;
+pub mod chain;
 pub mod ips;
+pub mod ppf;
+pub mod rup;
+pub mod star_rod;
+pub mod ups;
+pub mod vcdiff;
 
 pub trait Patch {
+    fn patch_path(&self) -> &Path;
+
+    fn source_path(&self) -> Option<&Path>;
+
+    fn patch_modified(&self) -> SystemTime;
+
+    // Name of the patch format, known since loading the patch
+    fn format(&self) -> &str;
+
     fn target_size(&self) -> u64;
 
+    // CRC32 of the target ROM, for formats storing it in the patch file
+    fn target_checksum(&self) -> Option<u32> {
+        None
+    }
+
+    fn metadata(&self) -> Option<&[u8]> {
+        None
+    }
+
     fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    // For formats able to produce the target front to back, so reads near the start
+    // are served without patching the whole ROM
+    fn partial_patched_rom(&self) -> Result<Option<Box<dyn PartialRom>>, Box<dyn Error>> {
+        Ok(None)
+    }
+}
+
+pub trait PartialRom: Send {
+    // Patches at least `end` bytes of the target, or all of it when it is shorter
+    fn patch_until(&mut self, end: u64) -> Result<(), Box<dyn Error>>;
+
+    fn patched_data(&self) -> &[u8];
+
+    // The target between `offset` and `end` when it is copied unchanged from the source, for
+    // serving it without patching up to it first
+    fn source_data(&mut self, _offset: u64, _end: u64) -> Option<&[u8]> {
+        None
+    }
+
+    fn is_complete(&self) -> bool;
+
+    fn into_patched_rom(self: Box<Self>) -> Vec<u8>;
+}
+
+#[derive(Debug)]
+pub struct TargetChecksumError {
+    pub expected: u32,
+    pub received: u32,
+}
+
+impl fmt::Display for TargetChecksumError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "invalid target checksum (expected: 0x{:08X}, received: 0x{:08X})",
+            self.expected, self.received
+        )
+    }
+}
+
+impl Error for TargetChecksumError {}
+
+// Patched targets are checked against the CRC32 stored in the patch, for formats having one
+pub fn verify_target_checksum(patch: &dyn Patch, target: &[u8]) -> Result<(), TargetChecksumError> {
+    if let Some(expected) = patch.target_checksum() {
+        let received = crc32::checksum_ieee(target);
+        if received != expected {
+            return Err(TargetChecksumError { expected, received });
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PatchFormat {
+    Aps,
+    ApsGba,
+    Bps,
+    Bsdiff,
+    Ips,
+    Ppf,
+    Rup,
+    StarRod,
+    Ups,
+    Vcdiff,
+}
+
+impl PatchFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            PatchFormat::Aps => "APS",
+            PatchFormat::ApsGba => "APS (GBA)",
+            PatchFormat::Bps => "BPS",
+            PatchFormat::Bsdiff => "bsdiff",
+            PatchFormat::Ips => "IPS",
+            PatchFormat::Ppf => "PPF",
+            PatchFormat::Rup => "RUP",
+            PatchFormat::StarRod => "Star Rod",
+            PatchFormat::Ups => "UPS",
+            PatchFormat::Vcdiff => "VCDIFF",
+        }
+    }
+
+    // Patch formats are detected by their format markers, file extensions are only considered
+    // for formats without one
+    pub fn detect(path: &Path) -> io::Result<Option<PatchFormat>> {
+        let patch_file = File::open(path)?;
+        let patch_size = patch_file.metadata()?.len();
+
+        let mut format_marker = Vec::new();
+        patch_file.take(16).read_to_end(&mut format_marker)?;
+
+        // Both APS flavors start with "APS1", they are told apart by the N64 patch type byte
+        // and the fixed record size of the GBA flavor
+        if format_marker.starts_with(&aps_gba::APS_GBA_FORMAT_MARKER) {
+            if format_marker.starts_with(&aps::APS_FORMAT_MARKER) && format_marker.get(5) == Some(&1) {
+                Ok(Some(PatchFormat::Aps))
+            } else if aps_gba::has_aps_gba_structure(patch_size) {
+                Ok(Some(PatchFormat::ApsGba))
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unrecognized APS patch flavor",
+                ))
+            }
+        } else if format_marker.starts_with(&bps::BPS_FORMAT_MARKER) {
+            Ok(Some(PatchFormat::Bps))
+        } else if format_marker.starts_with(&bsdiff::BSDIFF_FORMAT_MARKER) {
+            Ok(Some(PatchFormat::Bsdiff))
+        } else if format_marker.starts_with(&ips::IPS_FORMAT_MARKER)
+            || format_marker.starts_with(&ips::IPS32_FORMAT_MARKER)
+        {
+            Ok(Some(PatchFormat::Ips))
+        } else if format_marker.starts_with(&ppf::PPF2_FORMAT_MARKER)
+            || format_marker.starts_with(&ppf::PPF3_FORMAT_MARKER)
+        {
+            Ok(Some(PatchFormat::Ppf))
+        } else if format_marker.starts_with(&rup::RUP_FORMAT_MARKER) {
+            Ok(Some(PatchFormat::Rup))
+        } else if format_marker.starts_with(&ups::UPS_FORMAT_MARKER) {
+            Ok(Some(PatchFormat::Ups))
+        } else if format_marker.starts_with(&vcdiff::VCDIFF_FORMAT_MARKER) {
+            Ok(Some(PatchFormat::Vcdiff))
+        } else if path
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|extension| extension.eq_ignore_ascii_case(star_rod::STAR_ROD_EXTENSION))
+            && star_rod::has_star_rod_structure(path)?
+        {
+            Ok(Some(PatchFormat::StarRod))
+        } else {
+            Ok(None)
+        }
+    }
 }
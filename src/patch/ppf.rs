@@ -0,0 +1,374 @@
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::patch::{PartialRom, Patch, PatchFormat};
+use crate::utils::MappedFile;
+
+pub const PPF2_FORMAT_MARKER: [u8; 5] = [b'P', b'P', b'F', b'2', b'0'];
+pub const PPF3_FORMAT_MARKER: [u8; 5] = [b'P', b'P', b'F', b'3', b'0'];
+const PPF_HEADER_SIZE: u64 = 60;
+const PPF_MAX_RECORD_SIZE: u64 = 255;
+const PPF_VALIDATION_BLOCK_SIZE: usize = 1024;
+const PPF_BIN_VALIDATION_OFFSET: usize = 0x9320;
+const PPF_GI_VALIDATION_OFFSET: usize = 0x80A0;
+
+const PPF_DIZ_BEGIN_MARKER_SIZE: u64 = 18; // "@BEGIN_FILE_ID.DIZ"
+const PPF_DIZ_END_MARKER_SIZE: u64 = 16; // "@END_FILE_ID.DIZ"
+const PPF_DIZ_MARKER: [u8; 4] = [b'.', b'D', b'I', b'Z'];
+
+#[derive(Debug)]
+pub enum PpfError {
+    OutdatedCache,
+    FormatMarker { expected: [u8; 5], received: [u8; 5] },
+    Truncated { size: u64 },
+    ValidationBlock,
+    SourceSize { expected: u64, received: u64 },
+    TargetOverflow { offset: u64, target_size: u64 },
+}
+
+impl fmt::Display for PpfError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PpfError::OutdatedCache => write!(formatter, "outdated cache"),
+            PpfError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            PpfError::Truncated { size } => write!(formatter, "truncated patch file ({} bytes)", size),
+            PpfError::ValidationBlock => write!(formatter, "source image does not match the validation block"),
+            PpfError::SourceSize { expected, received } => write!(
+                formatter,
+                "source image size mismatch (expected: {} bytes, received: {} bytes)",
+                expected, received
+            ),
+            PpfError::TargetOverflow { offset, target_size } => write!(
+                formatter,
+                "patch record out of target bounds (offset: {}, target size: {})",
+                offset, target_size
+            ),
+        }
+    }
+}
+
+impl Error for PpfError {}
+
+// PPF2 records have 32-bit offsets and a mandatory validation block, PPF3 records 64-bit offsets
+// and optional undo data
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum PpfVersion {
+    Ppf2,
+    Ppf3,
+}
+
+#[derive(Debug)]
+struct PpfRecord {
+    offset: u64,
+    data: Vec<u8>,
+    // Records are applied in the order they are stored, later ones overwriting earlier ones
+    index: usize,
+}
+
+#[derive(Debug)]
+pub struct PpfPatch {
+    version: PpfVersion,
+    source_path: Option<PathBuf>,
+    source_size: u64,
+
+    patch_path: PathBuf,
+    patch_description: Vec<u8>,
+    patch_records_offset: u64,
+    patch_records_size: u64,
+    patch_modified: SystemTime,
+
+    // Only stored by PPF2 patches
+    image_size: Option<u64>,
+    validation_block: Option<(usize, Vec<u8>)>,
+    undo_data: bool,
+}
+
+impl PpfPatch {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut patch_file = File::open(patch_path)?;
+        let patch_size = patch_file.metadata()?.len();
+        let patch_modified = patch_file.metadata()?.modified()?;
+
+        if patch_size < PPF_HEADER_SIZE {
+            return Err(Box::new(PpfError::Truncated { size: patch_size }));
+        }
+
+        let mut format_marker: [u8; 5] = [0; 5];
+        patch_file.read_exact(&mut format_marker)?;
+        let version = match format_marker {
+            PPF2_FORMAT_MARKER => PpfVersion::Ppf2,
+            PPF3_FORMAT_MARKER => PpfVersion::Ppf3,
+            _ => {
+                return Err(Box::new(PpfError::FormatMarker {
+                    expected: PPF3_FORMAT_MARKER,
+                    received: format_marker,
+                }))
+            }
+        };
+
+        let _encoding_method = patch_file.read_u8()?;
+
+        let mut patch_description = vec![0; 50];
+        patch_file.read_exact(&mut patch_description)?;
+        let description_size = patch_description.iter().rposition(|&b| b != 0 && b != b' ');
+        patch_description.truncate(description_size.map_or(0, |size| size + 1));
+
+        let (image_size, image_type, block_check, undo_data) = match version {
+            PpfVersion::Ppf2 => (Some(patch_file.read_u32::<LittleEndian>()? as u64), 0, true, false),
+            PpfVersion::Ppf3 => {
+                let image_type = patch_file.read_u8()?;
+                let block_check = patch_file.read_u8()? != 0;
+                let undo_data = patch_file.read_u8()? != 0;
+                let _dummy = patch_file.read_u8()?;
+                (None, image_type, block_check, undo_data)
+            }
+        };
+
+        let validation_block = if block_check {
+            let validation_offset = if image_type == 0 {
+                PPF_BIN_VALIDATION_OFFSET
+            } else {
+                PPF_GI_VALIDATION_OFFSET
+            };
+
+            let mut validation_block = vec![0; PPF_VALIDATION_BLOCK_SIZE];
+            if patch_file.read_exact(&mut validation_block).is_err() {
+                return Err(Box::new(PpfError::Truncated { size: patch_size }));
+            }
+            Some((validation_offset, validation_block))
+        } else {
+            None
+        };
+
+        let patch_records_offset = patch_file.stream_position()?;
+
+        // The optional file_id.diz block is stored after the records, its size is at the very end
+        let diz_length_size = match version {
+            PpfVersion::Ppf2 => 4,
+            PpfVersion::Ppf3 => 2,
+        };
+        let mut diz_size = 0;
+        if patch_size >= patch_records_offset + 4 + diz_length_size {
+            patch_file.seek(SeekFrom::End(-4 - diz_length_size as i64))?;
+
+            let mut diz_marker: [u8; 4] = [0; 4];
+            patch_file.read_exact(&mut diz_marker)?;
+            if diz_marker == PPF_DIZ_MARKER {
+                let diz_length = match version {
+                    PpfVersion::Ppf2 => patch_file.read_u32::<LittleEndian>()? as u64,
+                    PpfVersion::Ppf3 => patch_file.read_u16::<LittleEndian>()? as u64,
+                };
+                diz_size = diz_length + PPF_DIZ_BEGIN_MARKER_SIZE + PPF_DIZ_END_MARKER_SIZE + diz_length_size;
+            }
+        }
+
+        if patch_records_offset + diz_size > patch_size {
+            return Err(Box::new(PpfError::Truncated { size: patch_size }));
+        }
+
+        Ok(Self {
+            version,
+            source_path: None,
+            source_size: 0,
+            patch_path: patch_path.to_owned(),
+            patch_description,
+            patch_records_offset,
+            patch_records_size: patch_size - patch_records_offset - diz_size,
+            patch_modified,
+            image_size,
+            validation_block,
+            undo_data,
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) -> Result<(), Box<dyn Error>> {
+        self.source_size = fs::metadata(source_path)?.len();
+        self.source_path = Some(source_path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn description(&self) -> String {
+        String::from_utf8_lossy(&self.patch_description).into_owned()
+    }
+
+    pub fn has_validation_block(&self) -> bool {
+        self.validation_block.is_some()
+    }
+
+    // Patches without a validation block accept any source image
+    pub fn verify_source(&self, source: &[u8]) -> Result<(), PpfError> {
+        if let Some(image_size) = self.image_size {
+            if source.len() as u64 != image_size {
+                return Err(PpfError::SourceSize {
+                    expected: image_size,
+                    received: source.len() as u64,
+                });
+            }
+        }
+
+        if let Some((validation_offset, validation_block)) = &self.validation_block {
+            let source_block = source.get(*validation_offset..(validation_offset + validation_block.len()));
+            if source_block != Some(validation_block.as_slice()) {
+                return Err(PpfError::ValidationBlock);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn partial_ppf_rom(&self) -> Result<PartialPpfRom, Box<dyn Error>> {
+        let patch_data = {
+            let mut patch_file = File::open(&self.patch_path)?;
+
+            if patch_file.metadata()?.modified()? != self.patch_modified {
+                return Err(Box::new(PpfError::OutdatedCache));
+            }
+
+            let mut patch_data = vec![0; self.patch_records_size as usize];
+            patch_file.seek(SeekFrom::Start(self.patch_records_offset))?;
+            patch_file.read_exact(&mut patch_data)?;
+            patch_data
+        };
+
+        let source = MappedFile::open(self.source_path.as_ref().unwrap())?;
+        self.verify_source(&source)?;
+
+        let mut records = Vec::new();
+        let mut patch_cursor = Cursor::new(&patch_data);
+
+        while patch_cursor.stream_position()? < patch_data.len() as u64 {
+            let offset = match self.version {
+                PpfVersion::Ppf2 => patch_cursor.read_u32::<LittleEndian>()? as u64,
+                PpfVersion::Ppf3 => patch_cursor.read_u64::<LittleEndian>()?,
+            };
+            let size = patch_cursor.read_u8()? as u64;
+
+            if offset.saturating_add(size) > source.len() as u64 {
+                return Err(Box::new(PpfError::TargetOverflow {
+                    offset,
+                    target_size: source.len() as u64,
+                }));
+            }
+
+            let mut data = vec![0; size as usize];
+            patch_cursor.read_exact(&mut data)?;
+            records.push(PpfRecord {
+                offset,
+                data,
+                index: records.len(),
+            });
+
+            if self.undo_data {
+                patch_cursor.seek(SeekFrom::Current(size as i64))?;
+            }
+        }
+
+        // Stable, records of the same offset keep their order
+        records.sort_by_key(|record| record.offset);
+
+        Ok(PartialPpfRom {
+            source,
+            target: Vec::new(),
+            records,
+        })
+    }
+}
+
+impl Patch for PpfPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::Ppf.name()
+    }
+
+    // PPF patches never change the size of the image
+    fn target_size(&self) -> u64 {
+        self.source_size
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut partial_rom = self.partial_ppf_rom()?;
+        partial_rom.patch_until(self.source_size)?;
+        Ok(partial_rom.target)
+    }
+
+    fn partial_patched_rom(&self) -> Result<Option<Box<dyn PartialRom>>, Box<dyn Error>> {
+        Ok(Some(Box::new(self.partial_ppf_rom()?)))
+    }
+}
+
+// Records may come in any order, but each of them only changes the bytes it covers. The target
+// is copied from the source front to back, applying the records overlapping the copied parts.
+pub struct PartialPpfRom {
+    source: MappedFile,
+    target: Vec<u8>,
+    // Sorted by offset, records never span more than the maximum record size
+    records: Vec<PpfRecord>,
+}
+
+impl PartialRom for PartialPpfRom {
+    fn patch_until(&mut self, end: u64) -> Result<(), Box<dyn Error>> {
+        let start = self.target.len() as u64;
+        let end = cmp::min(end, self.source.len() as u64);
+        if end <= start {
+            return Ok(());
+        }
+
+        self.target
+            .extend_from_slice(&self.source[start as usize..end as usize]);
+
+        let first = self
+            .records
+            .partition_point(|record| record.offset + PPF_MAX_RECORD_SIZE <= start);
+        let last = self.records.partition_point(|record| record.offset < end);
+
+        let mut records: Vec<&PpfRecord> = self.records[first..last]
+            .iter()
+            .filter(|record| record.offset + record.data.len() as u64 > start)
+            .collect();
+        records.sort_by_key(|record| record.index);
+
+        for record in records {
+            let copy_start = cmp::max(record.offset, start);
+            let copy_end = cmp::min(record.offset + record.data.len() as u64, end);
+            self.target[copy_start as usize..copy_end as usize].copy_from_slice(
+                &record.data[(copy_start - record.offset) as usize..(copy_end - record.offset) as usize],
+            );
+        }
+
+        Ok(())
+    }
+
+    fn patched_data(&self) -> &[u8] {
+        &self.target
+    }
+
+    fn is_complete(&self) -> bool {
+        self.target.len() == self.source.len()
+    }
+
+    fn into_patched_rom(self: Box<Self>) -> Vec<u8> {
+        self.target
+    }
+}
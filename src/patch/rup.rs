@@ -0,0 +1,307 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use byteorder::ReadBytesExt;
+
+use crate::md5::md5;
+use crate::patch::{Patch, PatchFormat};
+use crate::utils::MappedFile;
+
+pub const RUP_FORMAT_MARKER: [u8; 6] = [b'N', b'I', b'N', b'J', b'A', b'2'];
+const RUP_HEADER_SIZE: u64 = 0x800;
+
+const RUP_COMMAND_END: u8 = 0x00;
+const RUP_COMMAND_OPEN_FILE: u8 = 0x01;
+const RUP_COMMAND_XOR_RECORD: u8 = 0x02;
+
+// Growing targets store the appended bytes, shrinking ones the removed bytes
+const RUP_OVERFLOW_APPEND: u8 = b'A';
+
+#[derive(Debug)]
+pub enum RupError {
+    OutdatedCache,
+    FormatMarker { expected: [u8; 6], received: [u8; 6] },
+    Truncated { size: u64 },
+    Command { received: u8 },
+    RecordWithoutFile,
+    SourceLength { expected: u64, received: u64 },
+    SourceChecksum,
+    TargetChecksum,
+}
+
+impl fmt::Display for RupError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RupError::OutdatedCache => write!(formatter, "outdated cache"),
+            RupError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            RupError::Truncated { size } => write!(formatter, "truncated patch file ({} bytes)", size),
+            RupError::Command { received } => write!(formatter, "invalid command (0x{:02X})", received),
+            RupError::RecordWithoutFile => write!(formatter, "patch record outside of any file"),
+            RupError::SourceLength { expected, received } => write!(
+                formatter,
+                "source length mismatch (expected: {}, received: {})",
+                expected, received
+            ),
+            RupError::SourceChecksum => write!(formatter, "invalid source MD5 checksum"),
+            RupError::TargetChecksum => write!(formatter, "invalid target MD5 checksum"),
+        }
+    }
+}
+
+impl Error for RupError {}
+
+// Sizes and offsets are prefixed by their own length in bytes
+fn read_rup_vlv<R: Read>(reader: &mut R) -> Result<u64, Box<dyn Error>> {
+    let size = reader.read_u8()?;
+    let mut result: u64 = 0;
+    for i in 0..size {
+        let x = reader.read_u8()? as u64;
+        if i >= 8 {
+            return Err(Box::new(RupError::Command { received: size }));
+        }
+        result |= x << (i * 8);
+    }
+    Ok(result)
+}
+
+fn read_rup_bytes<R: Read>(reader: &mut R, size: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    reader.take(size).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != size {
+        return Err(Box::new(RupError::Truncated {
+            size: bytes.len() as u64,
+        }));
+    }
+    Ok(bytes)
+}
+
+// Zero padded text fields of the header
+fn read_rup_text<R: Read>(reader: &mut R, size: u64) -> Result<String, Box<dyn Error>> {
+    let mut text = read_rup_bytes(reader, size)?;
+    let text_size = text.iter().rposition(|&b| b != 0 && b != b' ');
+    text.truncate(text_size.map_or(0, |size| size + 1));
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+#[derive(Debug)]
+struct RupRecord {
+    offset: u64,
+    patch_offset: u64,
+    size: u64,
+}
+
+// NINJA 2.0 patches may hold patches for multiple files, each file is a separate target
+pub struct RupContainer {
+    pub author: String,
+    pub version: String,
+    pub title: String,
+    pub description: String,
+    pub patches: Vec<RupPatch>,
+}
+
+impl RupContainer {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut patch_file = File::open(patch_path)?;
+        let patch_size = patch_file.metadata()?.len();
+        let patch_modified = patch_file.metadata()?.modified()?;
+
+        if patch_size < RUP_HEADER_SIZE {
+            return Err(Box::new(RupError::Truncated { size: patch_size }));
+        }
+
+        let mut format_marker: [u8; 6] = [0; 6];
+        patch_file.read_exact(&mut format_marker)?;
+        if format_marker != RUP_FORMAT_MARKER {
+            return Err(Box::new(RupError::FormatMarker {
+                expected: RUP_FORMAT_MARKER,
+                received: format_marker,
+            }));
+        }
+
+        let _text_encoding = patch_file.read_u8()?;
+        let author = read_rup_text(&mut patch_file, 84)?;
+        let version = read_rup_text(&mut patch_file, 11)?;
+        let title = read_rup_text(&mut patch_file, 256)?;
+        let _genre = read_rup_text(&mut patch_file, 48)?;
+        let _language = read_rup_text(&mut patch_file, 48)?;
+        let _date = read_rup_text(&mut patch_file, 8)?;
+        let _web = read_rup_text(&mut patch_file, 512)?;
+        let description = read_rup_text(&mut patch_file, 1074)?.replace("\\n", "\n");
+
+        let patch_data = MappedFile::open(patch_path)?;
+        let mut patch_cursor = Cursor::new(&patch_data[..]);
+        patch_cursor.seek(SeekFrom::Start(RUP_HEADER_SIZE))?;
+
+        let mut patches: Vec<RupPatch> = Vec::new();
+
+        loop {
+            match patch_cursor.read_u8()? {
+                RUP_COMMAND_END => break,
+                RUP_COMMAND_OPEN_FILE => {
+                    let file_name_size = read_rup_vlv(&mut patch_cursor)?;
+                    let file_name = read_rup_bytes(&mut patch_cursor, file_name_size)?;
+                    let _rom_type = patch_cursor.read_u8()?;
+                    let source_size = read_rup_vlv(&mut patch_cursor)?;
+                    let target_size = read_rup_vlv(&mut patch_cursor)?;
+
+                    let mut source_md5: [u8; 16] = [0; 16];
+                    patch_cursor.read_exact(&mut source_md5)?;
+                    let mut target_md5: [u8; 16] = [0; 16];
+                    patch_cursor.read_exact(&mut target_md5)?;
+
+                    // Overflow bytes are stored XOR-ed with 0xFF
+                    let mut overflow = Vec::new();
+                    if source_size != target_size {
+                        let overflow_mode = patch_cursor.read_u8()?;
+                        let overflow_size = read_rup_vlv(&mut patch_cursor)?;
+                        let overflow_data = read_rup_bytes(&mut patch_cursor, overflow_size)?;
+                        if overflow_mode == RUP_OVERFLOW_APPEND {
+                            overflow = overflow_data.iter().map(|b| b ^ 0xFF).collect();
+                        }
+                    }
+
+                    patches.push(RupPatch {
+                        source_path: None,
+                        source_size,
+                        source_md5,
+                        target_size,
+                        target_md5,
+                        file_name: String::from_utf8_lossy(&file_name).into_owned(),
+                        patch_path: patch_path.to_owned(),
+                        patch_records: Vec::new(),
+                        patch_overflow: overflow,
+                        patch_modified,
+                    });
+                }
+                RUP_COMMAND_XOR_RECORD => {
+                    let offset = read_rup_vlv(&mut patch_cursor)?;
+                    let size = read_rup_vlv(&mut patch_cursor)?;
+                    let patch_offset = patch_cursor.position();
+
+                    if patch_offset + size > patch_size {
+                        return Err(Box::new(RupError::Truncated { size: patch_size }));
+                    }
+                    patch_cursor.seek(SeekFrom::Current(size as i64))?;
+
+                    let patch = patches.last_mut().ok_or(RupError::RecordWithoutFile)?;
+                    patch.patch_records.push(RupRecord {
+                        offset,
+                        patch_offset,
+                        size,
+                    });
+                }
+                command => return Err(Box::new(RupError::Command { received: command })),
+            }
+        }
+
+        Ok(Self {
+            author,
+            version,
+            title,
+            description,
+            patches,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RupPatch {
+    source_path: Option<PathBuf>,
+    source_size: u64,
+    source_md5: [u8; 16],
+
+    target_size: u64,
+    target_md5: [u8; 16],
+
+    file_name: String,
+    patch_path: PathBuf,
+    patch_records: Vec<RupRecord>,
+    patch_overflow: Vec<u8>,
+    patch_modified: SystemTime,
+}
+
+impl RupPatch {
+    pub fn set_source_path(&mut self, source_path: &Path) {
+        self.source_path = Some(source_path.to_path_buf());
+    }
+
+    // Name of the original file the patch was made for
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn verify_source(&self, source: &[u8]) -> Result<(), RupError> {
+        if source.len() as u64 != self.source_size {
+            return Err(RupError::SourceLength {
+                expected: self.source_size,
+                received: source.len() as u64,
+            });
+        }
+
+        if md5(source) != self.source_md5 {
+            return Err(RupError::SourceChecksum);
+        }
+
+        Ok(())
+    }
+}
+
+impl Patch for RupPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::Rup.name()
+    }
+
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if fs::metadata(&self.patch_path)?.modified()? != self.patch_modified {
+            return Err(Box::new(RupError::OutdatedCache));
+        }
+
+        let patch_data = MappedFile::open(&self.patch_path)?;
+        let source = MappedFile::open(self.source_path.as_ref().unwrap())?;
+        self.verify_source(&source)?;
+
+        let mut target = source.to_vec();
+        target.extend_from_slice(&self.patch_overflow);
+        target.resize(self.target_size as usize, 0);
+
+        // Record bytes past the end of the target belong to the removed part of the source
+        for record in &self.patch_records {
+            let record_data = &patch_data[record.patch_offset as usize..(record.patch_offset + record.size) as usize];
+            for (offset, x) in (record.offset as usize..).zip(record_data) {
+                if let Some(target_byte) = target.get_mut(offset) {
+                    *target_byte ^= x;
+                }
+            }
+        }
+
+        if md5(&target) != self.target_md5 {
+            return Err(Box::new(RupError::TargetChecksum));
+        }
+
+        Ok(target)
+    }
+}
@@ -0,0 +1,180 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::patch::{Patch, PatchFormat};
+use crate::utils::MappedFile;
+
+// Star Rod mods have no format marker, they are recognized by their extension and structure
+pub const STAR_ROD_EXTENSION: &str = "mod";
+const STAR_ROD_RECORD_HEADER_SIZE: u64 = 8;
+const STAR_ROD_MAX_TARGET_SIZE: u64 = 256 * 1024 * 1024;
+
+// Mods are always made against the big-endian dump of Paper Mario (USA)
+const PAPER_MARIO_SIZE: u64 = 40 * 1024 * 1024;
+const PAPER_MARIO_GAME_CODE: [u8; 4] = [b'N', b'M', b'Q', b'E'];
+const N64_GAME_CODE_OFFSET: usize = 0x3B;
+
+#[derive(Debug)]
+pub enum StarRodError {
+    OutdatedCache,
+    Structure,
+    SourceRom,
+}
+
+impl fmt::Display for StarRodError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StarRodError::OutdatedCache => write!(formatter, "outdated cache"),
+            StarRodError::Structure => write!(formatter, "invalid record structure"),
+            StarRodError::SourceRom => write!(formatter, "source ROM is not Paper Mario (USA)"),
+        }
+    }
+}
+
+impl Error for StarRodError {}
+
+#[derive(Debug)]
+struct StarRodRecord {
+    offset: u64,
+    patch_offset: u64,
+    size: u64,
+}
+
+// Big-endian offset and length pairs followed by the data, up until the end of the file
+fn read_records(patch_data: &[u8]) -> Result<Vec<StarRodRecord>, StarRodError> {
+    let patch_size = patch_data.len() as u64;
+    let mut patch_cursor = Cursor::new(patch_data);
+    let mut records = Vec::new();
+
+    while patch_cursor.position() < patch_size {
+        if patch_size - patch_cursor.position() < STAR_ROD_RECORD_HEADER_SIZE {
+            return Err(StarRodError::Structure);
+        }
+
+        let offset = patch_cursor.read_u32::<BigEndian>().unwrap() as u64;
+        let size = patch_cursor.read_u32::<BigEndian>().unwrap() as u64;
+        let patch_offset = patch_cursor.position();
+
+        if size == 0 || patch_offset + size > patch_size || offset + size > STAR_ROD_MAX_TARGET_SIZE {
+            return Err(StarRodError::Structure);
+        }
+
+        patch_cursor.set_position(patch_offset + size);
+        records.push(StarRodRecord {
+            offset,
+            patch_offset,
+            size,
+        });
+    }
+
+    if records.is_empty() {
+        return Err(StarRodError::Structure);
+    }
+
+    Ok(records)
+}
+
+pub fn has_star_rod_structure(patch_path: &Path) -> io::Result<bool> {
+    Ok(read_records(&MappedFile::open(patch_path)?).is_ok())
+}
+
+// Paper Mario mods made with Star Rod, plain data records without any checksums
+#[derive(Debug)]
+pub struct StarRodPatch {
+    source_path: Option<PathBuf>,
+
+    target_size: u64,
+
+    patch_path: PathBuf,
+    patch_records: Vec<StarRodRecord>,
+    patch_modified: SystemTime,
+}
+
+impl StarRodPatch {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let patch_modified = fs::metadata(patch_path)?.modified()?;
+        let patch_records = read_records(&MappedFile::open(patch_path)?)?;
+
+        // Mods may extend the ROM past its original size
+        let target_size = patch_records
+            .iter()
+            .map(|record| record.offset + record.size)
+            .fold(PAPER_MARIO_SIZE, u64::max);
+
+        Ok(Self {
+            source_path: None,
+            target_size,
+            patch_path: patch_path.to_owned(),
+            patch_records,
+            patch_modified,
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) {
+        self.source_path = Some(source_path.to_path_buf());
+    }
+
+    pub fn verify_source(&self, source: &[u8]) -> Result<(), StarRodError> {
+        let game_code = source.get(N64_GAME_CODE_OFFSET..(N64_GAME_CODE_OFFSET + PAPER_MARIO_GAME_CODE.len()));
+        if source.len() as u64 != PAPER_MARIO_SIZE || game_code != Some(&PAPER_MARIO_GAME_CODE[..]) {
+            return Err(StarRodError::SourceRom);
+        }
+
+        Ok(())
+    }
+}
+
+impl Patch for StarRodPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::StarRod.name()
+    }
+
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if fs::metadata(&self.patch_path)?.modified()? != self.patch_modified {
+            return Err(Box::new(StarRodError::OutdatedCache));
+        }
+
+        let patch_data = MappedFile::open(&self.patch_path)?;
+        let source = MappedFile::open(self.source_path.as_ref().unwrap())?;
+        self.verify_source(&source)?;
+
+        let mut target = source.to_vec();
+        target.resize(self.target_size as usize, 0);
+
+        for record in &self.patch_records {
+            let (offset, patch_offset, size) = (
+                record.offset as usize,
+                record.patch_offset as usize,
+                record.size as usize,
+            );
+            let record_data = patch_data
+                .get(patch_offset..(patch_offset + size))
+                .ok_or(StarRodError::Structure)?;
+            target[offset..(offset + size)].copy_from_slice(record_data);
+        }
+
+        Ok(target)
+    }
+}
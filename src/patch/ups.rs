@@ -0,0 +1,592 @@
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use crc::crc32::{self, Hasher32};
+
+use crate::patch::{Patch, PatchFormat};
+use crate::utils::{MappedFile, ReadExt};
+
+pub const UPS_FORMAT_MARKER: [u8; 4] = [b'U', b'P', b'S', b'1'];
+const UPS_FOOTER_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub enum UpsError {
+    OutdatedCache,
+    Truncated { size: u64 },
+    FormatMarker { expected: [u8; 4], received: [u8; 4] },
+    SourceLength { expected: u64, received: u64 },
+    TargetSize { size: u64, limit: u64 },
+    SourceChecksum { expected: u32, received: u32 },
+    TargetChecksum { expected: u32, received: u32 },
+    PatchChecksum { expected: u32, received: u32 },
+}
+
+impl fmt::Display for UpsError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpsError::OutdatedCache => write!(formatter, "outdated cache"),
+            UpsError::Truncated { size } => write!(formatter, "truncated patch file ({} bytes)", size),
+            UpsError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            UpsError::SourceLength { expected, received } => write!(
+                formatter,
+                "source length mismatch (expected: {}, received: {})",
+                expected, received
+            ),
+            UpsError::TargetSize { size, limit } => write!(
+                formatter,
+                "target size out of bounds (size: {}, limit: {})",
+                size, limit
+            ),
+            UpsError::SourceChecksum { expected, received } => write!(
+                formatter,
+                "invalid source checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+            UpsError::TargetChecksum { expected, received } => write!(
+                formatter,
+                "invalid target checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+            UpsError::PatchChecksum { expected, received } => write!(
+                formatter,
+                "invalid patch checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl Error for UpsError {}
+
+#[derive(Debug, Clone)]
+pub struct UpsPatch {
+    source_path: Option<PathBuf>,
+    source_size: u64,
+    source_checksum: u32,
+
+    target_size: u64,
+    target_checksum: u32,
+
+    patch_path: PathBuf,
+    patch_offset: u64,
+    patch_checksum: u32,
+    patch_modified: SystemTime,
+}
+
+impl UpsPatch {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut patch = Self::parse(&fs::read(patch_path)?)?;
+
+        patch.patch_path = patch_path.to_owned();
+        patch.patch_modified = fs::metadata(patch_path)?.modified()?;
+        Ok(patch)
+    }
+
+    fn parse(patch_data: &[u8]) -> Result<Self, UpsError> {
+        let truncated = || UpsError::Truncated {
+            size: patch_data.len() as u64,
+        };
+
+        if patch_data.len() < UPS_FORMAT_MARKER.len() + UPS_FOOTER_SIZE {
+            return Err(truncated());
+        }
+
+        let mut patch_cursor = Cursor::new(patch_data);
+
+        let mut format_marker: [u8; 4] = [0; 4];
+        patch_cursor.read_exact(&mut format_marker).map_err(|_| truncated())?;
+        if format_marker != UPS_FORMAT_MARKER {
+            return Err(UpsError::FormatMarker {
+                expected: UPS_FORMAT_MARKER,
+                received: format_marker,
+            });
+        }
+
+        let source_size = patch_cursor.read_vlq().map_err(|_| truncated())?;
+        let target_size = patch_cursor.read_vlq().map_err(|_| truncated())?;
+        let patch_offset = patch_cursor.position();
+
+        // The patch checksum covers everything but itself, truncated downloads are caught here
+        let footer_offset = patch_data.len() - UPS_FOOTER_SIZE;
+        let mut footer_cursor = Cursor::new(&patch_data[footer_offset..]);
+        let source_checksum = footer_cursor.read_u32::<LittleEndian>().map_err(|_| truncated())?;
+        let target_checksum = footer_cursor.read_u32::<LittleEndian>().map_err(|_| truncated())?;
+        let patch_checksum = footer_cursor.read_u32::<LittleEndian>().map_err(|_| truncated())?;
+
+        let computed_patch_checksum = crc32::checksum_ieee(&patch_data[0..(patch_data.len() - 4)]);
+        if computed_patch_checksum != patch_checksum {
+            return Err(UpsError::PatchChecksum {
+                expected: patch_checksum,
+                received: computed_patch_checksum,
+            });
+        }
+
+        if patch_offset > footer_offset as u64 {
+            return Err(truncated());
+        }
+
+        Ok(Self {
+            source_path: None,
+            source_size,
+            source_checksum,
+            target_size,
+            target_checksum,
+            patch_path: PathBuf::new(),
+            patch_offset,
+            patch_checksum,
+            patch_modified: SystemTime::UNIX_EPOCH,
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) {
+        self.source_path = Some(source_path.to_path_buf());
+    }
+
+    pub fn source_checksum(&self) -> u32 {
+        self.source_checksum
+    }
+
+    pub fn source_size(&self) -> u64 {
+        self.source_size
+    }
+
+    pub fn verify_target(&self, target: &[u8]) -> Result<(), UpsError> {
+        let target_checksum = crc32::checksum_ieee(target);
+        if target.len() as u64 != self.target_size || target_checksum != self.target_checksum {
+            return Err(UpsError::TargetChecksum {
+                expected: self.target_checksum,
+                received: target_checksum,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn verify_source(&self, source: &[u8]) -> Result<(), UpsError> {
+        if source.len() as u64 != self.source_size {
+            return Err(UpsError::SourceLength {
+                expected: self.source_size,
+                received: source.len() as u64,
+            });
+        }
+
+        let source_checksum = crc32::checksum_ieee(source);
+        if source_checksum != self.source_checksum {
+            return Err(UpsError::SourceChecksum {
+                expected: self.source_checksum,
+                received: source_checksum,
+            });
+        }
+
+        Ok(())
+    }
+
+    // UPS patches are symmetric, XOR-ing the target with the patch data gives back the source
+    pub fn unapply(&self, target: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let patch_data = self.read_patch_data()?;
+        self.verify_target(target)?;
+
+        let mut source = xor_patch_data(&patch_data, target, self.source_size)?;
+        let source_checksum = checksum_with_zeroes(&source, self.source_size);
+        if source_checksum != self.source_checksum {
+            return Err(Box::new(UpsError::SourceChecksum {
+                expected: self.source_checksum,
+                received: source_checksum,
+            }));
+        }
+
+        source.resize(self.source_size as usize, 0);
+        Ok(source)
+    }
+
+    // Only the XOR-ed blocks are returned, without the header and the footer
+    fn read_patch_data(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut patch_data = {
+            let mut patch_file = File::open(&self.patch_path)?;
+
+            if patch_file.metadata()?.modified()? != self.patch_modified {
+                return Err(Box::new(UpsError::OutdatedCache));
+            }
+
+            let mut patch_data = Vec::new();
+            patch_file.read_to_end(&mut patch_data)?;
+            patch_data
+        };
+
+        let patch_checksum = crc32::checksum_ieee(&patch_data[0..(patch_data.len() - 4)]);
+        if patch_checksum != self.patch_checksum {
+            return Err(Box::new(UpsError::PatchChecksum {
+                expected: self.patch_checksum,
+                received: patch_checksum,
+            }));
+        }
+
+        patch_data.truncate(patch_data.len() - UPS_FOOTER_SIZE);
+        patch_data.drain(0..self.patch_offset as usize);
+        Ok(patch_data)
+    }
+}
+
+impl Patch for UpsPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::Ups.name()
+    }
+
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn target_checksum(&self) -> Option<u32> {
+        Some(self.target_checksum)
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let patch_data = self.read_patch_data()?;
+
+        // Patches creating their targets from scratch never read their sources
+        let source = if self.source_size == 0 {
+            MappedFile::Empty
+        } else {
+            MappedFile::open(self.source_path.as_ref().unwrap())?
+        };
+        self.verify_source(&source)?;
+
+        // The target checksum is verified by the caller, the target size is capped by it too
+        let mut target = xor_patch_data(&patch_data, &source, self.target_size)?;
+        target.resize(self.target_size as usize, 0);
+        Ok(target)
+    }
+}
+
+// Patches the source in memory, without any files involved. The patch, the source and the target
+// are all verified against the checksums stored in the patch. Targets larger than
+// `max_target_size` are rejected before checksumming them.
+pub fn apply_ups(source: &[u8], patch_data: &[u8], max_target_size: u64) -> Result<Vec<u8>, UpsError> {
+    let patch = UpsPatch::parse(patch_data)?;
+    patch.verify_source(source)?;
+
+    if patch.target_size > max_target_size {
+        return Err(UpsError::TargetSize {
+            size: patch.target_size,
+            limit: max_target_size,
+        });
+    }
+
+    let patch_blocks = &patch_data[(patch.patch_offset as usize)..(patch_data.len() - UPS_FOOTER_SIZE)];
+    let mut target = xor_patch_data(patch_blocks, source, patch.target_size)?;
+
+    // The target size comes from the patch, nothing is allocated for it before the checksum
+    // matches
+    let target_checksum = checksum_with_zeroes(&target, patch.target_size);
+    if target_checksum != patch.target_checksum {
+        return Err(UpsError::TargetChecksum {
+            expected: patch.target_checksum,
+            received: target_checksum,
+        });
+    }
+
+    target.resize(patch.target_size as usize, 0);
+    Ok(target)
+}
+
+// CRC32 of the data followed by zeroes up to `size`, without allocating the zeroes
+fn checksum_with_zeroes(data: &[u8], size: u64) -> u32 {
+    let zeroes = [0; 64 * 1024];
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(data);
+
+    let mut remaining = size.saturating_sub(data.len() as u64);
+    while remaining > 0 {
+        let length = cmp::min(remaining, zeroes.len() as u64);
+        digest.write(&zeroes[..length as usize]);
+        remaining -= length;
+    }
+    digest.sum32()
+}
+
+// Bytes past the end of the input are XOR-ed with zeroes. The output is only as long as the
+// input and the patched bytes, the rest of it is zeroes left to the caller to fill in, so the
+// output size claimed by the patch is never allocated upfront.
+fn xor_patch_data(patch_data: &[u8], input: &[u8], output_size: u64) -> Result<Vec<u8>, UpsError> {
+    let truncated = |_| UpsError::Truncated {
+        size: patch_data.len() as u64,
+    };
+
+    let overlap_size = cmp::min(input.len() as u64, output_size) as usize;
+    let mut output = input[0..overlap_size].to_vec();
+
+    let mut patch_cursor = Cursor::new(patch_data);
+    let mut output_offset: usize = 0;
+
+    while patch_cursor.position() < patch_data.len() as u64 {
+        output_offset = output_offset.saturating_add(patch_cursor.read_vlq().map_err(truncated)? as usize);
+
+        loop {
+            let x = patch_cursor.read_u8().map_err(truncated)?;
+            if x == 0 {
+                output_offset = output_offset.saturating_add(1);
+                break;
+            }
+
+            // Patches of shrinking targets cover the source bytes past the end of the target
+            // too, so unapplying them gives back the whole source
+            if (output_offset as u64) < output_size {
+                if output_offset >= output.len() {
+                    output.resize(output_offset + 1, 0);
+                }
+                output[output_offset] ^= x;
+            }
+
+            output_offset += 1;
+        }
+    }
+
+    Ok(output)
+}
+
+// The original source ROM recovered from an already patched ROM
+#[derive(Debug)]
+pub struct UpsUnpatch {
+    patch: UpsPatch,
+    target_path: PathBuf,
+}
+
+impl UpsUnpatch {
+    pub fn new(patch: UpsPatch, target_path: &Path) -> Self {
+        Self {
+            patch,
+            target_path: target_path.to_owned(),
+        }
+    }
+}
+
+impl Patch for UpsUnpatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        Some(&self.target_path)
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::Ups.name()
+    }
+
+    // Unpatching produces the source of the patch
+    #[allow(clippy::misnamed_getters)]
+    fn target_size(&self) -> u64 {
+        self.patch.source_size
+    }
+
+    fn target_checksum(&self) -> Option<u32> {
+        Some(self.patch.source_checksum)
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let target = MappedFile::open(&self.target_path)?;
+        self.patch.unapply(&target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_manager::DEFAULT_MAX_TARGET_SIZE;
+    use crate::utils::write_vlq;
+    use std::env;
+
+    const SOURCE: &[u8] = b"The quick brown fox jumps over the lazy dog";
+    const TARGET: &[u8] = b"The quick red fox jumps over the lazy cat!!";
+
+    // Patches with the given checksums of the source and the target, the patch checksum is
+    // always valid
+    fn build_raw_patch(source: &[u8], target: &[u8], checksums: (u32, u32)) -> Vec<u8> {
+        let mut patch_data = UPS_FORMAT_MARKER.to_vec();
+        write_vlq(&mut patch_data, source.len() as u64);
+        write_vlq(&mut patch_data, target.len() as u64);
+
+        let size = cmp::max(source.len(), target.len());
+        let xor = |offset: usize| source.get(offset).unwrap_or(&0) ^ target.get(offset).unwrap_or(&0);
+        let mut offset = 0;
+        let mut block_end = 0;
+        while offset < size {
+            if xor(offset) == 0 {
+                offset += 1;
+                continue;
+            }
+
+            write_vlq(&mut patch_data, (offset - block_end) as u64);
+            while offset < size && xor(offset) != 0 {
+                patch_data.push(xor(offset));
+                offset += 1;
+            }
+            patch_data.push(0);
+            offset += 1;
+            block_end = offset;
+        }
+
+        patch_data.extend_from_slice(&checksums.0.to_le_bytes());
+        patch_data.extend_from_slice(&checksums.1.to_le_bytes());
+        let patch_checksum = crc32::checksum_ieee(&patch_data);
+        patch_data.extend_from_slice(&patch_checksum.to_le_bytes());
+        patch_data
+    }
+
+    fn build_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        build_raw_patch(
+            source,
+            target,
+            (crc32::checksum_ieee(source), crc32::checksum_ieee(target)),
+        )
+    }
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(
+            apply_ups(SOURCE, &build_patch(SOURCE, TARGET), DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            TARGET
+        );
+    }
+
+    #[test]
+    fn test_apply_growing_target() {
+        let target = b"The quick brown fox jumps over the lazy dog\0\0and the cat";
+        assert_eq!(
+            apply_ups(SOURCE, &build_patch(SOURCE, target), DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            target
+        );
+    }
+
+    #[test]
+    fn test_apply_shrinking_target() {
+        let target = b"The quick brown fox";
+        assert_eq!(
+            apply_ups(SOURCE, &build_patch(SOURCE, target), DEFAULT_MAX_TARGET_SIZE).unwrap(),
+            target
+        );
+    }
+
+    #[test]
+    fn test_source_checksum_mismatch() {
+        let mut source = SOURCE.to_vec();
+        source[0] = b't';
+
+        assert!(matches!(
+            apply_ups(&source, &build_patch(SOURCE, TARGET), DEFAULT_MAX_TARGET_SIZE),
+            Err(UpsError::SourceChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn test_source_length_mismatch() {
+        assert!(matches!(
+            apply_ups(&SOURCE[1..], &build_patch(SOURCE, TARGET), DEFAULT_MAX_TARGET_SIZE),
+            Err(UpsError::SourceLength {
+                expected: 43,
+                received: 42
+            })
+        ));
+    }
+
+    #[test]
+    fn test_target_checksum_mismatch() {
+        let patch_data = build_raw_patch(SOURCE, TARGET, (crc32::checksum_ieee(SOURCE), 0x12345678));
+
+        assert!(matches!(
+            apply_ups(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE),
+            Err(UpsError::TargetChecksum {
+                expected: 0x12345678,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_patch_checksum_mismatch() {
+        let mut patch_data = build_patch(SOURCE, TARGET);
+        patch_data[8] ^= 0xFF;
+
+        assert!(matches!(
+            apply_ups(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE),
+            Err(UpsError::PatchChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn test_huge_target_size() {
+        let mut patch_data = UPS_FORMAT_MARKER.to_vec();
+        write_vlq(&mut patch_data, SOURCE.len() as u64);
+        write_vlq(&mut patch_data, 1 << 60);
+        patch_data.extend_from_slice(&crc32::checksum_ieee(SOURCE).to_le_bytes());
+        patch_data.extend_from_slice(&0u32.to_le_bytes());
+        let patch_checksum = crc32::checksum_ieee(&patch_data);
+        patch_data.extend_from_slice(&patch_checksum.to_le_bytes());
+
+        assert!(matches!(
+            apply_ups(SOURCE, &patch_data, DEFAULT_MAX_TARGET_SIZE),
+            Err(UpsError::TargetSize {
+                size: 0x1000_0000_0000_0000,
+                limit: DEFAULT_MAX_TARGET_SIZE
+            })
+        ));
+    }
+
+    #[test]
+    fn test_checksum_with_zeroes() {
+        let mut data = b"abc".to_vec();
+        let checksum = checksum_with_zeroes(&data, 200_000);
+        data.resize(200_000, 0);
+        assert_eq!(checksum, crc32::checksum_ieee(&data));
+    }
+
+    // Patches are read from their files when unapplying them
+    fn unapply_patch(name: &str, source: &[u8], target: &[u8]) -> Vec<u8> {
+        let patch_path = env::temp_dir().join(format!("ups-{}-{}.ups", name, std::process::id()));
+        fs::write(&patch_path, build_patch(source, target)).unwrap();
+        let result = UpsPatch::new(&patch_path).and_then(|patch| patch.unapply(target));
+        fs::remove_file(&patch_path).unwrap();
+        result.unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (name, target) in &[
+            ("same-size", TARGET),
+            (
+                "growing",
+                &b"The quick brown fox jumps over the lazy dog\0\0and the cat"[..],
+            ),
+            ("shrinking", &b"The quick brown fox"[..]),
+        ] {
+            let target = apply_ups(SOURCE, &build_patch(SOURCE, target), DEFAULT_MAX_TARGET_SIZE).unwrap();
+            assert_eq!(unapply_patch(name, SOURCE, &target), SOURCE);
+        }
+    }
+}
@@ -0,0 +1,619 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::patch::{Patch, PatchFormat};
+use crate::utils::MappedFile;
+
+pub const VCDIFF_FORMAT_MARKER: [u8; 4] = [0xD6, 0xC3, 0xC4, 0x00];
+
+// Header indicator bits, the application header is an xdelta3 extension
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+// Window indicator bits, the window checksum is an xdelta3 extension
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+const VCD_NEAR_CACHE_SIZE: usize = 4;
+const VCD_SAME_CACHE_SIZE: usize = 3;
+
+#[derive(Debug)]
+pub enum VcdiffError {
+    OutdatedCache,
+    FormatMarker { expected: [u8; 4], received: [u8; 4] },
+    Unsupported { feature: String },
+    Truncated { offset: u64 },
+    IntegerOverflow,
+    WindowIndicator { received: u8 },
+    SourceSize { expected: u64, received: u64 },
+    SegmentOutOfBounds { position: u64, length: u64 },
+    AddressOutOfBounds { address: u64 },
+    WindowSize { expected: u64, received: u64 },
+    WindowChecksum { expected: u32, received: u32 },
+}
+
+impl fmt::Display for VcdiffError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VcdiffError::OutdatedCache => write!(formatter, "outdated cache"),
+            VcdiffError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            VcdiffError::Unsupported { feature } => write!(formatter, "unsupported feature: {}", feature),
+            VcdiffError::Truncated { offset } => write!(formatter, "truncated patch file (offset: {})", offset),
+            VcdiffError::IntegerOverflow => write!(formatter, "integer overflow"),
+            VcdiffError::WindowIndicator { received } => {
+                write!(formatter, "invalid window indicator (0x{:02X})", received)
+            }
+            VcdiffError::SourceSize { expected, received } => write!(
+                formatter,
+                "source too small (expected at least: {}, received: {})",
+                expected, received
+            ),
+            VcdiffError::SegmentOutOfBounds { position, length } => write!(
+                formatter,
+                "source segment out of bounds (position: {}, length: {})",
+                position, length
+            ),
+            VcdiffError::AddressOutOfBounds { address } => {
+                write!(formatter, "copy address out of bounds ({})", address)
+            }
+            VcdiffError::WindowSize { expected, received } => write!(
+                formatter,
+                "window size mismatch (expected: {}, received: {})",
+                expected, received
+            ),
+            VcdiffError::WindowChecksum { expected, received } => write!(
+                formatter,
+                "invalid window checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl Error for VcdiffError {}
+
+// Big-endian base 128 integers, unlike the little-endian ones of BPS and UPS
+fn read_integer<R: Read>(reader: &mut R) -> Result<u64, Box<dyn Error>> {
+    let mut result: u64 = 0;
+    loop {
+        let x = reader.read_u8()?;
+        if result.leading_zeros() < 7 {
+            return Err(Box::new(VcdiffError::IntegerOverflow));
+        }
+        result = (result << 7) | (x & 0x7F) as u64;
+        if x & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+}
+
+fn read_slice<'a>(cursor: &mut Cursor<&'a [u8]>, length: u64) -> Result<&'a [u8], Box<dyn Error>> {
+    let data: &'a [u8] = cursor.get_ref();
+    let start = cursor.position();
+    let end = start
+        .checked_add(length)
+        .filter(|&end| end <= data.len() as u64)
+        .ok_or(VcdiffError::Truncated { offset: start })?;
+
+    cursor.set_position(end);
+    Ok(&data[start as usize..end as usize])
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Instruction {
+    Noop,
+    Add { size: u8 },
+    Run { size: u8 },
+    Copy { size: u8, mode: u8 },
+}
+
+// The default instruction code table of RFC 3284, section 5.6
+fn default_code_table() -> Vec<[Instruction; 2]> {
+    use Instruction::*;
+
+    let mut code_table = Vec::with_capacity(256);
+    code_table.push([Run { size: 0 }, Noop]);
+
+    for size in 0..=17 {
+        code_table.push([Add { size }, Noop]);
+    }
+
+    for mode in 0..=8 {
+        code_table.push([Copy { size: 0, mode }, Noop]);
+        for size in 4..=18 {
+            code_table.push([Copy { size, mode }, Noop]);
+        }
+    }
+
+    for mode in 0..=5 {
+        for add_size in 1..=4 {
+            for copy_size in 4..=6 {
+                code_table.push([Add { size: add_size }, Copy { size: copy_size, mode }]);
+            }
+        }
+    }
+
+    for mode in 6..=8 {
+        for add_size in 1..=4 {
+            code_table.push([Add { size: add_size }, Copy { size: 4, mode }]);
+        }
+    }
+
+    for mode in 0..=8 {
+        code_table.push([Copy { size: 4, mode }, Add { size: 1 }]);
+    }
+
+    code_table
+}
+
+struct AddressCache {
+    near: [u64; VCD_NEAR_CACHE_SIZE],
+    next_slot: usize,
+    same: [u64; VCD_SAME_CACHE_SIZE * 256],
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        Self {
+            near: [0; VCD_NEAR_CACHE_SIZE],
+            next_slot: 0,
+            same: [0; VCD_SAME_CACHE_SIZE * 256],
+        }
+    }
+
+    fn decode_address(&mut self, reader: &mut Cursor<&[u8]>, here: u64, mode: u8) -> Result<u64, Box<dyn Error>> {
+        let mode = mode as usize;
+
+        let address = if mode == 0 {
+            read_integer(reader)?
+        } else if mode == 1 {
+            here.checked_sub(read_integer(reader)?)
+                .ok_or(VcdiffError::AddressOutOfBounds { address: here })?
+        } else if mode < 2 + VCD_NEAR_CACHE_SIZE {
+            self.near[mode - 2]
+                .checked_add(read_integer(reader)?)
+                .ok_or(VcdiffError::IntegerOverflow)?
+        } else {
+            self.same[(mode - 2 - VCD_NEAR_CACHE_SIZE) * 256 + reader.read_u8()? as usize]
+        };
+
+        self.near[self.next_slot] = address;
+        self.next_slot = (self.next_slot + 1) % VCD_NEAR_CACHE_SIZE;
+        self.same[(address % self.same.len() as u64) as usize] = address;
+
+        Ok(address)
+    }
+}
+
+struct Window<'a> {
+    indicator: u8,
+    segment_length: u64,
+    segment_position: u64,
+    target_size: u64,
+    checksum: Option<u32>,
+    data: &'a [u8],
+    instructions: &'a [u8],
+    addresses: &'a [u8],
+}
+
+// Returns the application header if there is any
+fn read_header<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<Option<&'a [u8]>, Box<dyn Error>> {
+    let mut format_marker: [u8; 4] = [0; 4];
+    cursor.read_exact(&mut format_marker)?;
+    if format_marker != VCDIFF_FORMAT_MARKER {
+        return Err(Box::new(VcdiffError::FormatMarker {
+            expected: VCDIFF_FORMAT_MARKER,
+            received: format_marker,
+        }));
+    }
+
+    let header_indicator = cursor.read_u8()?;
+
+    if header_indicator & VCD_DECOMPRESS != 0 {
+        let compressor_id = cursor.read_u8()?;
+        return Err(Box::new(VcdiffError::Unsupported {
+            feature: format!("secondary compressor (id: {})", compressor_id),
+        }));
+    }
+
+    if header_indicator & VCD_CODETABLE != 0 {
+        return Err(Box::new(VcdiffError::Unsupported {
+            feature: "application-defined code table".to_owned(),
+        }));
+    }
+
+    if header_indicator & VCD_APPHEADER != 0 {
+        let app_header_length = read_integer(cursor)?;
+        Ok(Some(read_slice(cursor, app_header_length)?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn read_window<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<Window<'a>, Box<dyn Error>> {
+    let indicator = cursor.read_u8()?;
+    let unknown_bits = indicator & !(VCD_SOURCE | VCD_TARGET | VCD_ADLER32) != 0;
+    let both_segments = indicator & (VCD_SOURCE | VCD_TARGET) == (VCD_SOURCE | VCD_TARGET);
+    if unknown_bits || both_segments {
+        return Err(Box::new(VcdiffError::WindowIndicator { received: indicator }));
+    }
+
+    let (segment_length, segment_position) = if indicator & (VCD_SOURCE | VCD_TARGET) != 0 {
+        (read_integer(cursor)?, read_integer(cursor)?)
+    } else {
+        (0, 0)
+    };
+
+    let _delta_length = read_integer(cursor)?;
+    let target_size = read_integer(cursor)?;
+
+    let delta_indicator = cursor.read_u8()?;
+    if delta_indicator != 0 {
+        return Err(Box::new(VcdiffError::Unsupported {
+            feature: "secondary compression of the window sections".to_owned(),
+        }));
+    }
+
+    let data_length = read_integer(cursor)?;
+    let instructions_length = read_integer(cursor)?;
+    let addresses_length = read_integer(cursor)?;
+
+    let checksum = if indicator & VCD_ADLER32 != 0 {
+        Some(cursor.read_u32::<BigEndian>()?)
+    } else {
+        None
+    };
+
+    Ok(Window {
+        indicator,
+        segment_length,
+        segment_position,
+        target_size,
+        checksum,
+        data: read_slice(cursor, data_length)?,
+        instructions: read_slice(cursor, instructions_length)?,
+        addresses: read_slice(cursor, addresses_length)?,
+    })
+}
+
+fn instruction_size(size: u8, instructions: &mut Cursor<&[u8]>) -> Result<usize, Box<dyn Error>> {
+    if size == 0 {
+        Ok(read_integer(instructions)? as usize)
+    } else {
+        Ok(size as usize)
+    }
+}
+
+fn decode_window(
+    window: &Window,
+    code_table: &[[Instruction; 2]],
+    source: &[u8],
+    target: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let segment_out_of_bounds = || VcdiffError::SegmentOutOfBounds {
+        position: window.segment_position,
+        length: window.segment_length,
+    };
+    let segment_start = window.segment_position as usize;
+    let segment_end = window
+        .segment_position
+        .checked_add(window.segment_length)
+        .ok_or_else(segment_out_of_bounds)? as usize;
+
+    // Target segments refer to the already decoded windows
+    let segment: Option<Cow<[u8]>> = if window.indicator & VCD_SOURCE != 0 {
+        source.get(segment_start..segment_end).map(Cow::Borrowed)
+    } else if window.indicator & VCD_TARGET != 0 {
+        target
+            .get(segment_start..segment_end)
+            .map(|segment| Cow::Owned(segment.to_vec()))
+    } else {
+        Some(Cow::Borrowed(&[]))
+    };
+
+    let segment = segment.ok_or_else(segment_out_of_bounds)?;
+
+    let window_start = target.len();
+    let window_size = window.target_size as usize;
+
+    let mut data = Cursor::new(window.data);
+    let mut instructions = Cursor::new(window.instructions);
+    let mut addresses = Cursor::new(window.addresses);
+    let mut address_cache = AddressCache::new();
+
+    while instructions.position() < window.instructions.len() as u64 {
+        let code = instructions.read_u8()? as usize;
+
+        for instruction in &code_table[code] {
+            let size = match *instruction {
+                Instruction::Noop => continue,
+                Instruction::Add { size } | Instruction::Run { size } | Instruction::Copy { size, .. } => {
+                    instruction_size(size, &mut instructions)?
+                }
+            };
+
+            let window_offset = target.len() - window_start;
+            if window_offset.checked_add(size).is_none_or(|end| end > window_size) {
+                return Err(Box::new(VcdiffError::WindowSize {
+                    expected: window.target_size,
+                    received: (window_offset as u64).saturating_add(size as u64),
+                }));
+            }
+
+            match *instruction {
+                Instruction::Noop => {}
+                Instruction::Add { .. } => {
+                    target.extend_from_slice(read_slice(&mut data, size as u64)?);
+                }
+                Instruction::Run { .. } => {
+                    let run_value = data.read_u8()?;
+                    target.resize(target.len() + size, run_value);
+                }
+                Instruction::Copy { mode, .. } => {
+                    let here = (segment.len() + target.len() - window_start) as u64;
+                    let address = address_cache.decode_address(&mut addresses, here, mode)?;
+                    if address >= here {
+                        return Err(Box::new(VcdiffError::AddressOutOfBounds { address }));
+                    }
+
+                    // Copies from the target window may overlap the bytes being written
+                    for address in address as usize..(address as usize + size) {
+                        let value = if address < segment.len() {
+                            segment[address]
+                        } else {
+                            target[window_start + address - segment.len()]
+                        };
+                        target.push(value);
+                    }
+                }
+            }
+        }
+    }
+
+    let received_size = target.len() - window_start;
+    if received_size != window_size {
+        return Err(Box::new(VcdiffError::WindowSize {
+            expected: window.target_size,
+            received: received_size as u64,
+        }));
+    }
+
+    if let Some(expected_checksum) = window.checksum {
+        let received_checksum = adler32(&target[window_start..]);
+        if received_checksum != expected_checksum {
+            return Err(Box::new(VcdiffError::WindowChecksum {
+                expected: expected_checksum,
+                received: received_checksum,
+            }));
+        }
+    }
+
+    Ok(())
+}
+
+// VCDIFF (RFC 3284) delta files, as produced by xdelta3 and open-vcdiff
+#[derive(Debug)]
+pub struct VcdiffPatch {
+    source_path: Option<PathBuf>,
+    source_name: Option<OsString>,
+    source_size: u64,
+
+    target_size: u64,
+
+    patch_path: PathBuf,
+    patch_modified: SystemTime,
+}
+
+impl VcdiffPatch {
+    // Every window is checked up front so unsupported features are reported at scan time
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let patch_modified = fs::metadata(patch_path)?.modified()?;
+        let patch_data = MappedFile::open(patch_path)?;
+        let mut patch_cursor = Cursor::new(&patch_data[..]);
+
+        let app_header = read_header(&mut patch_cursor)?;
+
+        let mut source_size = 0;
+        let mut target_size: u64 = 0;
+
+        while patch_cursor.position() < patch_data.len() as u64 {
+            let window = read_window(&mut patch_cursor)?;
+            let segment_end = window
+                .segment_position
+                .checked_add(window.segment_length)
+                .ok_or(VcdiffError::IntegerOverflow)?;
+
+            if window.indicator & VCD_SOURCE != 0 {
+                source_size = source_size.max(segment_end);
+            } else if window.indicator & VCD_TARGET != 0 && segment_end > target_size {
+                return Err(Box::new(VcdiffError::SegmentOutOfBounds {
+                    position: window.segment_position,
+                    length: window.segment_length,
+                }));
+            }
+
+            target_size = target_size
+                .checked_add(window.target_size)
+                .ok_or(VcdiffError::IntegerOverflow)?;
+        }
+
+        // xdelta3 stores "<target name>/<target compression>/<source name>/<source compression>"
+        let source_name = app_header
+            .map(String::from_utf8_lossy)
+            .and_then(|app_header| app_header.rsplit('/').nth(1).map(str::to_owned))
+            .and_then(|source_name| Path::new(&source_name).file_name().map(OsString::from));
+
+        Ok(Self {
+            source_path: None,
+            source_name,
+            source_size,
+            target_size,
+            patch_path: patch_path.to_owned(),
+            patch_modified,
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) -> Result<(), Box<dyn Error>> {
+        let source_size = fs::metadata(source_path)?.len();
+        if source_size < self.source_size {
+            return Err(Box::new(VcdiffError::SourceSize {
+                expected: self.source_size,
+                received: source_size,
+            }));
+        }
+
+        self.source_path = Some(source_path.to_path_buf());
+        Ok(())
+    }
+
+    // File name of the source recorded by the encoder, if any
+    pub fn source_name(&self) -> Option<&OsString> {
+        self.source_name.as_ref()
+    }
+}
+
+impl Patch for VcdiffPatch {
+    fn patch_path(&self) -> &Path {
+        &self.patch_path
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn patch_modified(&self) -> SystemTime {
+        self.patch_modified
+    }
+
+    fn format(&self) -> &str {
+        PatchFormat::Vcdiff.name()
+    }
+
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if File::open(&self.patch_path)?.metadata()?.modified()? != self.patch_modified {
+            return Err(Box::new(VcdiffError::OutdatedCache));
+        }
+
+        let patch_data = MappedFile::open(&self.patch_path)?;
+        let source = MappedFile::open(self.source_path.as_ref().unwrap())?;
+
+        apply(&source, &patch_data)
+    }
+}
+
+// Decodes a complete VCDIFF (xdelta3) delta, source segments are taken from `source`
+pub fn apply(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let code_table = default_code_table();
+    let mut target = Vec::new();
+
+    let mut patch_cursor = Cursor::new(patch);
+    read_header(&mut patch_cursor)?;
+
+    while patch_cursor.position() < patch.len() as u64 {
+        let window = read_window(&mut patch_cursor)?;
+        decode_window(&window, &code_table, source, &mut target)?;
+    }
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window<'a>(
+        indicator: u8,
+        segment: (u64, u64),
+        target_size: u64,
+        data: &'a [u8],
+        instructions: &'a [u8],
+    ) -> Window<'a> {
+        Window {
+            indicator,
+            segment_length: segment.1,
+            segment_position: segment.0,
+            target_size,
+            checksum: None,
+            data,
+            instructions,
+            addresses: &[],
+        }
+    }
+
+    fn decode(window: &Window, source: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut target = Vec::new();
+        decode_window(window, &default_code_table(), source, &mut target)?;
+        Ok(target)
+    }
+
+    #[test]
+    fn test_decode_window() {
+        // ADD 3, then RUN 2
+        let window = window(0, (0, 0), 5, b"abcx", &[4, 0, 2]);
+        assert_eq!(decode(&window, &[]).unwrap(), b"abcxx");
+    }
+
+    #[test]
+    fn test_overflowing_segment() {
+        let window = window(VCD_SOURCE, (u64::MAX, 2), 1, b"a", &[2]);
+        let err = decode(&window, b"source").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VcdiffError>(),
+            Some(VcdiffError::SegmentOutOfBounds {
+                position: u64::MAX,
+                length: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_overflowing_instruction_size() {
+        // ADD 1, then an ADD of u64::MAX bytes
+        let window = window(
+            0,
+            (0, 0),
+            4,
+            b"ab",
+            &[2, 1, 0x81, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F],
+        );
+        let err = decode(&window, &[]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VcdiffError>(),
+            Some(VcdiffError::WindowSize {
+                expected: 4,
+                received: u64::MAX
+            })
+        ));
+    }
+}
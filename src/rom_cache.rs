@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crc::crc32;
+use log::debug;
+
+use crate::patch::Patch;
+
+pub const DEFAULT_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+// Identical targets are told apart from the others by their sizes and CRC32 checksums
+type ContentKey = (u64, u32);
+
+struct CachedRom {
+    patch: Arc<dyn Patch + Send + Sync>,
+    data: Arc<Vec<u8>>,
+    // Only with deduplication
+    content_key: Option<ContentKey>,
+}
+
+struct CacheEntry {
+    data: Option<CachedRom>,
+    handle_count: usize,
+    last_read: u64,
+}
+
+// Patched ROMs shared between all the handles of a target. They are dropped when the last
+// handle gets released, or with `keep_cached` kept around until the memory budget forces
+// them out in least-recently-read order. With `dedup` targets of identical contents share their
+// data, taking the space of a single target.
+pub struct RomCache {
+    cache_size: u64,
+    keep_cached: bool,
+    dedup: bool,
+    occupied_size: u64,
+    entries: HashMap<PathBuf, CacheEntry>,
+    // The shared data along with the number of entries sharing it
+    contents: HashMap<ContentKey, (Arc<Vec<u8>>, usize)>,
+    clock: u64,
+}
+
+impl RomCache {
+    pub fn new(cache_size: u64, keep_cached: bool, dedup: bool) -> Self {
+        Self {
+            cache_size,
+            keep_cached,
+            dedup,
+            occupied_size: 0,
+            entries: HashMap::new(),
+            contents: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn open(&mut self, path: &Path) {
+        self.entries
+            .entry(path.to_owned())
+            .or_insert(CacheEntry {
+                data: None,
+                handle_count: 0,
+                last_read: 0,
+            })
+            .handle_count += 1;
+    }
+
+    pub fn release(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.get_mut(path) {
+            entry.handle_count -= 1;
+            if entry.handle_count == 0 && (entry.data.is_none() || !self.keep_cached) {
+                if let Some(cached_rom) = self.entries.remove(path).unwrap().data {
+                    self.release_data(cached_rom);
+                    debug!(
+                        "Released {:?}, cache occupancy: {}/{} bytes",
+                        path, self.occupied_size, self.cache_size
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn entry_count(&self) -> usize {
+        self.entries.values().filter(|entry| entry.data.is_some()).count()
+    }
+
+    // Shared data is only counted once
+    pub fn occupied_size(&self) -> u64 {
+        self.occupied_size
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.contents.clear();
+        self.occupied_size = 0;
+    }
+
+    // Handles holding the data keep it until released, only the cache lets go of it
+    pub fn evict(&mut self, path: &Path) -> bool {
+        match self.entries.get_mut(path).and_then(|entry| entry.data.take()) {
+            Some(cached_rom) => {
+                self.release_data(cached_rom);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns the number of evicted entries, the handle counts are kept
+    pub fn evict_all(&mut self) -> usize {
+        let evicted_count = self.entries.values_mut().filter_map(|entry| entry.data.take()).count();
+        self.contents.clear();
+        self.occupied_size = 0;
+        evicted_count
+    }
+
+    // Data patched by an earlier incarnation of the target (before a refresh) is never served
+    pub fn get(&mut self, path: &Path, patch: &Arc<dyn Patch + Send + Sync>) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+
+        let entry = self.entries.get_mut(path)?;
+        match &entry.data {
+            Some(cached_rom) if same_patch(&cached_rom.patch, patch) => {
+                entry.last_read = self.clock;
+                Some(cached_rom.data.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // Returns the data to serve, shared with an identical target when deduplicating
+    pub fn insert(&mut self, path: &Path, patch: &Arc<dyn Patch + Send + Sync>, data: Arc<Vec<u8>>) -> Arc<Vec<u8>> {
+        self.clock += 1;
+
+        if let Some(old_cached_rom) = self.entries.get_mut(path).and_then(|entry| entry.data.take()) {
+            self.release_data(old_cached_rom);
+        }
+
+        let content_key = if self.dedup {
+            Some((data.len() as u64, crc32::checksum_ieee(&data)))
+        } else {
+            None
+        };
+        let shared_data = content_key
+            .and_then(|content_key| self.contents.get(&content_key))
+            .map(|(shared_data, _)| shared_data.clone())
+            .filter(|shared_data| **shared_data == *data);
+
+        let data = match shared_data {
+            Some(shared_data) => {
+                debug!("Sharing the cached data of {:?} with an identical target", path);
+                shared_data
+            }
+            None => {
+                let data_size = data.len() as u64;
+                while self.occupied_size + data_size > self.cache_size && self.evict_one() {}
+                self.occupied_size += data_size;
+                data
+            }
+        };
+        if let Some(content_key) = content_key {
+            let (shared_data, entry_count) = self.contents.entry(content_key).or_insert_with(|| (data.clone(), 0));
+            if Arc::ptr_eq(shared_data, &data) {
+                *entry_count += 1;
+            }
+        }
+
+        let entry = self.entries.entry(path.to_owned()).or_insert(CacheEntry {
+            data: None,
+            handle_count: 0,
+            last_read: 0,
+        });
+        entry.data = Some(CachedRom {
+            patch: patch.clone(),
+            data: data.clone(),
+            content_key,
+        });
+        entry.last_read = self.clock;
+
+        debug!(
+            "Cached {:?}, cache occupancy: {}/{} bytes in {} entries",
+            path,
+            self.occupied_size,
+            self.cache_size,
+            self.entry_count()
+        );
+        data
+    }
+
+    // Shared data only frees its space along with the last entry sharing it. Checksums of
+    // colliding contents only ever share the first of them.
+    fn release_data(&mut self, cached_rom: CachedRom) {
+        let content_key = match cached_rom.content_key {
+            Some(content_key) => content_key,
+            None => {
+                self.occupied_size -= cached_rom.data.len() as u64;
+                return;
+            }
+        };
+
+        let (shared_data, entry_count) = self.contents.get_mut(&content_key).unwrap();
+        let is_shared = Arc::ptr_eq(shared_data, &cached_rom.data);
+        if is_shared {
+            *entry_count -= 1;
+        }
+        if !is_shared || *entry_count == 0 {
+            self.occupied_size -= cached_rom.data.len() as u64;
+        }
+        if is_shared && *entry_count == 0 {
+            self.contents.remove(&content_key);
+        }
+    }
+
+    // Entries with open handles are never evicted, the budget may be exceeded by them
+    fn evict_one(&mut self) -> bool {
+        let victim = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.handle_count == 0 && e.data.is_some())
+            .min_by_key(|(_, e)| e.last_read)
+            .map(|(path, _)| path.clone());
+
+        if let Some(path) = victim {
+            let entry = self.entries.remove(&path).unwrap();
+            self.release_data(entry.data.unwrap());
+            debug!(
+                "Evicted {:?}, cache occupancy: {}/{} bytes",
+                path, self.occupied_size, self.cache_size
+            );
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn same_patch(a: &Arc<dyn Patch + Send + Sync>, b: &Arc<dyn Patch + Send + Sync>) -> bool {
+    Arc::as_ptr(a) as *const u8 == Arc::as_ptr(b) as *const u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::time::SystemTime;
+
+    struct TestPatch;
+
+    impl Patch for TestPatch {
+        fn patch_path(&self) -> &Path {
+            Path::new("Hack.bps")
+        }
+
+        fn source_path(&self) -> Option<&Path> {
+            None
+        }
+
+        fn patch_modified(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH
+        }
+
+        fn format(&self) -> &str {
+            "test"
+        }
+
+        fn target_size(&self) -> u64 {
+            0
+        }
+
+        fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn patch() -> Arc<dyn Patch + Send + Sync> {
+        Arc::new(TestPatch)
+    }
+
+    // Targets read once and released again, kept around by `keep_cached`
+    fn insert_released(rom_cache: &mut RomCache, path: &str, patch: &Arc<dyn Patch + Send + Sync>, size: usize) {
+        rom_cache.open(Path::new(path));
+        rom_cache.insert(Path::new(path), patch, Arc::new(vec![0; size]));
+        rom_cache.release(Path::new(path));
+    }
+
+    #[test]
+    fn test_evict_least_recently_read() {
+        let patch = patch();
+        let mut rom_cache = RomCache::new(300, true, false);
+        insert_released(&mut rom_cache, "a.sfc", &patch, 100);
+        insert_released(&mut rom_cache, "b.sfc", &patch, 100);
+        insert_released(&mut rom_cache, "c.sfc", &patch, 100);
+
+        // Reading "a.sfc" makes "b.sfc" the least recently read one
+        assert!(rom_cache.get(Path::new("a.sfc"), &patch).is_some());
+        insert_released(&mut rom_cache, "d.sfc", &patch, 100);
+
+        assert!(rom_cache.get(Path::new("a.sfc"), &patch).is_some());
+        assert!(rom_cache.get(Path::new("b.sfc"), &patch).is_none());
+        assert!(rom_cache.get(Path::new("c.sfc"), &patch).is_some());
+        assert!(rom_cache.get(Path::new("d.sfc"), &patch).is_some());
+    }
+
+    #[test]
+    fn test_cache_size() {
+        let patch = patch();
+        let mut rom_cache = RomCache::new(250, true, false);
+        insert_released(&mut rom_cache, "a.sfc", &patch, 100);
+        insert_released(&mut rom_cache, "b.sfc", &patch, 100);
+        assert_eq!(rom_cache.occupied_size(), 200);
+
+        insert_released(&mut rom_cache, "c.sfc", &patch, 100);
+        assert_eq!(rom_cache.occupied_size(), 200);
+        assert_eq!(rom_cache.entry_count(), 2);
+
+        // Taking the whole budget evicts everything else
+        insert_released(&mut rom_cache, "d.sfc", &patch, 250);
+        assert_eq!(rom_cache.occupied_size(), 250);
+        assert_eq!(rom_cache.entry_count(), 1);
+    }
+
+    // Open handles keep their entries, even past the budget
+    #[test]
+    fn test_open_entries_kept() {
+        let patch = patch();
+        let mut rom_cache = RomCache::new(150, true, false);
+        rom_cache.open(Path::new("a.sfc"));
+        rom_cache.insert(Path::new("a.sfc"), &patch, Arc::new(vec![0; 100]));
+        insert_released(&mut rom_cache, "b.sfc", &patch, 100);
+
+        assert_eq!(rom_cache.occupied_size(), 200);
+        assert!(rom_cache.get(Path::new("a.sfc"), &patch).is_some());
+    }
+
+    #[test]
+    fn test_shared_between_handles() {
+        let patch = patch();
+        let mut rom_cache = RomCache::new(DEFAULT_CACHE_SIZE, false, false);
+        rom_cache.open(Path::new("a.sfc"));
+        rom_cache.open(Path::new("a.sfc"));
+
+        let data = rom_cache.insert(Path::new("a.sfc"), &patch, Arc::new(vec![1, 2, 3]));
+        assert!(Arc::ptr_eq(&rom_cache.get(Path::new("a.sfc"), &patch).unwrap(), &data));
+
+        // Dropped along with the last handle, without keep_cached
+        rom_cache.release(Path::new("a.sfc"));
+        assert!(Arc::ptr_eq(&rom_cache.get(Path::new("a.sfc"), &patch).unwrap(), &data));
+        rom_cache.release(Path::new("a.sfc"));
+        assert!(rom_cache.get(Path::new("a.sfc"), &patch).is_none());
+        assert_eq!(rom_cache.occupied_size(), 0);
+    }
+
+    // Data patched before a refresh belongs to another patch
+    #[test]
+    fn test_other_patch() {
+        let mut rom_cache = RomCache::new(DEFAULT_CACHE_SIZE, true, false);
+        insert_released(&mut rom_cache, "a.sfc", &patch(), 100);
+        assert!(rom_cache.get(Path::new("a.sfc"), &patch()).is_none());
+    }
+}
@@ -1,21 +1,76 @@
 use std::cmp;
 use std::collections::HashMap;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-//use std::time::SystemTime;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Instant, SystemTime};
 
+use crc::crc32;
 use fuse_mt::{DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo};
-use fuse_mt::{ResultEmpty, ResultEntry, ResultOpen, ResultReaddir};
-use log::error;
+use fuse_mt::{
+    ResultCreate, ResultData, ResultEmpty, ResultEntry, ResultOpen, ResultReaddir, ResultStatfs, ResultWrite,
+    ResultXattr, Statfs, Xattr,
+};
+use log::{debug, error, info, trace, warn};
 use time::Timespec;
 
-use crate::patch::Patch;
+use crate::checksums::ChecksumFormat;
+use crate::disk_cache::DiskCache;
+use crate::logging::Event;
+use crate::patch::{self, PartialRom, Patch};
+use crate::rom_cache::RomCache;
 use crate::rom_manager::RomManager;
+use crate::rom_watcher;
+use crate::stats::{Gauges, Stats, StatsFormat};
+use crate::utils::{self, MappedFile};
 
 const EPOCH: Timespec = Timespec { sec: 0, nsec: 0 };
-const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+// Attributes only change when the directories are refreshed, generated files change any time
+pub const DEFAULT_ATTR_TTL: u64 = 60;
+const GENERATED_TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+const BLOCK_SIZE: u64 = 4096;
+// Block counts of the attributes are in 512-byte units, whatever the block size of the filesystem
+const STAT_BLOCK_SIZE: u64 = 512;
+
+// Read-ahead patches in chunks, so reads only wait for the chunk being patched
+const READ_AHEAD_CHUNK_SIZE: u64 = 64 * 1024;
+
+// Generated on open, always reachable but only listed on request
+const STATS_FILE_NAME: &str = ".stats";
+const FOPEN_DIRECT_IO: u32 = 1 << 0;
+
+// Regenerated on every refresh, always listed
+const MANIFEST_FILE_NAME: &str = ".manifest.json";
+
+// Commands written here by the mounting user are run right away, reading it gives the result of
+// the last command
+const CONTROL_FILE_NAME: &str = ".control";
+
+// Patches producing no target ROMs are described here, one file for every patch
+const UNMATCHED_DIRECTORY: &str = ".unmatched";
+
+// Patch metadata is exposed next to the target ROMs on request, named after them
+const METADATA_XML_EXTENSION: &str = ".meta.xml";
+const METADATA_TXT_EXTENSION: &str = ".meta.txt";
+
+// Summaries of the patching of the target ROMs are listed next to them on request
+const PATCHINFO_EXTENSION: &str = ".patchinfo";
+
+const XATTR_BPS_METADATA: &str = "user.bps.metadata";
+const XATTR_ROM_CRC32: &str = "user.rom.crc32";
+const XATTR_ROM_SOURCE: &str = "user.rom.source";
+const XATTR_SOURCE_PATH: &str = "user.softpatch.source_path";
+const XATTR_PATCH_PATH: &str = "user.softpatch.patch_path";
+const XATTR_PATCH_FORMAT: &str = "user.softpatch.patch_format";
+const XATTR_PATCH_SIZE: &str = "user.softpatch.patch_size";
+const XATTR_SOURCE_CRC32: &str = "user.softpatch.source_crc32";
+const XATTR_TARGET_CRC32: &str = "user.softpatch.target_crc32";
 
-/*
 fn timespec_from(st: &SystemTime) -> Timespec {
     if let Ok(dur_since_epoch) = st.duration_since(std::time::UNIX_EPOCH) {
         Timespec::new(dur_since_epoch.as_secs() as i64, dur_since_epoch.subsec_nanos() as i32)
@@ -23,7 +78,6 @@ fn timespec_from(st: &SystemTime) -> Timespec {
         Timespec::new(0, 0)
     }
 }
-*/
 
 enum Handle {
     Directory {
@@ -31,35 +85,305 @@ enum Handle {
     },
     File {
         attr: FileAttr,
+        path: PathBuf,
         patch: Arc<dyn Patch + Send + Sync>,
-        data: Option<Vec<u8>>,
+        data: Option<Arc<Vec<u8>>>,
+        // Target patched up to the high-water mark of the handle, until it gets complete
+        partial_rom: Arc<Mutex<Option<Box<dyn PartialRom>>>>,
+        // At most one read-ahead is running for a handle
+        reading_ahead: Arc<AtomicBool>,
     },
+    // Files with contents generated when opened
+    Virtual {
+        attr: FileAttr,
+        data: Vec<u8>,
+    },
+    // Files of the underlying filesystem, read as they are
+    Passthrough {
+        attr: FileAttr,
+        file: File,
+    },
+    // The control file, open for writing commands or reading their results. The attributes change
+    // with every command.
+    Control,
+    // Checksum files of the targets listed when opened, generated on the first read as that may
+    // involve patching every target
+    Checksums {
+        attr: FileAttr,
+        format: ChecksumFormat,
+        targets: Arc<Vec<(PathBuf, Arc<dyn Patch + Send + Sync>)>>,
+        data: Arc<Mutex<Option<Vec<u8>>>>,
+    },
+}
+
+enum RomData<'a> {
+    Partial(&'a [u8]),
+    Complete(Arc<Vec<u8>>),
+    // Exactly the range being read, copied unchanged from the source by the patch
+    Source(&'a [u8]),
+}
+
+// The target between `offset` and `end` when it is not patched yet, but copied unchanged from the
+// source by the patch
+fn unpatched_source_data(partial_rom: &mut dyn PartialRom, offset: u64, end: u64) -> Option<&[u8]> {
+    if partial_rom.patched_data().len() as u64 >= end {
+        return None;
+    }
+
+    partial_rom.source_data(offset, end)
 }
 
 pub struct RomFilesystem {
-    rom_manager: Arc<Mutex<RomManager>>,
+    rom_manager: Arc<RwLock<RomManager>>,
     handles: Mutex<HashMap<u64, Handle>>,
     next_handle: Mutex<u64>,
+    store: RomStore,
+    stats: Arc<Stats>,
+    show_stats: bool,
+    stats_format: StatsFormat,
+    expose_metadata: bool,
+    expose_patchinfo: bool,
+    checksum_formats: Vec<ChecksumFormat>,
+    // Checksums computed from patched targets, along with the modification time of their patches
+    checksums: Mutex<HashMap<(PathBuf, ChecksumFormat), (SystemTime, String)>>,
+    read_ahead: u64,
+    attr_ttl: Timespec,
+    cache_size: u64,
+    // Every target ROM is patched after mounting on this many threads, when given
+    pregenerate_threads: Option<usize>,
+    control_result: Mutex<Vec<u8>>,
+}
+
+// Patched target ROMs shared between the filesystem and the threads patching them in the
+// background
+#[derive(Clone)]
+struct RomStore {
+    rom_cache: Arc<Mutex<RomCache>>,
+    disk_cache: Option<Arc<DiskCache>>,
+    verify: bool,
+    // Targets failing verification, along with the modification time of their patches. They are
+    // not patched again until their patches change.
+    corrupt_roms: Arc<Mutex<HashMap<PathBuf, SystemTime>>>,
+    stats: Arc<Stats>,
+}
+
+impl RomStore {
+    // Complete targets patched earlier, either by another handle or in an earlier mount
+    fn cached_rom_data(&self, target_path: &Path, patch: &Arc<dyn Patch + Send + Sync>) -> Option<Arc<Vec<u8>>> {
+        let mut rom_cache = self.rom_cache.lock().unwrap();
+
+        if let Some(data) = rom_cache.get(target_path, patch) {
+            return Some(data);
+        }
+
+        let data = Arc::new(self.disk_cache.as_ref()?.load(patch.as_ref())?);
+        Some(rom_cache.insert(target_path, patch, data))
+    }
+
+    fn store_rom_data(&self, target_path: &Path, patch: &Arc<dyn Patch + Send + Sync>, data: Vec<u8>) -> Arc<Vec<u8>> {
+        if let Some(disk_cache) = &self.disk_cache {
+            if let Err(err) = disk_cache.store(patch.as_ref(), &data) {
+                warn!("Failed to store {:?} in the disk cache: {}", target_path, err);
+            }
+        }
+
+        self.rom_cache
+            .lock()
+            .unwrap()
+            .insert(target_path, patch, Arc::new(data))
+    }
+
+    // Freshly patched targets are checked against the checksum stored in the patch, corrupt
+    // targets are never served
+    fn verify_rom_data(
+        &self,
+        target_path: &Path,
+        patch: &Arc<dyn Patch + Send + Sync>,
+        data: &[u8],
+    ) -> Result<(), libc::c_int> {
+        if !self.verify {
+            return Ok(());
+        }
+
+        patch::verify_target_checksum(patch.as_ref(), data).map_err(|err| {
+            Event::new("verify")
+                .path(target_path)
+                .error(&err)
+                .log(|| error!("Failed to patch {:?}: {}", target_path, err));
+            self.corrupt_roms
+                .lock()
+                .unwrap()
+                .insert(target_path.to_owned(), patch.patch_modified());
+            libc::EIO
+        })
+    }
+
+    fn is_corrupt_rom(&self, target_path: &Path, patch: &Arc<dyn Patch + Send + Sync>) -> bool {
+        self.corrupt_roms.lock().unwrap().get(target_path) == Some(&patch.patch_modified())
+    }
+
+    // Deferred ROM patching on first read, shared by every handle of the target.
+    // Evicted entries are patched again transparently.
+    fn patched_rom_data(
+        &self,
+        target_path: &Path,
+        patch: &Arc<dyn Patch + Send + Sync>,
+    ) -> Result<Arc<Vec<u8>>, libc::c_int> {
+        if let Some(data) = self.cached_rom_data(target_path, patch) {
+            return Ok(data);
+        }
+
+        if self.is_corrupt_rom(target_path, patch) {
+            debug!("Not patching {:?}, it failed verification before", target_path);
+            return Err(libc::EIO);
+        }
+
+        let patching_start = Instant::now();
+        let result = patch.patched_rom();
+        let patching_time = patching_start.elapsed();
+        self.stats.add_patching_time(patching_time);
+
+        match result {
+            Ok(data) => {
+                Event::new("patch")
+                    .path(target_path)
+                    .duration(patching_time)
+                    .log(|| info!("Patched {:?} in {:.3}s", target_path, patching_time.as_secs_f64()));
+                self.stats.add_patch_applied();
+                self.verify_rom_data(target_path, patch, &data)?;
+                Ok(self.store_rom_data(target_path, patch, data))
+            }
+            Err(err) => {
+                Event::new("patch")
+                    .path(target_path)
+                    .duration(patching_time)
+                    .error(&err)
+                    .log(|| error!("Failed to patch {:?}: {}", target_path, err));
+                Err(libc::EIO)
+            }
+        }
+    }
 }
 
 impl RomFilesystem {
-    pub fn new(rom_manager: Arc<Mutex<RomManager>>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        rom_manager: Arc<RwLock<RomManager>>,
+        cache_size: u64,
+        keep_cached: bool,
+        dedup: bool,
+        disk_cache: Option<DiskCache>,
+        verify: bool,
+        show_stats: bool,
+        stats_format: StatsFormat,
+        expose_metadata: bool,
+        expose_patchinfo: bool,
+        checksum_formats: Vec<ChecksumFormat>,
+        read_ahead: u64,
+        attr_ttl: u64,
+        pregenerate_threads: Option<usize>,
+    ) -> Self {
+        let stats = rom_manager.read().unwrap().stats.clone();
+
         Self {
             rom_manager,
             handles: Mutex::new(HashMap::new()),
             next_handle: Mutex::new(1),
+            store: RomStore {
+                rom_cache: Arc::new(Mutex::new(RomCache::new(cache_size, keep_cached, dedup))),
+                disk_cache: disk_cache.map(Arc::new),
+                verify,
+                corrupt_roms: Arc::new(Mutex::new(HashMap::new())),
+                stats: stats.clone(),
+            },
+            stats,
+            show_stats,
+            stats_format,
+            expose_metadata,
+            expose_patchinfo,
+            checksum_formats,
+            checksums: Mutex::new(HashMap::new()),
+            read_ahead,
+            attr_ttl: Timespec::new(attr_ttl as i64, 0),
+            cache_size,
+            pregenerate_threads,
+            control_result: Mutex::new(Vec::new()),
         }
     }
 
-    fn get_root_attr(&self) -> FileAttr {
+    fn render_stats(&self, rom_manager: &RomManager, handles: &HashMap<u64, Handle>, rom_cache: &RomCache) -> String {
+        self.stats.render(
+            self.stats_format,
+            &Gauges {
+                target_count: rom_manager.target_roms.len(),
+                open_handles: handles.len(),
+                cache_entries: rom_cache.entry_count(),
+                cache_bytes: rom_cache.occupied_size(),
+                format_counts: &rom_manager.format_counts,
+            },
+        )
+    }
+
+    // Directories take the modification time of the newest ROM inside them, the root directory
+    // changes along with refreshes changing the ROMs too
+    fn get_directory_attr(&self, rom_manager: &RomManager, directory: &Path) -> FileAttr {
+        let targets_changed = rom_manager.targets_changed.filter(|_| directory == Path::new(""));
+        let directory_modified = rom_manager
+            .target_roms
+            .iter()
+            .filter(|(rom_path, _)| rom_path.starts_with(directory))
+            .map(|(_, patch)| patch.patch_modified())
+            .chain(targets_changed)
+            .max()
+            .map_or(EPOCH, |modified| timespec_from(&modified));
+
         FileAttr {
             size: 0,
-            blocks: 0,
+            blocks: BLOCK_SIZE / STAT_BLOCK_SIZE,
+            atime: directory_modified,
+            mtime: directory_modified,
+            ctime: directory_modified,
+            crtime: directory_modified,
+            kind: FileType::Directory,
+            perm: 0o444,
+            nlink: 1,
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    // Patches have no embedded timestamps, the patch file's modification time is used instead
+    fn get_file_attr(&self, patch: &Arc<dyn Patch + Send + Sync>) -> FileAttr {
+        let patch_modified = timespec_from(&patch.patch_modified());
+
+        FileAttr {
+            size: patch.target_size(),
+            blocks: patch.target_size().div_ceil(STAT_BLOCK_SIZE),
+            atime: patch_modified,
+            mtime: patch_modified,
+            ctime: patch_modified,
+            crtime: patch_modified,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn get_virtual_attr(&self, size: u64) -> FileAttr {
+        FileAttr {
+            size,
+            blocks: size.div_ceil(STAT_BLOCK_SIZE),
             atime: EPOCH,
             mtime: EPOCH,
             ctime: EPOCH,
             crtime: EPOCH,
-            kind: FileType::Directory,
+            kind: FileType::RegularFile,
             perm: 0o444,
             nlink: 1,
             uid: unsafe { libc::geteuid() },
@@ -69,14 +393,144 @@ impl RomFilesystem {
         }
     }
 
-    fn get_file_attr(&self, patch: &Arc<dyn Patch + Send + Sync>) -> FileAttr {
+    // Only the mounting user may write commands
+    fn get_control_attr(&self) -> FileAttr {
         FileAttr {
-            size: patch.target_size(),
-            blocks: 0,
-            atime: EPOCH,  //timespec_from(&patch.access_time),
-            mtime: EPOCH,  //timespec_from(&patch.modify_time),
-            ctime: EPOCH,  //timespec_from(&patch.modify_time),
-            crtime: EPOCH, //timespec_from(&patch.create_time),
+            perm: 0o644,
+            ..self.get_virtual_attr(self.control_result.lock().unwrap().len() as u64)
+        }
+    }
+
+    // Every line is a command, the results are kept for reading them back
+    fn run_control_commands(&self, commands: &str) {
+        let result: String = commands
+            .lines()
+            .map(str::trim)
+            .filter(|command| !command.is_empty())
+            .map(|command| match self.run_control_command(command) {
+                Ok(result) => format!("ok: {}\n", result),
+                Err(err) => format!("error: {}\n", err),
+            })
+            .collect();
+
+        *self.control_result.lock().unwrap() = result.into_bytes();
+    }
+
+    fn run_control_command(&self, command: &str) -> Result<String, String> {
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, Some(argument.trim())),
+            None => (command, None),
+        };
+
+        // Targets are given relative to the root directory, with or without the leading slash
+        let target = |argument: Option<&str>| -> Result<(PathBuf, Arc<dyn Patch + Send + Sync>), String> {
+            let target_path = Path::new(argument.ok_or_else(|| format!("no target ROM is given to {}", name))?);
+            let target_path = target_path.strip_prefix("/").unwrap_or(target_path);
+            let rom_manager = self.rom_manager.read().unwrap();
+            match rom_manager.target_roms.get(target_path) {
+                Some(patch) => Ok((target_path.to_owned(), patch.clone())),
+                None => Err(format!("no such target ROM {:?}", target_path)),
+            }
+        };
+
+        match (name, argument) {
+            ("rescan", None) => {
+                rom_watcher::refresh_roms(&self.rom_manager);
+                Ok(format!(
+                    "found {} target ROMs",
+                    self.rom_manager.read().unwrap().target_roms.len()
+                ))
+            }
+            ("evict", _) => {
+                let (target_path, patch) = target(argument)?;
+                let mut evicted = self.store.rom_cache.lock().unwrap().evict(&target_path);
+                if let Some(disk_cache) = &self.store.disk_cache {
+                    evicted |= disk_cache.remove(patch.as_ref()).map_err(|err| err.to_string())?;
+                }
+                self.store.corrupt_roms.lock().unwrap().remove(&target_path);
+
+                if evicted {
+                    Ok(format!("evicted {:?}", target_path))
+                } else {
+                    Ok(format!("{:?} was not cached", target_path))
+                }
+            }
+            ("evict-all", None) => {
+                let mut evicted_count = self.store.rom_cache.lock().unwrap().evict_all();
+                if let Some(disk_cache) = &self.store.disk_cache {
+                    let patches: Vec<_> = self.rom_manager.read().unwrap().target_roms.values().cloned().collect();
+                    for patch in patches {
+                        if let Ok(true) = disk_cache.remove(patch.as_ref()) {
+                            evicted_count += 1;
+                        }
+                    }
+                }
+                self.store.corrupt_roms.lock().unwrap().clear();
+                Ok(format!("evicted {} cache entries", evicted_count))
+            }
+            // Patched again from scratch, whether verification is enabled or not
+            ("verify", _) => {
+                let (target_path, patch) = target(argument)?;
+                let target = patch
+                    .patched_rom()
+                    .map_err(|err| format!("failed to patch {:?}: {}", target_path, err))?;
+                patch::verify_target_checksum(patch.as_ref(), &target)
+                    .map_err(|err| format!("{:?} failed verification: {}", target_path, err))?;
+                Ok(format!(
+                    "{:?} verified (CRC32={:08x})",
+                    target_path,
+                    crc32::checksum_ieee(&target)
+                ))
+            }
+            ("rescan" | "evict-all", Some(_)) => Err(format!("{} takes no arguments", name)),
+            _ => Err(format!("unknown command {:?}", command)),
+        }
+    }
+
+    // Metadata files share the timestamps of their target ROMs
+    fn get_metadata_attr(&self, patch: &Arc<dyn Patch + Send + Sync>, metadata: &[u8]) -> FileAttr {
+        FileAttr {
+            size: metadata.len() as u64,
+            blocks: (metadata.len() as u64).div_ceil(STAT_BLOCK_SIZE),
+            ..self.get_file_attr(patch)
+        }
+    }
+
+    // The target ROM of a metadata file, target ROMs take precedence over metadata files of the
+    // same name
+    fn metadata_rom<'a>(
+        &self,
+        rom_manager: &'a RomManager,
+        path: &Path,
+    ) -> Option<(&'a Arc<dyn Patch + Send + Sync>, &'a [u8])> {
+        if !self.expose_metadata || rom_manager.target_roms.contains_key(path) {
+            return None;
+        }
+
+        let name = path.file_name()?;
+        let target_name = utils::strip_name_suffix(name, METADATA_XML_EXTENSION)
+            .or_else(|| utils::strip_name_suffix(name, METADATA_TXT_EXTENSION))?;
+        let target_path = path.with_file_name(target_name);
+
+        let rom = rom_manager.target_roms.get(&target_path)?;
+        let metadata = rom.metadata()?;
+        if metadata_name(&target_path, metadata) == path.file_name()? {
+            Some((rom, metadata))
+        } else {
+            None
+        }
+    }
+
+    fn get_passthrough_attr(&self, metadata: &fs::Metadata) -> FileAttr {
+        let modified = metadata.modified().map_or(EPOCH, |modified| timespec_from(&modified));
+
+        FileAttr {
+            size: metadata.len(),
+            blocks: metadata.len().div_ceil(STAT_BLOCK_SIZE),
+            atime: modified,
+            mtime: modified,
+            ctime: modified,
+            crtime: modified,
             kind: FileType::RegularFile,
             perm: 0o444,
             nlink: 1,
@@ -86,26 +540,382 @@ impl RomFilesystem {
             flags: 0,
         }
     }
+
+    // Symlinks share the timestamps of the target ROM they point at
+    fn get_link_attr(&self, link_target: &Path, patch: Option<&Arc<dyn Patch + Send + Sync>>) -> FileAttr {
+        let patch_modified = patch.map_or(EPOCH, |patch| timespec_from(&patch.patch_modified()));
+
+        FileAttr {
+            size: link_target.as_os_str().len() as u64,
+            blocks: (link_target.as_os_str().len() as u64).div_ceil(STAT_BLOCK_SIZE),
+            atime: patch_modified,
+            mtime: patch_modified,
+            ctime: patch_modified,
+            crtime: patch_modified,
+            kind: FileType::Symlink,
+            perm: 0o444,
+            nlink: 1,
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn get_file_xattr_names(&self, patch: &Arc<dyn Patch + Send + Sync>) -> Vec<&'static str> {
+        let mut names = Vec::new();
+
+        if patch.metadata().is_some() {
+            names.push(XATTR_BPS_METADATA);
+        }
+
+        names.push(XATTR_ROM_CRC32);
+
+        if patch.source_path().is_some() {
+            names.extend(&[XATTR_ROM_SOURCE, XATTR_SOURCE_PATH, XATTR_SOURCE_CRC32]);
+        }
+        names.extend(&[
+            XATTR_PATCH_PATH,
+            XATTR_PATCH_FORMAT,
+            XATTR_PATCH_SIZE,
+            XATTR_TARGET_CRC32,
+        ]);
+        names
+    }
+
+    // Values are only produced on request, formats without a stored target checksum need
+    // the patched ROM for computing it
+    fn get_file_xattr(
+        &self,
+        target_path: &Path,
+        patch: &Arc<dyn Patch + Send + Sync>,
+        name: &OsStr,
+    ) -> Result<Vec<u8>, libc::c_int> {
+        match name.to_str() {
+            Some(XATTR_BPS_METADATA) => patch.metadata().map(<[u8]>::to_vec).ok_or(libc::ENODATA),
+            // The source ROM the patch was matched with, formats embedding everything have none
+            Some(XATTR_ROM_SOURCE | XATTR_SOURCE_PATH) => patch
+                .source_path()
+                .map(|source_path| source_path.as_os_str().as_bytes().to_vec())
+                .ok_or(libc::ENODATA),
+            Some(XATTR_PATCH_PATH) => Ok(patch.patch_path().as_os_str().as_bytes().to_vec()),
+            Some(XATTR_PATCH_FORMAT) => Ok(patch.format().as_bytes().to_vec()),
+            Some(XATTR_PATCH_SIZE) => fs::metadata(patch.patch_path())
+                .map(|metadata| metadata.len().to_string().into_bytes())
+                .map_err(|err| {
+                    error!("Failed to read the metadata of {:?}: {}", patch.patch_path(), err);
+                    libc::EIO
+                }),
+            Some(XATTR_SOURCE_CRC32) => {
+                let source_path = patch.source_path().ok_or(libc::ENODATA)?;
+                let source = MappedFile::open(source_path).map_err(|err| {
+                    error!("Failed to open {:?}: {}", source_path, err);
+                    libc::EIO
+                })?;
+                Ok(format!("{:08x}", crc32::checksum_ieee(&source)).into_bytes())
+            }
+            Some(XATTR_ROM_CRC32 | XATTR_TARGET_CRC32) => {
+                let target_checksum = match patch.target_checksum() {
+                    Some(target_checksum) => target_checksum,
+                    None => crc32::checksum_ieee(&self.store.patched_rom_data(target_path, patch)?),
+                };
+                Ok(format!("{:08x}", target_checksum).into_bytes())
+            }
+            _ => Err(libc::ENODATA),
+        }
+    }
+
+    fn patchinfo_rom<'a>(&self, rom_manager: &'a RomManager, path: &Path) -> Option<&'a Arc<dyn Patch + Send + Sync>> {
+        if !self.expose_patchinfo || rom_manager.target_roms.contains_key(path) {
+            return None;
+        }
+
+        let target_name = utils::strip_name_suffix(path.file_name()?, PATCHINFO_EXTENSION)?;
+        rom_manager.target_roms.get(&path.with_file_name(target_name))
+    }
+
+    // Only what is known without patching, checksums not stored in the patches are left out
+    fn render_patchinfo(&self, rom_manager: &RomManager, patch: &Arc<dyn Patch + Send + Sync>) -> Vec<u8> {
+        let mut patchinfo = format!("Patch: {}\n", patch.patch_path().display());
+        patchinfo.push_str(&format!("Patch format: {}\n", patch.format()));
+
+        if let Some(source_path) = patch.source_path() {
+            patchinfo.push_str(&format!("Source: {}\n", source_path.display()));
+            if let Ok(metadata) = fs::metadata(source_path) {
+                patchinfo.push_str(&format!("Source size: {}\n", metadata.len()));
+            }
+            if let Some(source_checksum) = rom_manager.source_checksum(source_path) {
+                patchinfo.push_str(&format!("Source CRC32: {:08x}\n", source_checksum));
+            }
+        }
+
+        patchinfo.push_str(&format!("Target size: {}\n", patch.target_size()));
+        if let Some(target_checksum) = patch.target_checksum() {
+            patchinfo.push_str(&format!("Target CRC32: {:08x}\n", target_checksum));
+        }
+
+        if let Some(metadata) = patch.metadata() {
+            patchinfo.push_str("Metadata:\n");
+            patchinfo.push_str(String::from_utf8_lossy(metadata).trim_end());
+            patchinfo.push('\n');
+        }
+
+        patchinfo.into_bytes()
+    }
+
+    fn unmatched_report<'a>(&self, rom_manager: &'a RomManager, path: &Path) -> Option<&'a [u8]> {
+        if path.parent() != Some(Path::new(UNMATCHED_DIRECTORY)) {
+            return None;
+        }
+
+        rom_manager.unmatched_reports.get(path.file_name()?).map(Vec::as_slice)
+    }
+
+    fn checksum_format(&self, path: &Path) -> Option<ChecksumFormat> {
+        self.checksum_formats
+            .iter()
+            .copied()
+            .find(|format| path == Path::new(format.file_name()))
+    }
+
+    // Targets failing to patch are left out, the failures are logged
+    fn render_checksums(&self, format: ChecksumFormat, targets: &[(PathBuf, Arc<dyn Patch + Send + Sync>)]) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for (target_path, patch) in targets {
+            let checksum = match format.stored_checksum(patch.as_ref()) {
+                Some(checksum) => checksum,
+                None => {
+                    let key = (target_path.clone(), format);
+                    let cached_checksum = match self.checksums.lock().unwrap().get(&key) {
+                        Some((patch_modified, checksum)) if *patch_modified == patch.patch_modified() => {
+                            Some(checksum.clone())
+                        }
+                        _ => None,
+                    };
+
+                    match cached_checksum {
+                        Some(checksum) => checksum,
+                        None => match self.store.patched_rom_data(target_path, patch) {
+                            Ok(target) => {
+                                let checksum = format.checksum(&target);
+                                self.checksums
+                                    .lock()
+                                    .unwrap()
+                                    .insert(key, (patch.patch_modified(), checksum.clone()));
+                                checksum
+                            }
+                            Err(_) => continue,
+                        },
+                    }
+                }
+            };
+
+            data.extend_from_slice(&format.line(target_path, &checksum));
+        }
+
+        data
+    }
+
+    // Patches the target of the handle until `end`, only as much as needed for formats
+    // supporting it. Complete targets are also stored in the handle. Ranges not patched yet are
+    // read from the source instead when the patch copies them unchanged. Verified targets are
+    // patched completely on the first read instead, nothing is served before verifying them.
+    fn partial_rom_data<'a>(
+        &self,
+        fh: u64,
+        target_path: &Path,
+        patch: &Arc<dyn Patch + Send + Sync>,
+        partial_rom: &'a mut Option<Box<dyn PartialRom>>,
+        offset: u64,
+        mut end: u64,
+    ) -> Result<RomData<'a>, libc::c_int> {
+        if partial_rom.is_none() {
+            if self.store.is_corrupt_rom(target_path, patch) {
+                debug!("Not patching {:?}, it failed verification before", target_path);
+                return Err(libc::EIO);
+            }
+
+            match patch.partial_patched_rom() {
+                Ok(Some(new_partial_rom)) => *partial_rom = Some(new_partial_rom),
+                Ok(None) => {
+                    let data = self.store.patched_rom_data(target_path, patch)?;
+                    self.set_handle_data(fh, data.clone());
+                    return Ok(RomData::Complete(data));
+                }
+                Err(err) => {
+                    Event::new("patch")
+                        .path(target_path)
+                        .error(&err)
+                        .log(|| error!("Failed to patch {:?}: {}", target_path, err));
+                    return Err(libc::EIO);
+                }
+            }
+        }
+
+        if self.store.verify {
+            end = patch.target_size();
+        } else if unpatched_source_data(partial_rom.as_mut().unwrap().as_mut(), offset, end).is_some() {
+            return Ok(RomData::Source(
+                unpatched_source_data(partial_rom.as_mut().unwrap().as_mut(), offset, end).unwrap(),
+            ));
+        }
+
+        let patching_start = Instant::now();
+        let result = partial_rom.as_mut().unwrap().patch_until(end);
+        self.stats.add_patching_time(patching_start.elapsed());
+
+        if let Err(err) = result {
+            Event::new("patch")
+                .path(target_path)
+                .error(&err)
+                .log(|| error!("Failed to patch {:?}: {}", target_path, err));
+            *partial_rom = None;
+            return Err(libc::EIO);
+        }
+
+        if partial_rom.as_ref().unwrap().is_complete() {
+            Event::new("patch")
+                .path(target_path)
+                .log(|| info!("Patched {:?}", target_path));
+            self.stats.add_patch_applied();
+            let data = partial_rom.take().unwrap().into_patched_rom();
+            self.store.verify_rom_data(target_path, patch, &data)?;
+            let data = self.store.store_rom_data(target_path, patch, data);
+            self.set_handle_data(fh, data.clone());
+            Ok(RomData::Complete(data))
+        } else {
+            Ok(RomData::Partial(partial_rom.as_ref().unwrap().patched_data()))
+        }
+    }
+
+    // Keeps patching the target of a handle in the background until `end`, while the earlier
+    // parts are being read. Completing the target is left to the reads.
+    fn start_read_ahead(
+        &self,
+        partial_rom: Arc<Mutex<Option<Box<dyn PartialRom>>>>,
+        reading_ahead: Arc<AtomicBool>,
+        end: u64,
+    ) {
+        if reading_ahead.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let stats = self.stats.clone();
+        thread::spawn(move || {
+            loop {
+                let mut partial_rom = partial_rom.lock().unwrap();
+                let partial_rom = match partial_rom.as_mut() {
+                    Some(partial_rom) if !partial_rom.is_complete() => partial_rom,
+                    _ => break,
+                };
+
+                let patched_size = partial_rom.patched_data().len() as u64;
+                if patched_size >= end {
+                    break;
+                }
+
+                // Errors are reported by the reads running into them
+                let patching_start = Instant::now();
+                let result = partial_rom.patch_until(cmp::min(patched_size + READ_AHEAD_CHUNK_SIZE, end));
+                stats.add_patching_time(patching_start.elapsed());
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            reading_ahead.store(false, Ordering::Release);
+        });
+    }
+
+    // Patches every target ROM fitting in the memory cache in the background, so the first
+    // reads are cache hits too. Failures are logged, the targets are patched again on reading.
+    fn start_pregenerating(&self, thread_count: usize) {
+        let rom_manager = self.rom_manager.clone();
+        let store = self.store.clone();
+        let cache_size = self.cache_size;
+
+        thread::spawn(move || {
+            let mut targets: Vec<(PathBuf, Arc<dyn Patch + Send + Sync>)> = rom_manager
+                .read()
+                .unwrap()
+                .target_roms
+                .iter()
+                .map(|(target_path, patch)| (target_path.clone(), patch.clone()))
+                .collect();
+            targets.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            let mut remaining_size = cache_size;
+            let target_count = targets.len();
+            targets.retain(|(_, patch)| match remaining_size.checked_sub(patch.target_size()) {
+                Some(size) => {
+                    remaining_size = size;
+                    true
+                }
+                None => false,
+            });
+            if targets.len() < target_count {
+                warn!(
+                    "Skipping the pregeneration of {} target ROMs not fitting in the cache",
+                    target_count - targets.len()
+                );
+            }
+
+            info!("Pregenerating {} target ROMs", targets.len());
+            let pregenerated_count = AtomicUsize::new(0);
+            let results = utils::parallel_map(&targets, thread_count, |(target_path, patch)| {
+                let result = store.patched_rom_data(target_path, patch);
+                let count = pregenerated_count.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("Pregenerated {:?} ({}/{})", target_path, count, targets.len());
+                result.is_ok()
+            });
+
+            info!(
+                "Pregenerated {} target ROMs, {} failed",
+                results.iter().filter(|&&success| success).count(),
+                results.iter().filter(|&&success| !success).count()
+            );
+        });
+    }
+
+    fn set_handle_data(&self, fh: u64, data: Arc<Vec<u8>>) {
+        if let Some(Handle::File { data: handle_data, .. }) = self.handles.lock().unwrap().get_mut(&fh) {
+            *handle_data = Some(data);
+        }
+    }
 }
 
 impl FilesystemMT for RomFilesystem {
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        trace!(target: "fuse::init", "Initializing");
+        if let Some(thread_count) = self.pregenerate_threads {
+            self.start_pregenerating(thread_count);
+        }
         Ok(())
     }
 
+    // Unmounting does not release the individual handles
+    fn destroy(&self, _req: RequestInfo) {
+        trace!(target: "fuse::destroy", "Destroying");
+        self.handles.lock().unwrap().clear();
+        self.store.rom_cache.lock().unwrap().clear();
+    }
+
     fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+        trace!(target: "fuse::opendir", "{:?}", path);
         let path = path.strip_prefix("/").unwrap();
+        let rom_manager = self.rom_manager.read().unwrap();
         let mut handles = self.handles.lock().unwrap();
         let mut next_handle = self.next_handle.lock().unwrap();
 
-        if path == Path::new("") {
+        if rom_manager.target_directories.contains(path) || path == Path::new(UNMATCHED_DIRECTORY) {
             let handle = *next_handle;
             *next_handle += 1;
 
             handles.insert(
                 handle,
                 Handle::Directory {
-                    attr: self.get_root_attr(),
+                    attr: self.get_directory_attr(&rom_manager, path),
                 },
             );
             Ok((handle, 0))
@@ -114,8 +924,10 @@ impl FilesystemMT for RomFilesystem {
         }
     }
 
-    fn readdir(&self, _req: RequestInfo, _path: &Path, fh: u64) -> ResultReaddir {
-        let rom_manager = self.rom_manager.lock().unwrap();
+    fn readdir(&self, _req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
+        trace!(target: "fuse::readdir", "{:?} (fh={})", path, fh);
+        let path = path.strip_prefix("/").unwrap();
+        let rom_manager = self.rom_manager.read().unwrap();
         let handles = self.handles.lock().unwrap();
 
         if let Some(Handle::Directory { .. }) = handles.get(&fh) {
@@ -131,62 +943,299 @@ impl FilesystemMT for RomFilesystem {
                 kind: FileType::Directory,
             });
 
-            for path in rom_manager.target_roms.keys() {
+            // Only the immediate children of the directory are listed
+            for directory in rom_manager
+                .target_directories
+                .iter()
+                .filter(|d| d.parent() == Some(path))
+            {
+                files.push(DirectoryEntry {
+                    name: directory.file_name().unwrap().into(),
+                    kind: FileType::Directory,
+                });
+            }
+
+            for target_path in rom_manager.target_roms.keys().filter(|t| t.parent() == Some(path)) {
                 files.push(DirectoryEntry {
-                    name: path.into(),
+                    name: target_path.file_name().unwrap().into(),
                     kind: FileType::RegularFile,
                 });
             }
 
-            Ok(files)
-        } else {
-            Err(libc::ENOENT)
-        }
-    }
+            if self.expose_metadata {
+                for (target_path, rom) in rom_manager.target_roms.iter().filter(|(t, _)| t.parent() == Some(path)) {
+                    if let Some(metadata) = rom.metadata() {
+                        let name = metadata_name(target_path, metadata);
+                        if !rom_manager.target_roms.contains_key(&target_path.with_file_name(&name)) {
+                            files.push(DirectoryEntry {
+                                name,
+                                kind: FileType::RegularFile,
+                            });
+                        }
+                    }
+                }
+            }
 
-    fn releasedir(&self, _req: RequestInfo, _path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
-        let mut handles = self.handles.lock().unwrap();
+            if self.expose_patchinfo {
+                for target_path in rom_manager.target_roms.keys().filter(|t| t.parent() == Some(path)) {
+                    let mut name = target_path.file_name().unwrap().to_owned();
+                    name.push(PATCHINFO_EXTENSION);
+                    if !rom_manager.target_roms.contains_key(&target_path.with_file_name(&name)) {
+                        files.push(DirectoryEntry {
+                            name,
+                            kind: FileType::RegularFile,
+                        });
+                    }
+                }
+            }
 
-        if let Some(Handle::Directory { .. }) = handles.get(&fh) {
-            handles.remove(&fh);
-            Ok(())
-        } else {
-            Err(libc::ENOENT)
-        }
-    }
+            for patch_file_path in rom_manager
+                .passthrough_files
+                .keys()
+                .filter(|p| p.parent() == Some(path))
+            {
+                files.push(DirectoryEntry {
+                    name: patch_file_path.file_name().unwrap().into(),
+                    kind: FileType::RegularFile,
+                });
+            }
 
-    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
-        Ok(())
-    }
+            for link_path in rom_manager.target_links.keys().filter(|l| l.parent() == Some(path)) {
+                files.push(DirectoryEntry {
+                    name: link_path.file_name().unwrap().into(),
+                    kind: FileType::Symlink,
+                });
+            }
 
-    #[allow(clippy::collapsible_if)]
+            if path == Path::new("") {
+                files.push(DirectoryEntry {
+                    name: MANIFEST_FILE_NAME.into(),
+                    kind: FileType::RegularFile,
+                });
+                files.push(DirectoryEntry {
+                    name: UNMATCHED_DIRECTORY.into(),
+                    kind: FileType::Directory,
+                });
+            }
+
+            if path == Path::new(UNMATCHED_DIRECTORY) {
+                for name in rom_manager.unmatched_reports.keys() {
+                    files.push(DirectoryEntry {
+                        name: name.clone(),
+                        kind: FileType::RegularFile,
+                    });
+                }
+            }
+
+            if path == Path::new("") {
+                for format in &self.checksum_formats {
+                    files.push(DirectoryEntry {
+                        name: format.file_name().into(),
+                        kind: FileType::RegularFile,
+                    });
+                }
+            }
+
+            if path == Path::new("") {
+                files.push(DirectoryEntry {
+                    name: CONTROL_FILE_NAME.into(),
+                    kind: FileType::RegularFile,
+                });
+            }
+
+            if self.show_stats && path == Path::new("") {
+                files.push(DirectoryEntry {
+                    name: STATS_FILE_NAME.into(),
+                    kind: FileType::RegularFile,
+                });
+            }
+
+            Ok(files)
+        } else {
+            Err(libc::ENOENT)
+        }
+    }
+
+    fn releasedir(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
+        trace!(target: "fuse::releasedir", "{:?} (fh={})", path, fh);
+        let mut handles = self.handles.lock().unwrap();
+
+        if let Some(Handle::Directory { .. }) = handles.get(&fh) {
+            handles.remove(&fh);
+            Ok(())
+        } else {
+            Err(libc::ENOENT)
+        }
+    }
+
+    fn access(&self, _req: RequestInfo, path: &Path, mask: u32) -> ResultEmpty {
+        trace!(target: "fuse::access", "{:?} (mask={:o})", path, mask);
+        Ok(())
+    }
+
+    #[allow(clippy::collapsible_if)]
     fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
+        trace!(target: "fuse::getattr", "{:?} (fh={:?})", path, fh);
         let path = path.strip_prefix("/").unwrap();
-        let rom_manager = self.rom_manager.lock().unwrap();
+        let rom_manager = self.rom_manager.read().unwrap();
         let handles = self.handles.lock().unwrap();
 
         if let Some(fh) = fh {
             match handles.get(&fh) {
-                Some(Handle::Directory { attr }) => Ok((TTL, *attr)),
-                Some(Handle::File { attr, .. }) => Ok((TTL, *attr)),
+                Some(Handle::Directory { attr }) => Ok((self.attr_ttl, *attr)),
+                Some(Handle::File { attr, .. }) => Ok((self.attr_ttl, *attr)),
+                Some(Handle::Virtual { attr, .. }) => Ok((GENERATED_TTL, *attr)),
+                Some(Handle::Passthrough { attr, .. }) => Ok((self.attr_ttl, *attr)),
+                Some(Handle::Checksums { attr, .. }) => Ok((GENERATED_TTL, *attr)),
+                Some(Handle::Control) => Ok((GENERATED_TTL, self.get_control_attr())),
                 _ => Err(libc::ENOENT),
             }
         } else {
-            if path == Path::new("") {
-                Ok((TTL, self.get_root_attr()))
+            if path == Path::new(STATS_FILE_NAME) {
+                let stats = self.render_stats(&rom_manager, &handles, &self.store.rom_cache.lock().unwrap());
+                Ok((GENERATED_TTL, self.get_virtual_attr(stats.len() as u64)))
+            } else if path == Path::new(CONTROL_FILE_NAME) {
+                Ok((GENERATED_TTL, self.get_control_attr()))
+            } else if path == Path::new(MANIFEST_FILE_NAME) {
+                Ok((GENERATED_TTL, self.get_virtual_attr(rom_manager.manifest.len() as u64)))
+            } else if self.checksum_format(path).is_some() {
+                Ok((GENERATED_TTL, self.get_virtual_attr(0)))
+            } else if rom_manager.target_directories.contains(path) || path == Path::new(UNMATCHED_DIRECTORY) {
+                Ok((self.attr_ttl, self.get_directory_attr(&rom_manager, path)))
+            } else if let Some(report) = self.unmatched_report(&rom_manager, path) {
+                Ok((self.attr_ttl, self.get_virtual_attr(report.len() as u64)))
             } else if let Some(rom) = rom_manager.target_roms.get(path) {
-                Ok((TTL, self.get_file_attr(rom)))
+                Ok((self.attr_ttl, self.get_file_attr(rom)))
+            } else if let Some(link_target) = rom_manager.target_links.get(path) {
+                let rom = rom_manager.target_roms.get(&path.with_file_name(link_target));
+                Ok((self.attr_ttl, self.get_link_attr(link_target, rom)))
+            } else if let Some((rom, metadata)) = self.metadata_rom(&rom_manager, path) {
+                Ok((self.attr_ttl, self.get_metadata_attr(rom, metadata)))
+            } else if let Some(rom) = self.patchinfo_rom(&rom_manager, path) {
+                let patchinfo = self.render_patchinfo(&rom_manager, rom);
+                Ok((self.attr_ttl, self.get_metadata_attr(rom, &patchinfo)))
+            } else if let Some(file_path) = rom_manager.passthrough_files.get(path) {
+                match fs::metadata(file_path) {
+                    Ok(metadata) => Ok((self.attr_ttl, self.get_passthrough_attr(&metadata))),
+                    Err(_) => Err(libc::ENOENT),
+                }
             } else {
                 Err(libc::ENOENT)
             }
         }
     }
 
-    fn open(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+    fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
+        trace!(target: "fuse::readlink", "{:?}", path);
+        let path = path.strip_prefix("/").unwrap();
+        let rom_manager = self.rom_manager.read().unwrap();
+
+        match rom_manager.target_links.get(path) {
+            Some(link_target) => Ok(link_target.as_os_str().as_bytes().to_vec()),
+            None if rom_manager.target_roms.contains_key(path) || rom_manager.target_directories.contains(path) => {
+                Err(libc::EINVAL)
+            }
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn open(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        trace!(target: "fuse::open", "{:?} (flags={:o})", path, flags);
         let path = path.strip_prefix("/").unwrap();
-        let rom_manager = self.rom_manager.lock().unwrap();
+
+        // Commands are run on writing, there is nothing to generate on opening
+        if path == Path::new(CONTROL_FILE_NAME) {
+            if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY && req.uid != unsafe { libc::geteuid() } {
+                return Err(libc::EACCES);
+            }
+
+            // Locked in the same order as everywhere else
+            let mut handles = self.handles.lock().unwrap();
+            let mut next_handle = self.next_handle.lock().unwrap();
+            let handle = *next_handle;
+            *next_handle += 1;
+
+            handles.insert(handle, Handle::Control);
+            return Ok((handle, FOPEN_DIRECT_IO));
+        }
+
+        // Everything but the control file is read-only
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(libc::EROFS);
+        }
+        let rom_manager = self.rom_manager.read().unwrap();
         let mut handles = self.handles.lock().unwrap();
         let mut next_handle = self.next_handle.lock().unwrap();
+        let mut rom_cache = self.store.rom_cache.lock().unwrap();
+
+        // The size of generated files is only known once generated, the kernel must not rely
+        // on the size reported earlier
+        if path == Path::new(STATS_FILE_NAME) {
+            let handle = *next_handle;
+            *next_handle += 1;
+
+            let data = self.render_stats(&rom_manager, &handles, &rom_cache).into_bytes();
+            handles.insert(
+                handle,
+                Handle::Virtual {
+                    attr: self.get_virtual_attr(data.len() as u64),
+                    data,
+                },
+            );
+            return Ok((handle, FOPEN_DIRECT_IO));
+        }
+
+        if path == Path::new(MANIFEST_FILE_NAME) {
+            let handle = *next_handle;
+            *next_handle += 1;
+
+            let data = rom_manager.manifest.clone().into_bytes();
+            handles.insert(
+                handle,
+                Handle::Virtual {
+                    attr: self.get_virtual_attr(data.len() as u64),
+                    data,
+                },
+            );
+            return Ok((handle, 0));
+        }
+
+        if let Some(report) = self.unmatched_report(&rom_manager, path) {
+            let handle = *next_handle;
+            *next_handle += 1;
+
+            handles.insert(
+                handle,
+                Handle::Virtual {
+                    attr: self.get_virtual_attr(report.len() as u64),
+                    data: report.to_vec(),
+                },
+            );
+            return Ok((handle, 0));
+        }
+
+        if let Some(format) = self.checksum_format(path) {
+            let handle = *next_handle;
+            *next_handle += 1;
+
+            let mut targets: Vec<(PathBuf, Arc<dyn Patch + Send + Sync>)> = rom_manager
+                .target_roms
+                .iter()
+                .map(|(target_path, patch)| (target_path.clone(), patch.clone()))
+                .collect();
+            targets.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            handles.insert(
+                handle,
+                Handle::Checksums {
+                    attr: self.get_virtual_attr(0),
+                    format,
+                    targets: Arc::new(targets),
+                    data: Arc::new(Mutex::new(None)),
+                },
+            );
+            return Ok((handle, FOPEN_DIRECT_IO));
+        }
 
         if let Some(rom) = rom_manager.target_roms.get(path) {
             let handle = *next_handle;
@@ -196,11 +1245,56 @@ impl FilesystemMT for RomFilesystem {
                 handle,
                 Handle::File {
                     attr: self.get_file_attr(rom),
+                    path: path.to_owned(),
                     patch: rom.clone(),
                     data: None,
+                    partial_rom: Arc::new(Mutex::new(None)),
+                    reading_ahead: Arc::new(AtomicBool::new(false)),
                 },
             );
 
+            rom_cache.open(path);
+
+            Ok((handle, 0))
+        } else if let Some(file_path) = rom_manager.passthrough_files.get(path) {
+            let file = File::open(file_path).map_err(|err| {
+                error!("Failed to open {:?}: {}", file_path, err);
+                err.raw_os_error().unwrap_or(libc::EIO)
+            })?;
+            let attr = self.get_passthrough_attr(&file.metadata().map_err(|err| {
+                error!("Failed to read the metadata of {:?}: {}", file_path, err);
+                libc::EIO
+            })?);
+
+            let handle = *next_handle;
+            *next_handle += 1;
+
+            handles.insert(handle, Handle::Passthrough { attr, file });
+            Ok((handle, 0))
+        } else if let Some((rom, metadata)) = self.metadata_rom(&rom_manager, path) {
+            let handle = *next_handle;
+            *next_handle += 1;
+
+            handles.insert(
+                handle,
+                Handle::Virtual {
+                    attr: self.get_metadata_attr(rom, metadata),
+                    data: metadata.to_vec(),
+                },
+            );
+            Ok((handle, 0))
+        } else if let Some(rom) = self.patchinfo_rom(&rom_manager, path) {
+            let handle = *next_handle;
+            *next_handle += 1;
+
+            let patchinfo = self.render_patchinfo(&rom_manager, rom);
+            handles.insert(
+                handle,
+                Handle::Virtual {
+                    attr: self.get_metadata_attr(rom, &patchinfo),
+                    data: patchinfo,
+                },
+            );
             Ok((handle, 0))
         } else {
             Err(libc::ENOENT)
@@ -210,59 +1304,866 @@ impl FilesystemMT for RomFilesystem {
     fn read(
         &self,
         _req: RequestInfo,
-        _path: &Path,
+        path: &Path,
         fh: u64,
         offset: u64,
         size: u32,
         result: impl FnOnce(Result<&[u8], libc::c_int>),
     ) {
-        let mut handles = self.handles.lock().unwrap();
+        trace!(target: "fuse::read", "{:?} (fh={}, offset={}, size={})", path, fh, offset, size);
+
+        // Generated without holding the lock of the handles
+        let checksums = match self.handles.lock().unwrap().get(&fh) {
+            Some(Handle::Checksums {
+                format, targets, data, ..
+            }) => Some((*format, targets.clone(), data.clone())),
+            _ => None,
+        };
+        if let Some((format, targets, data)) = checksums {
+            let mut data = data.lock().unwrap();
+            let data = data.get_or_insert_with(|| self.render_checksums(format, &targets));
+            result(Ok(read_slice(data, offset, size)));
+            return;
+        }
 
-        if let Some(Handle::File { data, patch, .. }) = handles.get_mut(&fh) {
-            // Deferred ROM patching on first read
-            if data.is_none() {
-                match patch.patched_rom() {
-                    Ok(patched_rom) => *data = Some(patched_rom),
+        let (target_path, patch, data, partial_rom, reading_ahead) = match self.handles.lock().unwrap().get(&fh) {
+            Some(Handle::File {
+                path,
+                patch,
+                data,
+                partial_rom,
+                reading_ahead,
+                ..
+            }) => (
+                path.clone(),
+                patch.clone(),
+                data.clone(),
+                partial_rom.clone(),
+                reading_ahead.clone(),
+            ),
+            Some(Handle::Virtual { data, .. }) => {
+                result(Ok(read_slice(data, offset, size)));
+                return;
+            }
+            Some(Handle::Control) => {
+                result(Ok(read_slice(&self.control_result.lock().unwrap(), offset, size)));
+                return;
+            }
+            Some(Handle::Passthrough { file, .. }) => {
+                let mut buffer = vec![0; size as usize];
+                match file.read_at(&mut buffer, offset) {
+                    Ok(length) => result(Ok(&buffer[..length])),
                     Err(err) => {
-                        error!("Failed to patch ROM: {}", err);
-                        result(Err(libc::EIO));
-                        return;
+                        error!("Failed to read {:?} (offset={}, size={}): {}", path, offset, size, err);
+                        result(Err(err.raw_os_error().unwrap_or(libc::EIO)))
                     }
                 }
+                return;
             }
+            _ => {
+                warn!("Failed to read {:?}: unknown handle {}", path, fh);
+                result(Err(libc::ENOENT));
+                return;
+            }
+        };
 
-            if let Some(data) = data {
-                if offset as usize > data.len() {
-                    result(Ok(&[]));
-                } else {
-                    let offset = offset as usize;
-                    let size = cmp::min(size as usize, data.len() - offset);
-                    result(Ok(&data[offset..offset + size]));
+        let result = |data: Result<&[u8], libc::c_int>| {
+            if let Ok(data) = data {
+                self.stats.add_bytes_served(data.len() as u64);
+            }
+            result(data)
+        };
+
+        if let Some(data) = data {
+            self.stats.add_cache_hit();
+            result(Ok(read_slice(&data, offset, size)));
+            return;
+        }
+
+        if let Some(data) = self.store.cached_rom_data(&target_path, &patch) {
+            self.stats.add_cache_hit();
+            self.set_handle_data(fh, data.clone());
+            result(Ok(read_slice(&data, offset, size)));
+            return;
+        }
+
+        self.stats.add_cache_miss();
+
+        let end = offset + size as u64;
+        let partial = {
+            let mut partial_rom = partial_rom.lock().unwrap();
+            match self.partial_rom_data(fh, &target_path, &patch, &mut partial_rom, offset, end) {
+                Ok(RomData::Partial(data)) => {
+                    result(Ok(read_slice(data, offset, size)));
+                    true
+                }
+                // Reading ahead would patch the range skipped by the read
+                Ok(RomData::Source(data)) => {
+                    result(Ok(data));
+                    false
+                }
+                Ok(RomData::Complete(data)) => {
+                    result(Ok(read_slice(&data, offset, size)));
+                    false
+                }
+                Err(err) => {
+                    result(Err(err));
+                    false
                 }
-            } else {
-                unreachable!();
             }
+        };
+
+        // Sequential reads are likely to continue past the end of this one
+        if partial && self.read_ahead > 0 {
+            self.start_read_ahead(partial_rom, reading_ahead, end + self.read_ahead);
+        }
+    }
+
+    // Nothing is ever written, there is nothing to flush or sync either
+    fn flush(&self, _req: RequestInfo, path: &Path, fh: u64, _lock_owner: u64) -> ResultEmpty {
+        trace!(target: "fuse::flush", "{:?} (fh={})", path, fh);
+
+        if let Some(
+            Handle::File { .. }
+            | Handle::Virtual { .. }
+            | Handle::Passthrough { .. }
+            | Handle::Checksums { .. }
+            | Handle::Control,
+        ) = self.handles.lock().unwrap().get(&fh)
+        {
+            Ok(())
         } else {
-            result(Err(libc::ENOENT));
+            Err(libc::EBADF)
         }
     }
 
+    fn fsync(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
+        trace!(target: "fuse::fsync", "{:?} (fh={}, datasync={})", path, fh, datasync);
+
+        if let Some(
+            Handle::File { .. }
+            | Handle::Virtual { .. }
+            | Handle::Passthrough { .. }
+            | Handle::Checksums { .. }
+            | Handle::Control,
+        ) = self.handles.lock().unwrap().get(&fh)
+        {
+            Ok(())
+        } else {
+            Err(libc::EBADF)
+        }
+    }
+
+    // Modifications are refused as on any read-only filesystem, instead of being reported
+    // as unimplemented
+    fn chmod(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
+        trace!(target: "fuse::chmod", "{:?} (fh={:?}, mode={:o})", path, fh, mode);
+        Err(libc::EROFS)
+    }
+
+    fn chown(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: Option<u64>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> ResultEmpty {
+        trace!(target: "fuse::chown", "{:?} (fh={:?}, uid={:?}, gid={:?})", path, fh, uid, gid);
+        Err(libc::EROFS)
+    }
+
+    fn truncate(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+        trace!(target: "fuse::truncate", "{:?} (fh={:?}, size={})", path, fh, size);
+
+        // Shells truncate the control file when redirecting commands into it
+        if path == Path::new("/").join(CONTROL_FILE_NAME) {
+            Ok(())
+        } else {
+            Err(libc::EROFS)
+        }
+    }
+
+    fn utimens(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: Option<u64>,
+        _atime: Option<Timespec>,
+        _mtime: Option<Timespec>,
+    ) -> ResultEmpty {
+        trace!(target: "fuse::utimens", "{:?} (fh={:?})", path, fh);
+        Err(libc::EROFS)
+    }
+
+    fn mknod(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, _rdev: u32) -> ResultEntry {
+        trace!(target: "fuse::mknod", "{:?} (name={:?}, mode={:o})", parent, name, mode);
+        Err(libc::EROFS)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32) -> ResultEntry {
+        trace!(target: "fuse::mkdir", "{:?} (name={:?}, mode={:o})", parent, name, mode);
+        Err(libc::EROFS)
+    }
+
+    fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        trace!(target: "fuse::unlink", "{:?} (name={:?})", parent, name);
+        Err(libc::EROFS)
+    }
+
+    fn rmdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        trace!(target: "fuse::rmdir", "{:?} (name={:?})", parent, name);
+        Err(libc::EROFS)
+    }
+
+    fn symlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr, target: &Path) -> ResultEntry {
+        trace!(target: "fuse::symlink", "{:?} (name={:?}, target={:?})", parent, name, target);
+        Err(libc::EROFS)
+    }
+
+    fn rename(&self, _req: RequestInfo, parent: &Path, name: &OsStr, newparent: &Path, newname: &OsStr) -> ResultEmpty {
+        trace!(target: "fuse::rename", "{:?} (name={:?}, newparent={:?}, newname={:?})", parent, name, newparent, newname);
+        Err(libc::EROFS)
+    }
+
+    fn link(&self, _req: RequestInfo, path: &Path, newparent: &Path, newname: &OsStr) -> ResultEntry {
+        trace!(target: "fuse::link", "{:?} (newparent={:?}, newname={:?})", path, newparent, newname);
+        Err(libc::EROFS)
+    }
+
+    fn write(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
+        trace!(target: "fuse::write", "{:?} (fh={}, offset={}, size={})", path, fh, offset, data.len());
+
+        if !matches!(self.handles.lock().unwrap().get(&fh), Some(Handle::Control)) {
+            return Err(libc::EROFS);
+        }
+
+        // Commands may take a while, they are run without holding the lock of the handles
+        match std::str::from_utf8(&data) {
+            Ok(commands) => self.run_control_commands(commands),
+            Err(_) => *self.control_result.lock().unwrap() = b"error: commands are not valid UTF-8\n".to_vec(),
+        }
+        Ok(data.len() as u32)
+    }
+
+    fn create(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: u32) -> ResultCreate {
+        trace!(target: "fuse::create", "{:?} (name={:?}, mode={:o}, flags={:o})", parent, name, mode, flags);
+        Err(libc::EROFS)
+    }
+
+    fn setxattr(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        name: &OsStr,
+        value: &[u8],
+        _flags: u32,
+        _position: u32,
+    ) -> ResultEmpty {
+        trace!(target: "fuse::setxattr", "{:?} (name={:?}, size={})", path, name, value.len());
+        Err(libc::EROFS)
+    }
+
+    fn removexattr(&self, _req: RequestInfo, path: &Path, name: &OsStr) -> ResultEmpty {
+        trace!(target: "fuse::removexattr", "{:?} (name={:?})", path, name);
+        Err(libc::EROFS)
+    }
+
+    // Read-only filesystem, reported as completely full
+    fn statfs(&self, _req: RequestInfo, path: &Path) -> ResultStatfs {
+        trace!(target: "fuse::statfs", "{:?}", path);
+        let rom_manager = self.rom_manager.read().unwrap();
+
+        let blocks = rom_manager
+            .target_roms
+            .values()
+            .map(|rom| rom.target_size().div_ceil(BLOCK_SIZE))
+            .sum();
+
+        Ok(Statfs {
+            blocks,
+            bfree: 0,
+            bavail: 0,
+            files: (rom_manager.target_roms.len()
+                + rom_manager.target_directories.len()
+                + rom_manager.target_links.len()) as u64,
+            ffree: 0,
+            bsize: BLOCK_SIZE as u32,
+            namelen: 255,
+            frsize: BLOCK_SIZE as u32,
+        })
+    }
+
+    fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
+        trace!(target: "fuse::getxattr", "{:?} (name={:?}, size={})", path, name, size);
+        let path = path.strip_prefix("/").unwrap();
+        let rom_manager = self.rom_manager.read().unwrap();
+
+        let rom = if let Some(rom) = rom_manager.target_roms.get(path) {
+            rom.clone()
+        } else if rom_manager.target_directories.contains(path)
+            || rom_manager.target_links.contains_key(path)
+            || path == Path::new(STATS_FILE_NAME)
+            || path == Path::new(MANIFEST_FILE_NAME)
+            || path == Path::new(CONTROL_FILE_NAME)
+            || path == Path::new(UNMATCHED_DIRECTORY)
+            || self.unmatched_report(&rom_manager, path).is_some()
+            || self.checksum_format(path).is_some()
+            || rom_manager.passthrough_files.contains_key(path)
+            || self.metadata_rom(&rom_manager, path).is_some()
+            || self.patchinfo_rom(&rom_manager, path).is_some()
+        {
+            return Err(libc::ENODATA);
+        } else {
+            return Err(libc::ENOENT);
+        };
+
+        // Patching may take a while, other requests are not blocked meanwhile
+        drop(rom_manager);
+        let value = self.get_file_xattr(path, &rom, name)?;
+
+        xattr_reply(value, size)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
+        trace!(target: "fuse::listxattr", "{:?} (size={})", path, size);
+        let path = path.strip_prefix("/").unwrap();
+        let rom_manager = self.rom_manager.read().unwrap();
+
+        let mut names = Vec::new();
+        if let Some(rom) = rom_manager.target_roms.get(path) {
+            for name in self.get_file_xattr_names(rom) {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        } else if !rom_manager.target_directories.contains(path)
+            && !rom_manager.target_links.contains_key(path)
+            && path != Path::new(STATS_FILE_NAME)
+            && path != Path::new(MANIFEST_FILE_NAME)
+            && path != Path::new(CONTROL_FILE_NAME)
+            && path != Path::new(UNMATCHED_DIRECTORY)
+            && self.unmatched_report(&rom_manager, path).is_none()
+            && self.checksum_format(path).is_none()
+            && !rom_manager.passthrough_files.contains_key(path)
+            && self.metadata_rom(&rom_manager, path).is_none()
+            && self.patchinfo_rom(&rom_manager, path).is_none()
+        {
+            return Err(libc::ENOENT);
+        }
+
+        xattr_reply(names, size)
+    }
+
     fn release(
         &self,
         _req: RequestInfo,
-        _path: &Path,
+        path: &Path,
         fh: u64,
         _flags: u32,
         _lock_owner: u64,
         _flush: bool,
     ) -> ResultEmpty {
+        trace!(target: "fuse::release", "{:?} (fh={})", path, fh);
         let mut handles = self.handles.lock().unwrap();
+        let mut rom_cache = self.store.rom_cache.lock().unwrap();
 
-        if let Some(Handle::File { .. }) = handles.get(&fh) {
-            handles.remove(&fh);
-            Ok(())
-        } else {
-            Err(libc::ENOENT)
+        match handles.get(&fh) {
+            Some(Handle::File { path, .. }) => {
+                rom_cache.release(path);
+                handles.remove(&fh);
+                Ok(())
+            }
+            Some(Handle::Virtual { .. } | Handle::Passthrough { .. } | Handle::Checksums { .. } | Handle::Control) => {
+                handles.remove(&fh);
+                Ok(())
+            }
+            _ => Err(libc::ENOENT),
         }
     }
 }
+
+// Metadata files are XML files when they look like one, plain text files otherwise
+fn metadata_name(target_path: &Path, metadata: &[u8]) -> OsString {
+    let mut name = target_path.file_name().unwrap().to_owned();
+    if metadata.trim_ascii_start().starts_with(b"<") {
+        name.push(METADATA_XML_EXTENSION);
+    } else {
+        name.push(METADATA_TXT_EXTENSION);
+    }
+    name
+}
+
+// A zero size request probes for the buffer size needed to hold the value
+fn xattr_reply(value: Vec<u8>, size: u32) -> ResultXattr {
+    if size == 0 {
+        Ok(Xattr::Size(value.len() as u32))
+    } else if value.len() > size as usize {
+        Err(libc::ERANGE)
+    } else {
+        Ok(Xattr::Data(value))
+    }
+}
+
+// Reads at or past the end of the target are short reads, not errors
+fn read_slice(data: &[u8], offset: u64, size: u32) -> &[u8] {
+    if offset >= data.len() as u64 {
+        &[]
+    } else {
+        let offset = offset as usize;
+        let size = cmp::min(size as usize, data.len() - offset);
+        &data[offset..offset + size]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::bps::BpsPatch;
+    use crate::rom_cache::DEFAULT_CACHE_SIZE;
+    use crate::rom_manager;
+    use crate::utils::write_vlq;
+    use std::env;
+
+    const SOURCE: &[u8] = b"The quick brown fox jumps over the lazy dog";
+    const TARGET: &[u8] = b"The quick brown fox jumps over the lazy cat";
+
+    // A SourceRead of everything but the last word, which is a TargetRead
+    fn build_patch() -> Vec<u8> {
+        build_target_read_patch(b"cat")
+    }
+
+    // Patches with the last word replaced, the checksums are those of the target all the same
+    fn build_target_read_patch(last_word: &[u8]) -> Vec<u8> {
+        let mut patch_data = b"BPS1".to_vec();
+        write_vlq(&mut patch_data, SOURCE.len() as u64);
+        write_vlq(&mut patch_data, TARGET.len() as u64);
+        write_vlq(&mut patch_data, 0);
+        write_vlq(&mut patch_data, (40 - 1) << 2);
+        write_vlq(&mut patch_data, ((3 - 1) << 2) | 1);
+        patch_data.extend_from_slice(last_word);
+        patch_data.extend_from_slice(&crc32::checksum_ieee(SOURCE).to_le_bytes());
+        patch_data.extend_from_slice(&crc32::checksum_ieee(TARGET).to_le_bytes());
+        let patch_checksum = crc32::checksum_ieee(&patch_data);
+        patch_data.extend_from_slice(&patch_checksum.to_le_bytes());
+        patch_data
+    }
+
+    // Filesystems of a patch directory with the given files, removed when dropped
+    struct TestMount {
+        directory: PathBuf,
+        filesystem: RomFilesystem,
+    }
+
+    impl TestMount {
+        fn new(name: &str, files: &[(&str, &[u8])]) -> Self {
+            Self::with_rom_manager(name, files, |_| {})
+        }
+
+        fn with_rom_manager(name: &str, files: &[(&str, &[u8])], configure: impl FnOnce(&mut RomManager)) -> Self {
+            let directory = env::temp_dir().join(format!("rom-filesystem-{}-{}", name, std::process::id()));
+            for (file_name, data) in files {
+                let file_path = directory.join(file_name);
+                fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+                fs::write(file_path, data).unwrap();
+            }
+
+            let rom_manager = rom_manager::test_rom_manager(&directory, configure);
+            let filesystem = RomFilesystem::new(
+                Arc::new(RwLock::new(rom_manager)),
+                DEFAULT_CACHE_SIZE,
+                false,
+                false,
+                None,
+                true,
+                false,
+                StatsFormat::Text,
+                false,
+                false,
+                Vec::new(),
+                0,
+                DEFAULT_ATTR_TTL,
+                None,
+            );
+            Self { directory, filesystem }
+        }
+
+        fn request(&self) -> RequestInfo {
+            RequestInfo {
+                unique: 0,
+                uid: unsafe { libc::geteuid() },
+                gid: unsafe { libc::getegid() },
+                pid: 0,
+            }
+        }
+
+        fn open(&self, path: &str, flags: i32) -> Result<u64, libc::c_int> {
+            let (fh, _) = self.filesystem.open(self.request(), Path::new(path), flags as u32)?;
+            Ok(fh)
+        }
+
+        fn read(&self, path: &str, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, libc::c_int> {
+            let mut data = None;
+            self.filesystem
+                .read(self.request(), Path::new(path), fh, offset, size, |result| {
+                    data = Some(result.map(<[u8]>::to_vec))
+                });
+            data.unwrap()
+        }
+
+        fn write(&self, path: &str, fh: u64, data: &[u8]) -> Result<u32, libc::c_int> {
+            self.filesystem
+                .write(self.request(), Path::new(path), fh, 0, data.to_vec(), 0)
+        }
+
+        fn release(&self, path: &str, fh: u64) {
+            self.filesystem
+                .release(self.request(), Path::new(path), fh, 0, 0, false)
+                .unwrap();
+        }
+
+        fn getxattr(&self, path: &str, name: &str, size: u32) -> Result<Xattr, libc::c_int> {
+            self.filesystem
+                .getxattr(self.request(), Path::new(path), OsStr::new(name), size)
+        }
+
+        fn listxattr(&self, path: &str, size: u32) -> Result<Xattr, libc::c_int> {
+            self.filesystem.listxattr(self.request(), Path::new(path), size)
+        }
+
+        fn readlink(&self, path: &str) -> Result<Vec<u8>, libc::c_int> {
+            self.filesystem.readlink(self.request(), Path::new(path))
+        }
+
+        fn read_file(&self, path: &str) -> Result<Vec<u8>, libc::c_int> {
+            let fh = self.open(path, libc::O_RDONLY)?;
+            let data = self.read(path, fh, 0, u32::MAX);
+            self.release(path, fh);
+            data
+        }
+
+        fn readdir(&self, path: &str) -> Vec<OsString> {
+            let (fh, _) = self.filesystem.opendir(self.request(), Path::new(path), 0).unwrap();
+            let entries = self.filesystem.readdir(self.request(), Path::new(path), fh).unwrap();
+            self.filesystem
+                .releasedir(self.request(), Path::new(path), fh, 0)
+                .unwrap();
+            entries.into_iter().map(|entry| entry.name).collect()
+        }
+
+        fn stat(&self, name: &str) -> String {
+            let stats = String::from_utf8(self.read_file("/.stats").unwrap()).unwrap();
+            let prefix = format!("{}: ", name);
+            stats.lines().find(|line| line.starts_with(&prefix)).unwrap().to_owned()
+        }
+
+        fn patches_applied(&self) -> String {
+            self.stat("patches_applied")
+        }
+    }
+
+    impl Drop for TestMount {
+        fn drop(&mut self) {
+            self.filesystem.rom_manager.read().unwrap().remove_extracted_patches();
+            let _ = fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    #[test]
+    fn test_control_commands() {
+        let mount = TestMount::new("control", &[("Game.sfc", SOURCE), ("Hack.bps", &build_patch())]);
+        // Cached while the target is open
+        let rom_fh = mount.open("/Hack.sfc", libc::O_RDONLY).unwrap();
+        assert_eq!(mount.read("/Hack.sfc", rom_fh, 0, 4096).unwrap(), TARGET);
+
+        let fh = mount.open("/.control", libc::O_WRONLY).unwrap();
+        let commands = b"rescan\nfrobnicate\nevict Hack.sfc\nevict /Hack.sfc\n";
+        assert_eq!(mount.write("/.control", fh, commands), Ok(commands.len() as u32));
+        mount.release("/.control", fh);
+        mount.release("/Hack.sfc", rom_fh);
+
+        assert_eq!(
+            String::from_utf8(mount.read_file("/.control").unwrap()).unwrap(),
+            "ok: found 1 target ROMs\n\
+             error: unknown command \"frobnicate\"\n\
+             ok: evicted \"Hack.sfc\"\n\
+             ok: \"Hack.sfc\" was not cached\n"
+        );
+    }
+
+    #[test]
+    fn test_corrupt_target() {
+        let patch_data = build_target_read_patch(b"cow");
+        let mount = TestMount::new("corrupt", &[("Game.sfc", SOURCE), ("Hack.bps", &patch_data)]);
+
+        // Copied unchanged from the source, but never served before verifying the whole target
+        let fh = mount.open("/Hack.sfc", libc::O_RDONLY).unwrap();
+        assert_eq!(mount.read("/Hack.sfc", fh, 4, 5), Err(libc::EIO));
+        mount.release("/Hack.sfc", fh);
+    }
+
+    #[test]
+    fn test_corrupt_target_memoized() {
+        let patch_data = build_target_read_patch(b"cow");
+        let mount = TestMount::new("corrupt-memoized", &[("Game.sfc", SOURCE), ("Hack.bps", &patch_data)]);
+        let patches_applied = || mount.patches_applied();
+
+        // Patched past the range being read, but not served either
+        let fh = mount.open("/Hack.sfc", libc::O_RDONLY).unwrap();
+        assert_eq!(mount.read("/Hack.sfc", fh, 36, 4), Err(libc::EIO));
+        assert_eq!(patches_applied(), "patches_applied: 1");
+
+        // Not patched again, whether by the same handle or another one
+        assert_eq!(mount.read("/Hack.sfc", fh, 0, 2), Err(libc::EIO));
+        mount.release("/Hack.sfc", fh);
+        assert_eq!(mount.read_file("/Hack.sfc"), Err(libc::EIO));
+        assert_eq!(patches_applied(), "patches_applied: 1");
+    }
+
+    #[test]
+    fn test_unverified_source_data() {
+        let patch_data = build_target_read_patch(b"cow");
+        let mut mount = TestMount::new("unverified", &[("Game.sfc", SOURCE), ("Hack.bps", &patch_data)]);
+        mount.filesystem.store.verify = false;
+
+        let fh = mount.open("/Hack.sfc", libc::O_RDONLY).unwrap();
+        assert_eq!(mount.read("/Hack.sfc", fh, 4, 5).unwrap(), b"quick");
+        assert_eq!(mount.read("/Hack.sfc", fh, 40, 3).unwrap(), b"cow");
+        mount.release("/Hack.sfc", fh);
+    }
+
+    fn xattr_data(xattr: Result<Xattr, libc::c_int>) -> Vec<u8> {
+        match xattr {
+            Ok(Xattr::Data(data)) => data,
+            _ => panic!("no xattr data"),
+        }
+    }
+
+    #[test]
+    fn test_patch_format_xattr() {
+        // Copies the target unchanged, stacked on the patch producing it
+        let mut identity_patch_data = b"BPS1".to_vec();
+        write_vlq(&mut identity_patch_data, TARGET.len() as u64);
+        write_vlq(&mut identity_patch_data, TARGET.len() as u64);
+        write_vlq(&mut identity_patch_data, 0);
+        write_vlq(&mut identity_patch_data, (TARGET.len() as u64 - 1) << 2);
+        identity_patch_data.extend_from_slice(&crc32::checksum_ieee(TARGET).to_le_bytes());
+        identity_patch_data.extend_from_slice(&crc32::checksum_ieee(TARGET).to_le_bytes());
+        let patch_checksum = crc32::checksum_ieee(&identity_patch_data);
+        identity_patch_data.extend_from_slice(&patch_checksum.to_le_bytes());
+
+        let mount = TestMount::new(
+            "patch-format",
+            &[
+                ("Game.sfc", SOURCE),
+                ("Hack.bps", &build_patch()),
+                ("Identity.bps", &identity_patch_data),
+                ("Chain.chain", b"Hack.bps\nIdentity.bps\n"),
+            ],
+        );
+
+        assert_eq!(
+            xattr_data(mount.getxattr("/Hack.sfc", XATTR_PATCH_FORMAT, 4096)),
+            b"BPS"
+        );
+        assert_eq!(
+            xattr_data(mount.getxattr("/Chain.sfc", XATTR_PATCH_FORMAT, 4096)),
+            b"BPS + BPS"
+        );
+        assert_eq!(mount.read_file("/Chain.sfc").unwrap(), TARGET);
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut ips_patch_data = b"PATCH".to_vec();
+        ips_patch_data.extend_from_slice(&[0x00, 0x00, 40, 0x00, 0x03]);
+        ips_patch_data.extend_from_slice(b"cat");
+        ips_patch_data.extend_from_slice(b"EOF");
+        let mut mount = TestMount::new(
+            "dedup",
+            &[
+                ("Game.sfc", SOURCE),
+                ("Hack.bps", &build_patch()),
+                ("Hack.ips", &ips_patch_data),
+            ],
+        );
+        mount.filesystem.store.rom_cache = Arc::new(Mutex::new(RomCache::new(DEFAULT_CACHE_SIZE, false, true)));
+
+        // Both targets are listed, but only one of them takes space in the cache
+        let target_names: Vec<OsString> = mount
+            .readdir("/")
+            .into_iter()
+            .filter(|name| name.to_str().unwrap().ends_with(".sfc") && name != "Game.sfc")
+            .collect();
+        assert_eq!(target_names.len(), 2);
+
+        let handles: Vec<(String, u64)> = target_names
+            .iter()
+            .map(|name| {
+                let path = format!("/{}", name.to_str().unwrap());
+                let fh = mount.open(&path, libc::O_RDONLY).unwrap();
+                assert_eq!(mount.read(&path, fh, 0, 4096).unwrap(), TARGET);
+                (path, fh)
+            })
+            .collect();
+        assert_eq!(mount.stat("cache_entries"), "cache_entries: 2");
+        assert_eq!(mount.stat("cache_bytes"), format!("cache_bytes: {}", TARGET.len()));
+
+        for (path, fh) in handles {
+            mount.release(&path, fh);
+        }
+    }
+
+    #[test]
+    fn test_patchinfo() {
+        let mut mount = TestMount::new("patchinfo", &[("Game.sfc", SOURCE), ("Hack.bps", &build_patch())]);
+        mount.filesystem.expose_patchinfo = true;
+        assert!(mount.readdir("/").contains(&OsString::from("Hack.sfc.patchinfo")));
+
+        let patchinfo = String::from_utf8(mount.read_file("/Hack.sfc.patchinfo").unwrap()).unwrap();
+        let fields: HashMap<&str, &str> = patchinfo.lines().filter_map(|line| line.split_once(": ")).collect();
+        let source_path = mount.directory.join("Game.sfc");
+        let patch_path = mount.directory.join("Hack.bps");
+        let source_checksum = format!("{:08x}", crc32::checksum_ieee(SOURCE));
+        let target_checksum = format!("{:08x}", crc32::checksum_ieee(TARGET));
+
+        assert_eq!(fields["Patch"], patch_path.to_str().unwrap());
+        assert_eq!(fields["Patch format"], "BPS");
+        assert_eq!(fields["Source"], source_path.to_str().unwrap());
+        assert_eq!(fields["Source size"], "43");
+        assert_eq!(fields["Source CRC32"], source_checksum);
+        assert_eq!(fields["Target size"], "43");
+        assert_eq!(fields["Target CRC32"], target_checksum);
+    }
+
+    #[test]
+    fn test_no_patchinfo() {
+        let mount = TestMount::new("no-patchinfo", &[("Game.sfc", SOURCE), ("Hack.bps", &build_patch())]);
+
+        assert!(!mount.readdir("/").contains(&OsString::from("Hack.sfc.patchinfo")));
+        assert_eq!(mount.read_file("/Hack.sfc.patchinfo"), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_stored_crc32_xattr() {
+        let mount = TestMount::new("stored-crc32", &[("Game.sfc", SOURCE), ("Hack.bps", &build_patch())]);
+
+        // Stored in the patch, nothing is patched for it
+        let checksum = xattr_data(mount.getxattr("/Hack.sfc", XATTR_ROM_CRC32, 4096));
+        assert_eq!(mount.patches_applied(), "patches_applied: 0");
+
+        let target = mount.read_file("/Hack.sfc").unwrap();
+        assert_eq!(checksum, format!("{:08x}", crc32::checksum_ieee(&target)).as_bytes());
+    }
+
+    #[test]
+    fn test_computed_crc32_xattr() {
+        let mut patch_data = b"PATCH".to_vec();
+        patch_data.extend_from_slice(&[0x00, 0x00, 40, 0x00, 0x03]);
+        patch_data.extend_from_slice(b"cow");
+        patch_data.extend_from_slice(b"EOF");
+        let mount = TestMount::new("computed-crc32", &[("Game.sfc", SOURCE), ("Hack.ips", &patch_data)]);
+
+        // IPS patches store no checksums, the target is patched for computing it
+        let checksum = xattr_data(mount.getxattr("/Hack.sfc", XATTR_ROM_CRC32, 4096));
+        assert_eq!(mount.patches_applied(), "patches_applied: 1");
+        assert_eq!(
+            checksum,
+            format!(
+                "{:08x}",
+                crc32::checksum_ieee(b"The quick brown fox jumps over the lazy cow")
+            )
+            .as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_unknown_xattr() {
+        let mount = TestMount::new("unknown-xattr", &[("Game.sfc", SOURCE), ("Hack.bps", &build_patch())]);
+
+        assert!(matches!(
+            mount.getxattr("/Hack.sfc", "user.softpatch.frobnicate", 4096),
+            Err(libc::ENODATA)
+        ));
+        assert!(matches!(
+            mount.getxattr("/Hack.sfc", "user.softpatch.frobnicate", 0),
+            Err(libc::ENODATA)
+        ));
+    }
+
+    #[test]
+    fn test_listxattr_size() {
+        let mount = TestMount::new("listxattr", &[("Game.sfc", SOURCE), ("Hack.bps", &build_patch())]);
+        let names = xattr_data(mount.listxattr("/Hack.sfc", 4096));
+        assert!(names
+            .split(|&byte| byte == 0)
+            .any(|name| name == XATTR_PATCH_FORMAT.as_bytes()));
+
+        // Empty buffers only probe the size of the list
+        assert!(matches!(
+            mount.listxattr("/Hack.sfc", 0),
+            Ok(Xattr::Size(size)) if size as usize == names.len()
+        ));
+        assert!(matches!(
+            mount.listxattr("/Hack.sfc", names.len() as u32 - 1),
+            Err(libc::ERANGE)
+        ));
+    }
+
+    #[test]
+    fn test_latest_link() {
+        let patch_data = build_patch();
+        let mount = TestMount::with_rom_manager(
+            "latest-link",
+            &[
+                ("Game.sfc", SOURCE),
+                ("Hack v1.2.bps", &patch_data),
+                ("Hack v1.10.bps", &patch_data),
+                ("Hack v1.9.bps", &patch_data),
+            ],
+            |rom_manager| rom_manager.latest_links = true,
+        );
+
+        // Versions are compared numerically, not as text
+        assert_eq!(mount.readlink("/Hack latest.sfc").unwrap(), b"Hack v1.10.sfc");
+        assert_eq!(mount.readlink("/Hack v1.10.sfc"), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_no_latest_link() {
+        let mount = TestMount::with_rom_manager(
+            "no-latest-link",
+            &[("Game.sfc", SOURCE), ("Hack.bps", &build_patch())],
+            |rom_manager| rom_manager.latest_links = true,
+        );
+
+        assert!(mount.filesystem.rom_manager.read().unwrap().target_links.is_empty());
+        assert_eq!(mount.readlink("/Hack latest.sfc"), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_unpatched_source_data() {
+        let directory = env::temp_dir().join(format!("rom-filesystem-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let source_path = directory.join("Game.sfc");
+        let patch_path = directory.join("Hack.bps");
+        fs::write(&source_path, SOURCE).unwrap();
+        fs::write(&patch_path, build_patch()).unwrap();
+
+        let mut patch = BpsPatch::new(&patch_path).unwrap();
+        patch.set_source_path(&source_path);
+        let mut partial_rom = patch.partial_patched_rom().unwrap().unwrap();
+
+        // Served from the source without patching anything
+        assert_eq!(unpatched_source_data(partial_rom.as_mut(), 4, 9), Some(&b"quick"[..]));
+        assert!(partial_rom.patched_data().is_empty());
+
+        // Reaching into the part read from the patch
+        assert_eq!(unpatched_source_data(partial_rom.as_mut(), 36, 43), None);
+
+        // Patched already
+        partial_rom.patch_until(20).unwrap();
+        assert_eq!(unpatched_source_data(partial_rom.as_mut(), 4, 9), None);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,16 +1,41 @@
-use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::fs::{self, DirEntry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{self, DirEntry, File};
 use std::io;
-use std::path::{Path, PathBuf};
+use std::iter;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
 use crc::crc32;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 
+use crate::archive::ArchiveFormat;
+use crate::checksum_index::{self, ChecksumIndex};
+use crate::config::TargetConfig;
+use crate::json::JsonValue;
+use crate::logging::Event;
+use crate::mapping::{self, PatchMapping};
+use crate::patch::aps::ApsPatch;
+use crate::patch::aps_gba::ApsGbaPatch;
 use crate::patch::bps::BpsPatch;
+use crate::patch::bsdiff::BsdiffPatch;
+use crate::patch::chain::{self, PatchChain};
 use crate::patch::ips::IpsPatch;
-use crate::patch::Patch;
+use crate::patch::ppf::PpfPatch;
+use crate::patch::rup::RupContainer;
+use crate::patch::star_rod::StarRodPatch;
+use crate::patch::ups::{UpsPatch, UpsUnpatch};
+use crate::patch::vcdiff::VcdiffPatch;
+use crate::patch::{Patch, PatchFormat};
+use crate::scan_progress::ScanProgress;
+use crate::stats::Stats;
+use crate::utils::{self, MappedFile};
 
 #[rustfmt::skip]
 const ROM_EXTENSIONS: &[&str] = &[
@@ -26,102 +51,2099 @@ const ROM_EXTENSIONS: &[&str] = &[
     "gba", "agb",        // Game Boy Advance
     "nds",               // Nintendo DS
     "3ds",               // Nintendo 3DS
+    // Sony
+    "iso",               // PlayStation
 ];
 
+// The extraction directories are named like "softpatch-a1B2c3"
+const EXTRACTION_DIRECTORY_PREFIX: &str = "softpatch";
+
+// Sources recovered from patched ROMs are listed separately from the targets
+const UNPATCHED_DIRECTORY: &str = "unpatched";
+
+// The patch files of the target ROMs are listed here on request, mirroring the target directories
+const PATCHES_DIRECTORY: &str = ".patches";
+
+// Intermediate targets of patch chains are written here while patching
+const CHAINED_DIRECTORY: &str = "chained";
+
+// Intermediate targets of the configured target ROMs, one directory for every target
+const CONFIGURED_DIRECTORY: &str = "configured";
+
+// Headerless copies of source ROMs are made here when only they match a patch
+const HEADERLESS_DIRECTORY: &str = "headerless";
+
+// SNES copier devices prepend a 512-byte header to the ROMs, headered ROMs are considered for
+// stripping by default when they have the extension of the copier format
+const COPIER_HEADER_SIZE: usize = 512;
+const COPIER_HEADER_EXTENSIONS: &[&str] = &["smc"];
+
+pub const DEFAULT_MAX_TARGET_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+fn extension_matches(path: &Path, extensions: &[&str]) -> bool {
+    let extension = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    extensions.contains(&extension.as_str())
+}
+
+fn json_string(value: Option<impl Into<String>>) -> JsonValue {
+    value.map_or(JsonValue::Null, |value| JsonValue::String(value.into()))
+}
+
+fn json_path(path: Option<&Path>) -> JsonValue {
+    json_string(path.map(|path| path.to_string_lossy()))
+}
+
+fn json_number(value: Option<u64>) -> JsonValue {
+    value.map_or(JsonValue::Null, |value| JsonValue::Number(value as f64))
+}
+
+// Paths have to match any of the include patterns when there are some, and none of the exclude
+// patterns
+fn patterns_include(include_patterns: &[String], exclude_patterns: &[String], path: &str) -> bool {
+    let matches = |pattern: &String| utils::glob_matches(pattern, path);
+
+    (include_patterns.is_empty() || include_patterns.iter().any(matches)) && !exclude_patterns.iter().any(matches)
+}
+
+// Colliding target ROMs get the checksum of their target, or that of their patch file when the
+// target checksum is unknown, between their names and extensions: "Game.sfc" -> "Game.1a2b3c4d.sfc"
+fn disambiguated_path(target_path: &Path, patch: &dyn Patch) -> PathBuf {
+    let checksum = patch
+        .target_checksum()
+        .unwrap_or_else(|| MappedFile::open(patch.patch_path()).map_or(0, |data| crc32::checksum_ieee(&data)));
+
+    let mut file_name = target_path.file_stem().unwrap_or_default().to_owned();
+    file_name.push(format!(".{:08x}", checksum));
+    if let Some(extension) = target_path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    target_path.with_file_name(file_name)
+}
+
+// Later patches of chains take the target of the previous patch as their source
+fn stacked_patch(patch_path: &Path, intermediate_path: &Path) -> Result<Arc<dyn Patch + Send + Sync>, Box<dyn Error>> {
+    match PatchFormat::detect(patch_path) {
+        Ok(Some(PatchFormat::Bps)) => BpsPatch::new(patch_path).map(|mut patch| {
+            patch.set_source_path(intermediate_path);
+            Arc::new(patch) as Arc<dyn Patch + Send + Sync>
+        }),
+        Ok(Some(PatchFormat::Ups)) => UpsPatch::new(patch_path).map(|mut patch| {
+            patch.set_source_path(intermediate_path);
+            Arc::new(patch) as Arc<dyn Patch + Send + Sync>
+        }),
+        Ok(Some(_)) => Err("only BPS and UPS patches can be stacked".into()),
+        Ok(None) => Err("unrecognized patch format".into()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Patches of every format take the given source ROM, verified the same way as when matching
+// source ROMs automatically
+fn mapped_patch(
+    patch_path: &Path,
+    source_path: &Path,
+    verify: bool,
+    max_target_size: u64,
+) -> Result<Arc<dyn Patch + Send + Sync>, Box<dyn Error>> {
+    let source = MappedFile::open(source_path)?;
+
+    let patch: Arc<dyn Patch + Send + Sync> = match PatchFormat::detect(patch_path)? {
+        Some(PatchFormat::Aps) => {
+            let mut patch = ApsPatch::new(patch_path)?;
+            if verify {
+                patch.verify_source(&source)?;
+            }
+            patch.set_source_path(source_path);
+            Arc::new(patch)
+        }
+        Some(PatchFormat::ApsGba) => {
+            let mut patch = ApsGbaPatch::new(patch_path)?;
+            if verify {
+                patch.verify_source(&source)?;
+            }
+            patch.set_source_path(source_path);
+            Arc::new(patch)
+        }
+        Some(PatchFormat::Bps) => {
+            let mut patch = BpsPatch::new(patch_path)?;
+            if verify {
+                patch.verify_source(&source)?;
+            } else {
+                patch.set_ignore_source_checksum();
+            }
+            patch.set_source_path(source_path);
+            Arc::new(patch)
+        }
+        Some(PatchFormat::Bsdiff) => {
+            let mut patch = BsdiffPatch::new(patch_path)?;
+            patch.set_source_path(source_path)?;
+            Arc::new(patch)
+        }
+        Some(PatchFormat::Ips) => Arc::new(IpsPatch::new(patch_path, source_path, max_target_size)?),
+        Some(PatchFormat::Ppf) => {
+            let mut patch = PpfPatch::new(patch_path)?;
+            if verify {
+                patch.verify_source(&source)?;
+            }
+            patch.set_source_path(source_path)?;
+            Arc::new(patch)
+        }
+        Some(PatchFormat::Rup) => {
+            let mut container = RupContainer::new(patch_path)?;
+            if container.patches.len() != 1 {
+                return Err("only single-file RUP patches can be mapped".into());
+            }
+            let mut patch = container.patches.remove(0);
+            if verify {
+                patch.verify_source(&source)?;
+            }
+            patch.set_source_path(source_path);
+            Arc::new(patch)
+        }
+        Some(PatchFormat::StarRod) => {
+            let mut patch = StarRodPatch::new(patch_path)?;
+            if verify {
+                patch.verify_source(&source)?;
+            }
+            patch.set_source_path(source_path);
+            Arc::new(patch)
+        }
+        Some(PatchFormat::Ups) => {
+            let mut patch = UpsPatch::new(patch_path)?;
+            if verify {
+                patch.verify_source(&source)?;
+            }
+            patch.set_source_path(source_path);
+            Arc::new(patch)
+        }
+        Some(PatchFormat::Vcdiff) => {
+            let mut patch = VcdiffPatch::new(patch_path)?;
+            patch.set_source_path(source_path)?;
+            Arc::new(patch)
+        }
+        None => return Err("unrecognized patch format".into()),
+    };
+
+    Ok(patch)
+}
+
+// Files of the directory and its subdirectories up to `max_depth` levels deep. Symlinked
+// directories are followed, but every directory is only listed once. Subdirectories failing to
+// be listed are skipped.
+fn list_files(directory: &Path, max_depth: Option<usize>) -> io::Result<Vec<DirEntry>> {
+    let mut files = Vec::new();
+    let mut visited_directories = HashSet::new();
+
+    let metadata = fs::metadata(directory)?;
+    visited_directories.insert((metadata.dev(), metadata.ino()));
+    list_directory_files(directory, max_depth, &mut visited_directories, &mut files)?;
+
+    Ok(files)
+}
+
+fn list_directory_files(
+    directory: &Path,
+    max_depth: Option<usize>,
+    visited_directories: &mut HashSet<(u64, u64)>,
+    files: &mut Vec<DirEntry>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(directory)?.filter_map(Result::ok) {
+        // Dangling symlinks are left to fail when opened, like other unreadable files
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                files.push(entry);
+                continue;
+            }
+        };
+
+        if !metadata.is_dir() {
+            files.push(entry);
+        } else if max_depth != Some(0) && visited_directories.insert((metadata.dev(), metadata.ino())) {
+            let max_depth = max_depth.map(|max_depth| max_depth - 1);
+            if let Err(err) = list_directory_files(&entry.path(), max_depth, visited_directories, files) {
+                warn!("Failed to list {:?}: {}", entry.path(), err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Versioned names end with a dotted numeric version after a "v" and a separator, like
+// "game-v1.2", returning the name before the separator, the separator and the version
+fn split_version(name: &OsStr) -> Option<(&OsStr, &OsStr, Vec<u64>)> {
+    let name = name.as_bytes();
+    let index = name.iter().rposition(|&b| matches!(b, b'v' | b'V'))?;
+    let (base, separator) = match &name[..index] {
+        [base @ .., separator @ (b'-' | b'_' | b' ')] => (base, std::slice::from_ref(separator)),
+        _ => return None,
+    };
+
+    let version = name[(index + 1)..]
+        .split(|&b| b == b'.')
+        .map(|part| {
+            if !part.is_empty() && part.iter().all(|b| b.is_ascii_digit()) {
+                std::str::from_utf8(part).ok()?.parse().ok()
+            } else {
+                None
+            }
+        })
+        .collect::<Option<Vec<u64>>>()?;
+
+    if base.is_empty() {
+        None
+    } else {
+        Some((OsStr::from_bytes(base), OsStr::from_bytes(separator), version))
+    }
+}
+
+// What to do with patches whose source ROM is present, but does not match the source checksum
+// stored in the patch
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BadSourcePolicy {
+    // The target ROM is not listed at all
+    Hide,
+    // The target ROM is listed, but reading it fails
+    Error,
+    // The target ROM is patched from the mismatching source ROM anyway
+    Ignore,
+}
+
+// Which source ROMs get their copier headers stripped, when only that makes them match a patch
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HeaderStripping {
+    // Source ROMs have to match as they are
+    Never,
+    // Only the ROMs with the extension of the copier format
+    CopierExtensions,
+    // Every ROM of a size fitting a copier header
+    Always,
+}
+
+// How the target ROMs are arranged in the filesystem
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Layout {
+    // Target ROMs mirror the patch files
+    Flat,
+    // Every target ROM gets a directory of its own, along with its patch
+    PerRom,
+}
+
+// Why a patch produced no target ROMs, along with the source ROM it expects when the format
+// tells it
+#[derive(Clone)]
+struct UnmatchedReason {
+    reason: String,
+    expected_source: Option<(u64, u32)>,
+}
+
+#[derive(Clone)]
 pub struct RomManager {
-    pub base_directory: PathBuf,
+    // Patch directories in increasing precedence, later ones shadow the target ROMs of earlier ones
+    pub base_directories: Vec<PathBuf>,
+    pub source_directory: PathBuf,
+    // Libraries of source ROMs, indexed along with the source directory
+    pub rom_directories: Vec<PathBuf>,
     pub source_roms: HashMap<u32, PathBuf>,
+    // Source ROMs having a copier header, indexed by the CRC32 checksums of their data without it
+    headered_source_roms: HashMap<u32, PathBuf>,
     pub target_roms: HashMap<PathBuf, Arc<dyn Patch + Send + Sync>>,
+    pub target_directories: HashSet<PathBuf>,
+    // Symlinks to the newest version of versioned target ROMs, relative to their directory
+    pub target_links: HashMap<PathBuf, PathBuf>,
+    // Patch files of the target ROMs and the source ROMs listed along with them, served as they are
+    pub passthrough_files: HashMap<PathBuf, PathBuf>,
+    // Patch files recognized but producing no target ROMs, the reasons are logged while scanning
+    pub unmatched_patches: Vec<PathBuf>,
+    // Patch files failing to load, unmatched as well
+    pub failed_patches: Vec<PathBuf>,
+    // Descriptions of the unmatched patches named after them, rendered on every refresh
+    pub unmatched_reports: HashMap<OsString, Vec<u8>>,
+    unmatched_reasons: HashMap<PathBuf, UnmatchedReason>,
+    // Every target ROM along with its patch and source ROM, rendered on every refresh
+    pub manifest: String,
+    // Number of target ROMs of every patch format, "other" for those without a patch format
+    pub format_counts: BTreeMap<&'static str, usize>,
+    // Kept across refreshes, for the lifetime of the mount
+    pub stats: Arc<Stats>,
+    // When a refresh after the first one last changed the target ROMs. The kernel cannot be told
+    // about the changes by this FUSE version, the root directory is dated no earlier than this
+    // for the tools caching the directory listings.
+    pub targets_changed: Option<SystemTime>,
+    refreshed: bool,
+    // Patches found in archives are extracted here, mirroring the patch directories. Every
+    // refresh extracts into a private directory of its own, the state being replaced keeps its
+    // files until the refreshed one is swapped in.
+    extraction_directory: PathBuf,
+    // Target ROMs loaded so far, including the shadowed ones
+    loaded_target_count: usize,
+    // Target ROMs of the patch directory, mappings or configuration being loaded. Their
+    // collisions are accidental, they are disambiguated instead of shadowing each other.
+    layer_target_paths: HashSet<PathBuf>,
+    pub(crate) latest_links: bool,
+    bad_source_policy: BadSourcePolicy,
+    max_target_size: u64,
+    // Unlimited when missing
+    max_depth: Option<usize>,
+    // Patches assigned to source ROMs explicitly
+    mapping_path: Option<PathBuf>,
+    // Glob patterns of the patch paths relative to their patch directories. Patches have to match
+    // any of the include patterns when there are some, and none of the exclude patterns.
+    include_patterns: Vec<String>,
+    exclude_patterns: Vec<String>,
+    header_stripping: HeaderStripping,
+    expose_patches: bool,
+    include_sources: bool,
+    layout: Layout,
+    // BPS targets are named after the `<name>` element of their metadata when there is one
+    metadata_names: bool,
+    // Checksums of the source ROMs kept between mounts, missing without a cache directory
+    checksum_index: Option<ChecksumIndex>,
+    // Source ROMs are hashed and patches are detected on this many threads
+    scan_threads: usize,
+    // Target ROMs given explicitly by the configuration file
+    configured_targets: Vec<TargetConfig>,
+    // Counters of the ongoing refresh
+    scan_progress: Arc<ScanProgress>,
+    // Patches failing to load fail the refresh, instead of being skipped
+    strict: bool,
 }
 
 impl RomManager {
-    pub fn new(base_directory: &Path) -> io::Result<RomManager> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        base_directories: &[PathBuf],
+        source_directory: Option<&Path>,
+        rom_directories: &[PathBuf],
+        latest_links: bool,
+        bad_source_policy: BadSourcePolicy,
+        max_target_size: u64,
+        max_depth: Option<usize>,
+        mapping_path: Option<&Path>,
+        include_patterns: Vec<String>,
+        exclude_patterns: Vec<String>,
+        header_stripping: HeaderStripping,
+        expose_patches: bool,
+        include_sources: bool,
+        layout: Layout,
+        metadata_names: bool,
+        rebuild_index: bool,
+        scan_threads: usize,
+        configured_targets: Vec<TargetConfig>,
+        scan_progress: Arc<ScanProgress>,
+        strict: bool,
+    ) -> io::Result<RomManager> {
         let mut result = Self {
-            base_directory: base_directory.to_owned(),
+            base_directories: base_directories.to_owned(),
+            source_directory: source_directory.unwrap_or(&base_directories[0]).to_owned(),
+            rom_directories: rom_directories.to_owned(),
             source_roms: HashMap::new(),
+            headered_source_roms: HashMap::new(),
             target_roms: HashMap::new(),
+            target_directories: HashSet::new(),
+            target_links: HashMap::new(),
+            passthrough_files: HashMap::new(),
+            unmatched_patches: Vec::new(),
+            failed_patches: Vec::new(),
+            unmatched_reports: HashMap::new(),
+            unmatched_reasons: HashMap::new(),
+            manifest: String::new(),
+            format_counts: BTreeMap::new(),
+            stats: Arc::new(Stats::default()),
+            targets_changed: None,
+            refreshed: false,
+            extraction_directory: PathBuf::new(),
+            loaded_target_count: 0,
+            layer_target_paths: HashSet::new(),
+            latest_links,
+            bad_source_policy,
+            max_target_size,
+            max_depth,
+            mapping_path: mapping_path.map(Path::to_owned),
+            include_patterns,
+            exclude_patterns,
+            header_stripping,
+            expose_patches,
+            include_sources,
+            layout,
+            metadata_names,
+            checksum_index: ChecksumIndex::default_path()
+                .map(|index_path| ChecksumIndex::new(&index_path, rebuild_index)),
+            scan_threads,
+            configured_targets,
+            scan_progress,
+            strict,
         };
-        result.refresh()?;
+        if let Err(err) = result.refresh() {
+            result.remove_extracted_patches();
+            return Err(err);
+        }
         Ok(result)
     }
 
-    pub fn refresh(&mut self) -> io::Result<()> {
+    // Returns the number of target ROMs not found by the previous refresh
+    pub fn refresh(&mut self) -> io::Result<usize> {
         info!("Refreshing");
+        let refresh_start = Instant::now();
+        let previous_targets: HashMap<PathBuf, SystemTime> = self
+            .target_roms
+            .iter()
+            .map(|(target_path, patch)| (target_path.clone(), patch.patch_modified()))
+            .collect();
+        self.scan_progress.reset();
+
         self.source_roms.clear();
+        self.headered_source_roms.clear();
         self.target_roms.clear();
+        self.target_directories.clear();
+        self.target_directories.insert(PathBuf::new());
+        self.target_links.clear();
+        self.passthrough_files.clear();
+        self.unmatched_patches.clear();
+        self.failed_patches.clear();
+        self.unmatched_reasons.clear();
+        self.unmatched_reports.clear();
+        self.extraction_directory = utils::create_private_directory(EXTRACTION_DIRECTORY_PREFIX)?;
 
-        fn extension_matches(path: &Path, extensions: &[&str]) -> bool {
-            let extension = path
-                .extension()
-                .and_then(OsStr::to_str)
-                .map(str::to_ascii_lowercase)
-                .unwrap_or_default();
-            extensions.contains(&extension.as_str())
+        let source_directory = self.source_directory.clone();
+        self.scan_roms(&source_directory)?;
+        for rom_directory in self.rom_directories.clone() {
+            self.scan_roms(&rom_directory)?;
+        }
+        if let Some(checksum_index) = &mut self.checksum_index {
+            if let Err(err) = checksum_index.save() {
+                warn!("Failed to save the checksum index: {}", err);
+            }
         }
 
-        let entries: Vec<DirEntry> = fs::read_dir(&self.base_directory)?
-            .filter_map(Result::ok)
-            .filter(|e| !e.file_type().unwrap().is_dir())
+        let mappings = match &self.mapping_path {
+            Some(mapping_path) => match mapping::read_mapping_file(mapping_path) {
+                Ok(mappings) => mappings,
+                Err(err) => {
+                    error!("Failed to load {:?}: {}", mapping_path, err);
+                    Vec::new()
+                }
+            },
+            None => Vec::new(),
+        };
+        let mapped_patch_paths: HashSet<PathBuf> = mappings
+            .iter()
+            .map(|mapping| &mapping.patch_path)
+            .chain(self.configured_targets.iter().flat_map(|target| &target.patch_paths))
+            .filter_map(|patch_path| fs::canonicalize(patch_path).ok())
             .collect();
 
-        for entry in entries.iter().filter(|e| extension_matches(&e.path(), ROM_EXTENSIONS)) {
-            let crc = crc32::checksum_ieee(&fs::read(entry.path())?);
-            self.source_roms.insert(crc, entry.path().to_owned());
+        if self.source_roms.is_empty() && mappings.is_empty() && self.configured_targets.is_empty() {
+            warn!(
+                "No source ROMs were found in {:?}",
+                iter::once(&self.source_directory)
+                    .chain(&self.rom_directories)
+                    .collect::<Vec<_>>()
+            );
+            self.manifest = self.render_manifest();
+            self.format_counts = self.count_target_formats();
+            self.record_target_changes(&previous_targets);
+            return Ok(0);
         }
 
-        if self.source_roms.is_empty() {
-            warn!("No source ROMs were found in {:?}", self.base_directory);
-            return Ok(());
+        let mut directory_patch_paths = Vec::new();
+        let mut directory_chains = Vec::new();
+        let mut filtered_patch_count = 0;
+
+        for base_directory in self.base_directories.clone() {
+            let mut patch_paths = Vec::new();
+            let mut chains = Vec::new();
+
+            for entry in list_files(&base_directory, self.max_depth)?
+                .iter()
+                .filter(|e| !extension_matches(&e.path(), ROM_EXTENSIONS))
+            {
+                if extension_matches(&entry.path(), &[chain::CHAIN_EXTENSION]) {
+                    match chain::read_chain_file(&entry.path()) {
+                        Ok(chain_patch_paths) if !chain_patch_paths.is_empty() => {
+                            chains.push((entry.path(), chain_patch_paths))
+                        }
+                        Ok(_) => warn!("No patches are listed in {:?}", entry.path()),
+                        Err(err) => self.patch_failed(&entry.path(), err),
+                    }
+                    continue;
+                }
+
+                match ArchiveFormat::detect(&entry.path()) {
+                    Ok(Some(archive_format)) => patch_paths.extend(self.extract_archive(&entry.path(), archive_format)),
+                    Ok(None) => patch_paths.push(entry.path()),
+                    Err(err) => self.patch_failed(&entry.path(), err),
+                }
+            }
+
+            let patch_count = patch_paths.len() + chains.len();
+            self.scan_progress.add_files_discovered(patch_count as u64);
+            patch_paths.retain(|patch_path| self.is_patch_included(patch_path));
+            chains.retain(|(chain_path, _)| self.is_patch_included(chain_path));
+            filtered_patch_count += patch_count - patch_paths.len() - chains.len();
+
+            directory_patch_paths.push(patch_paths);
+            directory_chains.push(chains);
         }
 
-        for entry in entries.iter().filter(|e| extension_matches(&e.path(), &["bps"])) {
-            match BpsPatch::new(&entry.path()) {
-                Ok(mut patch) => {
-                    if let Some(source_path) = self.source_roms.get(&patch.source_checksum()) {
-                        patch.set_source_path(source_path);
+        if filtered_patch_count > 0 {
+            info!("Filtered out {} patches", filtered_patch_count);
+        }
 
-                        let mut target_path = entry.path().strip_prefix(&self.base_directory).unwrap().to_owned();
-                        target_path.set_extension(source_path.extension().unwrap_or_default());
-                        self.target_roms.insert(target_path, Arc::new(patch));
-                    } else {
-                        warn!(
-                            "No source ROM was found for {:?} (CRC32=0x{:08X})",
-                            entry.path(),
-                            patch.source_checksum()
-                        );
+        // Patches stacked on other patches are only loaded as parts of their chains
+        let stacked_patch_paths: HashSet<PathBuf> = directory_chains
+            .iter()
+            .flatten()
+            .flat_map(|(_, chain_patch_paths)| chain_patch_paths.iter().skip(1).cloned())
+            .collect();
+
+        // Every directory is loaded entirely before the next one, chains included
+        for (patch_paths, chains) in directory_patch_paths.into_iter().zip(directory_chains) {
+            self.layer_target_paths.clear();
+            let patch_formats = utils::parallel_map(&patch_paths, self.scan_threads, |patch_path| {
+                PatchFormat::detect(patch_path)
+            });
+
+            for (patch_path, patch_format) in patch_paths.into_iter().zip(patch_formats) {
+                if stacked_patch_paths.contains(&patch_path) {
+                    continue;
+                }
+
+                // Mapped patches are only loaded along with their mappings
+                if !mapped_patch_paths.is_empty()
+                    && fs::canonicalize(&patch_path).is_ok_and(|path| mapped_patch_paths.contains(&path))
+                {
+                    continue;
+                }
+
+                let target_count = self.loaded_target_count;
+
+                match patch_format {
+                    Ok(Some(PatchFormat::Aps)) => self.load_aps_patch(&patch_path),
+                    Ok(Some(PatchFormat::ApsGba)) => self.load_aps_gba_patch(&patch_path),
+                    Ok(Some(PatchFormat::Bps)) => self.load_bps_patch(&patch_path),
+                    Ok(Some(PatchFormat::Bsdiff)) => self.load_bsdiff_patch(&patch_path),
+                    Ok(Some(PatchFormat::Ips)) => self.load_ips_patch(&patch_path),
+                    Ok(Some(PatchFormat::Ppf)) => self.load_ppf_patch(&patch_path),
+                    Ok(Some(PatchFormat::Rup)) => self.load_rup_patch(&patch_path),
+                    Ok(Some(PatchFormat::StarRod)) => self.load_star_rod_patch(&patch_path),
+                    Ok(Some(PatchFormat::Ups)) => self.load_ups_patch(&patch_path),
+                    Ok(Some(PatchFormat::Vcdiff)) => self.load_vcdiff_patch(&patch_path),
+                    Ok(None) => continue,
+                    Err(err) => {
+                        self.patch_failed(&patch_path, err);
                     }
                 }
-                Err(err) => {
-                    error!("Failed to load {:?}: {}", entry.path(), err);
+
+                self.record_match(patch_path, target_count);
+            }
+
+            for (chain_path, chain_patch_paths) in chains {
+                let target_count = self.loaded_target_count;
+                self.load_patch_chain(&chain_path, &chain_patch_paths);
+                self.record_match(chain_path, target_count);
+            }
+        }
+
+        // Mapped patches take precedence over the ones matched automatically
+        self.layer_target_paths.clear();
+        for mapping in &mappings {
+            let target_count = self.loaded_target_count;
+            self.load_mapped_patch(mapping);
+            self.record_match(mapping.patch_path.clone(), target_count);
+        }
+
+        // Configured target ROMs take precedence over everything else
+        self.layer_target_paths.clear();
+        for (index, target) in self.configured_targets.clone().iter().enumerate() {
+            self.load_configured_target(index, target);
+        }
+
+        self.reject_oversized_targets();
+        if self.layout == Layout::PerRom {
+            self.arrange_per_rom();
+        }
+        self.index_directories();
+        if self.latest_links {
+            self.index_latest_links();
+        }
+        if self.expose_patches {
+            self.index_patch_files();
+        }
+        if self.include_sources {
+            self.index_source_files()?;
+        }
+        self.manifest = self.render_manifest();
+        self.format_counts = self.count_target_formats();
+        self.unmatched_reports = self.render_unmatched_reports();
+
+        if self.strict && !self.failed_patches.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} patches failed to load", self.failed_patches.len()),
+            ));
+        }
+
+        let target_count = self.target_roms.len();
+        Event::new("scan")
+            .duration(refresh_start.elapsed())
+            .count(target_count)
+            .log(|| info!("Found {} target ROMs", target_count));
+        if !self.unmatched_patches.is_empty() {
+            warn!("Skipped {} patches:", self.unmatched_patches.len());
+            for patch_path in &self.unmatched_patches {
+                warn!("    {:?}", patch_path);
+            }
+        }
+
+        self.record_target_changes(&previous_targets);
+        Ok(self
+            .target_roms
+            .keys()
+            .filter(|target_path| !previous_targets.contains_key(*target_path))
+            .count())
+    }
+
+    // Targets added, removed or patched by modified patches since the previous refresh
+    fn record_target_changes(&mut self, previous_targets: &HashMap<PathBuf, SystemTime>) {
+        let changed = previous_targets.len() != self.target_roms.len()
+            || self
+                .target_roms
+                .iter()
+                .any(|(target_path, patch)| previous_targets.get(target_path) != Some(&patch.patch_modified()));
+
+        if self.refreshed && changed {
+            self.targets_changed = Some(SystemTime::now());
+        }
+        self.refreshed = true;
+    }
+
+    // Checksums of the indexed source ROMs, headerless copies included
+    pub fn source_checksum(&self, source_path: &Path) -> Option<u32> {
+        self.source_roms
+            .iter()
+            .find(|(_, path)| path.as_path() == source_path)
+            .map(|(&checksum, _)| checksum)
+    }
+
+    fn count_target_formats(&self) -> BTreeMap<&'static str, usize> {
+        let mut format_counts = BTreeMap::new();
+        for patch in self.target_roms.values() {
+            let patch_format = PatchFormat::detect(patch.patch_path()).ok().flatten();
+            *format_counts
+                .entry(patch_format.map_or("other", PatchFormat::name))
+                .or_insert(0) += 1;
+        }
+        format_counts
+    }
+
+    // Checksums are only listed when known without patching
+    fn render_manifest(&self) -> String {
+        let source_checksums: HashMap<&PathBuf, u32> =
+            self.source_roms.iter().map(|(&crc, path)| (path, crc)).collect();
+
+        let mut target_paths: Vec<&PathBuf> = self.target_roms.keys().collect();
+        target_paths.sort();
+
+        let targets = target_paths
+            .into_iter()
+            .map(|target_path| {
+                let patch = &self.target_roms[target_path];
+                let patch_format = PatchFormat::detect(patch.patch_path()).ok().flatten();
+                let source_path = patch.source_path().map(Path::to_path_buf);
+                let source_size = source_path
+                    .as_ref()
+                    .and_then(|source_path| fs::metadata(source_path).ok())
+                    .map(|metadata| metadata.len());
+                let source_checksum = source_path
+                    .as_ref()
+                    .and_then(|source_path| source_checksums.get(source_path));
+
+                let members: HashMap<String, JsonValue> = vec![
+                    ("target", json_path(Some(target_path))),
+                    ("patch_path", json_path(Some(patch.patch_path()))),
+                    (
+                        "patch_directory",
+                        json_path(
+                            self.relative_patch_path(patch.patch_path())
+                                .map(|(index, _)| self.base_directories[index].as_path()),
+                        ),
+                    ),
+                    ("patch_format", json_string(patch_format.map(PatchFormat::name))),
+                    ("source_path", json_path(source_path.as_deref())),
+                    ("source_size", json_number(source_size)),
+                    (
+                        "source_crc32",
+                        json_string(source_checksum.map(|crc| format!("{:08x}", crc))),
+                    ),
+                    ("target_size", json_number(Some(patch.target_size()))),
+                    (
+                        "target_crc32",
+                        json_string(patch.target_checksum().map(|crc| format!("{:08x}", crc))),
+                    ),
+                ]
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect();
+                JsonValue::Object(members)
+            })
+            .collect();
+
+        let mut manifest = HashMap::new();
+        manifest.insert("targets".to_owned(), JsonValue::Array(targets));
+        format!("{}\n", JsonValue::Object(manifest))
+    }
+
+    // Report names are the file names of the patches, colliding ones are numbered
+    fn render_unmatched_reports(&self) -> HashMap<OsString, Vec<u8>> {
+        let mut patch_paths: Vec<&PathBuf> = self.unmatched_patches.iter().collect();
+        patch_paths.sort();
+        patch_paths.dedup();
+
+        let mut reports = HashMap::new();
+        for patch_path in patch_paths {
+            let mut report = format!("Patch: {}\n", patch_path.display());
+            if let Ok(Some(patch_format)) = PatchFormat::detect(patch_path) {
+                report.push_str(&format!("Patch format: {}\n", patch_format.name()));
+            }
+
+            let unmatched_reason = self.unmatched_reasons.get(patch_path);
+            if let Some((source_size, source_checksum)) = unmatched_reason.and_then(|reason| reason.expected_source) {
+                report.push_str(&format!("Expected source size: {}\n", source_size));
+                report.push_str(&format!("Expected source CRC32: {:08x}\n", source_checksum));
+            }
+            report.push_str(&format!(
+                "Reason: {}\n",
+                unmatched_reason.map_or("no target ROM was loaded", |reason| reason.reason.as_str())
+            ));
+
+            let file_name = patch_path.file_name().unwrap_or_default();
+            let mut name = file_name.to_owned();
+            name.push(".txt");
+            for number in 2.. {
+                if !reports.contains_key(&name) {
+                    break;
                 }
+                name = file_name.to_owned();
+                name.push(format!(" ({}).txt", number));
             }
+            reports.insert(name, report.into_bytes());
         }
+        reports
+    }
+
+    // Target sizes come from the patch files, patching is refused for targets allocating more
+    // than the limit
+    fn reject_oversized_targets(&mut self) {
+        let max_target_size = self.max_target_size;
+        let unmatched_patches = &mut self.unmatched_patches;
+        let unmatched_reasons = &mut self.unmatched_reasons;
+        self.target_roms.retain(|target_path, patch| {
+            if patch.target_size() <= max_target_size {
+                return true;
+            }
+
+            warn!(
+                "Target ROM {:?} of {:?} exceeds the maximum target size ({} > {} bytes), skipping",
+                target_path,
+                patch.patch_path(),
+                patch.target_size(),
+                max_target_size
+            );
+            if !unmatched_patches
+                .iter()
+                .any(|patch_path| patch_path == patch.patch_path())
+            {
+                unmatched_patches.push(patch.patch_path().to_owned());
+            }
+            unmatched_reasons.insert(
+                patch.patch_path().to_owned(),
+                UnmatchedReason {
+                    reason: format!(
+                        "the target ROM exceeds the maximum target size ({} > {} bytes)",
+                        patch.target_size(),
+                        max_target_size
+                    ),
+                    expected_source: None,
+                },
+            );
+            false
+        });
+    }
 
-        for entry in entries.iter().filter(|e| extension_matches(&e.path(), &["ips"])) {
-            if self.source_roms.len() > 1 {
+    // The subdirectories of the patches are mirrored as target directories. Target ROMs colliding
+    // with the name of a directory are dropped, the directory takes precedence.
+    fn index_directories(&mut self) {
+        for target_path in self.target_roms.keys() {
+            for directory in target_path.ancestors().skip(1) {
+                self.target_directories.insert(directory.to_owned());
+            }
+        }
+
+        let target_directories = &self.target_directories;
+        self.target_roms.retain(|target_path, patch| {
+            if target_directories.contains(target_path) {
                 warn!(
-                    "Multiple source ROMs were found for {:?}, cannot decide which one to choose",
-                    entry.path()
+                    "Target ROM of {:?} collides with the directory {:?}, skipping",
+                    patch.patch_path(),
+                    target_path
                 );
+                false
             } else {
-                let source_path = self.source_roms.values().next().unwrap();
+                true
+            }
+        });
+    }
 
-                match IpsPatch::new(&entry.path(), source_path) {
-                    Ok(patch) => {
-                        let mut target_path = entry.path().strip_prefix(&self.base_directory).unwrap().to_owned();
-                        target_path.set_extension(source_path.extension().unwrap_or_default());
-                        self.target_roms.insert(target_path, Arc::new(patch));
-                    }
-                    Err(err) => {
-                        error!("Failed to load {:?}: {}", entry.path(), err);
+    // Source ROMs are indexed by their CRC32 checksums, so patches find their sources regardless
+    // of file names. When the patches share the directory only known ROM extensions are indexed.
+    pub fn scan_roms(&mut self, directory: &Path) -> io::Result<()> {
+        let mut files = Vec::new();
+        for entry in self.list_source_files(directory)? {
+            let path = entry.path();
+            let metadata = fs::metadata(&path)?;
+
+            // The size of copier headers is not a multiple of the size of ROM banks
+            let headered = match self.header_stripping {
+                HeaderStripping::Never => false,
+                HeaderStripping::CopierExtensions => extension_matches(&path, COPIER_HEADER_EXTENSIONS),
+                HeaderStripping::Always => true,
+            } && metadata.len() % 1024 == COPIER_HEADER_SIZE as u64;
+
+            let indexed_checksums = self
+                .checksum_index
+                .as_mut()
+                .and_then(|checksum_index| checksum_index.lookup(&path, &metadata, headered));
+            files.push((path, metadata, headered, indexed_checksums));
+        }
+        self.scan_progress.add_files_discovered(files.len() as u64);
+
+        // Only the files missing from the index are read
+        let unindexed_files: Vec<(&Path, u64, bool)> = files
+            .iter()
+            .filter(|(_, _, _, indexed_checksums)| indexed_checksums.is_none())
+            .map(|(path, metadata, headered, _)| (path.as_path(), metadata.len(), *headered))
+            .collect();
+        let scan_progress = &self.scan_progress;
+        let mut hashed_checksums =
+            utils::parallel_map(&unindexed_files, self.scan_threads, |&(path, size, headered)| {
+                debug!("Hashing {:?}", path);
+                let checksums = checksum_index::file_checksums(path, headered);
+                scan_progress.add_file_hashed(size);
+                checksums
+            })
+            .into_iter();
+
+        for (path, metadata, _, indexed_checksums) in files {
+            let (crc, headerless_crc) = match indexed_checksums {
+                Some(checksums) => checksums,
+                None => {
+                    let checksums = hashed_checksums.next().unwrap()?;
+                    if let Some(checksum_index) = &mut self.checksum_index {
+                        checksum_index.insert(&path, &metadata, checksums);
                     }
+                    checksums
                 }
+            };
+
+            if let Some(headerless_crc) = headerless_crc {
+                self.headered_source_roms.insert(headerless_crc, path.clone());
+            }
+
+            if let Some(duplicate_path) = self.source_roms.insert(crc, path.clone()) {
+                debug!(
+                    "Identical source ROMs {:?} and {:?} (CRC32=0x{:08X})",
+                    duplicate_path, path, crc
+                );
             }
         }
 
-        // TODO: UPS support
-        // With the same CRC32-matching logic as BPS
+        Ok(())
+    }
+
+    fn list_source_files(&self, directory: &Path) -> io::Result<Vec<DirEntry>> {
+        let shared_directory = self
+            .base_directories
+            .iter()
+            .any(|base_directory| base_directory == directory);
+        let mut files = list_files(directory, self.max_depth)?;
+        files.retain(|e| !shared_directory || extension_matches(&e.path(), ROM_EXTENSIONS));
+        Ok(files)
+    }
+
+    // Source ROMs are listed under their paths in the source directory, unless a target ROM,
+    // directory or another file already has that path. Target ROMs always take precedence.
+    fn index_source_files(&mut self) -> io::Result<()> {
+        let source_directory = self.source_directory.clone();
+
+        for entry in self.list_source_files(&source_directory)? {
+            let source_file_path = entry.path().strip_prefix(&source_directory).unwrap().to_owned();
+
+            let collides = self.target_roms.contains_key(&source_file_path)
+                || self.target_directories.contains(&source_file_path)
+                || self.target_links.contains_key(&source_file_path)
+                || self.passthrough_files.contains_key(&source_file_path)
+                || source_file_path.ancestors().skip(1).any(|directory| {
+                    self.target_roms.contains_key(directory) || self.target_links.contains_key(directory)
+                });
+            if collides {
+                debug!("Source ROM {:?} collides with a target ROM, skipping", entry.path());
+                continue;
+            }
+
+            for directory in source_file_path.ancestors().skip(1) {
+                self.target_directories.insert(directory.to_owned());
+            }
+            self.passthrough_files.insert(source_file_path, entry.path());
+        }
 
         Ok(())
     }
+
+    // Versioned target ROMs of the same directory, name and extension get a "latest" symlink
+    // pointing at the highest version, unless a target ROM already has the name of the link
+    fn index_latest_links(&mut self) {
+        let mut latest_versions: HashMap<PathBuf, (Vec<u64>, PathBuf)> = HashMap::new();
+
+        for target_path in self.target_roms.keys() {
+            let (base, separator, version) = match target_path.file_stem().and_then(split_version) {
+                Some(split) => split,
+                None => continue,
+            };
+
+            let mut link_name = base.to_owned();
+            link_name.push(separator);
+            link_name.push("latest");
+            if let Some(extension) = target_path.extension() {
+                link_name.push(".");
+                link_name.push(extension);
+            }
+            let link_path = target_path.with_file_name(link_name);
+
+            match latest_versions.get(&link_path) {
+                Some((latest_version, _)) if *latest_version >= version => {}
+                _ => {
+                    latest_versions.insert(link_path, (version, target_path.clone()));
+                }
+            }
+        }
+
+        for (link_path, (_, target_path)) in latest_versions {
+            if self.target_roms.contains_key(&link_path) || self.target_directories.contains(&link_path) {
+                warn!(
+                    "Target ROM {:?} collides with a latest version symlink, skipping the symlink",
+                    link_path
+                );
+                continue;
+            }
+
+            self.target_links
+                .insert(link_path, PathBuf::from(target_path.file_name().unwrap()));
+        }
+    }
+
+    // Target ROMs are moved into directories named after them, next to their patch files
+    fn arrange_per_rom(&mut self) {
+        let passthrough_files = &mut self.passthrough_files;
+        self.target_roms = mem::take(&mut self.target_roms)
+            .into_iter()
+            .map(|(target_path, patch)| {
+                let rom_directory = target_path.with_extension("");
+                passthrough_files.insert(
+                    rom_directory.join(patch.patch_path().file_name().unwrap()),
+                    patch.patch_path().to_owned(),
+                );
+                (rom_directory.join(target_path.file_name().unwrap()), patch)
+            })
+            .collect();
+    }
+
+    // Patch files are listed in the patch directory under the directory of their target ROMs
+    fn index_patch_files(&mut self) {
+        for (target_path, patch) in &self.target_roms {
+            let patch_file_path = Path::new(PATCHES_DIRECTORY)
+                .join(target_path.parent().unwrap())
+                .join(patch.patch_path().file_name().unwrap());
+            self.passthrough_files
+                .insert(patch_file_path, patch.patch_path().to_owned());
+        }
+
+        for patch_file_path in self.passthrough_files.keys() {
+            for directory in patch_file_path.ancestors().skip(1) {
+                self.target_directories.insert(directory.to_owned());
+            }
+        }
+    }
+
+    // Archived patches are extracted into a directory named after the archive, compressed
+    // patches in place of the compressed file, keeping the modification times of the archive
+    // members. Nothing resembling a ROM is extracted.
+    fn extract_archive(&self, archive_path: &Path, archive_format: ArchiveFormat) -> Vec<PathBuf> {
+        let members =
+            match archive_format.extract(archive_path, |name| !extension_matches(Path::new(name), ROM_EXTENSIONS)) {
+                Ok(members) => members,
+                Err(err) => {
+                    error!("Failed to extract {:?}: {}", archive_path, err);
+                    return Vec::new();
+                }
+            };
+
+        let (index, relative_path) = self.relative_patch_path(archive_path).unwrap();
+        let mirror_directory = self.mirror_directory(index);
+        let archive_directory = if archive_format.is_compressed_file() {
+            mirror_directory.join(relative_path.parent().unwrap())
+        } else {
+            mirror_directory.join(relative_path.with_extension(""))
+        };
+        let mut patch_paths = Vec::new();
+
+        for member in members {
+            let result = member.and_then(|member| {
+                // Members are kept inside the extraction directory whatever their names are
+                let member_path: PathBuf = Path::new(&member.name)
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect();
+                if member_path.as_os_str().is_empty() {
+                    return Ok(None);
+                }
+
+                let patch_path = archive_directory.join(member_path);
+                fs::create_dir_all(patch_path.parent().unwrap())?;
+                fs::write(&patch_path, &member.data)?;
+                File::options()
+                    .write(true)
+                    .open(&patch_path)?
+                    .set_modified(member.modified)?;
+                Ok(Some(patch_path))
+            });
+
+            match result {
+                Ok(Some(patch_path)) => patch_paths.push(patch_path),
+                Ok(None) => {}
+                Err(err) => warn!("Failed to extract a member of {:?}: {}", archive_path, err),
+            }
+        }
+
+        patch_paths
+    }
+
+    // Only the files of this refresh are removed, other refreshes have directories of their own
+    pub fn remove_extracted_patches(&self) {
+        if let Err(err) = fs::remove_dir_all(&self.extraction_directory) {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove {:?}: {}", self.extraction_directory, err);
+            }
+        }
+    }
+
+    fn is_patch_included(&self, patch_path: &Path) -> bool {
+        match self.relative_patch_path(patch_path) {
+            Some((_, relative_path)) => patterns_include(
+                &self.include_patterns,
+                &self.exclude_patterns,
+                &relative_path.to_string_lossy(),
+            ),
+            None => true,
+        }
+    }
+
+    // Patches extracted from the archives of a patch directory, named after its index
+    fn mirror_directory(&self, index: usize) -> PathBuf {
+        self.extraction_directory.join(index.to_string())
+    }
+
+    // The index of the patch directory a patch comes from, along with the path of the patch
+    // relative to it. Extracted patches come from the directory of their archives.
+    fn relative_patch_path<'a>(&self, patch_path: &'a Path) -> Option<(usize, &'a Path)> {
+        self.base_directories
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(index, base_directory)| {
+                patch_path
+                    .strip_prefix(base_directory)
+                    .or_else(|_| patch_path.strip_prefix(self.mirror_directory(index)))
+                    .ok()
+                    .map(|relative_path| (index, relative_path))
+            })
+    }
+
+    fn target_path(&self, patch_path: &Path, source_path: &Path) -> PathBuf {
+        let mut target_path = self.relative_patch_path(patch_path).unwrap().1.to_owned();
+        target_path.set_extension(source_path.extension().unwrap_or_default());
+        target_path
+    }
+
+    // The metadata name keeps the directory of the patch and takes the extension of the source ROM
+    fn named_target_path(&self, patch_path: &Path, source_path: &Path, metadata_name: Option<String>) -> PathBuf {
+        let target_path = self.target_path(patch_path, source_path);
+        let name = match metadata_name
+            .filter(|_| self.metadata_names)
+            .and_then(|metadata_name| utils::sanitize_file_name(&metadata_name))
+        {
+            Some(name) => name,
+            None => return target_path,
+        };
+
+        let mut file_name = OsString::from(name);
+        if let Some(extension) = source_path.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        target_path.with_file_name(file_name)
+    }
+
+    // Patches failing to load are skipped, the others are loaded all the same
+    fn patch_failed(&mut self, patch_path: &Path, err: impl fmt::Display) {
+        Event::new("load")
+            .path(patch_path)
+            .error(&err)
+            .log(|| error!("Failed to load {:?}: {}", patch_path, err));
+        self.failed_patches.push(patch_path.to_owned());
+        self.scan_progress.add_patch_failed();
+        self.unmatched_reasons.insert(
+            patch_path.to_owned(),
+            UnmatchedReason {
+                reason: format!("failed to load: {}", err),
+                expected_source: None,
+            },
+        );
+    }
+
+    // The reason is kept for the unmatched directory, `expected_source` is the size and the
+    // CRC32 checksum of the source ROM
+    fn patch_unmatched(&mut self, patch_path: &Path, expected_source: Option<(u64, u32)>, reason: String) {
+        Event::new("load")
+            .path(patch_path)
+            .error(&reason)
+            .log(|| warn!("Skipping {:?}: {}", patch_path, reason));
+        self.unmatched_reasons.insert(
+            patch_path.to_owned(),
+            UnmatchedReason {
+                reason,
+                expected_source,
+            },
+        );
+    }
+
+    // Patches loading no target ROMs since `target_count` are unmatched
+    fn record_match(&mut self, patch_path: PathBuf, target_count: usize) {
+        if self.loaded_target_count == target_count {
+            self.unmatched_patches.push(patch_path);
+            self.scan_progress.add_patch_unmatched();
+        } else {
+            self.scan_progress.add_patch_matched();
+        }
+    }
+
+    // Later patch directories take precedence, the target ROMs of earlier ones are replaced
+    fn insert_target_rom(&mut self, target_path: PathBuf, patch: Arc<dyn Patch + Send + Sync>) {
+        self.loaded_target_count += 1;
+
+        if self.layer_target_paths.contains(&target_path) {
+            // The patch sorting first keeps the name, whatever order the patches were found in
+            let mut colliding_patch = self.target_roms.remove(&target_path).unwrap();
+            let mut patch = patch;
+            if colliding_patch.patch_path() > patch.patch_path() {
+                mem::swap(&mut colliding_patch, &mut patch);
+            }
+            self.target_roms.insert(target_path.clone(), colliding_patch);
+
+            let disambiguated_path = disambiguated_path(&target_path, patch.as_ref());
+            if !self.target_roms.contains_key(&disambiguated_path) {
+                warn!(
+                    "Target ROMs of {:?} and {:?} collide at {:?}, renaming the latter to {:?}",
+                    self.target_roms[&target_path].patch_path(),
+                    patch.patch_path(),
+                    target_path,
+                    disambiguated_path
+                );
+                self.layer_target_paths.insert(disambiguated_path.clone());
+                self.target_roms.insert(disambiguated_path, patch);
+                return;
+            }
+
+            warn!(
+                "Target ROM {:?} of {:?} collides with the one of {:?}, skipping it",
+                target_path,
+                patch.patch_path(),
+                self.target_roms[&target_path].patch_path()
+            );
+            return;
+        }
+
+        if let Some(shadowed_patch) = self.target_roms.get(&target_path) {
+            warn!(
+                "Target ROM {:?} of {:?} shadows the one of {:?}",
+                target_path,
+                patch.patch_path(),
+                shadowed_patch.patch_path()
+            );
+        }
+
+        self.layer_target_paths.insert(target_path.clone());
+        self.target_roms.insert(target_path, patch);
+    }
+
+    // For formats identifying their source ROMs by CRC32 checksums. Copier headers are only
+    // stripped when the source ROM matches without the header, but not with it.
+    fn source_rom(&mut self, checksum: u32) -> Option<PathBuf> {
+        if let Some(source_path) = self.source_roms.get(&checksum) {
+            return Some(source_path.clone());
+        }
+
+        let headered_path = self.headered_source_roms.remove(&checksum)?;
+        let headerless_path = self
+            .extraction_directory
+            .join(HEADERLESS_DIRECTORY)
+            .join(format!("{:08x}", checksum))
+            .join(headered_path.file_name().unwrap());
+
+        let result = MappedFile::open(&headered_path).and_then(|data| {
+            fs::create_dir_all(headerless_path.parent().unwrap())?;
+            // The source ROM may have been truncated since indexing
+            let headerless_data = data
+                .get(COPIER_HEADER_SIZE..)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            fs::write(&headerless_path, headerless_data)
+        });
+
+        match result {
+            Ok(()) => {
+                info!("Stripped the copier header of {:?}", headered_path);
+                self.source_roms.insert(checksum, headerless_path.clone());
+                Some(headerless_path)
+            }
+            Err(err) => {
+                warn!("Failed to strip the copier header of {:?}: {}", headered_path, err);
+                None
+            }
+        }
+    }
+
+    // For formats not identifying their source ROMs at all, only unambiguous when there is
+    // a single source ROM
+    fn single_source_rom(&mut self, patch_path: &Path) -> Option<PathBuf> {
+        match self.source_roms.len() {
+            0 => {
+                self.patch_unmatched(patch_path, None, "no source ROM was found".to_owned());
+                None
+            }
+            1 => self.source_roms.values().next().cloned(),
+            _ => {
+                self.patch_unmatched(
+                    patch_path,
+                    None,
+                    "multiple source ROMs were found, cannot decide which one to choose".to_owned(),
+                );
+                None
+            }
+        }
+    }
+
+    // A lone source ROM is most likely the one meant for a patch not matching it, only a bad dump,
+    // as is the lone source ROM of the expected size among several of them
+    fn bad_source_rom(&self, source_size: u64) -> Option<(u32, PathBuf)> {
+        if self.source_roms.len() == 1 {
+            return self
+                .source_roms
+                .iter()
+                .next()
+                .map(|(&source_checksum, source_path)| (source_checksum, source_path.clone()));
+        }
+
+        let mut candidates = self.source_roms.iter().filter(|(_, source_path)| {
+            fs::metadata(source_path)
+                .map(|metadata| metadata.len() == source_size)
+                .unwrap_or(false)
+        });
+        match (candidates.next(), candidates.next()) {
+            (Some((&source_checksum, source_path)), None) => Some((source_checksum, source_path.clone())),
+            _ => None,
+        }
+    }
+
+    // For formats identifying their source ROMs by something else than a CRC32 checksum
+    fn find_source_rom(&self, matches: impl Fn(&[u8]) -> bool) -> Option<PathBuf> {
+        self.source_roms
+            .values()
+            .find(|source_path| {
+                MappedFile::open(source_path)
+                    .map(|source| matches(&source))
+                    .unwrap_or(false)
+            })
+            .cloned()
+    }
+
+    fn load_aps_patch(&mut self, patch_path: &Path) {
+        let mut patch = match ApsPatch::new(patch_path) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+                return;
+            }
+        };
+
+        info!("Loaded {:?}: {}", patch_path, patch.description());
+
+        if let Some(source_path) = self.find_source_rom(|source| patch.verify_source(source).is_ok()) {
+            patch.set_source_path(&source_path);
+
+            let target_path = self.target_path(patch_path, &source_path);
+            self.insert_target_rom(target_path, Arc::new(patch));
+        } else {
+            self.patch_unmatched(
+                patch_path,
+                None,
+                "no source ROM was found matching its cartridge ID and CRC".to_owned(),
+            );
+        }
+    }
+
+    fn load_aps_gba_patch(&mut self, patch_path: &Path) {
+        let mut patch = match ApsGbaPatch::new(patch_path) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+                return;
+            }
+        };
+
+        if let Some(source_path) = self.find_source_rom(|source| patch.verify_source(source).is_ok()) {
+            patch.set_source_path(&source_path);
+
+            let target_path = self.target_path(patch_path, &source_path);
+            self.insert_target_rom(target_path, Arc::new(patch));
+        } else {
+            self.patch_unmatched(
+                patch_path,
+                None,
+                "no source ROM was found matching its block checksums".to_owned(),
+            );
+        }
+    }
+
+    fn load_bps_patch(&mut self, patch_path: &Path) {
+        match BpsPatch::new(patch_path) {
+            Ok(mut patch) => {
+                if let Some(source_path) = self.source_rom(patch.source_checksum()) {
+                    patch.set_source_path(&source_path);
+
+                    let target_path = self.named_target_path(patch_path, &source_path, patch.metadata_name());
+                    self.insert_target_rom(target_path, Arc::new(patch));
+                    return;
+                }
+
+                let (source_checksum, source_path) = match self.bad_source_rom(patch.source_size()) {
+                    Some(source_rom) => source_rom,
+                    None => {
+                        if self.bad_source_policy != BadSourcePolicy::Hide && self.source_roms.len() > 1 {
+                            warn!(
+                                "Ignoring --on-bad-source for {:?}: none of the source ROMs stands out by its size",
+                                patch_path
+                            );
+                        }
+                        self.patch_unmatched(
+                            patch_path,
+                            Some((patch.source_size(), patch.source_checksum())),
+                            format!("no source ROM was found (CRC32=0x{:08X})", patch.source_checksum()),
+                        );
+                        return;
+                    }
+                };
+
+                let mismatch = format!(
+                    "source ROM {:?} does not match (expected CRC32=0x{:08X}, received CRC32=0x{:08X})",
+                    source_path,
+                    patch.source_checksum(),
+                    source_checksum
+                );
+
+                if let BadSourcePolicy::Hide = self.bad_source_policy {
+                    self.patch_unmatched(
+                        patch_path,
+                        Some((patch.source_size(), patch.source_checksum())),
+                        mismatch,
+                    );
+                    return;
+                }
+
+                warn!("Patching {:?} anyway: {}", patch_path, mismatch);
+                if let BadSourcePolicy::Ignore = self.bad_source_policy {
+                    patch.set_ignore_source_checksum();
+                }
+
+                patch.set_source_path(&source_path);
+                let target_path = self.named_target_path(patch_path, &source_path, patch.metadata_name());
+                self.insert_target_rom(target_path, Arc::new(patch));
+            }
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+            }
+        }
+    }
+
+    fn load_bsdiff_patch(&mut self, patch_path: &Path) {
+        let mut patch = match BsdiffPatch::new(patch_path) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+                return;
+            }
+        };
+
+        let source_path = match self.single_source_rom(patch_path) {
+            Some(source_path) => source_path,
+            None => return,
+        };
+
+        if let Err(err) = patch.set_source_path(&source_path) {
+            self.patch_failed(patch_path, err);
+            return;
+        }
+
+        let target_path = self.target_path(patch_path, &source_path);
+        self.insert_target_rom(target_path, Arc::new(patch));
+    }
+
+    fn load_ups_patch(&mut self, patch_path: &Path) {
+        let mut patch = match UpsPatch::new(patch_path) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+                return;
+            }
+        };
+
+        // Already patched ROMs are unpatched back into their original sources
+        let target_rom_path = patch
+            .target_checksum()
+            .and_then(|target_checksum| self.source_roms.get(&target_checksum))
+            .cloned();
+        if let Some(target_rom_path) = &target_rom_path {
+            let unpatched_path = Path::new(UNPATCHED_DIRECTORY).join(self.target_path(patch_path, target_rom_path));
+            self.insert_target_rom(
+                unpatched_path,
+                Arc::new(UpsUnpatch::new(patch.clone(), target_rom_path)),
+            );
+        }
+
+        if let Some(source_path) = self.source_rom(patch.source_checksum()) {
+            patch.set_source_path(&source_path);
+
+            let target_path = self.target_path(patch_path, &source_path);
+            self.insert_target_rom(target_path, Arc::new(patch));
+        } else if target_rom_path.is_none() {
+            self.patch_unmatched(
+                patch_path,
+                Some((patch.source_size(), patch.source_checksum())),
+                format!("no source ROM was found (CRC32=0x{:08X})", patch.source_checksum()),
+            );
+        }
+    }
+
+    fn load_ips_patch(&mut self, patch_path: &Path) {
+        let source_path = match self.single_source_rom(patch_path) {
+            Some(source_path) => source_path,
+            None => return,
+        };
+
+        match IpsPatch::new(patch_path, &source_path, self.max_target_size) {
+            Ok(patch) => {
+                if let Some(title) = patch.ebp_field("title") {
+                    info!(
+                        "Loaded {:?}: {} by {}",
+                        patch_path,
+                        title,
+                        patch.ebp_field("author").unwrap_or("unknown author")
+                    );
+                }
+
+                let target_path = self.target_path(patch_path, &source_path);
+                self.insert_target_rom(target_path, Arc::new(patch));
+            }
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+            }
+        }
+    }
+
+    fn load_ppf_patch(&mut self, patch_path: &Path) {
+        let mut patch = match PpfPatch::new(patch_path) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+                return;
+            }
+        };
+
+        info!("Loaded {:?}: {}", patch_path, patch.description());
+
+        // Without a validation block there is nothing to tell the source images apart
+        let source_path = if patch.has_validation_block() {
+            match self.find_source_rom(|source| patch.verify_source(source).is_ok()) {
+                Some(source_path) => source_path,
+                None => {
+                    self.patch_unmatched(
+                        patch_path,
+                        None,
+                        "no source image matches its validation block".to_owned(),
+                    );
+                    return;
+                }
+            }
+        } else {
+            match self.single_source_rom(patch_path) {
+                Some(source_path) => source_path,
+                None => return,
+            }
+        };
+
+        if let Err(err) = patch.set_source_path(&source_path) {
+            self.patch_failed(patch_path, err);
+            return;
+        }
+
+        let target_path = self.target_path(patch_path, &source_path);
+        self.insert_target_rom(target_path, Arc::new(patch));
+    }
+
+    fn load_vcdiff_patch(&mut self, patch_path: &Path) {
+        let mut patch = match VcdiffPatch::new(patch_path) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+                return;
+            }
+        };
+
+        // VCDIFF carries no source checksum, only the source file name recorded by xdelta3
+        let named_source_path = patch.source_name().and_then(|source_name| {
+            self.source_roms
+                .values()
+                .find(|source_path| source_path.file_name() == Some(source_name))
+                .cloned()
+        });
+
+        let source_path = if let Some(source_path) = named_source_path {
+            source_path
+        } else {
+            match self.single_source_rom(patch_path) {
+                Some(source_path) => source_path,
+                None => return,
+            }
+        };
+
+        if let Err(err) = patch.set_source_path(&source_path) {
+            self.patch_failed(patch_path, err);
+            return;
+        }
+
+        let target_path = self.target_path(patch_path, &source_path);
+        self.insert_target_rom(target_path, Arc::new(patch));
+    }
+
+    // Mapped patches are placed like the ones of the patch directories, or in the root directory
+    // when they are elsewhere
+    fn load_mapped_patch(&mut self, mapping: &PatchMapping) {
+        if !mapping.patch_path.is_file() {
+            error!("Mapped patch {:?} does not exist", mapping.patch_path);
+            return;
+        }
+        if !mapping.source_path.is_file() {
+            error!(
+                "Source ROM {:?} mapped to {:?} does not exist",
+                mapping.source_path, mapping.patch_path
+            );
+            return;
+        }
+
+        let patch = match mapped_patch(
+            &mapping.patch_path,
+            &mapping.source_path,
+            mapping.verify,
+            self.max_target_size,
+        ) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.patch_failed(&mapping.patch_path, err);
+                return;
+            }
+        };
+
+        let mut target_path = if self.relative_patch_path(&mapping.patch_path).is_some() {
+            self.target_path(&mapping.patch_path, &mapping.source_path)
+        } else {
+            let mut target_path = PathBuf::from(mapping.patch_path.file_name().unwrap());
+            target_path.set_extension(mapping.source_path.extension().unwrap_or_default());
+            target_path
+        };
+        if let Some(target_name) = &mapping.target_name {
+            target_path.set_file_name(target_name);
+        }
+
+        self.insert_target_rom(target_path, patch);
+    }
+
+    fn load_configured_target(&mut self, index: usize, target: &TargetConfig) {
+        // The files were checked when loading the configuration, but may have disappeared since
+        if let Some(path) = iter::once(&target.source_path)
+            .chain(&target.patch_paths)
+            .find(|path| !path.is_file())
+        {
+            error!(
+                "File {:?} of the configured target ROM {:?} does not exist",
+                path, target.name
+            );
+            return;
+        }
+
+        let first_patch = match mapped_patch(
+            &target.patch_paths[0],
+            &target.source_path,
+            target.verify,
+            self.max_target_size,
+        ) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.patch_failed(&target.patch_paths[0], err);
+                return;
+            }
+        };
+
+        // Single patches are served as they are, unless their targets have to be checked
+        if target.patch_paths.len() == 1 && target.crc32.is_none() {
+            self.insert_target_rom(target.name.clone(), first_patch);
+            return;
+        }
+
+        let intermediate_directory = self
+            .extraction_directory
+            .join(CONFIGURED_DIRECTORY)
+            .join(index.to_string());
+        let mut patches = vec![first_patch];
+        let mut intermediate_paths = Vec::new();
+
+        for (index, patch_path) in target.patch_paths.iter().enumerate().skip(1) {
+            let intermediate_path = intermediate_directory.join(index.to_string());
+            match stacked_patch(patch_path, &intermediate_path) {
+                Ok(patch) => {
+                    patches.push(patch);
+                    intermediate_paths.push(intermediate_path);
+                }
+                Err(err) => {
+                    error!("Failed to load {:?} of {:?}: {}", patch_path, target.name, err);
+                    self.failed_patches.push(patch_path.clone());
+                    self.scan_progress.add_patch_failed();
+                    return;
+                }
+            }
+        }
+
+        match PatchChain::new(&target.config_path, patches, intermediate_paths) {
+            Ok(mut patch_chain) => {
+                if let Some(crc32) = target.crc32 {
+                    patch_chain.set_target_checksum(crc32);
+                }
+                self.insert_target_rom(target.name.clone(), Arc::new(patch_chain));
+            }
+            Err(err) => self.patch_failed(&target.config_path, err),
+        }
+    }
+
+    // The first patch of the chain is loaded as usual and finds the source ROM, the others take
+    // intermediate targets as their sources. Only formats not reading their sources while loading
+    // can be stacked.
+    fn load_patch_chain(&mut self, chain_path: &Path, patch_paths: &[PathBuf]) {
+        let first_patch = match self
+            .target_roms
+            .values()
+            .find(|patch| patch.patch_path() == patch_paths[0])
+        {
+            Some(patch) => patch.clone(),
+            None => {
+                warn!("No target ROM of {:?} was found for {:?}", patch_paths[0], chain_path);
+                return;
+            }
+        };
+        let source_path = match first_patch.source_path() {
+            Some(source_path) => source_path.to_owned(),
+            None => return,
+        };
+
+        let (index, relative_path) = self.relative_patch_path(chain_path).unwrap();
+        let chain_directory = self
+            .extraction_directory
+            .join(CHAINED_DIRECTORY)
+            .join(index.to_string())
+            .join(relative_path);
+        let mut patches = vec![first_patch];
+        let mut intermediate_paths = Vec::new();
+
+        for (index, patch_path) in patch_paths.iter().enumerate().skip(1) {
+            let intermediate_path = chain_directory.join(index.to_string());
+
+            match stacked_patch(patch_path, &intermediate_path) {
+                Ok(patch) => {
+                    patches.push(patch);
+                    intermediate_paths.push(intermediate_path);
+                }
+                Err(err) => {
+                    error!("Failed to load {:?} in {:?}: {}", patch_path, chain_path, err);
+                    self.failed_patches.push(patch_path.clone());
+                    self.scan_progress.add_patch_failed();
+                    return;
+                }
+            }
+        }
+
+        match PatchChain::new(chain_path, patches, intermediate_paths) {
+            Ok(patch_chain) => {
+                let target_path = self.target_path(chain_path, &source_path);
+                self.insert_target_rom(target_path, Arc::new(patch_chain));
+            }
+            Err(err) => self.patch_failed(chain_path, err),
+        }
+    }
+
+    fn load_rup_patch(&mut self, patch_path: &Path) {
+        let container = match RupContainer::new(patch_path) {
+            Ok(container) => container,
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+                return;
+            }
+        };
+
+        info!(
+            "Loaded {:?}: {} {} by {}",
+            patch_path, container.title, container.version, container.author
+        );
+        if !container.description.is_empty() {
+            info!("{}", container.description);
+        }
+
+        // Multi-file patches get a directory named after the patch, with the targets named after
+        // the original files
+        let multi_file = container.patches.len() > 1;
+
+        for mut patch in container.patches {
+            let source_path = match self.find_source_rom(|source| patch.verify_source(source).is_ok()) {
+                Some(source_path) => source_path,
+                None => {
+                    let reason = format!(
+                        "no source ROM was found matching the MD5 checksum of {:?}",
+                        patch.file_name()
+                    );
+                    self.patch_unmatched(patch_path, None, reason);
+                    continue;
+                }
+            };
+
+            patch.set_source_path(&source_path);
+
+            let target_path = if multi_file {
+                // Patches made on Windows keep the backslashes of the original paths
+                let file_name = patch.file_name().rsplit(['/', '\\']).next().unwrap_or_default();
+                let mut target_path = self.target_path(patch_path, &source_path).with_extension("");
+                target_path.push(file_name);
+                target_path.set_extension(source_path.extension().unwrap_or_default());
+                target_path
+            } else {
+                self.target_path(patch_path, &source_path)
+            };
+
+            self.insert_target_rom(target_path, Arc::new(patch));
+        }
+    }
+
+    fn load_star_rod_patch(&mut self, patch_path: &Path) {
+        let mut patch = match StarRodPatch::new(patch_path) {
+            Ok(patch) => patch,
+            Err(err) => {
+                self.patch_failed(patch_path, err);
+                return;
+            }
+        };
+
+        if let Some(source_path) = self.find_source_rom(|source| patch.verify_source(source).is_ok()) {
+            patch.set_source_path(&source_path);
+
+            let target_path = self.target_path(patch_path, &source_path);
+            self.insert_target_rom(target_path, Arc::new(patch));
+        } else {
+            self.patch_unmatched(patch_path, None, "no Paper Mario (USA) source ROM was found".to_owned());
+        }
+    }
+}
+
+// ROM managers of a single patch directory holding the source ROMs too, with the default options
+// changed by `configure` before refreshing again. The checksum index is kept out of the cache
+// directory of the user.
+#[cfg(test)]
+pub fn test_rom_manager(directory: &Path, configure: impl FnOnce(&mut RomManager)) -> RomManager {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        std::env::set_var(
+            "XDG_CACHE_HOME",
+            std::env::temp_dir().join(format!("softpatch-tests-{}", std::process::id())),
+        )
+    });
+
+    let mut rom_manager = RomManager::new(
+        &[directory.to_owned()],
+        None,
+        &[],
+        false,
+        BadSourcePolicy::Hide,
+        DEFAULT_MAX_TARGET_SIZE,
+        None,
+        None,
+        Vec::new(),
+        Vec::new(),
+        HeaderStripping::CopierExtensions,
+        false,
+        false,
+        Layout::Flat,
+        false,
+        false,
+        1,
+        Vec::new(),
+        Arc::new(ScanProgress::default()),
+        false,
+    )
+    .unwrap();
+    configure(&mut rom_manager);
+    rom_manager.remove_extracted_patches();
+    rom_manager.refresh().unwrap();
+    rom_manager
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::write_vlq;
+    use std::env;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|&pattern| pattern.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_no_patterns() {
+        assert!(patterns_include(&[], &[], "Hack/Hack v1.0.bps"));
+    }
+
+    #[test]
+    fn test_include_patterns() {
+        let include_patterns = patterns(&["*.bps", "*.ups"]);
+
+        assert!(patterns_include(&include_patterns, &[], "Hack v1.0.bps"));
+        assert!(patterns_include(&include_patterns, &[], "Hacks/Hack v1.0.ups"));
+        assert!(!patterns_include(&include_patterns, &[], "Hack v1.0.ips"));
+    }
+
+    #[test]
+    fn test_exclude_patterns() {
+        let exclude_patterns = patterns(&["*beta*"]);
+
+        assert!(patterns_include(&[], &exclude_patterns, "Hack v1.0.bps"));
+        assert!(!patterns_include(&[], &exclude_patterns, "Hack v1.1 beta.bps"));
+        assert!(!patterns_include(&[], &exclude_patterns, "beta/Hack v1.0.bps"));
+    }
+
+    #[test]
+    fn test_exclude_wins_over_include() {
+        let include_patterns = patterns(&["*.bps"]);
+        let exclude_patterns = patterns(&["*beta*"]);
+
+        assert!(patterns_include(&include_patterns, &exclude_patterns, "Hack v1.0.bps"));
+        assert!(!patterns_include(
+            &include_patterns,
+            &exclude_patterns,
+            "Hack v1.1 beta.bps"
+        ));
+        assert!(!patterns_include(
+            &include_patterns,
+            &exclude_patterns,
+            "Hack v1.1 beta.ips"
+        ));
+    }
+
+    const SOURCE: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+    // BPS patches replacing the last three bytes of the source
+    fn build_patch(source: &[u8], last_word: &[u8]) -> Vec<u8> {
+        let kept_size = source.len() as u64 - 3;
+        let mut target = source[..(kept_size as usize)].to_vec();
+        target.extend_from_slice(last_word);
+
+        let mut patch_data = b"BPS1".to_vec();
+        write_vlq(&mut patch_data, source.len() as u64);
+        write_vlq(&mut patch_data, target.len() as u64);
+        write_vlq(&mut patch_data, 0);
+        write_vlq(&mut patch_data, (kept_size - 1) << 2);
+        write_vlq(&mut patch_data, ((last_word.len() as u64 - 1) << 2) | 1);
+        patch_data.extend_from_slice(last_word);
+        patch_data.extend_from_slice(&crc32::checksum_ieee(source).to_le_bytes());
+        patch_data.extend_from_slice(&crc32::checksum_ieee(&target).to_le_bytes());
+        let patch_checksum = crc32::checksum_ieee(&patch_data);
+        patch_data.extend_from_slice(&patch_checksum.to_le_bytes());
+        patch_data
+    }
+
+    // Zip archives of stored members
+    fn build_zip(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip_data = Vec::new();
+        let mut central_directory = Vec::new();
+
+        for (name, data) in members {
+            let mut header = Vec::new();
+            header.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+            header.extend_from_slice(&crc32::checksum_ieee(data).to_le_bytes());
+            header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&[0, 0]);
+
+            central_directory.extend_from_slice(&[b'P', b'K', 0x01, 0x02, 20, 0]);
+            central_directory.extend_from_slice(&header);
+            central_directory.extend_from_slice(&[0; 10]);
+            central_directory.extend_from_slice(&(zip_data.len() as u32).to_le_bytes());
+            central_directory.extend_from_slice(name.as_bytes());
+
+            zip_data.extend_from_slice(&[b'P', b'K', 0x03, 0x04]);
+            zip_data.extend_from_slice(&header);
+            zip_data.extend_from_slice(name.as_bytes());
+            zip_data.extend_from_slice(data);
+        }
+
+        let directory_offset = zip_data.len() as u32;
+        zip_data.extend_from_slice(&central_directory);
+        zip_data.extend_from_slice(&[b'P', b'K', 0x05, 0x06, 0, 0, 0, 0]);
+        zip_data.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        zip_data.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        zip_data.extend_from_slice(&(central_directory.len() as u32).to_le_bytes());
+        zip_data.extend_from_slice(&directory_offset.to_le_bytes());
+        zip_data.extend_from_slice(&[0, 0]);
+        zip_data
+    }
+
+    // Patch directories with the given files, removed when dropped along with the extracted
+    // patches
+    struct TestDirectory {
+        directory: PathBuf,
+        rom_manager: RomManager,
+    }
+
+    impl TestDirectory {
+        fn new(name: &str, files: &[(&str, &[u8])]) -> Self {
+            Self::with_rom_manager(name, files, |_| {})
+        }
+
+        fn with_rom_manager(name: &str, files: &[(&str, &[u8])], configure: impl FnOnce(&mut RomManager)) -> Self {
+            let directory = env::temp_dir().join(format!("rom-manager-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&directory).unwrap();
+            for (file_name, data) in files {
+                fs::write(directory.join(file_name), data).unwrap();
+            }
+
+            let rom_manager = test_rom_manager(&directory, configure);
+            Self { directory, rom_manager }
+        }
+
+        fn target_paths(&self) -> Vec<&Path> {
+            let mut target_paths: Vec<&Path> = self.rom_manager.target_roms.keys().map(PathBuf::as_path).collect();
+            target_paths.sort();
+            target_paths
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            self.rom_manager.remove_extracted_patches();
+            let _ = fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    #[test]
+    fn test_archive_of_patches() {
+        let zip_data = build_zip(&[
+            ("Hack A.bps", &build_patch(SOURCE, b"cat")),
+            ("Hack B.bps", &build_patch(SOURCE, b"cow")),
+        ]);
+        let directory = TestDirectory::new("archive", &[("Game.sfc", SOURCE), ("Hacks.zip", &zip_data)]);
+
+        assert_eq!(
+            directory.target_paths(),
+            [Path::new("Hacks/Hack A.sfc"), Path::new("Hacks/Hack B.sfc")]
+        );
+        assert!(directory.rom_manager.failed_patches.is_empty());
+    }
+
+    #[test]
+    fn test_archive_without_patches() {
+        let zip_data = build_zip(&[("README.txt", b"Patch the ROM with Floating IPS")]);
+        let directory = TestDirectory::new("readme-archive", &[("Game.sfc", SOURCE), ("Readme.zip", &zip_data)]);
+
+        assert!(directory.target_paths().is_empty());
+        assert!(directory.rom_manager.failed_patches.is_empty());
+        assert!(directory.rom_manager.unmatched_patches.is_empty());
+    }
+
+    // Sources of the size of a ROM bank, the only size telling copier headers apart
+    fn bank_source() -> Vec<u8> {
+        let mut source = vec![0; 1024 - SOURCE.len()];
+        source.extend_from_slice(SOURCE);
+        source
+    }
+
+    fn stripped_targets(source_name: &str, header_stripping: HeaderStripping) -> Vec<Vec<u8>> {
+        let mut headered_source = vec![0xFF; COPIER_HEADER_SIZE];
+        headered_source.extend_from_slice(&bank_source());
+        let directory = TestDirectory::with_rom_manager(
+            &format!("strip-header-{}-{:?}", source_name, header_stripping),
+            &[
+                (source_name, &headered_source),
+                ("Hack.bps", &build_patch(&bank_source(), b"cat")),
+            ],
+            |rom_manager| rom_manager.header_stripping = header_stripping,
+        );
+
+        directory
+            .rom_manager
+            .target_roms
+            .values()
+            .map(|patch| patch.patched_rom().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_strip_copier_header() {
+        let mut target = bank_source();
+        target.truncate(target.len() - 3);
+        target.extend_from_slice(b"cat");
+
+        // Only matching once the header is stripped
+        for (source_name, header_stripping) in &[
+            ("Game.smc", HeaderStripping::CopierExtensions),
+            ("Game.smc", HeaderStripping::Always),
+            ("Game.sfc", HeaderStripping::Always),
+        ] {
+            assert_eq!(stripped_targets(source_name, *header_stripping), [&target[..]]);
+        }
+    }
+
+    #[test]
+    fn test_keep_copier_header() {
+        assert!(stripped_targets("Game.sfc", HeaderStripping::CopierExtensions).is_empty());
+        assert!(stripped_targets("Game.smc", HeaderStripping::Never).is_empty());
+    }
 }
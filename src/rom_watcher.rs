@@ -1,51 +1,133 @@
+use std::fs;
 use std::io;
-use std::sync::{Arc, Mutex};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
 
-use inotify::{EventMask, Inotify, WatchMask};
-use log::error;
+use inotify::{EventMask, Events, Inotify, WatchMask};
+use log::{error, info};
 
+use crate::logging::Event;
 use crate::rom_manager::RomManager;
 
+const DEBOUNCE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Inotify watches are not recursive, every subdirectory needs its own watch
+fn add_watches(inotify: &mut Inotify, directory: &Path) -> io::Result<()> {
+    inotify.add_watch(directory, WatchMask::ALL_EVENTS)?;
+
+    for entry in fs::read_dir(directory)?.filter_map(Result::ok) {
+        if entry.file_type()?.is_dir() {
+            add_watches(inotify, &entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn add_rom_manager_watches(inotify: &mut Inotify, rom_manager: &RomManager) -> io::Result<()> {
+    for base_directory in &rom_manager.base_directories {
+        add_watches(inotify, base_directory)?;
+    }
+    if !rom_manager.base_directories.contains(&rom_manager.source_directory) {
+        add_watches(inotify, &rom_manager.source_directory)?;
+    }
+    for rom_directory in &rom_manager.rom_directories {
+        add_watches(inotify, rom_directory)?;
+    }
+    Ok(())
+}
+
+// Refreshes a copy of the ROM manager and swaps it in once complete, the filesystem keeps
+// serving the previous state meanwhile. Failed refreshes keep the previous state as well.
+// Patches are extracted from archives into a directory of the refresh, the files of the state
+// losing out are removed.
+pub fn refresh_roms(rom_manager: &RwLock<RomManager>) {
+    let mut refreshed_rom_manager = rom_manager.read().unwrap().clone();
+
+    match refreshed_rom_manager.refresh() {
+        Ok(new_target_count) => {
+            let previous_rom_manager = mem::replace(&mut *rom_manager.write().unwrap(), refreshed_rom_manager);
+            previous_rom_manager.remove_extracted_patches();
+            if new_target_count > 0 {
+                info!("Found {} new target ROMs", new_target_count);
+            }
+        }
+        Err(err) => {
+            refreshed_rom_manager.remove_extracted_patches();
+            Event::new("scan")
+                .error(&err)
+                .log(|| error!("Failed to refresh ROMs: {}", err));
+        }
+    }
+}
+
+#[derive(Default)]
+struct WatchedChanges {
+    changed: bool,
+    new_directory: bool,
+}
+
+impl WatchedChanges {
+    // Returns whether there were any events at all
+    fn add_events(&mut self, events: Events) -> bool {
+        let mut any_events = false;
+
+        for event in events {
+            any_events = true;
+            self.changed |= event.mask.contains(EventMask::MOVED_FROM);
+            self.changed |= event.mask.contains(EventMask::MOVED_TO);
+            self.changed |= event.mask.contains(EventMask::DELETE);
+            self.changed |= event.mask.contains(EventMask::CLOSE_WRITE);
+            self.new_directory |= event.mask.contains(EventMask::ISDIR)
+                && (event.mask.contains(EventMask::CREATE) || event.mask.contains(EventMask::MOVED_TO));
+        }
+
+        any_events
+    }
+}
+
 pub struct RomWatcher {
     #[allow(dead_code)]
     inotify: Arc<Mutex<Inotify>>,
 }
 
 impl RomWatcher {
-    pub fn new(rom_manager: Arc<Mutex<RomManager>>) -> io::Result<Self> {
+    pub fn new(rom_manager: Arc<RwLock<RomManager>>) -> io::Result<Self> {
         let inotify = Arc::new(Mutex::new(Inotify::init()?));
 
-        {
-            let rom_manager = rom_manager.lock().unwrap();
-            let base_directory = rom_manager.base_directory.as_path();
-            inotify
-                .lock()
-                .unwrap()
-                .add_watch(base_directory, WatchMask::ALL_EVENTS)?;
-        }
+        add_rom_manager_watches(&mut inotify.lock().unwrap(), &rom_manager.read().unwrap())?;
 
         {
             let inotify = inotify.clone();
             thread::spawn(move || {
                 let mut buffer = [0; 4096];
                 loop {
-                    let events = inotify.lock().unwrap().read_events_blocking(&mut buffer).unwrap();
+                    let mut changes = WatchedChanges::default();
+                    changes.add_events(inotify.lock().unwrap().read_events_blocking(&mut buffer).unwrap());
 
-                    let mut changed = false;
-
-                    for event in events {
-                        changed |= event.mask.contains(EventMask::MOVED_FROM);
-                        changed |= event.mask.contains(EventMask::MOVED_TO);
-                        changed |= event.mask.contains(EventMask::DELETE);
-                        changed |= event.mask.contains(EventMask::CLOSE_WRITE);
+                    // Editors and downloads write files in several steps, the refresh waits until
+                    // the events settle
+                    loop {
+                        thread::sleep(DEBOUNCE_INTERVAL);
+                        if !changes.add_events(inotify.lock().unwrap().read_events(&mut buffer).unwrap()) {
+                            break;
+                        }
                     }
 
-                    if changed {
-                        if let Err(err) = rom_manager.lock().unwrap().refresh() {
-                            error!("Failed to refresh ROMs: {}", err);
+                    if changes.new_directory {
+                        if let Err(err) =
+                            add_rom_manager_watches(&mut inotify.lock().unwrap(), &rom_manager.read().unwrap())
+                        {
+                            error!("Failed to watch new directories: {}", err);
                         }
                     }
+
+                    if changes.changed || changes.new_directory {
+                        refresh_roms(&rom_manager);
+                    }
                 }
             });
         }
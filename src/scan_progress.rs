@@ -0,0 +1,163 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Terminals get a single line redrawn in place, logs get a line every few seconds
+const TERMINAL_INTERVAL: Duration = Duration::from_millis(200);
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+// Counters of the current scan, updated by the scanning threads and observed by the reporter
+#[derive(Default)]
+pub struct ScanProgress {
+    files_discovered: AtomicU64,
+    files_hashed: AtomicU64,
+    bytes_hashed: AtomicU64,
+    patches_matched: AtomicU64,
+    patches_unmatched: AtomicU64,
+    // Unmatched as well
+    patches_failed: AtomicU64,
+}
+
+impl ScanProgress {
+    pub fn reset(&self) {
+        for counter in self.counters() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_files_discovered(&self, count: u64) {
+        self.files_discovered.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_file_hashed(&self, bytes: u64) {
+        self.files_hashed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_hashed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_patch_matched(&self) {
+        self.patches_matched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_patch_unmatched(&self) {
+        self.patches_unmatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_patch_failed(&self) {
+        self.patches_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counters(&self) -> [&AtomicU64; 6] {
+        [
+            &self.files_discovered,
+            &self.files_hashed,
+            &self.bytes_hashed,
+            &self.patches_matched,
+            &self.patches_unmatched,
+            &self.patches_failed,
+        ]
+    }
+
+    fn render_line(&self) -> String {
+        format!(
+            "Scanning: {} files discovered, {} hashed ({}), {} patches matched, {} unmatched",
+            self.files_discovered.load(Ordering::Relaxed),
+            self.files_hashed.load(Ordering::Relaxed),
+            format_bytes(self.bytes_hashed.load(Ordering::Relaxed)),
+            self.patches_matched.load(Ordering::Relaxed),
+            self.patches_unmatched.load(Ordering::Relaxed),
+        )
+    }
+
+    fn render_summary(&self, target_count: usize, elapsed: Duration) -> String {
+        format!(
+            "Scan summary:\n  Files discovered   {}\n  Files hashed       {} ({})\n  Patches loaded     {}\n  \
+             Patches skipped    {} ({} failed to load)\n  Target ROMs        {}\n  Scan time          {:.1}s\n",
+            self.files_discovered.load(Ordering::Relaxed),
+            self.files_hashed.load(Ordering::Relaxed),
+            format_bytes(self.bytes_hashed.load(Ordering::Relaxed)),
+            self.patches_matched.load(Ordering::Relaxed),
+            self.patches_unmatched.load(Ordering::Relaxed),
+            self.patches_failed.load(Ordering::Relaxed),
+            target_count,
+            elapsed.as_secs_f64(),
+        )
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// Reports the progress to stderr until finished, the summary is printed at the end
+pub struct ProgressReporter {
+    progress: Arc<ScanProgress>,
+    started: Instant,
+    finished: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl ProgressReporter {
+    pub fn new(progress: &Arc<ScanProgress>) -> Self {
+        let terminal = unsafe { libc::isatty(libc::STDERR_FILENO) } == 1;
+        let interval = if terminal { TERMINAL_INTERVAL } else { LOG_INTERVAL };
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let progress = progress.clone();
+            let finished = finished.clone();
+            thread::spawn(move || {
+                let mut reported = Instant::now();
+                while !finished.load(Ordering::Relaxed) {
+                    thread::park_timeout(interval.saturating_sub(reported.elapsed()));
+                    if finished.load(Ordering::Relaxed) || reported.elapsed() < interval {
+                        continue;
+                    }
+                    reported = Instant::now();
+
+                    let mut stderr = io::stderr().lock();
+                    if terminal {
+                        let _ = write!(stderr, "\r\x1b[K{}", progress.render_line());
+                    } else {
+                        let _ = writeln!(stderr, "{}", progress.render_line());
+                    }
+                    let _ = stderr.flush();
+                }
+
+                // The progress line gives way to the summary
+                if terminal {
+                    let _ = write!(io::stderr(), "\r\x1b[K");
+                }
+            })
+        };
+
+        Self {
+            progress: progress.clone(),
+            started: Instant::now(),
+            finished,
+            thread,
+        }
+    }
+
+    pub fn finish(self, target_count: usize) {
+        self.finished.store(true, Ordering::Relaxed);
+        self.thread.thread().unpark();
+        let _ = self.thread.join();
+
+        eprint!("{}", self.progress.render_summary(target_count, self.started.elapsed()));
+    }
+}
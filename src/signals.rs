@@ -0,0 +1,63 @@
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use log::{error, info};
+
+use crate::rom_manager::RomManager;
+use crate::rom_watcher;
+
+// Unmounting ends the session the same way an external unmount does, the cleanup after the
+// session runs as usual
+fn unmount(mount_point: &Path) {
+    match Command::new("fusermount").arg("-u").arg(mount_point).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => error!("Failed to unmount {:?}: fusermount exited with {}", mount_point, status),
+        Err(err) => error!("Failed to unmount {:?}: {}", mount_point, err),
+    }
+}
+
+// The signals are blocked in every thread and received by a dedicated one instead, so this has
+// to be called before spawning any other threads. SIGUSR1 refreshes the ROMs the same way the
+// watcher does, handles of the targets gone keep their patches until released.
+pub fn handle_signals(mount_point: &Path, rom_manager: Arc<RwLock<RomManager>>) -> io::Result<()> {
+    let mount_point: PathBuf = mount_point.to_owned();
+
+    let signals = unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGUSR1);
+
+        let result = libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+
+        signals
+    };
+
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            continue;
+        }
+
+        if signal == libc::SIGUSR1 {
+            info!("Received signal {}, refreshing ROMs", signal);
+            rom_watcher::refresh_roms(&rom_manager);
+            continue;
+        }
+
+        // Busy filesystems stay mounted, signalling again retries
+        info!("Received signal {}, unmounting {:?}", signal, mount_point);
+        unmount(&mount_point);
+    });
+
+    Ok(())
+}
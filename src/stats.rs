@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::json::JsonValue;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+}
+
+// Values sampled from the ROM manager and the cache along with the counters
+pub struct Gauges<'a> {
+    pub target_count: usize,
+    pub open_handles: usize,
+    pub cache_entries: usize,
+    pub cache_bytes: u64,
+    pub format_counts: &'a BTreeMap<&'static str, usize>,
+}
+
+// Counters of the filesystem since mounting, updated without taking any locks
+pub struct Stats {
+    started: Instant,
+    patches_applied: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    bytes_served: AtomicU64,
+    patching_nanos: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            patches_applied: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            patching_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Stats {
+    pub fn add_patching_time(&self, duration: Duration) {
+        self.patching_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_patch_applied(&self) {
+        self.patches_applied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_served(&self, bytes: u64) {
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Every value is loaded once, both formats report the same snapshot
+    pub fn render(&self, format: StatsFormat, gauges: &Gauges) -> String {
+        let uptime = self.started.elapsed().as_secs_f64();
+        let patching_time = Duration::from_nanos(self.patching_nanos.load(Ordering::Relaxed)).as_secs_f64();
+        let counters = [
+            ("targets", gauges.target_count as u64),
+            ("open_handles", gauges.open_handles as u64),
+            ("patches_applied", self.patches_applied.load(Ordering::Relaxed)),
+            ("cache_entries", gauges.cache_entries as u64),
+            ("cache_bytes", gauges.cache_bytes),
+            ("cache_hits", self.cache_hits.load(Ordering::Relaxed)),
+            ("cache_misses", self.cache_misses.load(Ordering::Relaxed)),
+            ("bytes_served", self.bytes_served.load(Ordering::Relaxed)),
+        ];
+
+        match format {
+            StatsFormat::Text => {
+                let mut result = format!("uptime: {:.3}s\n", uptime);
+                for (name, value) in &counters {
+                    result += &format!("{}: {}\n", name, value);
+                }
+                result += &format!("patching_time: {:.3}s\n", patching_time);
+                for (format_name, count) in gauges.format_counts {
+                    result += &format!("targets.{}: {}\n", format_name, count);
+                }
+                result
+            }
+            StatsFormat::Json => {
+                let mut members: HashMap<String, JsonValue> = counters
+                    .iter()
+                    .map(|&(name, value)| (name.to_owned(), JsonValue::Number(value as f64)))
+                    .collect();
+                members.insert("uptime".to_owned(), JsonValue::Number(uptime));
+                members.insert("patching_time".to_owned(), JsonValue::Number(patching_time));
+                members.insert(
+                    "formats".to_owned(),
+                    JsonValue::Object(
+                        gauges
+                            .format_counts
+                            .iter()
+                            .map(|(&format_name, &count)| (format_name.to_owned(), JsonValue::Number(count as f64)))
+                            .collect(),
+                    ),
+                );
+                format!("{}\n", JsonValue::Object(members))
+            }
+        }
+    }
+}
@@ -1,19 +1,56 @@
+use std::env;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::File;
 use std::io::{self, Read};
+use std::ops::Deref;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
 use byteorder::ReadBytesExt;
+use memmap2::Mmap;
+
+#[derive(Debug)]
+pub struct VlqOverflowError;
+
+impl fmt::Display for VlqOverflowError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "variable-length integer overflow")
+    }
+}
+
+impl Error for VlqOverflowError {}
+
+// 64-bit values take at most 10 bytes of 7 bits
+const VLQ_MAX_LENGTH: usize = 10;
 
 pub trait ReadExt: Read {
+    // Values not fitting in 64 bits are reported as `VlqOverflowError`s wrapped in `InvalidData`
+    // errors, and cannot run on endlessly
     fn read_vlq(&mut self) -> io::Result<u64> {
-        let mut data = 0;
-        let mut shift = 1;
-        loop {
+        let overflow = || io::Error::new(io::ErrorKind::InvalidData, VlqOverflowError);
+
+        let mut data: u64 = 0;
+        let mut shift: u64 = 1;
+        for length in 1.. {
+            if length > VLQ_MAX_LENGTH {
+                return Err(overflow());
+            }
+
             let x = self.read_u8()?;
-            data += ((x as u64) & 0x7F) * shift;
+            data = ((x as u64) & 0x7F)
+                .checked_mul(shift)
+                .and_then(|value| data.checked_add(value))
+                .ok_or_else(overflow)?;
             if x & 0x80 != 0 {
                 break;
             }
-            shift <<= 7;
-            data += shift;
+            shift = shift.checked_mul(0x80).ok_or_else(overflow)?;
+            data = data.checked_add(shift).ok_or_else(overflow)?;
         }
         Ok(data)
     }
@@ -31,3 +68,250 @@ pub trait ReadExt: Read {
 }
 
 impl<T> ReadExt for T where T: Read {}
+
+// The encoding read by `read_vlq`, for building patches in the tests
+#[cfg(test)]
+pub fn write_vlq(output: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let x = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            output.push(x | 0x80);
+            return;
+        }
+        output.push(x);
+        value -= 1;
+    }
+}
+
+// Directories with random names and mode 0700 in $XDG_RUNTIME_DIR, or in the temporary directory
+// without it, so no other user can guess their names or write into them
+pub fn create_private_directory(prefix: &str) -> io::Result<PathBuf> {
+    let parent = env::var_os("XDG_RUNTIME_DIR").map_or_else(env::temp_dir, PathBuf::from);
+
+    let mut template = parent.join(format!("{}-XXXXXX", prefix)).into_os_string().into_vec();
+    template.push(0);
+    if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    template.pop();
+
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+// File names are raw bytes, they are not necessarily valid UTF-8
+pub fn strip_name_suffix<'a>(name: &'a OsStr, suffix: &str) -> Option<&'a OsStr> {
+    name.as_bytes().strip_suffix(suffix.as_bytes()).map(OsStr::from_bytes)
+}
+
+// Names coming from patch files: path separators are replaced, control characters are dropped
+// and leading dots are stripped so they never turn into hidden files. Missing when nothing is
+// left of the name.
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
+    let name = name.trim().trim_start_matches('.').trim_start();
+
+    // Room is left for the extensions within the usual limit of 255 bytes
+    let mut end = name.len().min(200);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let name = name[..end].trim_end();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+// Maps the items on up to `thread_count` threads, the results are in the order of the items
+// whatever order the threads finish them in
+pub fn parallel_map<T: Sync, R: Send>(items: &[T], thread_count: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let thread_count = thread_count.clamp(1, items.len().max(1));
+    if thread_count == 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next_index = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<Option<R>>>());
+
+    thread::scope(|scope| {
+        for _ in 0..thread_count {
+            scope.spawn(|| loop {
+                let index = next_index.fetch_add(1, Ordering::Relaxed);
+                if index >= items.len() {
+                    break;
+                }
+                let result = f(&items[index]);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+
+    results.into_inner().unwrap().into_iter().map(Option::unwrap).collect()
+}
+
+// Shell-style wildcards, `*` matching any run of characters and `?` any single character.
+// Path separators are not treated specially, `*.bps` matches patches in subdirectories too.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last star and the text it is matching up to, for backtracking
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            star = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Read-only view of a file paged in on demand by the OS instead of read into memory upfront
+pub enum MappedFile {
+    Mapped(Mmap),
+    // Zero-length files cannot be mapped on every platform
+    Empty,
+}
+
+impl MappedFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(MappedFile::Empty);
+        }
+
+        // Safety: the mapping is only ever read, modifying the source ROM while it's being
+        // patched is a user error the checksums of the patch formats are meant to catch.
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(MappedFile::Mapped(mmap))
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            MappedFile::Mapped(mmap) => mmap,
+            MappedFile::Empty => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn test_vlq_round_trip() {
+        for &value in &[
+            0,
+            1,
+            0x7F,
+            0x80,
+            0x407F,
+            0x4080,
+            u32::MAX as u64,
+            u64::MAX - 1,
+            u64::MAX,
+        ] {
+            let mut data = Vec::new();
+            write_vlq(&mut data, value);
+            assert!(data.len() <= VLQ_MAX_LENGTH);
+            assert_eq!(Cursor::new(&data).read_vlq().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_vlq_overflow() {
+        // Eleven bytes without the final byte marker
+        let err = Cursor::new(&[0x00; 11]).read_vlq().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.get_ref().unwrap().is::<VlqOverflowError>());
+
+        // Ten bytes, but past the largest 64-bit value
+        let err = Cursor::new(&[0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0x81])
+            .read_vlq()
+            .unwrap_err();
+        assert!(err.get_ref().unwrap().is::<VlqOverflowError>());
+    }
+
+    #[test]
+    fn test_truncated_vlq() {
+        let err = Cursor::new(&[0x00, 0x00]).read_vlq().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_signed_vlq() {
+        assert_eq!(Cursor::new(&[0x84]).read_signed_vlq().unwrap(), 2);
+        assert_eq!(Cursor::new(&[0x85]).read_signed_vlq().unwrap(), -2);
+    }
+
+    #[test]
+    fn test_glob_literal() {
+        assert!(glob_matches("Hack.bps", "Hack.bps"));
+        assert!(!glob_matches("Hack.bps", "Hack.ups"));
+        assert!(!glob_matches("Hack.bps", "Hack.bps.bak"));
+        assert!(!glob_matches("Hack.bps", "Hack"));
+    }
+
+    #[test]
+    fn test_glob_star() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*.bps", "Hack v1.0.bps"));
+        assert!(glob_matches("*beta*", "Hack beta 2.bps"));
+        assert!(glob_matches("*beta*", "beta"));
+        assert!(!glob_matches("*beta*", "Hack v1.0.bps"));
+        // Path separators are matched like any other character
+        assert!(glob_matches("*.bps", "Hacks/Hack.bps"));
+        // Backtracking past an earlier partial match
+        assert!(glob_matches("*ab*ab", "xabyabab"));
+        assert!(!glob_matches("*ab*ab", "xabyaba"));
+    }
+
+    #[test]
+    fn test_glob_question_mark() {
+        assert!(glob_matches("Hack v1.?.bps", "Hack v1.2.bps"));
+        assert!(!glob_matches("Hack v1.?.bps", "Hack v1.10.bps"));
+        assert!(glob_matches("?*", "x"));
+        assert!(!glob_matches("?*", ""));
+    }
+
+    #[test]
+    fn test_private_directory() {
+        let first_directory = create_private_directory("softpatch-test").unwrap();
+        let second_directory = create_private_directory("softpatch-test").unwrap();
+        assert_ne!(first_directory, second_directory);
+
+        let metadata = fs::metadata(&first_directory).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+
+        fs::remove_dir(&first_directory).unwrap();
+        fs::remove_dir(&second_directory).unwrap();
+    }
+}
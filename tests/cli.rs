@@ -0,0 +1,66 @@
+use std::env;
+use std::fs;
+use std::process::{Command, Output};
+
+fn bps_fuse(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bps-fuse"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_help() {
+    let output = bps_fuse(&["--help"]);
+    assert!(output.status.success());
+
+    let usage = String::from_utf8(output.stdout).unwrap();
+    assert!(usage.starts_with("Usage: "));
+    for flag in &[
+        "--source-dir",
+        "--rom-dir",
+        "--patch-dir",
+        "--mountpoint",
+        "--watch",
+        "--no-watch",
+        "--config",
+        "--map",
+        "--include",
+        "--exclude",
+        "--cache-size",
+        "--cache-dir",
+        "--read-ahead",
+        "--dry-run",
+        "--show-stats",
+        "--layout",
+        "--on-bad-source",
+        "--max-target-size",
+        "--verify-only",
+        "--verbose",
+        "--quiet",
+        "--log-format",
+        "--help",
+    ] {
+        assert!(usage.contains(&format!("{} ", flag)), "{} is not documented", flag);
+    }
+}
+
+#[test]
+fn test_missing_mountpoint() {
+    let patch_directory = env::temp_dir().join(format!("cli-missing-mountpoint-{}", std::process::id()));
+    fs::create_dir_all(&patch_directory).unwrap();
+    let output = bps_fuse(&[patch_directory.to_str().unwrap()]);
+    fs::remove_dir_all(&patch_directory).unwrap();
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("Usage: "));
+}
+
+#[test]
+fn test_unknown_flag() {
+    let output = bps_fuse(&["--no-such-flag"]);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("Usage: "));
+}